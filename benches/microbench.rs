@@ -0,0 +1,740 @@
+use criterion::{
+    criterion_group,
+    criterion_main,
+    BenchmarkGroup,
+    BenchmarkId,
+    Criterion,
+    Throughput,
+    measurement::WallTime,
+};
+use parallel_histograms::{
+    impls::*,
+    thread_id::*,
+    traits::*,
+};
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro128Plus;
+use rayon::prelude::*;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+        RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Parameters of the benchmarks are configured here
+const RNG_SEED: [u8; 16] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0,
+                            0x0f, 0xed, 0xcb, 0xa9, 0x87, 0x56, 0x43, 0x21];
+
+// Every strategy is measured in the scenarios discussed in the results
+#[derive(Clone, Copy)]
+struct Scenario {
+    num_bins: usize,
+    batch_size: usize,
+    num_buckets: usize,
+}
+
+const SCENARIOS: [Scenario; 3] = [
+    // Extremely pessimistic scenario
+    Scenario { num_bins: 1, batch_size: 1, num_buckets: 1 },
+    // More realistic scenario
+    Scenario { num_bins: 1000, batch_size: 100, num_buckets: 2 },
+    // Extremely optimistic scenario
+    Scenario { num_bins: 10000, batch_size: 10000, num_buckets: 8 },
+];
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bins={}/batch={}/buckets={}",
+               self.num_bins, self.batch_size, self.num_buckets)
+    }
+}
+
+// Generate a bunch of random numbers
+#[inline(never)]
+fn gen_input<'a>(rng: &mut impl rand::Rng,
+                 buf: &'a mut Vec<f32>,
+                 batch_size: usize) -> &'a [f32] {
+    buf.clear();
+    for _ in 0..batch_size {
+        buf.push(rng.gen())
+    }
+    &buf[..]
+}
+
+// Fill the histogram sequentially with `iters` batches of random numbers
+fn sequential_microbench(histogram: &mut impl Histogram,
+                         batch_size: usize,
+                         iters: u64) -> Duration {
+    let id = ThreadID::load();
+    let mut rng = Xoshiro128Plus::from_seed(RNG_SEED);
+    let mut buf = Vec::with_capacity(batch_size);
+    let start = Instant::now();
+    for _ in 0..iters {
+        histogram.fill_with_id_mut(gen_input(&mut rng, &mut buf, batch_size), id);
+    }
+    histogram.flush_mut();
+    let duration = start.elapsed();
+    assert_eq!(histogram.num_hits() as u64, iters * batch_size as u64);
+    duration
+}
+
+// Fill the histogram in parallel with `iters` batches of random numbers
+fn parallel_microbench(histogram: &impl SyncHistogram,
+                       batch_size: usize,
+                       iters: u64) -> Duration {
+    let rng = Mutex::new(Xoshiro128Plus::from_seed(RNG_SEED));
+    let start = Instant::now();
+    (0..iters)
+        .into_par_iter()
+        .for_each_init(
+            || {
+                let mut rng_lock = rng.lock().unwrap();
+                let thread_rng = rng_lock.clone();
+                rng_lock.jump();
+                (thread_rng, ThreadID::load(), Vec::with_capacity(batch_size))
+            },
+            |(rng, id, buf), _| histogram.fill_with_id(gen_input(rng, buf, batch_size), *id)
+        );
+    histogram.flush();
+    let duration = start.elapsed();
+    assert_eq!(histogram.num_hits() as u64, iters * batch_size as u64);
+    duration
+}
+
+// Fill the histogram in parallel with `iters` batches where every value falls
+// into the same bin, which is the worst case for contention
+fn contention_microbench(histogram: &impl SyncHistogram,
+                         batch_size: usize,
+                         iters: u64) -> Duration {
+    let batch = vec![0.5; batch_size];
+    let start = Instant::now();
+    (0..iters)
+        .into_par_iter()
+        .for_each_init(
+            ThreadID::load,
+            |id, _| histogram.fill_with_id(&batch, *id)
+        );
+    histogram.flush();
+    let duration = start.elapsed();
+    assert_eq!(histogram.num_hits() as u64, iters * batch_size as u64);
+    duration
+}
+
+// Measure sequential filling of a histogram built by `make_histogram`
+fn bench_sequential<H: Histogram>(group: &mut BenchmarkGroup<WallTime>,
+                                  make_histogram: impl Fn(Scenario) -> H) {
+    for &scenario in SCENARIOS.iter() {
+        group.throughput(Throughput::Elements(scenario.batch_size as u64));
+        group.bench_with_input(
+            BenchmarkId::new("sequential", scenario),
+            &scenario,
+            |b, &scenario| b.iter_custom(|iters| {
+                let mut histogram = make_histogram(scenario);
+                sequential_microbench(&mut histogram, scenario.batch_size, iters)
+            })
+        );
+    }
+}
+
+// Measure parallel filling of a histogram built by `make_histogram`
+fn bench_parallel<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                                    make_histogram: impl Fn(Scenario) -> H) {
+    bench_parallel_named(group, "parallel", make_histogram)
+}
+
+// Same, for groups which compare several histograms
+fn bench_parallel_named<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                                          name: &str,
+                                          make_histogram: impl Fn(Scenario) -> H) {
+    for &scenario in SCENARIOS.iter() {
+        group.throughput(Throughput::Elements(scenario.batch_size as u64));
+        group.bench_with_input(
+            BenchmarkId::new(name, scenario),
+            &scenario,
+            |b, &scenario| b.iter_custom(|iters| {
+                let histogram = make_histogram(scenario);
+                parallel_microbench(&histogram, scenario.batch_size, iters)
+            })
+        );
+    }
+}
+
+// Measure worst-case contention on a histogram built by `make_histogram`
+fn bench_contention<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                                      name: &str,
+                                      make_histogram: impl Fn(Scenario) -> H) {
+    for &scenario in SCENARIOS.iter() {
+        group.throughput(Throughput::Elements(scenario.batch_size as u64));
+        group.bench_with_input(
+            BenchmarkId::new(name, scenario),
+            &scenario,
+            |b, &scenario| b.iter_custom(|iters| {
+                let histogram = make_histogram(scenario);
+                contention_microbench(&histogram, scenario.batch_size, iters)
+            })
+        );
+    }
+}
+
+fn raw(c: &mut Criterion) {
+    let mut group = c.benchmark_group("raw");
+    bench_sequential(&mut group, |s| ToyHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn atomic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("atomic");
+    bench_sequential(&mut group, |s| AtomicHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| AtomicHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn padded_atomic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("padded_atomic");
+    bench_sequential(&mut group, |s| PaddedAtomicHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| PaddedAtomicHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn atomic_u16(c: &mut Criterion) {
+    let mut group = c.benchmark_group("atomic_u16");
+    bench_sequential(&mut group, |s| AtomicU16Histogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| AtomicU16Histogram::new(s.num_bins));
+    group.finish();
+}
+
+fn atomic_u32(c: &mut Criterion) {
+    let mut group = c.benchmark_group("atomic_u32");
+    bench_sequential(&mut group, |s| AtomicU32Histogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| AtomicU32Histogram::new(s.num_bins));
+    group.finish();
+}
+
+// The skewed case, where sorting pays off most, is in the contention group
+fn sorted_atomic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sorted_atomic");
+    bench_sequential(&mut group, |s| SortedAtomicHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| SortedAtomicHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn mutex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mutex");
+    bench_sequential(&mut group, |s| Mutex::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| Mutex::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn rwlock(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rwlock");
+    bench_sequential(&mut group, |s| RwLock::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| RwLock::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn parking_lot_mutex(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parking_lot_mutex");
+    bench_sequential(&mut group, |s| parking_lot::Mutex::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| parking_lot::Mutex::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn parking_lot_rwlock(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parking_lot_rwlock");
+    bench_sequential(&mut group, |s| parking_lot::RwLock::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| parking_lot::RwLock::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn spinlock(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spinlock");
+    bench_sequential(&mut group, |s| SpinLock::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| SpinLock::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn ticket_lock(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ticket_lock");
+    bench_sequential(&mut group, |s| TicketLock::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| TicketLock::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn mcs_lock(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mcs_lock");
+    bench_sequential(&mut group, |s| McsLock::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| McsLock::new(ToyHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn tsx(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tsx");
+    bench_sequential(&mut group, |s| TsxHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| TsxHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn seqlock(c: &mut Criterion) {
+    let mut group = c.benchmark_group("seqlock");
+    bench_sequential(&mut group, |s| SeqlockHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| SeqlockHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn epoch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("epoch");
+    bench_sequential(&mut group, |s| EpochHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| EpochHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn double_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("double_buffer");
+    bench_sequential(&mut group, |s| DoubleBufferedHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| DoubleBufferedHistogram::new(s.num_bins));
+    group.finish();
+}
+
+// Number of shards of the bin-sharded strategy
+const NUM_SHARDS: usize = 16;
+
+fn bin_sharded(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bin_sharded");
+    bench_sequential(&mut group, |s| BinShardedHistogram::new(s.num_bins, NUM_SHARDS));
+    bench_parallel(&mut group, |s| BinShardedHistogram::new(s.num_bins, NUM_SHARDS));
+    group.finish();
+}
+
+fn flat_combining(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flat_combining");
+    bench_sequential(&mut group, |s| FlatCombiningHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| FlatCombiningHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn channel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("channel");
+    bench_sequential(&mut group, |s| ChannelHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| ChannelHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn ring_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ring_buffer");
+    bench_sequential(&mut group, |s| RingBufferHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| RingBufferHistogram::new(s.num_bins));
+    group.finish();
+}
+
+#[cfg(feature = "gpu")]
+fn gpu(c: &mut Criterion) {
+    if !GpuHistogram::is_available() {
+        eprintln!("Skipping the gpu benchmarks: no usable GPU was found");
+        return;
+    }
+    let gpu = |s: Scenario| GpuHistogram::try_new(s.num_bins).expect("GPU went away");
+    let mut group = c.benchmark_group("gpu");
+    bench_sequential(&mut group, gpu);
+    bench_parallel(&mut group, gpu);
+    group.finish();
+}
+
+fn thread_bucketized(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread_bucketized");
+    bench_sequential(&mut group, |s| ThreadBucketizedHistogram::new(s.num_bins, s.num_buckets));
+    bench_parallel(&mut group, |s| ThreadBucketizedHistogram::new(s.num_bins, s.num_buckets));
+    group.finish();
+}
+
+fn parking_lot_thread_bucketized(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parking_lot_thread_bucketized");
+    bench_sequential(&mut group, |s| ParkingLotBucketizedHistogram::with_locks(s.num_bins, s.num_buckets));
+    bench_parallel(&mut group, |s| ParkingLotBucketizedHistogram::with_locks(s.num_bins, s.num_buckets));
+    group.finish();
+}
+
+fn spinlock_thread_bucketized(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spinlock_thread_bucketized");
+    bench_sequential(&mut group, |s| SpinBucketizedHistogram::with_locks(s.num_bins, s.num_buckets));
+    bench_parallel(&mut group, |s| SpinBucketizedHistogram::with_locks(s.num_bins, s.num_buckets));
+    group.finish();
+}
+
+fn per_core(c: &mut Criterion) {
+    let mut group = c.benchmark_group("per_core");
+    bench_sequential(&mut group, |s| PerCoreHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| PerCoreHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn rseq(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rseq");
+    bench_sequential(&mut group, |s| RseqHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| RseqHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn tls(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tls");
+    bench_sequential(&mut group, |s| TlsHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| TlsHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn buffered(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffered");
+    bench_sequential(&mut group, |s| BufferedHistogram::new(AtomicHistogram::new(s.num_bins)));
+    bench_parallel(&mut group, |s| BufferedHistogram::new(AtomicHistogram::new(s.num_bins)));
+    group.finish();
+}
+
+fn sparse_dashmap(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sparse_dashmap");
+    bench_sequential(&mut group, |s| DashMapHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| DashMapHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn sparse_thread_local(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sparse_thread_local");
+    bench_sequential(&mut group, |s| SparseThreadLocalHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| SparseThreadLocalHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn lazy_atomic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lazy_atomic");
+    bench_sequential(&mut group, |s| LazyAtomicHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| LazyAtomicHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn lazy_thread_local(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lazy_thread_local");
+    bench_sequential(&mut group, |s| LazyThreadLocalHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| LazyThreadLocalHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn count_min(c: &mut Criterion) {
+    let mut group = c.benchmark_group("count_min");
+    bench_sequential(&mut group, |s| CountMinHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| CountMinHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn adaptive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("adaptive");
+    bench_sequential(&mut group, |s| AdaptiveHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| AdaptiveHistogram::new(s.num_bins));
+    group.finish();
+}
+
+fn two_level(c: &mut Criterion) {
+    let mut group = c.benchmark_group("two_level");
+    bench_sequential(&mut group, |s| TwoLevelHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| TwoLevelHistogram::new(s.num_bins));
+    group.finish();
+}
+
+// Uniform and exponential binning of the same values, under several
+// synchronization strategies. Exponential bins span [0.001, 1[, so that most of
+// the uniformly distributed values fall into the last few bins.
+fn exponential(c: &mut Criterion) {
+    fn exp<H: SyncHistogram>(num_bins: usize, make_inner: impl FnOnce(usize) -> H)
+        -> ExponentialHistogram<H>
+    {
+        ExponentialHistogram::new(0.001, 1.0, num_bins, make_inner)
+    }
+    let mut group = c.benchmark_group("exponential");
+    bench_parallel_named(&mut group, "atomic/uniform", |s| AtomicHistogram::new(s.num_bins));
+    bench_parallel_named(&mut group, "atomic/exponential",
+                         |s| exp(s.num_bins, AtomicHistogram::new));
+    bench_parallel_named(&mut group, "mutex/uniform",
+                         |s| Mutex::new(ToyHistogram::new(s.num_bins)));
+    bench_parallel_named(&mut group, "mutex/exponential",
+                         |s| exp(s.num_bins, |n| Mutex::new(ToyHistogram::new(n))));
+    bench_parallel_named(&mut group, "thread_local/uniform",
+                         |s| ThreadLocalHistogram::new(s.num_bins));
+    bench_parallel_named(&mut group, "thread_local/exponential",
+                         |s| exp(s.num_bins, ThreadLocalHistogram::new));
+    group.finish();
+}
+
+fn thread_local(c: &mut Criterion) {
+    let mut group = c.benchmark_group("thread_local");
+    bench_sequential(&mut group, |s| ThreadLocalHistogram::new(s.num_bins));
+    bench_parallel(&mut group, |s| ThreadLocalHistogram::new(s.num_bins));
+    group.finish();
+}
+
+// All strategies are compared side by side in the worst-case scenario
+fn contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention");
+    bench_contention(&mut group, "atomic", |s| AtomicHistogram::new(s.num_bins));
+    bench_contention(&mut group, "padded_atomic", |s| PaddedAtomicHistogram::new(s.num_bins));
+    bench_contention(&mut group, "atomic_u16", |s| AtomicU16Histogram::new(s.num_bins));
+    bench_contention(&mut group, "atomic_u32", |s| AtomicU32Histogram::new(s.num_bins));
+    bench_contention(&mut group, "sorted_atomic", |s| SortedAtomicHistogram::new(s.num_bins));
+    bench_contention(&mut group, "mutex", |s| Mutex::new(ToyHistogram::new(s.num_bins)));
+    bench_contention(&mut group, "rwlock", |s| RwLock::new(ToyHistogram::new(s.num_bins)));
+    bench_contention(&mut group, "parking_lot_mutex", |s| {
+        parking_lot::Mutex::new(ToyHistogram::new(s.num_bins))
+    });
+    bench_contention(&mut group, "parking_lot_rwlock", |s| {
+        parking_lot::RwLock::new(ToyHistogram::new(s.num_bins))
+    });
+    bench_contention(&mut group, "spinlock", |s| SpinLock::new(ToyHistogram::new(s.num_bins)));
+    bench_contention(&mut group, "ticket_lock", |s| TicketLock::new(ToyHistogram::new(s.num_bins)));
+    bench_contention(&mut group, "mcs_lock", |s| McsLock::new(ToyHistogram::new(s.num_bins)));
+    bench_contention(&mut group, "tsx", |s| TsxHistogram::new(s.num_bins));
+    bench_contention(&mut group, "seqlock", |s| SeqlockHistogram::new(s.num_bins));
+    bench_contention(&mut group, "epoch", |s| EpochHistogram::new(s.num_bins));
+    bench_contention(&mut group, "double_buffer", |s| DoubleBufferedHistogram::new(s.num_bins));
+    bench_contention(&mut group, "bin_sharded", |s| BinShardedHistogram::new(s.num_bins, NUM_SHARDS));
+    bench_contention(&mut group, "flat_combining", |s| FlatCombiningHistogram::new(s.num_bins));
+    bench_contention(&mut group, "channel", |s| ChannelHistogram::new(s.num_bins));
+    bench_contention(&mut group, "ring_buffer", |s| RingBufferHistogram::new(s.num_bins));
+    #[cfg(feature = "gpu")]
+    if GpuHistogram::is_available() {
+        bench_contention(&mut group, "gpu", |s| {
+            GpuHistogram::try_new(s.num_bins).expect("GPU went away")
+        });
+    }
+    bench_contention(&mut group, "thread_bucketized", |s| {
+        ThreadBucketizedHistogram::new(s.num_bins, s.num_buckets)
+    });
+    bench_contention(&mut group, "parking_lot_thread_bucketized", |s| {
+        ParkingLotBucketizedHistogram::with_locks(s.num_bins, s.num_buckets)
+    });
+    bench_contention(&mut group, "spinlock_thread_bucketized", |s| {
+        SpinBucketizedHistogram::with_locks(s.num_bins, s.num_buckets)
+    });
+    bench_contention(&mut group, "per_core", |s| PerCoreHistogram::new(s.num_bins));
+    bench_contention(&mut group, "rseq", |s| RseqHistogram::new(s.num_bins));
+    bench_contention(&mut group, "tls", |s| TlsHistogram::new(s.num_bins));
+    bench_contention(&mut group, "buffered",
+                     |s| BufferedHistogram::new(AtomicHistogram::new(s.num_bins)));
+    bench_contention(&mut group, "sparse_dashmap", |s| DashMapHistogram::new(s.num_bins));
+    bench_contention(&mut group, "sparse_thread_local",
+                     |s| SparseThreadLocalHistogram::new(s.num_bins));
+    bench_contention(&mut group, "lazy_atomic", |s| LazyAtomicHistogram::new(s.num_bins));
+    bench_contention(&mut group, "lazy_thread_local",
+                     |s| LazyThreadLocalHistogram::new(s.num_bins));
+    bench_contention(&mut group, "count_min", |s| CountMinHistogram::new(s.num_bins));
+    bench_contention(&mut group, "adaptive", |s| AdaptiveHistogram::new(s.num_bins));
+    bench_contention(&mut group, "two_level", |s| TwoLevelHistogram::new(s.num_bins));
+    bench_contention(&mut group, "thread_local", |s| ThreadLocalHistogram::new(s.num_bins));
+    group.finish();
+}
+
+// Sources of the thread IDs which select buckets and publication slots, under
+// worst-case contention. Fills go through fill() rather than fill_with_id(), so
+// that the cost of the providers is measured along with their bucket mapping.
+fn thread_ids(c: &mut Criterion) {
+    fn bench<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                               name: &str,
+                               make_histogram: impl Fn(Scenario) -> H) {
+        for &scenario in SCENARIOS.iter() {
+            group.throughput(Throughput::Elements(scenario.batch_size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(name, scenario),
+                &scenario,
+                |b, &scenario| b.iter_custom(|iters| {
+                    let histogram = make_histogram(scenario);
+                    let batch = vec![0.5; scenario.batch_size];
+                    let start = Instant::now();
+                    (0..iters).into_par_iter().for_each(|_| histogram.fill(&batch));
+                    start.elapsed()
+                })
+            );
+        }
+    }
+
+    fn bench_provider<P: ThreadIdProvider>(group: &mut BenchmarkGroup<WallTime>, name: &str) {
+        bench(group, &format!("thread_bucketized/{}", name), |s| {
+            ThreadBucketizedHistogram::<Mutex<ToyHistogram>, P>::with_locks(s.num_bins,
+                                                                            s.num_buckets)
+        });
+        bench(group, &format!("flat_combining/{}", name), |s| {
+            FlatCombiningHistogram::<P>::with_provider(s.num_bins)
+        });
+    }
+
+    let mut group = c.benchmark_group("thread_ids");
+    bench_provider::<TlsCounter>(&mut group, "tls_counter");
+    #[cfg(feature = "rayon")]
+    bench_provider::<RayonIndex>(&mut group, "rayon_index");
+    bench_provider::<OsThreadHash>(&mut group, "os_thread_hash");
+    bench_provider::<CurrentCpu>(&mut group, "current_cpu");
+    bench_provider::<CurrentNode>(&mut group, "current_node");
+    group.finish();
+}
+
+// Readout of histograms with many bins which every thread filled, whose replicas
+// are summed in parallel by the global rayon pool or sequentially by a pool of
+// one thread
+fn readouts(c: &mut Criterion) {
+    const NUM_BINS: usize = 1 << 22;
+    fn bench<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                               name: &str,
+                               histogram: H) {
+        let num_threads = rayon::current_num_threads();
+        let batch = (0..num_threads).map(|i| i as f32 / num_threads as f32).collect::<Vec<_>>();
+        rayon::broadcast(|_| histogram.fill(&batch));
+        let sequential = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        group.bench_function(format!("{}/sequential", name),
+                             |b| b.iter(|| sequential.install(|| histogram.bins())));
+        group.bench_function(format!("{}/parallel", name), |b| b.iter(|| histogram.bins()));
+    }
+
+    let mut group = c.benchmark_group("readouts");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(NUM_BINS as u64));
+    bench(&mut group, "thread_bucketized",
+          ThreadBucketizedHistogram::new(NUM_BINS, rayon::current_num_threads()));
+    bench(&mut group, "thread_local", ThreadLocalHistogram::new(NUM_BINS));
+    group.finish();
+}
+
+// Queries of the number of hits of a histogram with many bins while other
+// threads keep filling it, as in online monitoring, where aggregated histograms
+// answer from the last snapshot which their aggregator published
+fn monitoring(c: &mut Criterion) {
+    const NUM_BINS: usize = 1 << 20;
+    fn bench<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                               name: &str,
+                               histogram: H) {
+        let done = AtomicBool::new(false);
+        let num_cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let num_fillers = num_cpus.saturating_sub(1).max(1);
+        thread::scope(|s| {
+            for filler in 0..num_fillers {
+                let (histogram, done) = (&histogram, &done);
+                s.spawn(move || {
+                    let batch = vec![filler as f32 / num_fillers as f32; 100];
+                    while !done.load(Ordering::Relaxed) {
+                        histogram.fill(&batch);
+                    }
+                });
+            }
+            group.bench_function(name, |b| b.iter(|| histogram.num_hits()));
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    let mut group = c.benchmark_group("monitoring");
+    bench(&mut group, "thread_local", ThreadLocalHistogram::new(NUM_BINS));
+    bench(&mut group, "aggregated_thread_local",
+          AggregatedHistogram::new(ThreadLocalHistogram::new(NUM_BINS)));
+    group.finish();
+}
+
+// Placement of the buckets of bucketized strategies on NUMA nodes
+#[cfg(feature = "numa")]
+fn numa_placement(c: &mut Criterion) {
+    use parallel_histograms::numa::Placement;
+    let placements = [("first_touch", Placement::FirstTouch),
+                      ("local", Placement::Local),
+                      ("interleaved", Placement::Interleaved)];
+    let mut group = c.benchmark_group("numa_placement");
+    for &(name, placement) in placements.iter() {
+        bench_parallel_named(&mut group, &format!("thread_bucketized/{}", name), |s| {
+            ThreadBucketizedHistogram::<Mutex<ToyHistogram>>::with_placement(s.num_bins,
+                                                                             s.num_buckets,
+                                                                             placement)
+        });
+        bench_parallel_named(&mut group, &format!("thread_local/{}", name), |s| {
+            ThreadLocalHistogram::with_placement(s.num_bins, placement)
+        });
+    }
+    group.finish();
+}
+
+// Filling of histograms whose bins are spread over so many pages that most
+// fills miss the TLB, with and without transparent huge pages
+#[cfg(feature = "huge_pages")]
+fn huge_pages(c: &mut Criterion) {
+    const SCENARIO: Scenario = Scenario { num_bins: 1 << 24, batch_size: 1000, num_buckets: 1 };
+    fn bench<H: SyncHistogram>(group: &mut BenchmarkGroup<WallTime>,
+                               name: &str,
+                               make_histogram: impl Fn(usize) -> H) {
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}/sequential", name), SCENARIO),
+            &SCENARIO,
+            |b, &scenario| b.iter_custom(|iters| {
+                let mut histogram = make_histogram(scenario.num_bins);
+                sequential_microbench(&mut histogram, scenario.batch_size, iters)
+            })
+        );
+        group.bench_with_input(
+            BenchmarkId::new(format!("{}/parallel", name), SCENARIO),
+            &SCENARIO,
+            |b, &scenario| b.iter_custom(|iters| {
+                let histogram = make_histogram(scenario.num_bins);
+                parallel_microbench(&histogram, scenario.batch_size, iters)
+            })
+        );
+    }
+
+    let mut group = c.benchmark_group("huge_pages");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SCENARIO.batch_size as u64));
+    bench(&mut group, "atomic", AtomicHistogram::new);
+    bench(&mut group, "atomic/huge_pages", AtomicHistogram::with_huge_pages);
+    group.finish();
+}
+
+// Cost of building a histogram with many bins and filling it once, which for
+// lazily allocated bins only materializes the pages that get hits
+fn first_touch(c: &mut Criterion) {
+    const NUM_BINS: usize = 1 << 24;
+    const BATCH_SIZE: usize = 1000;
+    fn bench<H: Histogram>(group: &mut BenchmarkGroup<WallTime>,
+                           name: &str,
+                           batch: &[f32],
+                           make_histogram: impl Fn() -> H) {
+        group.bench_function(name, |b| b.iter_with_large_drop(|| {
+            let mut histogram = make_histogram();
+            histogram.fill_mut(batch);
+            histogram
+        }));
+    }
+
+    let mut rng = Xoshiro128Plus::from_seed(RNG_SEED);
+    let mut buf = Vec::with_capacity(BATCH_SIZE);
+    let batch = gen_input(&mut rng, &mut buf, BATCH_SIZE);
+    let mut group = c.benchmark_group("first_touch");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+    bench(&mut group, "atomic", batch, || AtomicHistogram::new(NUM_BINS));
+    bench(&mut group, "lazy_atomic", batch, || LazyAtomicHistogram::new(NUM_BINS));
+    bench(&mut group, "thread_local", batch, || ThreadLocalHistogram::new(NUM_BINS));
+    bench(&mut group, "lazy_thread_local", batch, || LazyThreadLocalHistogram::new(NUM_BINS));
+    group.finish();
+}
+
+criterion_group!(benches, raw, atomic, padded_atomic, atomic_u16, atomic_u32, sorted_atomic,
+                 mutex, rwlock, parking_lot_mutex, parking_lot_rwlock, spinlock, ticket_lock,
+                 mcs_lock, tsx, seqlock, epoch, double_buffer, bin_sharded, flat_combining,
+                 channel, ring_buffer, thread_bucketized, parking_lot_thread_bucketized,
+                 spinlock_thread_bucketized, per_core, rseq, tls, buffered, sparse_dashmap,
+                 sparse_thread_local, lazy_atomic, lazy_thread_local, count_min, exponential,
+                 adaptive, two_level, thread_local, contention, thread_ids, first_touch,
+                 readouts, monitoring);
+
+// Groups which need optional features are empty without them
+#[cfg(not(feature = "numa"))]
+fn numa_placement(_: &mut Criterion) {}
+#[cfg(not(feature = "huge_pages"))]
+fn huge_pages(_: &mut Criterion) {}
+#[cfg(not(feature = "gpu"))]
+fn gpu(_: &mut Criterion) {}
+
+criterion_group!(optional, numa_placement, huge_pages, gpu);
+criterion_main!(benches, optional);
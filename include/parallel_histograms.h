@@ -0,0 +1,83 @@
+// C interface to the parallel histogram implementations, see src/ffi.rs
+#ifndef PARALLEL_HISTOGRAMS_H
+#define PARALLEL_HISTOGRAMS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// Synchronization strategies which can be passed to ph_histogram_new()
+#define PH_STRATEGY_MUTEX 0u
+#define PH_STRATEGY_ATOMIC 1u
+#define PH_STRATEGY_THREAD_BUCKETIZED 2u
+#define PH_STRATEGY_THREAD_LOCAL 3u
+#define PH_STRATEGY_PADDED_ATOMIC 4u
+#define PH_STRATEGY_BIN_SHARDED 5u
+#define PH_STRATEGY_RWLOCK 6u
+#define PH_STRATEGY_PARKING_LOT_MUTEX 7u
+#define PH_STRATEGY_PARKING_LOT_RWLOCK 8u
+#define PH_STRATEGY_PARKING_LOT_THREAD_BUCKETIZED 9u
+#define PH_STRATEGY_SPINLOCK 10u
+#define PH_STRATEGY_SPINLOCK_THREAD_BUCKETIZED 11u
+#define PH_STRATEGY_SEQLOCK 12u
+#define PH_STRATEGY_TICKET_LOCK 13u
+#define PH_STRATEGY_MCS_LOCK 14u
+#define PH_STRATEGY_FLAT_COMBINING 15u
+#define PH_STRATEGY_CHANNEL 16u
+#define PH_STRATEGY_RING_BUFFER 17u
+#define PH_STRATEGY_EPOCH 18u
+#define PH_STRATEGY_DOUBLE_BUFFER 19u
+#define PH_STRATEGY_TSX 20u
+#define PH_STRATEGY_PER_CORE 21u
+#define PH_STRATEGY_RSEQ 22u
+#define PH_STRATEGY_TLS 23u
+#define PH_STRATEGY_BUFFERED 24u
+#define PH_STRATEGY_SORTED_ATOMIC 25u
+#define PH_STRATEGY_ATOMIC_U16 26u
+#define PH_STRATEGY_ATOMIC_U32 27u
+#define PH_STRATEGY_SPARSE_DASHMAP 28u
+#define PH_STRATEGY_SPARSE_THREAD_LOCAL 29u
+#define PH_STRATEGY_LAZY_ATOMIC 30u
+#define PH_STRATEGY_LAZY_THREAD_LOCAL 31u
+#define PH_STRATEGY_COUNT_MIN 32u
+#define PH_STRATEGY_ADAPTIVE 33u
+#define PH_STRATEGY_TWO_LEVEL 34u
+
+// Opaque histogram handle, can be filled from multiple threads in parallel
+typedef struct PhHistogram PhHistogram;
+
+// Create a histogram, or return NULL if the configuration is invalid.
+// num_buckets is only used by bucketized strategies, and is the number of
+// shards of the bin-sharded strategy.
+PhHistogram* ph_histogram_new(uint32_t strategy,
+                              size_t num_bins,
+                              size_t num_buckets);
+
+// Destroy a histogram. Passing NULL is allowed and does nothing.
+void ph_histogram_free(PhHistogram* histogram);
+
+// Insert len values, which must lie in the [0, 1[ range, into the histogram
+void ph_histogram_fill(const PhHistogram* histogram,
+                       const float* values,
+                       size_t len);
+
+// Total number of values that were inserted into the histogram
+size_t ph_histogram_num_hits(const PhHistogram* histogram);
+
+// Copy up to out_len bins into out, and return the total number of bins
+size_t ph_histogram_read_bins(const PhHistogram* histogram,
+                              size_t* out,
+                              size_t out_len);
+
+// Add the contents of source into dest. Returns 0 on success, -1 on error.
+int32_t ph_histogram_merge(const PhHistogram* dest,
+                           const PhHistogram* source);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
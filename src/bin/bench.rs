@@ -0,0 +1,550 @@
+// Command-line benchmark runner, which runs every strategy in every mode with
+// the parameters specified on the command line and prints a summary.
+
+use {
+    clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum},
+    parallel_histograms::harness::{self, Backend, Burst, Config, Distribution, Matrix,
+                                   MatrixFile, Strategy},
+    std::{
+        convert::TryFrom,
+        fs::File,
+        io::{self, BufWriter, Write},
+        path::PathBuf,
+        process,
+    },
+};
+#[cfg(feature = "padded_atomic")]
+use parallel_histograms::impls::PaddedAtomicHistogram;
+
+// Allocations go through dhat, which records them while --dhat is profiling
+#[cfg(feature = "dhat")]
+#[global_allocator]
+static ALLOCATOR: dhat::Alloc = dhat::Alloc;
+
+#[derive(Parser)]
+#[command(about = "Microbenchmark parallel histogramming strategies")]
+struct Args {
+    /// Comma-separated list of strategies to run, by name, instead of all of
+    /// the enabled ones (the sequential baseline is always run)
+    #[arg(long, value_delimiter = ',')]
+    strategies: Vec<Strategy>,
+
+    /// Load the strategies, bin counts, batch sizes, thread counts,
+    /// distributions and other settings of the benchmarks from this TOML file,
+    /// whose settings override those of the corresponding options
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// How many bins the histogram has
+    #[arg(long, default_value_t = Config::default().num_bins, value_parser = positive)]
+    bins: usize,
+
+    /// How much data is inserted into histograms
+    #[arg(long, default_value_t = Config::default().num_rolls, value_parser = positive)]
+    rolls: usize,
+
+    /// How many entries are inserted per histogram fill
+    #[arg(long, default_value_t = Config::default().batch_size, value_parser = positive)]
+    batch_size: usize,
+
+    /// Number of buckets of bucketized strategies
+    #[arg(long, default_value_t = Config::default().num_buckets, value_parser = positive)]
+    buckets: usize,
+
+    /// Number of independently locked groups of bins of the bin-sharded strategy
+    #[arg(long, default_value_t = Config::default().num_shards, value_parser = positive)]
+    shards: usize,
+
+    /// Number of bins per cache line of the padded atomic strategy, which must
+    /// be a power of two
+    #[arg(long, default_value_t = Config::default().bins_per_line, value_parser = cache_line_bins)]
+    bins_per_line: usize,
+
+    /// Number of threads used by parallel benchmarks
+    #[arg(long, default_value_t = Config::default().num_threads, value_parser = positive)]
+    threads: usize,
+
+    /// Number of extra threads which repeatedly read the number of hits while
+    /// parallel benchmarks fill the histogram
+    #[arg(long, default_value_t = Config::default().num_readers)]
+    readers: usize,
+
+    /// Driver of parallel benchmarks: a rayon thread pool, plain threads which
+    /// each process a contiguous chunk of the input, or (with the async
+    /// feature) many tasks on a tokio runtime
+    #[arg(long, value_enum, default_value_t = BackendArg::Rayon)]
+    backend: BackendArg,
+
+    /// Pin the i-th worker thread of parallel benchmarks to the i-th CPU
+    #[arg(long)]
+    pin_threads: bool,
+
+    /// Comma-separated list of CPUs which worker threads are pinned to, in
+    /// order (implies --pin-threads)
+    #[arg(long, value_delimiter = ',')]
+    cpus: Vec<usize>,
+
+    /// Comma-separated list of NUMA nodes which worker threads are spread
+    /// across, by pinning them to the CPUs of these nodes in turn (Linux only,
+    /// overrides --cpus)
+    #[arg(long, value_delimiter = ',')]
+    numa_nodes: Vec<usize>,
+
+    /// Comma-separated list of NUMA nodes: every benchmark is run once with all
+    /// of its memory bound to each of these nodes, instead of being allocated
+    /// on the node of the thread which first touches it (Linux only)
+    #[arg(long, value_delimiter = ',')]
+    memory_nodes: Vec<usize>,
+
+    /// Number of unmeasured runs of each benchmark before the measured ones
+    #[arg(long, default_value_t = Config::default().warmup_runs)]
+    warmup_runs: usize,
+
+    /// Number of measured runs of each benchmark, whose median time is reported
+    #[arg(long, default_value_t = Config::default().repetitions, value_parser = positive)]
+    repetitions: usize,
+
+    /// Distribution of the inserted values: uniform, gaussian[:sigma],
+    /// exponential[:lambda], zipf[:exponent] or single_bin (worst-case
+    /// contention, every value falls into the same bin)
+    #[arg(long, default_value_t = Distribution::default())]
+    distribution: Distribution,
+
+    /// Make every thread alternate between bursts of this many batches and
+    /// idle periods, instead of filling continuously
+    #[arg(long, value_parser = positive)]
+    burst: Option<usize>,
+
+    /// Percentage of the time which threads spend filling with --burst
+    #[arg(long, default_value_t = 50.0, value_parser = percentage)]
+    duty_cycle: f64,
+
+    /// Split the input of parallel benchmarks statically across threads, so
+    /// that runs with the same parameters fill identical histograms, and
+    /// report a checksum of the bin contents
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Pass values to histograms as iterators which generate them while they
+    /// are inserted, instead of generating each batch into a buffer first
+    #[arg(long)]
+    fill_iter: bool,
+
+    /// Insert values one by one with fill_one(), as call sites which fill one
+    /// value per event do, instead of in batches
+    #[arg(long, conflicts_with = "fill_iter")]
+    fill_one: bool,
+
+    /// Fill and read out histograms from within functions named after the
+    /// strategy and mode, such as labels::atomic and labels::parallel, so that
+    /// profiles tell strategies apart. This costs indirect calls per batch in
+    /// load-balanced parallel benchmarks.
+    #[arg(long)]
+    label_frames: bool,
+
+    /// Run parallel benchmarks with every thread count from 1 to --threads
+    #[arg(long)]
+    thread_sweep: bool,
+
+    /// Run every benchmark with bin counts from 10 to 10M instead of --bins
+    #[arg(long)]
+    bin_sweep: bool,
+
+    /// Run every benchmark with batch sizes from 1 to 10k instead of --batch-size
+    #[arg(long)]
+    batch_sweep: bool,
+
+    /// Run the padded atomic strategy with 1, 2, 4 and 8 bins per cache line
+    /// instead of --bins-per-line
+    #[arg(long)]
+    bins_per_line_sweep: bool,
+
+    /// Instead of measuring performance, check that every strategy fills the
+    /// same bins as a sequential ToyHistogram given the same inputs
+    #[arg(long)]
+    verify: bool,
+
+    /// Print a chart of the inputs of the benchmarks before the results, with
+    /// this many rows
+    #[arg(long, value_parser = positive)]
+    show_inputs: Option<usize>,
+
+    /// Use a logarithmic scale in the chart of --show-inputs
+    #[arg(long)]
+    log_scale: bool,
+
+    /// Format in which results are emitted
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    /// Write results to this file instead of standard output
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Save results to this file, for later use with --compare-baseline
+    #[arg(long)]
+    save_baseline: Option<PathBuf>,
+
+    /// Compare results with a baseline saved by --save-baseline, and exit with
+    /// an error status if a regression is detected
+    #[arg(long)]
+    compare_baseline: Option<PathBuf>,
+
+    /// Slowdown (in percent) above which a result is flagged as a regression
+    #[arg(long, default_value_t = 5.0)]
+    regression_threshold: f64,
+
+    /// Also write the results into this self-contained HTML report, which
+    /// draws interactive scaling charts from them
+    #[arg(long)]
+    html_report: Option<PathBuf>,
+
+    /// Render scaling charts into this directory after the run
+    #[cfg(feature = "plot")]
+    #[arg(long)]
+    plot: Option<PathBuf>,
+
+    /// Image format of the scaling charts
+    #[cfg(feature = "plot")]
+    #[arg(long, value_enum, default_value_t = PlotFormat::Svg)]
+    plot_format: PlotFormat,
+
+    /// Record the spans of fills, flushes, merges, readouts and lock
+    /// acquisitions into this Chrome trace file, which timeline viewers such
+    /// as Perfetto can open
+    #[cfg(feature = "chrome_trace")]
+    #[arg(long)]
+    trace: Option<PathBuf>,
+
+    /// Write the time range and parameters of each benchmark into this file,
+    /// for selecting their samples in a `perf record -k CLOCK_MONOTONIC`
+    /// profile with `perf script --time <range>` (Linux only)
+    #[arg(long)]
+    perf_markers: Option<PathBuf>,
+
+    /// Profile heap allocations into this dhat profile, which dhat's viewer
+    /// can open, and report the allocations of each strategy in the results
+    #[cfg(feature = "dhat")]
+    #[arg(long)]
+    dhat: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Table,
+    Markdown,
+    Csv,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BackendArg {
+    Rayon,
+    Threads,
+    #[cfg(feature = "async")]
+    Tokio,
+}
+
+impl From<BackendArg> for Backend {
+    fn from(backend: BackendArg) -> Self {
+        match backend {
+            BackendArg::Rayon => Backend::Rayon,
+            BackendArg::Threads => Backend::Threads,
+            #[cfg(feature = "async")]
+            BackendArg::Tokio => Backend::Tokio,
+        }
+    }
+}
+
+#[cfg(feature = "plot")]
+#[derive(Clone, Copy, ValueEnum)]
+enum PlotFormat {
+    Svg,
+    Png,
+}
+
+#[cfg(feature = "plot")]
+impl From<PlotFormat> for harness::ImageFormat {
+    fn from(format: PlotFormat) -> Self {
+        match format {
+            PlotFormat::Svg => harness::ImageFormat::Svg,
+            PlotFormat::Png => harness::ImageFormat::Png,
+        }
+    }
+}
+
+// Parse a nonzero integer argument
+fn positive(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        Ok(value) => Ok(value),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parse a number of bins per cache line
+fn cache_line_bins(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(value) if !value.is_power_of_two() => Err("must be a power of two".to_owned()),
+        #[cfg(feature = "padded_atomic")]
+        Ok(value) if value > PaddedAtomicHistogram::MAX_BINS_PER_LINE => {
+            Err(format!("at most {} bins fit in a cache line",
+                        PaddedAtomicHistogram::MAX_BINS_PER_LINE))
+        }
+        Ok(value) => Ok(value),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parse a percentage in ]0, 100]
+fn percentage(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(value) if value > 0.0 && value <= 100.0 => Ok(value),
+        Ok(_) => Err("must be in ]0, 100]".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl TryFrom<&Args> for Config {
+    type Error = io::Error;
+
+    fn try_from(args: &Args) -> io::Result<Self> {
+        Ok(Self {
+            num_bins: args.bins,
+            num_rolls: args.rolls,
+            batch_size: args.batch_size,
+            num_buckets: args.buckets,
+            num_shards: args.shards,
+            bins_per_line: args.bins_per_line,
+            num_threads: args.threads,
+            num_readers: args.readers,
+            backend: args.backend.into(),
+            cpu_affinity: if !args.numa_nodes.is_empty() {
+                Some(harness::interleaved_cpus(&args.numa_nodes)?)
+            } else if !args.cpus.is_empty() {
+                Some(args.cpus.clone())
+            } else if args.pin_threads {
+                Some(harness::cpu_ids())
+            } else {
+                None
+            },
+            warmup_runs: args.warmup_runs,
+            repetitions: args.repetitions,
+            distribution: args.distribution,
+            burst: args.burst.map(|batches| Burst {
+                batches,
+                duty_cycle: args.duty_cycle / 100.0,
+            }),
+            memory_node: None,
+            deterministic: args.deterministic,
+            fill_iter: args.fill_iter,
+            fill_one: args.fill_one,
+            label_frames: args.label_frames,
+        })
+    }
+}
+
+// Width of the bars of the chart of --show-inputs
+const CHART_WIDTH: usize = 60;
+
+// Display a list of benchmark parameters
+fn list(values: &[usize]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let config = Config::try_from(&args)?;
+
+    // The trace file is written when the guard is dropped
+    #[cfg(feature = "chrome_trace")]
+    let trace = args.trace.as_ref().map(|path| {
+        use tracing_subscriber::prelude::*;
+        let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().file(path)
+                                                                  .include_args(true)
+                                                                  .build();
+        tracing_subscriber::registry().with(layer).init();
+        guard
+    });
+
+    // Likewise for the heap profile
+    #[cfg(feature = "dhat")]
+    let heap_profiler = args.dhat.as_deref().map(harness::HeapProfiler::new);
+
+    // Load the baseline first, so that a wrong path is reported right away
+    let baseline = args.compare_baseline.as_deref()
+        .map(harness::load_baseline)
+        .transpose()?;
+    let matrix_file = args.config.as_deref().map(MatrixFile::load).transpose()?;
+
+    let mut matrix = Matrix::new(config);
+    if !args.strategies.is_empty() {
+        matrix = matrix.with_strategies(&args.strategies);
+    }
+    if args.thread_sweep {
+        matrix = matrix.with_thread_sweep();
+    }
+    if args.bin_sweep {
+        matrix = matrix.with_bin_sweep();
+    }
+    if args.batch_sweep {
+        matrix = matrix.with_batch_sweep();
+    }
+    if args.bins_per_line_sweep {
+        matrix = matrix.with_bins_per_line_sweep();
+    }
+    if !args.memory_nodes.is_empty() {
+        // Check that the nodes exist, as failing to bind memory is not fatal
+        for &node in &args.memory_nodes {
+            harness::node_cpus(node)?;
+        }
+        matrix = matrix.with_memory_nodes(&args.memory_nodes);
+    }
+    if let Some(file) = &matrix_file {
+        matrix = file.apply(matrix);
+    }
+    // Fills are made of whole batches, so there must be enough rolls for one
+    let num_rolls = matrix.config.num_rolls;
+    if let Some(batch_size) = matrix.batch_sizes.iter().find(|&&b| b > num_rolls) {
+        Args::command().error(ErrorKind::ValueValidation,
+                              format!("{} rolls do not fill a batch of {} values",
+                                      num_rolls, batch_size))
+                       .exit();
+    }
+    if args.verify {
+        let (mismatches, accuracies) = matrix.verify();
+        for mismatch in &mismatches {
+            eprintln!("Mismatch: {}", mismatch);
+        }
+        if !mismatches.is_empty() {
+            process::exit(1);
+        }
+        for accuracy in &accuracies {
+            eprintln!("Accuracy: {}", accuracy);
+        }
+        if accuracies.is_empty() {
+            eprintln!("Every strategy filled the same bins as the reference");
+        } else {
+            eprintln!("Every exact strategy filled the same bins as the reference, and \
+                       approximate strategies never underestimated a bin");
+        }
+        return Ok(());
+    }
+    let results = match &args.perf_markers {
+        Some(path) => matrix.run_with_markers(BufWriter::new(File::create(path)?))?,
+        None => matrix.run(),
+    };
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+    match args.format {
+        Format::Table | Format::Markdown => {
+            writeln!(out, "# Parallel histogram benchmark")?;
+            writeln!(out)?;
+            let config = &matrix.config;
+            if matrix.strategies != Strategy::ALL {
+                let names = matrix.strategies.iter().map(|s| s.name()).collect::<Vec<_>>();
+                writeln!(out, "- Strategies: {}", names.join(", "))?;
+            }
+            writeln!(out, "- Bins: {}", list(&matrix.bin_counts))?;
+            writeln!(out, "- Rolls: {}", config.num_rolls)?;
+            writeln!(out, "- Batch size: {}", list(&matrix.batch_sizes))?;
+            writeln!(out, "- Buckets: {}", config.num_buckets)?;
+            writeln!(out, "- Shards: {}", config.num_shards)?;
+            writeln!(out, "- Bins per cache line: {}", list(&matrix.bins_per_line))?;
+            writeln!(out, "- Threads: {}", list(&matrix.thread_counts))?;
+            writeln!(out, "- Parallel backend: {}", config.backend)?;
+            if config.num_readers > 0 {
+                writeln!(out, "- Reader threads: {}", config.num_readers)?;
+            }
+            if !args.numa_nodes.is_empty() {
+                writeln!(out, "- Spread across NUMA nodes: {}", list(&args.numa_nodes))?;
+            }
+            if let Some(cpus) = &config.cpu_affinity {
+                writeln!(out, "- Pinned to CPUs: {}", list(cpus))?;
+            }
+            if !args.memory_nodes.is_empty() {
+                writeln!(out, "- Memory bound to NUMA nodes: {}", list(&args.memory_nodes))?;
+            }
+            let distributions = matrix.distributions.iter()
+                                                    .map(|d| d.to_string())
+                                                    .collect::<Vec<_>>();
+            writeln!(out, "- Distribution: {}", distributions.join(", "))?;
+            writeln!(out, "- Runs: {} warm-up, {} measured", config.warmup_runs, config.repetitions)?;
+            if let Some(burst) = config.burst {
+                writeln!(out, "- Bursts: {}", burst)?;
+            }
+            if config.deterministic {
+                writeln!(out, "- Deterministic input partitioning")?;
+            }
+            if config.fill_iter {
+                writeln!(out, "- Values generated during fills")?;
+            }
+            if config.fill_one {
+                writeln!(out, "- Values inserted one by one")?;
+            }
+            if config.label_frames {
+                writeln!(out, "- Labeled frames")?;
+            }
+            writeln!(out)?;
+            if let Some(rows) = args.show_inputs {
+                let inputs = harness::input_histogram(config, rows.min(config.num_bins));
+                for line in inputs.render_ascii(CHART_WIDTH, args.log_scale).lines() {
+                    writeln!(out, "    {}", line)?;
+                }
+                writeln!(out)?;
+            }
+            match args.format {
+                Format::Markdown => harness::write_markdown(&results, &mut out)?,
+                _ => harness::write_table(&results, &mut out)?,
+            }
+        }
+        Format::Csv => harness::write_csv(&results, &mut out)?,
+        Format::Json => harness::write_json(&results, &mut out)?,
+    }
+    out.flush()?;
+
+    if let Some(path) = &args.html_report {
+        let mut report = BufWriter::new(File::create(path)?);
+        harness::write_html_report(&results, &mut report)?;
+        report.flush()?;
+        eprintln!("Wrote {}", path.display());
+    }
+
+    #[cfg(feature = "plot")]
+    {
+        if let Some(dir) = &args.plot {
+            let charts = harness::plot_scaling(&results, dir, args.plot_format.into())
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            for path in charts {
+                eprintln!("Wrote {}", path.display());
+            }
+        }
+    }
+
+    if let Some(path) = &args.save_baseline {
+        harness::save_baseline(&results, path)?;
+    }
+    // Before a regression makes the process exit
+    #[cfg(feature = "chrome_trace")]
+    drop(trace);
+    #[cfg(feature = "dhat")]
+    drop(heap_profiler);
+    if let Some(baseline) = baseline {
+        let comparisons = harness::compare(&results,
+                                           &baseline,
+                                           args.regression_threshold / 100.0);
+        eprintln!();
+        eprintln!("Comparison with baseline:");
+        harness::write_comparison(&comparisons, io::stderr())?;
+        let num_regressions = comparisons.iter().filter(|c| c.regression).count();
+        if num_regressions > 0 {
+            eprintln!();
+            eprintln!("{} regression(s) detected", num_regressions);
+            process::exit(1);
+        }
+    }
+    Ok(())
+}
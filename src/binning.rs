@@ -0,0 +1,194 @@
+// Conversion of values to bin indices
+//
+// Every fill converts its values to bin indices with a multiplication and a
+// float-to-integer conversion, which are a nontrivial share of the cost of a
+// fill when the synchronization itself is cheap. Every implementation goes
+// through the Binner below, so that they all bin values identically and at the
+// same cost, and only differ in how they synchronize. With the simd feature,
+// the conversion is vectorized on x86_64, so that comparing results with and
+// without it tells how much of the measured overhead is actually due to
+// synchronization.
+//
+// Both paths give the same indices, which are clamped to the range of the
+// histogram: negative values and NaNs go to the first bin, and values past the
+// end go to the last bin. This lets the fill loops below index bins without
+// bounds checks, which would otherwise be part of every measurement.
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+use core::convert::TryInto;
+
+// Mapping from values in the [0, 1[ range to the bins of a histogram, whose
+// parameters are computed once when the histogram is built
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Binner {
+    num_bins: usize,
+
+    // Inverse of the width of a bin, which values are multiplied by
+    scale: f32,
+}
+
+impl Binner {
+    pub(crate) fn new(num_bins: usize) -> Self {
+        Self {
+            num_bins,
+            scale: num_bins as f32,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "padded_atomic", feature = "bin_sharded", feature = "tls",
+                       feature = "count_min", feature = "gpu")),
+               allow(dead_code))]
+    pub(crate) fn num_bins(&self) -> usize {
+        self.num_bins
+    }
+
+    // Parameters of the binning, for implementations which bin values outside
+    // of Rust code
+    #[cfg(feature = "gpu")]
+    pub(crate) fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    // Bin of a single value. NaNs and negative values go to the first bin, as
+    // `as usize` does. The last bin is clamped to as an integer, since its
+    // index may round up to the number of bins as an f32.
+    #[inline]
+    fn index(&self, value: f32) -> usize {
+        ((value * self.scale).max(0.0) as usize).min(self.num_bins - 1)
+    }
+
+    // Call `f` with the bin of each value, in order
+    #[cfg_attr(not(any(feature = "atomic", feature = "seqlock", feature = "tsx",
+                       feature = "double_buffer", feature = "epoch", feature = "rseq",
+                       feature = "tls")),
+               allow(dead_code))]
+    #[inline]
+    pub(crate) fn for_each_bin<T>(&self, bins: &[T], values: &[f32], mut f: impl FnMut(&T)) {
+        assert_eq!(bins.len(), self.num_bins, "Histogram binning mismatch");
+        // Safe because indices are always smaller than the number of bins
+        self.for_each_index(values, |bin| f(unsafe { bins.get_unchecked(bin) }))
+    }
+
+    // Same, with mutable access to the bins
+    #[inline]
+    pub(crate) fn for_each_bin_mut<T>(&self,
+                                      bins: &mut [T],
+                                      values: &[f32],
+                                      mut f: impl FnMut(&mut T)) {
+        assert_eq!(bins.len(), self.num_bins, "Histogram binning mismatch");
+        // Safe for the same reason
+        self.for_each_index(values, |bin| f(unsafe { bins.get_unchecked_mut(bin) }))
+    }
+
+    // Call `f` with the index of the bin of each value, in order. Indices are
+    // guaranteed to be smaller than the number of bins.
+    #[inline]
+    pub(crate) fn for_each_index(&self, values: &[f32], mut f: impl FnMut(usize)) {
+        if values.is_empty() {
+            return;
+        }
+        assert!(self.num_bins > 0, "Cannot fill a histogram without bins");
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        let values = if self.num_bins <= simd::MAX_BINS {
+            let chunks = values.chunks_exact(simd::LANES);
+            let remainder = chunks.remainder();
+            for chunk in chunks {
+                for bin in simd::bin_indices(chunk.try_into().unwrap(), self) {
+                    f(bin)
+                }
+            }
+            remainder
+        } else {
+            values
+        };
+        for &value in values {
+            f(self.index(value))
+        }
+    }
+
+    // Same for values which are not in a slice, which are binned one by one
+    #[inline]
+    pub(crate) fn for_each_index_iter(&self,
+                                      values: impl IntoIterator<Item = f32>,
+                                      mut f: impl FnMut(usize)) {
+        for value in values {
+            f(self.bin_index(value))
+        }
+    }
+
+    // Index of the bin of a single value
+    #[inline]
+    pub(crate) fn bin_index(&self, value: f32) -> usize {
+        assert!(self.num_bins > 0, "Cannot fill a histogram without bins");
+        self.index(value)
+    }
+}
+
+// SSE2 is part of the x86_64 baseline, so there is no need for runtime
+// detection here
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd {
+    use {
+        core::arch::x86_64::*,
+        super::Binner,
+    };
+
+    pub const LANES: usize = 4;
+
+    // Beyond this, the number of bins may not be exactly representable as an
+    // f32, and the scalar path must be used
+    pub const MAX_BINS: usize = 1 << 24;
+
+    #[inline]
+    pub fn bin_indices(values: &[f32; LANES], binner: &Binner) -> [usize; LANES] {
+        let mut indices = [0i32; LANES];
+        // Safe because the loads and stores are unaligned and of the right size
+        unsafe {
+            let scaled = _mm_mul_ps(_mm_loadu_ps(values.as_ptr()), _mm_set1_ps(binner.scale));
+            // Like `as usize`, send NaNs and negative values to zero, then
+            // clamp to the last bin like the scalar path, whose index is exact
+            // as an f32 below MAX_BINS. Clamping as floats also keeps the
+            // conversion to i32 from overflowing.
+            let clamped = _mm_min_ps(_mm_max_ps(scaled, _mm_setzero_ps()),
+                                     _mm_set1_ps((binner.num_bins - 1) as f32));
+            _mm_storeu_si128(indices.as_mut_ptr().cast(), _mm_cvttps_epi32(clamped));
+        }
+        indices.map(|index| index as usize)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        alloc::vec::Vec,
+    };
+
+    #[test]
+    fn same_as_scalar() {
+        let values = [0.0, 0.1, 0.25, 0.5, 0.999, -0.5, f32::NAN, 1.0, 0.3, f32::INFINITY, 0.7];
+        for num_bins in [1, 3, 10, 1000] {
+            let binner = Binner::new(num_bins);
+            let mut bins = Vec::new();
+            binner.for_each_index(&values, |bin| bins.push(bin));
+            let expected = values.iter().map(|&v| binner.index(v)).collect::<Vec<_>>();
+            assert_eq!(bins, expected);
+            assert!(bins.iter().all(|&bin| bin < num_bins));
+        }
+    }
+
+    #[test]
+    fn inexact_bin_counts() {
+        // These bin counts are not representable as f32, and round up
+        for num_bins in [16_777_217, 16_777_220, 33_554_436] {
+            let binner = Binner::new(num_bins);
+            assert!(binner.bin_index(0.999_999_9) < num_bins);
+            for value in [1.0, 2.0, f32::INFINITY] {
+                assert_eq!(binner.bin_index(value), num_bins - 1);
+            }
+            assert_eq!(binner.bin_index(-1.0), 0);
+            assert_eq!(binner.bin_index(f32::NAN), 0);
+        }
+    }
+}
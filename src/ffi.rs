@@ -0,0 +1,260 @@
+// C interface to the histogram implementations
+//
+// This allows C/C++ event processing frameworks to benchmark our
+// synchronization strategies side by side with their own. The matching C
+// declarations can be found in include/parallel_histograms.h.
+//
+// Histograms are handed out as opaque pointers, which must be released with
+// ph_histogram_free() once the client is done with them. Every function taking
+// a histogram pointer expects either null or a pointer returned by
+// ph_histogram_new() that has not been freed yet.
+//
+#![allow(clippy::missing_safety_doc)]
+
+use {
+    crate::{
+        impls::*,
+        traits::SyncHistogram,
+    },
+    std::{
+        ptr,
+        slice,
+    },
+};
+#[cfg(feature = "mutex")]
+use std::sync::Mutex;
+#[cfg(feature = "rwlock")]
+use std::sync::RwLock;
+
+// Synchronization strategies which can be selected by C code. Strategies whose
+// cargo feature is disabled cannot be created.
+pub const PH_STRATEGY_MUTEX: u32 = 0;
+pub const PH_STRATEGY_ATOMIC: u32 = 1;
+pub const PH_STRATEGY_THREAD_BUCKETIZED: u32 = 2;
+pub const PH_STRATEGY_THREAD_LOCAL: u32 = 3;
+pub const PH_STRATEGY_PADDED_ATOMIC: u32 = 4;
+pub const PH_STRATEGY_BIN_SHARDED: u32 = 5;
+pub const PH_STRATEGY_RWLOCK: u32 = 6;
+pub const PH_STRATEGY_PARKING_LOT_MUTEX: u32 = 7;
+pub const PH_STRATEGY_PARKING_LOT_RWLOCK: u32 = 8;
+pub const PH_STRATEGY_PARKING_LOT_THREAD_BUCKETIZED: u32 = 9;
+pub const PH_STRATEGY_SPINLOCK: u32 = 10;
+pub const PH_STRATEGY_SPINLOCK_THREAD_BUCKETIZED: u32 = 11;
+pub const PH_STRATEGY_SEQLOCK: u32 = 12;
+pub const PH_STRATEGY_TICKET_LOCK: u32 = 13;
+pub const PH_STRATEGY_MCS_LOCK: u32 = 14;
+pub const PH_STRATEGY_FLAT_COMBINING: u32 = 15;
+pub const PH_STRATEGY_CHANNEL: u32 = 16;
+pub const PH_STRATEGY_RING_BUFFER: u32 = 17;
+pub const PH_STRATEGY_EPOCH: u32 = 18;
+pub const PH_STRATEGY_DOUBLE_BUFFER: u32 = 19;
+pub const PH_STRATEGY_TSX: u32 = 20;
+pub const PH_STRATEGY_PER_CORE: u32 = 21;
+pub const PH_STRATEGY_RSEQ: u32 = 22;
+pub const PH_STRATEGY_TLS: u32 = 23;
+pub const PH_STRATEGY_BUFFERED: u32 = 24;
+pub const PH_STRATEGY_SORTED_ATOMIC: u32 = 25;
+pub const PH_STRATEGY_ATOMIC_U16: u32 = 26;
+pub const PH_STRATEGY_ATOMIC_U32: u32 = 27;
+pub const PH_STRATEGY_SPARSE_DASHMAP: u32 = 28;
+pub const PH_STRATEGY_SPARSE_THREAD_LOCAL: u32 = 29;
+pub const PH_STRATEGY_LAZY_ATOMIC: u32 = 30;
+pub const PH_STRATEGY_LAZY_THREAD_LOCAL: u32 = 31;
+pub const PH_STRATEGY_COUNT_MIN: u32 = 32;
+pub const PH_STRATEGY_ADAPTIVE: u32 = 33;
+pub const PH_STRATEGY_TWO_LEVEL: u32 = 34;
+
+// Opaque histogram handle. Can be filled from multiple threads in parallel.
+pub struct PhHistogram(Box<dyn SyncHistogram + Send>);
+
+// Create a histogram using the specified strategy, or return null if the
+// configuration is invalid. num_buckets is only used by bucketized strategies,
+// and is the number of shards of the bin-sharded strategy.
+#[no_mangle]
+#[cfg_attr(not(any(feature = "bin_sharded", feature = "thread_bucketized")),
+           allow(unused_variables))]
+pub extern "C" fn ph_histogram_new(strategy: u32,
+                                   num_bins: usize,
+                                   num_buckets: usize) -> *mut PhHistogram {
+    if num_bins == 0 {
+        return ptr::null_mut();
+    }
+    let inner: Box<dyn SyncHistogram + Send> = match strategy {
+        #[cfg(feature = "mutex")]
+        PH_STRATEGY_MUTEX => Box::new(Mutex::new(ToyHistogram::new(num_bins))),
+        #[cfg(feature = "atomic")]
+        PH_STRATEGY_ATOMIC => Box::new(AtomicHistogram::new(num_bins)),
+        #[cfg(feature = "thread_bucketized")]
+        PH_STRATEGY_THREAD_BUCKETIZED if num_buckets > 0 => {
+            Box::new(ThreadBucketizedHistogram::new(num_bins, num_buckets))
+        }
+        #[cfg(feature = "thread_local")]
+        PH_STRATEGY_THREAD_LOCAL => Box::new(ThreadLocalHistogram::new(num_bins)),
+        #[cfg(feature = "padded_atomic")]
+        PH_STRATEGY_PADDED_ATOMIC => Box::new(PaddedAtomicHistogram::new(num_bins)),
+        #[cfg(feature = "rwlock")]
+        PH_STRATEGY_RWLOCK => Box::new(RwLock::new(ToyHistogram::new(num_bins))),
+        #[cfg(feature = "parking_lot")]
+        PH_STRATEGY_PARKING_LOT_MUTEX => {
+            Box::new(parking_lot::Mutex::new(ToyHistogram::new(num_bins)))
+        }
+        #[cfg(feature = "parking_lot")]
+        PH_STRATEGY_PARKING_LOT_RWLOCK => {
+            Box::new(parking_lot::RwLock::new(ToyHistogram::new(num_bins)))
+        }
+        #[cfg(all(feature = "parking_lot", feature = "thread_bucketized"))]
+        PH_STRATEGY_PARKING_LOT_THREAD_BUCKETIZED if num_buckets > 0 => {
+            Box::new(ParkingLotBucketizedHistogram::with_locks(num_bins, num_buckets))
+        }
+        #[cfg(feature = "bin_sharded")]
+        PH_STRATEGY_BIN_SHARDED if num_buckets > 0 => {
+            Box::new(BinShardedHistogram::new(num_bins, num_buckets))
+        }
+        #[cfg(feature = "spinlock")]
+        PH_STRATEGY_SPINLOCK => Box::new(SpinLock::new(ToyHistogram::new(num_bins))),
+        #[cfg(all(feature = "spinlock", feature = "thread_bucketized"))]
+        PH_STRATEGY_SPINLOCK_THREAD_BUCKETIZED if num_buckets > 0 => {
+            Box::new(SpinBucketizedHistogram::with_locks(num_bins, num_buckets))
+        }
+        #[cfg(feature = "seqlock")]
+        PH_STRATEGY_SEQLOCK => Box::new(SeqlockHistogram::new(num_bins)),
+        #[cfg(feature = "ticket_lock")]
+        PH_STRATEGY_TICKET_LOCK => Box::new(TicketLock::new(ToyHistogram::new(num_bins))),
+        #[cfg(feature = "mcs_lock")]
+        PH_STRATEGY_MCS_LOCK => Box::new(McsLock::new(ToyHistogram::new(num_bins))),
+        #[cfg(feature = "flat_combining")]
+        PH_STRATEGY_FLAT_COMBINING => Box::new(FlatCombiningHistogram::new(num_bins)),
+        #[cfg(feature = "channel")]
+        PH_STRATEGY_CHANNEL => Box::new(ChannelHistogram::new(num_bins)),
+        #[cfg(feature = "ring_buffer")]
+        PH_STRATEGY_RING_BUFFER => Box::new(RingBufferHistogram::new(num_bins)),
+        #[cfg(feature = "epoch")]
+        PH_STRATEGY_EPOCH => Box::new(EpochHistogram::new(num_bins)),
+        #[cfg(feature = "double_buffer")]
+        PH_STRATEGY_DOUBLE_BUFFER => Box::new(DoubleBufferedHistogram::new(num_bins)),
+        #[cfg(feature = "tsx")]
+        PH_STRATEGY_TSX => Box::new(TsxHistogram::new(num_bins)),
+        #[cfg(feature = "per_core")]
+        PH_STRATEGY_PER_CORE => Box::new(PerCoreHistogram::new(num_bins)),
+        #[cfg(feature = "rseq")]
+        PH_STRATEGY_RSEQ => Box::new(RseqHistogram::new(num_bins)),
+        #[cfg(feature = "tls")]
+        PH_STRATEGY_TLS => Box::new(TlsHistogram::new(num_bins)),
+        #[cfg(feature = "buffered")]
+        PH_STRATEGY_BUFFERED => Box::new(BufferedHistogram::new(AtomicHistogram::new(num_bins))),
+        #[cfg(feature = "sorted")]
+        PH_STRATEGY_SORTED_ATOMIC => Box::new(SortedAtomicHistogram::new(num_bins)),
+        #[cfg(feature = "narrow_atomic")]
+        PH_STRATEGY_ATOMIC_U16 => Box::new(AtomicU16Histogram::new(num_bins)),
+        #[cfg(feature = "narrow_atomic")]
+        PH_STRATEGY_ATOMIC_U32 => Box::new(AtomicU32Histogram::new(num_bins)),
+        #[cfg(feature = "sparse")]
+        PH_STRATEGY_SPARSE_DASHMAP => Box::new(DashMapHistogram::new(num_bins)),
+        #[cfg(feature = "sparse")]
+        PH_STRATEGY_SPARSE_THREAD_LOCAL => Box::new(SparseThreadLocalHistogram::new(num_bins)),
+        #[cfg(feature = "lazy")]
+        PH_STRATEGY_LAZY_ATOMIC => Box::new(LazyAtomicHistogram::new(num_bins)),
+        #[cfg(feature = "lazy")]
+        PH_STRATEGY_LAZY_THREAD_LOCAL => Box::new(LazyThreadLocalHistogram::new(num_bins)),
+        #[cfg(feature = "count_min")]
+        PH_STRATEGY_COUNT_MIN => Box::new(CountMinHistogram::new(num_bins)),
+        #[cfg(feature = "adaptive")]
+        PH_STRATEGY_ADAPTIVE => Box::new(AdaptiveHistogram::new(num_bins)),
+        #[cfg(feature = "two_level")]
+        PH_STRATEGY_TWO_LEVEL => Box::new(TwoLevelHistogram::new(num_bins)),
+        _ => return ptr::null_mut(),
+    };
+    Box::into_raw(Box::new(PhHistogram(inner)))
+}
+
+// Destroy a histogram. Passing null is allowed and does nothing.
+#[no_mangle]
+pub unsafe extern "C" fn ph_histogram_free(histogram: *mut PhHistogram) {
+    if !histogram.is_null() {
+        drop(Box::from_raw(histogram));
+    }
+}
+
+// Insert `len` values, which must lie in the [0, 1[ range, into the histogram
+#[no_mangle]
+pub unsafe extern "C" fn ph_histogram_fill(histogram: *const PhHistogram,
+                                           values: *const f32,
+                                           len: usize) {
+    if histogram.is_null() || len == 0 {
+        return;
+    }
+    (*histogram).0.fill(slice::from_raw_parts(values, len))
+}
+
+// Total number of values that were inserted into the histogram
+#[no_mangle]
+pub unsafe extern "C" fn ph_histogram_num_hits(histogram: *const PhHistogram) -> usize {
+    if histogram.is_null() {
+        return 0;
+    }
+    (*histogram).0.num_hits()
+}
+
+// Copy up to `out_len` bins into `out`, and return the total number of bins.
+// Call with out_len = 0 to query the number of bins without reading them.
+#[no_mangle]
+pub unsafe extern "C" fn ph_histogram_read_bins(histogram: *const PhHistogram,
+                                                out: *mut usize,
+                                                out_len: usize) -> usize {
+    if histogram.is_null() {
+        return 0;
+    }
+    let bins = (*histogram).0.bins();
+    let num_copied = bins.len().min(out_len);
+    if num_copied > 0 {
+        slice::from_raw_parts_mut(out, num_copied).copy_from_slice(&bins[..num_copied]);
+    }
+    bins.len()
+}
+
+// Add the contents of `source` into `dest`, which may use another strategy.
+// Returns 0 on success, and -1 if the two histograms have different binning.
+#[no_mangle]
+pub unsafe extern "C" fn ph_histogram_merge(dest: *const PhHistogram,
+                                            source: *const PhHistogram) -> i32 {
+    if dest.is_null() || source.is_null() {
+        return -1;
+    }
+    let (dest, source) = (&(*dest).0, &(*source).0);
+    let source_bins = source.bins();
+    if dest.bins().len() != source_bins.len() {
+        return -1;
+    }
+    dest.merge_bins(&source_bins);
+    0
+}
+
+
+#[cfg(all(test, feature = "mutex", feature = "thread_bucketized", feature = "thread_local",
+          not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_read_merge() {
+        let values = [0.05f32, 0.15, 0.95, 0.96];
+        unsafe {
+            let mutex = ph_histogram_new(PH_STRATEGY_MUTEX, 10, 0);
+            let local = ph_histogram_new(PH_STRATEGY_THREAD_LOCAL, 10, 0);
+            assert!(!mutex.is_null() && !local.is_null());
+            assert!(ph_histogram_new(PH_STRATEGY_THREAD_BUCKETIZED, 10, 0).is_null());
+
+            ph_histogram_fill(mutex, values.as_ptr(), values.len());
+            ph_histogram_fill(local, values.as_ptr(), 2);
+            assert_eq!(ph_histogram_merge(local, mutex), 0);
+            assert_eq!(ph_histogram_num_hits(local), 6);
+
+            let mut bins = [0; 10];
+            assert_eq!(ph_histogram_read_bins(local, bins.as_mut_ptr(), bins.len()), 10);
+            assert_eq!(bins, [2, 2, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+            ph_histogram_free(mutex);
+            ph_histogram_free(local);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Heap allocations of benchmark runs, counted by the dhat heap profiler
+//
+// When the "dhat" feature is enabled and the benchmark runner installed dhat's
+// global allocator, a HeapProfiler records every allocation of the process
+// into a profile which dhat's viewer can open. While it runs, the allocations
+// of each benchmark run are also counted in three phases: the construction of
+// the histogram, its fill and the readout of its bins. Otherwise, no counts are
+// available.
+//
+// Fill counts include the allocations of the harness, such as spawning threads,
+// their input buffers and rayon's jobs, which are the same for every strategy
+// given a mode and backend. Comparing with the atomic strategy, which does not
+// allocate when filled, tells them apart from those of a strategy, such as
+// per-thread buckets allocated on first use or temporary buffers. Readout counts
+// include the vector of bins which is returned.
+
+#[cfg(feature = "dhat")]
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Heap allocations which were performed during some phase of a run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Allocations {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+impl Allocations {
+    fn since(&self, earlier: &Allocations) -> Allocations {
+        Allocations {
+            blocks: self.blocks - earlier.blocks,
+            bytes: self.bytes - earlier.bytes,
+        }
+    }
+}
+
+// Allocations of each phase of a benchmark run
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunAllocations {
+    pub construction: Allocations,
+    pub fill: Allocations,
+    pub readout: Allocations,
+}
+
+// Whether a HeapProfiler is running, without which dhat has no statistics
+#[cfg(feature = "dhat")]
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+// Heap profiler, which writes its profile when dropped. Only one can run at a
+// time, and allocations are only recorded if the program's global allocator is
+// dhat::Alloc.
+#[cfg(feature = "dhat")]
+pub struct HeapProfiler(dhat::Profiler);
+
+#[cfg(feature = "dhat")]
+impl HeapProfiler {
+    pub fn new(path: &Path) -> Self {
+        let profiler = dhat::Profiler::builder().file_name(path).build();
+        PROFILING.store(true, Ordering::Relaxed);
+        Self(profiler)
+    }
+}
+
+#[cfg(feature = "dhat")]
+impl Drop for HeapProfiler {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Relaxed);
+    }
+}
+
+// Allocations performed so far, if a heap profiler is running
+fn snapshot() -> Option<Allocations> {
+    #[cfg(feature = "dhat")]
+    {
+        if PROFILING.load(Ordering::Relaxed) {
+            let stats = dhat::HeapStats::get();
+            return Some(Allocations {
+                blocks: stats.total_blocks,
+                bytes: stats.total_bytes,
+            });
+        }
+    }
+    None
+}
+
+// Counter of the allocations of successive phases of a run
+pub(super) struct PhaseAllocations(Option<Allocations>);
+
+impl PhaseAllocations {
+    // Start counting the allocations of the first phase
+    pub fn start() -> Self {
+        Self(snapshot())
+    }
+
+    // Allocations since the previous phase ended, which starts the next one
+    pub fn end_phase(&mut self) -> Option<Allocations> {
+        let now = snapshot();
+        let phase = now.zip(self.0).map(|(now, start)| now.since(&start));
+        self.0 = now;
+        phase
+    }
+}
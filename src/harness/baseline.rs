@@ -0,0 +1,84 @@
+// Storage of benchmark results as a baseline, and regression detection
+//
+// Baselines are stored as JSON, in the same format as the runner's JSON output.
+// When comparing a run against a baseline, results are matched by strategy and
+// benchmark parameters, and a slowdown is flagged when the median time per
+// inserted value grew by more than a relative threshold, and by more than twice
+// the combined standard deviation of both measurements.
+
+use {
+    super::BenchResult,
+    std::{
+        fs::File,
+        io::{self, BufReader, BufWriter, Write},
+        path::Path,
+    },
+};
+
+pub fn save_baseline(results: &[BenchResult], path: &Path) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    super::write_json(results, &mut out)?;
+    out.flush()
+}
+
+pub fn load_baseline(path: &Path) -> io::Result<Vec<BenchResult>> {
+    let input = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(input)?)
+}
+
+// Comparison of a benchmark result with the matching baseline result
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub result: BenchResult,
+    pub baseline_ns_per_iter: f64,
+
+    // Relative change in time per inserted value, positive means slower
+    pub change: f64,
+
+    // Truth that the slowdown exceeds the regression threshold and the noise
+    pub regression: bool,
+}
+
+// Compare results with a baseline. Results which have no counterpart in the
+// baseline are ignored. `threshold` is a relative change, e.g. 0.05 for 5%.
+pub fn compare(results: &[BenchResult],
+               baseline: &[BenchResult],
+               threshold: f64) -> Vec<Comparison> {
+    results.iter()
+        .filter_map(|result| {
+            let old = baseline.iter().find(|b| same_benchmark(b, result))?;
+            let change = result.ns_per_iter / old.ns_per_iter - 1.0;
+            let noise = 2.0 * result.stddev_ns_per_iter.hypot(old.stddev_ns_per_iter);
+            Some(Comparison {
+                result: result.clone(),
+                baseline_ns_per_iter: old.ns_per_iter,
+                change,
+                regression: change > threshold
+                            && result.ns_per_iter - old.ns_per_iter > noise,
+            })
+        })
+        .collect()
+}
+
+fn same_benchmark(a: &BenchResult, b: &BenchResult) -> bool {
+    (a.strategy, a.mode, a.threads, a.bins, a.batch_size, a.buckets, a.distribution)
+        == (b.strategy, b.mode, b.threads, b.bins, b.batch_size, b.buckets, b.distribution)
+    && (a.bins_per_line, a.shards, a.backend, a.readers, a.memory_node, a.burst_batches,
+        a.duty_cycle)
+        == (b.bins_per_line, b.shards, b.backend, b.readers, b.memory_node, b.burst_batches,
+            b.duty_cycle)
+    && (a.deterministic, a.fill_iter, a.fill_one) == (b.deterministic, b.fill_iter, b.fill_one)
+}
+
+pub fn write_comparison(comparisons: &[Comparison], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "{:<30} {:<12} {:>10} {:>6} {:>8} {:>12} {:>12} {:>9}",
+             "Strategy", "Mode", "Bins", "Batch", "Threads", "Baseline", "ns/iter", "Change")?;
+    for c in comparisons {
+        let r = &c.result;
+        writeln!(out, "{:<30} {:<12} {:>10} {:>6} {:>8} {:>12.3} {:>12.3} {:>+8.1}%{}",
+                 r.strategy, r.mode, r.bins, r.batch_size, r.threads,
+                 c.baseline_ns_per_iter, r.ns_per_iter, c.change * 100.0,
+                 if c.regression { "  REGRESSION" } else { "" })?;
+    }
+    Ok(())
+}
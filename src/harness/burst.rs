@@ -0,0 +1,70 @@
+// Bursty workloads, where each thread alternates between bursts of filling and
+// idle periods, so that contention arrives in spikes
+//
+// After each burst of batches, a thread stays idle for long enough that it only
+// spends a `duty_cycle` fraction of its time filling. Idle periods are spent
+// spinning rather than sleeping, as sleeping is too imprecise at this time
+// scale, so the time spent filling is `duty_cycle` times the elapsed time.
+
+use std::{
+    fmt,
+    hint,
+    time::Instant,
+};
+
+// Shape of a bursty workload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Burst {
+    // Number of batches inserted by a thread per burst
+    pub batches: usize,
+
+    // Fraction of the time which threads spend filling, in ]0, 1]
+    pub duty_cycle: f64,
+}
+
+impl fmt::Display for Burst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} batches per burst, {}% duty cycle",
+               self.batches, self.duty_cycle * 100.0)
+    }
+}
+
+// Per-thread state of a bursty workload, which keeps a steady stream of
+// batches if there is no burst configuration
+pub(super) struct Pacer {
+    burst: Option<Burst>,
+    burst_batches: usize,
+    burst_start: Instant,
+}
+
+impl Pacer {
+    pub(super) fn new(burst: Option<Burst>) -> Self {
+        if let Some(burst) = burst {
+            assert!(burst.batches > 0, "Bursts must contain at least one batch");
+            assert!(burst.duty_cycle > 0.0 && burst.duty_cycle <= 1.0, "Invalid duty cycle");
+        }
+        Self {
+            burst,
+            burst_batches: 0,
+            burst_start: Instant::now(),
+        }
+    }
+
+    // Must be called after every batch, idles at the end of each burst
+    pub(super) fn after_batch(&mut self) {
+        let burst = match self.burst {
+            Some(burst) => burst,
+            None => return,
+        };
+        self.burst_batches += 1;
+        if self.burst_batches == burst.batches {
+            let busy_time = self.burst_start.elapsed();
+            let idle_end = Instant::now() + busy_time.mul_f64(1.0 / burst.duty_cycle - 1.0);
+            while Instant::now() < idle_end {
+                hint::spin_loop();
+            }
+            self.burst_batches = 0;
+            self.burst_start = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// Hardware performance counters, measured around benchmark runs
+//
+// Counters are only available on Linux when the "perf" feature is enabled, and
+// when the kernel lets us monitor our own process (see perf_event_paranoid).
+// Otherwise, benchmarks are only timed, and a warning is printed once.
+//
+// Counters are inherited by threads which are spawned after their creation, and
+// their readout includes the counts of these threads. So they must be created
+// before the thread pool of parallel benchmarks.
+
+use serde::Serialize;
+
+// Hardware event counts, aggregated over every benchmark thread
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct HardwareCounts {
+    pub cycles: u64,
+    pub cache_misses: u64,
+    pub llc_loads: u64,
+}
+
+pub struct HardwareCounters {
+    #[cfg(all(feature = "perf", target_os = "linux"))]
+    counters: Option<perf::Counters>,
+}
+
+impl HardwareCounters {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(all(feature = "perf", target_os = "linux"))]
+            counters: perf::Counters::new()
+                .map_err(|e| perf::warn_unavailable(&e))
+                .ok(),
+        }
+    }
+
+    // Count hardware events while running `f`, if possible
+    pub fn measure<R>(&mut self, f: impl FnOnce() -> R) -> (R, Option<HardwareCounts>) {
+        #[cfg(all(feature = "perf", target_os = "linux"))]
+        {
+            if let Some(counters) = &mut self.counters {
+                return counters.measure(f);
+            }
+        }
+        (f(), None)
+    }
+}
+
+#[cfg(all(feature = "perf", target_os = "linux"))]
+mod perf {
+    use {
+        super::HardwareCounts,
+        perf_event::{
+            events::{Cache, CacheOp, CacheResult, Event, Hardware, WhichCache},
+            Builder,
+            Counter,
+        },
+        std::{
+            io,
+            sync::Once,
+        },
+    };
+
+    const LLC_LOADS: Cache = Cache {
+        which: WhichCache::LL,
+        operation: CacheOp::READ,
+        result: CacheResult::ACCESS,
+    };
+
+    pub struct Counters {
+        cycles: Counter,
+        cache_misses: Counter,
+        llc_loads: Counter,
+    }
+
+    impl Counters {
+        pub fn new() -> io::Result<Self> {
+            // Groups cannot be read when inherited, so counters are independent
+            let build = |kind: Event| {
+                let mut builder = Builder::new().kind(kind);
+                builder.inherit(true);
+                builder.build()
+            };
+            Ok(Self {
+                cycles: build(Hardware::CPU_CYCLES.into())?,
+                cache_misses: build(Hardware::CACHE_MISSES.into())?,
+                llc_loads: build(LLC_LOADS.into())?,
+            })
+        }
+
+        pub fn measure<R>(&mut self, f: impl FnOnce() -> R) -> (R, Option<HardwareCounts>) {
+            let enabled = self.for_each(|c| {
+                c.reset()?;
+                c.enable()
+            });
+            let result = f();
+            let counts = enabled
+                .and_then(|()| self.for_each(Counter::disable))
+                .and_then(|()| Ok(HardwareCounts {
+                    cycles: self.cycles.read()?,
+                    cache_misses: self.cache_misses.read()?,
+                    llc_loads: self.llc_loads.read()?,
+                }));
+            (result, counts.map_err(|e| warn_unavailable(&e)).ok())
+        }
+
+        fn for_each(&mut self,
+                    mut op: impl FnMut(&mut Counter) -> io::Result<()>) -> io::Result<()> {
+            op(&mut self.cycles)?;
+            op(&mut self.cache_misses)?;
+            op(&mut self.llc_loads)
+        }
+    }
+
+    pub fn warn_unavailable(error: &io::Error) {
+        static WARNING: Once = Once::new();
+        WARNING.call_once(|| {
+            eprintln!("WARNING: Hardware performance counters are unavailable ({})", error)
+        });
+    }
+}
@@ -0,0 +1,298 @@
+// Input distributions which histograms can be filled with
+//
+// Every distribution generates values in the [0, 1[ range of the histogram
+// axis. Skewed distributions concentrate hits in few bins, which increases
+// contention on the corresponding counters or cache lines.
+
+use {
+    rand::Rng,
+    rand_distr::{Distribution as _, Normal},
+    rand_xoshiro::Xoshiro128Plus,
+    serde::{de, Deserialize, Deserializer, Serialize, Serializer},
+    std::{
+        fmt,
+        str::FromStr,
+    },
+};
+
+// Random number generator used by the benchmarks
+pub type BenchRng = Xoshiro128Plus;
+
+// Source of histogram input values
+pub trait InputGenerator: Send + Sync {
+    // Generate one value in the [0, 1[ range
+    fn gen(&self, rng: &mut BenchRng) -> f32;
+
+    // Replace the contents of `buf` with a batch of values
+    fn gen_batch<'a>(&self,
+                     rng: &mut BenchRng,
+                     buf: &'a mut Vec<f32>,
+                     batch_size: usize) -> &'a [f32] {
+        buf.clear();
+        for _ in 0..batch_size {
+            buf.push(self.gen(rng))
+        }
+        &buf[..]
+    }
+}
+
+// Uniform distribution over the histogram axis
+pub struct Uniform;
+
+impl InputGenerator for Uniform {
+    fn gen(&self, rng: &mut BenchRng) -> f32 {
+        rng.gen()
+    }
+}
+
+// Normal distribution centered on the histogram axis, truncated to [0, 1[
+pub struct Gaussian {
+    normal: Normal<f32>,
+}
+
+impl Gaussian {
+    pub fn new(sigma: f32) -> Self {
+        Self {
+            normal: Normal::new(0.5, sigma).expect("Invalid standard deviation"),
+        }
+    }
+}
+
+impl InputGenerator for Gaussian {
+    fn gen(&self, rng: &mut BenchRng) -> f32 {
+        loop {
+            let value = self.normal.sample(rng);
+            if (0.0..1.0).contains(&value) {
+                return value;
+            }
+        }
+    }
+}
+
+// Exponential distribution with rate `lambda`, truncated to [0, 1[
+pub struct Exponential {
+    lambda: f32,
+    scale: f32,
+}
+
+impl Exponential {
+    pub fn new(lambda: f32) -> Self {
+        assert!(lambda > 0.0, "Invalid exponential rate");
+        Self {
+            lambda,
+            scale: 1.0 - (-lambda).exp(),
+        }
+    }
+}
+
+impl InputGenerator for Exponential {
+    // Inverse of the CDF of the truncated distribution
+    fn gen(&self, rng: &mut BenchRng) -> f32 {
+        let u: f32 = rng.gen();
+        let value = -(1.0 - u * self.scale).ln() / self.lambda;
+        value.min(LARGEST_VALUE)
+    }
+}
+
+// Zipf distribution over bins: the k-th bin gets a share of the hits which is
+// proportional to 1/k^exponent. Values are generated at bin centers.
+//
+// This uses the rejection-inversion sampling method of Hörmann and Derflinger,
+// which runs in constant time and memory regardless of the number of bins.
+//
+pub struct Zipf {
+    num_bins: f64,
+    exponent: f64,
+    t: f64,
+    q: f64,
+}
+
+impl Zipf {
+    pub fn new(num_bins: usize, exponent: f64) -> Self {
+        assert!(exponent >= 0.0, "Invalid Zipf exponent");
+        let n = num_bins as f64;
+        let (t, q) = if exponent != 1.0 {
+            ((n.powf(1.0 - exponent) - exponent) / (1.0 - exponent), 1.0 / (1.0 - exponent))
+        } else {
+            (1.0 + n.ln(), 0.0)
+        };
+        Self { num_bins: n, exponent, t, q }
+    }
+
+    fn inv_cdf(&self, p: f64) -> f64 {
+        let pt = p * self.t;
+        if pt <= 1.0 {
+            pt
+        } else if self.exponent != 1.0 {
+            (pt * (1.0 - self.exponent) + self.exponent).powf(self.q)
+        } else {
+            (pt - 1.0).exp()
+        }
+    }
+
+    // Draw a 1-based bin rank
+    fn rank(&self, rng: &mut BenchRng) -> f64 {
+        loop {
+            let inv_b = self.inv_cdf(rng.gen());
+            let x = (inv_b + 1.0).floor();
+            let mut ratio = x.powf(-self.exponent);
+            if x > 1.0 {
+                ratio *= inv_b.powf(self.exponent);
+            }
+            if rng.gen::<f64>() < ratio {
+                return x.min(self.num_bins);
+            }
+        }
+    }
+}
+
+impl InputGenerator for Zipf {
+    fn gen(&self, rng: &mut BenchRng) -> f32 {
+        let value = ((self.rank(rng) - 0.5) / self.num_bins) as f32;
+        value.min(LARGEST_VALUE)
+    }
+}
+
+// Worst-case contention scenario: every value falls into the middle bin
+//
+// No random number is generated, so that the measurement is dominated by the
+// cost of concurrent accesses to a single counter (or cache line).
+//
+pub struct SingleBin;
+
+impl InputGenerator for SingleBin {
+    fn gen(&self, _rng: &mut BenchRng) -> f32 {
+        0.5
+    }
+}
+
+// Largest f32 below 1, guards against rounding up to the end of the axis
+const LARGEST_VALUE: f32 = 1.0 - f32::EPSILON / 2.0;
+
+// Input distribution of a benchmark, as selected by the user
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Distribution {
+    #[default]
+    Uniform,
+    Gaussian { sigma: f32 },
+    Exponential { lambda: f32 },
+    Zipf { exponent: f64 },
+    SingleBin,
+}
+
+impl Distribution {
+    pub fn generator(self, num_bins: usize) -> Box<dyn InputGenerator> {
+        match self {
+            Distribution::Uniform => Box::new(Uniform),
+            Distribution::Gaussian { sigma } => Box::new(Gaussian::new(sigma)),
+            Distribution::Exponential { lambda } => Box::new(Exponential::new(lambda)),
+            Distribution::Zipf { exponent } => Box::new(Zipf::new(num_bins, exponent)),
+            Distribution::SingleBin => Box::new(SingleBin),
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Distribution::Uniform => f.pad("uniform"),
+            Distribution::Gaussian { sigma } => f.pad(&format!("gaussian:{}", sigma)),
+            Distribution::Exponential { lambda } => f.pad(&format!("exponential:{}", lambda)),
+            Distribution::Zipf { exponent } => f.pad(&format!("zipf:{}", exponent)),
+            Distribution::SingleBin => f.pad("single_bin"),
+        }
+    }
+}
+
+// Parses "uniform", "gaussian[:sigma]", "exponential[:lambda]",
+// "zipf[:exponent]" or "single_bin", which is also the Display format
+impl FromStr for Distribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let param = parts.next();
+        let parse_param = |default: f64| -> Result<f64, String> {
+            match param {
+                Some(p) => p.parse::<f64>()
+                    .ok()
+                    .filter(|&x| x.is_finite() && x > 0.0)
+                    .ok_or_else(|| format!("invalid {} parameter '{}'", name, p)),
+                None => Ok(default),
+            }
+        };
+        match name {
+            "uniform" if param.is_none() => Ok(Distribution::Uniform),
+            "gaussian" => Ok(Distribution::Gaussian { sigma: parse_param(0.1)? as f32 }),
+            "exponential" => Ok(Distribution::Exponential { lambda: parse_param(10.0)? as f32 }),
+            "zipf" => Ok(Distribution::Zipf { exponent: parse_param(1.1)? }),
+            "single_bin" if param.is_none() => Ok(Distribution::SingleBin),
+            _ => Err(format!("unknown distribution '{}'", s)),
+        }
+    }
+}
+
+impl Serialize for Distribution {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Distribution {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        rand::SeedableRng,
+    };
+
+    #[test]
+    fn values_in_range() {
+        let mut rng = BenchRng::seed_from_u64(42);
+        for &dist in ["uniform", "gaussian:0.5", "exponential:0.1", "zipf:2", "zipf:1",
+                      "single_bin"].iter() {
+            let input = dist.parse::<Distribution>().unwrap().generator(10_000_000);
+            for _ in 0..100_000 {
+                let value = input.gen(&mut rng);
+                assert!((0.0..1.0).contains(&value), "{} generated {}", dist, value);
+                assert!(((value * 10_000_000.0) as usize) < 10_000_000);
+            }
+        }
+    }
+
+    #[test]
+    fn zipf_frequencies() {
+        let (num_bins, exponent) = (100, 1.5);
+        let zipf = Zipf::new(num_bins, exponent);
+        let mut rng = BenchRng::seed_from_u64(42);
+        let mut bins = vec![0usize; num_bins];
+        let num_samples = 1_000_000;
+        for _ in 0..num_samples {
+            bins[(zipf.gen(&mut rng) * num_bins as f32) as usize] += 1;
+        }
+        let norm = (1..=num_bins).map(|k| (k as f64).powf(-exponent)).sum::<f64>();
+        for (k, &count) in bins.iter().enumerate().take(5) {
+            let expected = ((k + 1) as f64).powf(-exponent) / norm;
+            let observed = count as f64 / num_samples as f64;
+            assert!((observed - expected).abs() < 0.01 * expected + 0.001,
+                    "bin {}: expected {}, observed {}", k, expected, observed);
+        }
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for &s in ["uniform", "gaussian:0.2", "exponential:3", "zipf:1.1", "single_bin"].iter() {
+            assert_eq!(s.parse::<Distribution>().unwrap().to_string(), s);
+        }
+        assert!("uniform:1".parse::<Distribution>().is_err());
+        assert!("zipf:0".parse::<Distribution>().is_err());
+        assert!("poisson".parse::<Distribution>().is_err());
+    }
+}
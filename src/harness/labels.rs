@@ -0,0 +1,140 @@
+// Named frames of benchmark runs, for telling strategies apart in profiles
+//
+// Flamegraphs of `perf record` runs group samples by call stack, but the fills
+// of different strategies mostly go through the same generic harness functions,
+// and the worker threads of parallel benchmarks do not even go through run().
+// So when asked to, the harness fills and reads out histograms from within a
+// function named after the mode, such as `parallel`, called from within a
+// function named after the strategy, such as `atomic`, neither of which is
+// ever inlined. Worker threads go through them once per chunk of input
+// batches, or once per batch when batches are load-balanced, which costs two
+// indirect calls per batch in the measured region. This is why frames are not
+// labeled by default, in which case the code is called directly.
+//
+// Other benchmark parameters are only known at run time, so they cannot name
+// functions. Instead, the runner can write markers with the time range of each
+// run on the CLOCK_MONOTONIC clock, which is the one that
+// `perf record -k CLOCK_MONOTONIC` timestamps samples with, so that the samples
+// of a run can be selected with `perf script --time <start>,<end>`. Perf maps
+// are of no help there, as perf only reads them for JIT-compiled code.
+
+use {
+    super::{Config, Mode, Strategy},
+    std::{hint::black_box, io},
+};
+
+// Function which runs its argument under the name of a strategy or mode
+type Frame = fn(&mut dyn FnMut());
+
+// Frames of the strategy and mode of a benchmark, which its code runs under if
+// frames are labeled
+#[derive(Clone, Copy)]
+pub(super) struct Label(Option<(Frame, Frame)>);
+
+impl Label {
+    #[inline]
+    pub fn call<R>(self, f: impl FnOnce() -> R) -> R {
+        let Some((strategy, mode)) = self.0 else {
+            return f();
+        };
+        let mut f = Some(f);
+        let mut result = None;
+        strategy(&mut || mode(&mut || result = f.take().map(|f| f())));
+        result.expect("Labeled function should have been called")
+    }
+}
+
+// Define a function named after each strategy, and the label of each strategy
+// and mode, from the table of strategies
+macro_rules! labels {
+    (
+        sequential_only {
+            $($(#[$s_attr:meta])* $s_variant:ident: $s_name:ident => |$s_config:ident| $s_make:expr;)*
+        }
+        thread_safe {
+            $($(#[$attr:meta])* $variant:ident: $name:ident => |$config:ident| $make:expr;)*
+        }
+    ) => {
+        $(
+            $(#[$s_attr])*
+            frame!($s_name);
+        )*
+        $(
+            $(#[$attr])*
+            frame!($name);
+        )*
+
+        pub(super) fn label(strategy: Strategy, mode: Mode, config: &Config) -> Label {
+            if !config.label_frames {
+                return Label(None);
+            }
+            let strategy_frame = match strategy {
+                $($(#[$s_attr])* Strategy::$s_variant => $s_name,)*
+                $($(#[$attr])* Strategy::$variant => $name,)*
+            };
+            let mode_frame = match mode {
+                Mode::Sequential => sequential,
+                Mode::Parallel => parallel,
+            };
+            Label(Some((strategy_frame, mode_frame)))
+        }
+    };
+}
+
+// Define a function which calls its argument under the given name
+macro_rules! frame {
+    ($name:ident) => {
+        #[inline(never)]
+        fn $name(f: &mut dyn FnMut()) {
+            f();
+            // Keeps the call out of tail position, where it would replace the
+            // frame of this function, and keeps the compiler from merging
+            // functions whose bodies would otherwise be the same
+            black_box(stringify!($name));
+        }
+    };
+}
+
+frame!(sequential);
+frame!(parallel);
+strategies!(labels);
+
+// Time on the CLOCK_MONOTONIC clock, in seconds
+pub(super) fn monotonic_time() -> io::Result<f64> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(time.tv_sec as f64 + time.tv_nsec as f64 * 1e-9)
+    }
+    #[cfg(not(target_os = "linux"))]
+    Err(io::Error::new(io::ErrorKind::Unsupported, "perf markers are only available on Linux"))
+}
+
+// Marker line of a benchmark run which took place between `start` and `end`,
+// in the format of the time ranges of `perf script --time`
+pub(super) fn marker(strategy: Strategy,
+                     mode: Mode,
+                     config: &Config,
+                     start: f64,
+                     end: f64) -> String {
+    let threads = match mode {
+        Mode::Sequential => 1,
+        Mode::Parallel => config.num_threads,
+    };
+    let mut marker = format!("{:.6},{:.6} {} {} bins={} batch={} threads={}",
+                             start, end, strategy, mode, config.num_bins, config.batch_size,
+                             threads);
+    #[cfg(feature = "padded_atomic")]
+    {
+        if strategy == Strategy::PaddedAtomic {
+            marker += &format!(" bins_per_line={}", config.bins_per_line);
+        }
+    }
+    if let Some(node) = config.memory_node {
+        marker += &format!(" memory_node={}", node);
+    }
+    marker
+}
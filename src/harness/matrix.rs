@@ -0,0 +1,222 @@
+// Benchmark matrix, i.e. the set of strategies, modes and parameters which a
+// benchmark run goes through
+
+use {
+    super::{labels, Accuracy, BenchResult, Config, Distribution, Mismatch, Mode, Strategy},
+    std::io::{self, Write},
+};
+
+// Every strategy is run in every mode with the base configuration, for each
+// requested NUMA memory node, input distribution, bin count and batch size.
+// Parallel benchmarks are additionally repeated for each requested thread
+// count, and the padded atomic strategy for each requested number of bins per
+// cache line. The sequential runs include the baseline which parallel speedups
+// are computed against, even if only some other strategies were selected.
+#[derive(Clone, Debug)]
+pub struct Matrix {
+    pub config: Config,
+    pub strategies: Vec<Strategy>,
+    pub bin_counts: Vec<usize>,
+    pub batch_sizes: Vec<usize>,
+    pub thread_counts: Vec<usize>,
+    pub memory_nodes: Vec<Option<usize>>,
+    pub distributions: Vec<Distribution>,
+    pub bins_per_line: Vec<usize>,
+}
+
+// Bin counts of the bin-count sweep, from L1-resident histograms to histograms
+// which exceed the last-level cache of current CPUs
+const BIN_SWEEP: [usize; 7] = [10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+// Bins per cache line of the padded atomic strategy in the bins-per-line sweep
+const BINS_PER_LINE_SWEEP: [usize; 4] = [1, 2, 4, 8];
+
+// Batch sizes of the batch-size sweep, starting with filling values one by one
+const BATCH_SWEEP: [usize; 5] = [1, 10, 100, 1_000, 10_000];
+
+impl Matrix {
+    // Run every strategy once, with the parameters of the base configuration
+    pub fn new(config: Config) -> Self {
+        let bin_counts = vec![config.num_bins];
+        let batch_sizes = vec![config.batch_size];
+        let thread_counts = vec![config.num_threads];
+        let memory_nodes = vec![config.memory_node];
+        let distributions = vec![config.distribution];
+        let bins_per_line = vec![config.bins_per_line];
+        Self {
+            config,
+            strategies: Strategy::ALL.to_vec(),
+            bin_counts,
+            batch_sizes,
+            thread_counts,
+            memory_nodes,
+            distributions,
+            bins_per_line,
+        }
+    }
+
+    // Only run these strategies, and the baseline
+    pub fn with_strategies(mut self, strategies: &[Strategy]) -> Self {
+        self.strategies = strategies.to_vec();
+        self
+    }
+
+    // Run every strategy with bin counts from 10 to 10M
+    pub fn with_bin_sweep(mut self) -> Self {
+        self.bin_counts = BIN_SWEEP.to_vec();
+        self
+    }
+
+    // Run every strategy with batch sizes from 1 (one fill per value) to 10k
+    pub fn with_batch_sweep(mut self) -> Self {
+        self.batch_sizes = BATCH_SWEEP.to_vec();
+        self
+    }
+
+    // Run parallel strategies with every thread count from 1 to the thread
+    // count of the base configuration
+    pub fn with_thread_sweep(mut self) -> Self {
+        self.thread_counts = (1..=self.config.num_threads).collect();
+        self
+    }
+
+    // Run the padded atomic strategy with 1, 2, 4 and 8 bins per cache line
+    pub fn with_bins_per_line_sweep(mut self) -> Self {
+        self.bins_per_line = BINS_PER_LINE_SWEEP.to_vec();
+        self
+    }
+
+    // Run every strategy with memory bound to each of these NUMA nodes in turn
+    pub fn with_memory_nodes(mut self, nodes: &[usize]) -> Self {
+        self.memory_nodes = nodes.iter().map(|&node| Some(node)).collect();
+        self
+    }
+
+    // Run every strategy with each of these input distributions in turn
+    pub fn with_distributions(mut self, distributions: &[Distribution]) -> Self {
+        self.distributions = distributions.to_vec();
+        self
+    }
+
+    // Every benchmark of the matrix, in the order where they are run, except
+    // for those of the strategies which are unavailable on this machine
+    fn benchmarks(&self) -> Vec<(Strategy, Mode, Config)> {
+        for strategy in &self.strategies {
+            if let Some(reason) = strategy.unavailability() {
+                eprintln!("Skipping {}: {}", strategy, reason);
+            }
+        }
+        let mut benchmarks = Vec::new();
+        for &memory_node in &self.memory_nodes {
+            for &distribution in &self.distributions {
+                for &num_bins in &self.bin_counts {
+                    for &batch_size in &self.batch_sizes {
+                        let config = Config {
+                            num_bins,
+                            batch_size,
+                            memory_node,
+                            distribution,
+                            ..self.config.clone()
+                        };
+                        self.push_benchmarks(&config, &mut benchmarks);
+                    }
+                }
+            }
+        }
+        benchmarks
+    }
+
+    // Benchmarks of every selected strategy with a configuration, in every mode
+    // and with every thread count
+    fn push_benchmarks(&self, config: &Config, benchmarks: &mut Vec<(Strategy, Mode, Config)>) {
+        let selected = Strategy::ALL.iter().copied().filter(|strategy| {
+            (self.strategies.contains(strategy) || *strategy == Strategy::BASELINE)
+                && strategy.unavailability().is_none()
+        });
+        for strategy in selected.clone().filter(|s| s.supports(Mode::Sequential)) {
+            for config in self.strategy_configs(strategy, config) {
+                benchmarks.push((strategy, Mode::Sequential, config));
+            }
+        }
+        for strategy in selected.filter(|s| s.supports(Mode::Parallel)) {
+            for &num_threads in &self.thread_counts {
+                let config = Config { num_threads, ..config.clone() };
+                for config in self.strategy_configs(strategy, &config) {
+                    benchmarks.push((strategy, Mode::Parallel, config));
+                }
+            }
+        }
+    }
+
+    // Variations of a configuration for strategy-specific parameters
+    fn strategy_configs(&self, strategy: Strategy, config: &Config) -> Vec<Config> {
+        match strategy {
+            #[cfg(feature = "padded_atomic")]
+            Strategy::PaddedAtomic => {
+                self.bins_per_line
+                    .iter()
+                    .map(|&bins_per_line| Config { bins_per_line, ..config.clone() })
+                    .collect()
+            }
+            _ => vec![config.clone()],
+        }
+    }
+
+    pub fn run(&self) -> Vec<BenchResult> {
+        let mut results = self.benchmarks()
+            .into_iter()
+            .map(|(strategy, mode, config)| super::run(strategy, mode, &config))
+            .collect::<Vec<_>>();
+        compute_scaling(&mut results);
+        results
+    }
+
+    // Same, and write a marker with the CLOCK_MONOTONIC time range and the
+    // parameters of each benchmark into `markers`, one per line, which selects
+    // its samples in a profile recorded with `perf record -k CLOCK_MONOTONIC`
+    pub fn run_with_markers(&self, mut markers: impl Write) -> io::Result<Vec<BenchResult>> {
+        let mut results = Vec::new();
+        for (strategy, mode, config) in self.benchmarks() {
+            let start = labels::monotonic_time()?;
+            results.push(super::run(strategy, mode, &config));
+            let end = labels::monotonic_time()?;
+            writeln!(markers, "{}", labels::marker(strategy, mode, &config, start, end))?;
+        }
+        markers.flush()?;
+        compute_scaling(&mut results);
+        Ok(results)
+    }
+
+    // Check the bins of every benchmark against the sequential ToyHistogram
+    // instead of measuring performance, and measure the accuracy of the
+    // approximate strategies
+    pub fn verify(&self) -> (Vec<Mismatch>, Vec<Accuracy>) {
+        let (mut mismatches, mut accuracies) = (Vec::new(), Vec::new());
+        for (strategy, mode, config) in self.benchmarks() {
+            match super::verify(strategy, mode, &config) {
+                Ok(accuracy) => accuracies.extend(accuracy),
+                Err(mismatch) => mismatches.push(mismatch),
+            }
+        }
+        (mismatches, accuracies)
+    }
+}
+
+// Compute the speedup and parallel efficiency of parallel benchmarks with
+// respect to the sequential baseline, i.e. filling an unsynchronized
+// ToyHistogram from a single thread with the same bins, batches, inputs and
+// memory placement
+fn compute_scaling(results: &mut [BenchResult]) {
+    let baselines = results.iter()
+        .filter(|r| r.strategy == Strategy::BASELINE && r.mode == Mode::Sequential)
+        .map(|r| ((r.bins, r.batch_size, r.distribution, r.memory_node), r.ns_per_iter))
+        .collect::<Vec<_>>();
+    for r in results.iter_mut().filter(|r| r.mode == Mode::Parallel) {
+        let key = (r.bins, r.batch_size, r.distribution, r.memory_node);
+        if let Some(&(_, baseline_ns)) = baselines.iter().find(|(k, _)| *k == key) {
+            let speedup = baseline_ns / r.ns_per_iter;
+            r.speedup = Some(speedup);
+            r.efficiency = Some(speedup / r.threads as f64);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Benchmark matrix declared in a TOML file
+//
+// Studies which go through many strategies and parameters are easier to
+// reproduce from a file which is checked in along with their results than from
+// a long command line. The file may declare the strategies, the values of the
+// parameters which the matrix goes through, and the other benchmark settings,
+// using the names of the command-line options of the runner:
+//
+//     strategies = ["atomic", "thread_bucketized", "thread_local"]
+//     bins = [1000, 1000000]
+//     threads = [1, 2, 4, 8]
+//     distributions = ["uniform", "zipf:1.1"]
+//     repetitions = 10
+//
+// Settings which the file does not declare are left as they were, and unknown
+// settings are rejected, so that typos do not go unnoticed.
+
+use {
+    super::{Backend, Distribution, Matrix, Strategy},
+    serde::Deserialize,
+    std::{fs, io, path::Path},
+};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MatrixFile {
+    // Parameters which the matrix goes through, if declared
+    pub strategies: Option<Vec<Strategy>>,
+    pub bins: Option<Vec<usize>>,
+    pub batch_sizes: Option<Vec<usize>>,
+    pub threads: Option<Vec<usize>>,
+    pub distributions: Option<Vec<Distribution>>,
+
+    // Settings of every benchmark, if declared
+    pub rolls: Option<usize>,
+    pub warmup_runs: Option<usize>,
+    pub repetitions: Option<usize>,
+    pub buckets: Option<usize>,
+    pub shards: Option<usize>,
+    pub readers: Option<usize>,
+    pub backend: Option<Backend>,
+    pub deterministic: Option<bool>,
+}
+
+impl MatrixFile {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        };
+        let file: Self = toml::from_str(&fs::read_to_string(path)?).map_err(|e| invalid(&e))?;
+        file.check().map_err(|e| invalid(&e))?;
+        Ok(file)
+    }
+
+    // Check the values which the command-line options would have rejected
+    fn check(&self) -> Result<(), String> {
+        let lists = [("bins", &self.bins), ("batch-sizes", &self.batch_sizes),
+                     ("threads", &self.threads)];
+        for (name, list) in lists {
+            match list {
+                Some(list) if list.is_empty() => return Err(format!("{} is empty", name)),
+                Some(list) if list.contains(&0) => return Err(format!("{} contains 0", name)),
+                _ => {}
+            }
+        }
+        if self.distributions.as_ref().is_some_and(|d| d.is_empty()) {
+            return Err("distributions is empty".to_owned());
+        }
+        let values = [("rolls", self.rolls), ("repetitions", self.repetitions),
+                      ("buckets", self.buckets), ("shards", self.shards)];
+        for (name, value) in values {
+            if value == Some(0) {
+                return Err(format!("{} must be positive", name));
+            }
+        }
+        if let (Some(rolls), Some(batch_sizes)) = (self.rolls, &self.batch_sizes) {
+            if let Some(batch_size) = batch_sizes.iter().find(|&&b| b > rolls) {
+                return Err(format!("{} rolls do not fill a batch of {} values",
+                                   rolls, batch_size));
+            }
+        }
+        Ok(())
+    }
+
+    // Replace the settings of a matrix with those which the file declares
+    pub fn apply(&self, mut matrix: Matrix) -> Matrix {
+        if let Some(strategies) = &self.strategies {
+            matrix = matrix.with_strategies(strategies);
+        }
+        if let Some(bins) = &self.bins {
+            matrix.bin_counts = bins.clone();
+        }
+        if let Some(batch_sizes) = &self.batch_sizes {
+            matrix.batch_sizes = batch_sizes.clone();
+        }
+        if let Some(threads) = &self.threads {
+            matrix.thread_counts = threads.clone();
+        }
+        if let Some(distributions) = &self.distributions {
+            matrix = matrix.with_distributions(distributions);
+        }
+        let config = &mut matrix.config;
+        config.num_rolls = self.rolls.unwrap_or(config.num_rolls);
+        config.warmup_runs = self.warmup_runs.unwrap_or(config.warmup_runs);
+        config.repetitions = self.repetitions.unwrap_or(config.repetitions);
+        config.num_buckets = self.buckets.unwrap_or(config.num_buckets);
+        config.num_shards = self.shards.unwrap_or(config.num_shards);
+        config.num_readers = self.readers.unwrap_or(config.num_readers);
+        config.backend = self.backend.unwrap_or(config.backend);
+        config.deterministic = self.deterministic.unwrap_or(config.deterministic);
+        matrix
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::harness::Config};
+
+    #[test]
+    fn declare_matrix() {
+        let file: MatrixFile = toml::from_str(r#"
+            strategies = ["atomic"]
+            bins = [10, 1000]
+            distributions = ["uniform", "zipf:1.1"]
+            repetitions = 3
+            backend = "threads"
+        "#).unwrap();
+        file.check().unwrap();
+        let matrix = file.apply(Matrix::new(Config::default()));
+        assert_eq!(matrix.bin_counts, [10, 1000]);
+        assert_eq!(matrix.distributions,
+                   [Distribution::Uniform, Distribution::Zipf { exponent: 1.1 }]);
+        assert_eq!(matrix.thread_counts, [Config::default().num_threads]);
+        assert_eq!(matrix.config.repetitions, 3);
+        assert_eq!(matrix.config.backend, Backend::Threads);
+
+        assert!(toml::from_str::<MatrixFile>("bin = [10]").is_err());
+        let file: MatrixFile = toml::from_str("threads = [0, 2]").unwrap();
+        assert!(file.check().is_err());
+        let file: MatrixFile = toml::from_str("rolls = 0").unwrap();
+        assert!(file.check().is_err());
+        let file: MatrixFile = toml::from_str("rolls = 100\nbatch-sizes = [10, 1000]").unwrap();
+        assert!(file.check().is_err());
+    }
+}
@@ -0,0 +1,793 @@
+// Benchmark harness, used by the command-line benchmark runner (src/bin)
+//
+// Each benchmark fills a histogram with random numbers (uniformly distributed
+// by default), either from a single thread or from a rayon thread pool, and
+// measures how much time is spent per inserted value. Random number generation
+// is included in the measurement, which is good for studying parallel
+// scalability.
+//
+// Parallel benchmarks are only run if a thread-safe implementation is enabled.
+#![cfg_attr(not(any(feature = "atomic", feature = "padded_atomic", feature = "mutex",
+                    feature = "rwlock", feature = "parking_lot", feature = "bin_sharded",
+                    feature = "thread_bucketized", feature = "spinlock", feature = "seqlock",
+                    feature = "ticket_lock", feature = "mcs_lock", feature = "flat_combining",
+                    feature = "channel", feature = "ring_buffer", feature = "epoch",
+                    feature = "double_buffer", feature = "tsx", feature = "per_core",
+                    feature = "rseq", feature = "tls", feature = "buffered", feature = "sorted",
+                    feature = "narrow_atomic", feature = "sparse", feature = "lazy",
+                    feature = "gpu", feature = "count_min", feature = "adaptive",
+                    feature = "two_level", feature = "thread_local", feature = "aggregated")),
+            allow(dead_code))]
+
+// Defines the table of strategies, which the following modules go through
+#[macro_use]
+mod strategies;
+
+mod allocations;
+mod baseline;
+mod burst;
+mod counters;
+mod input;
+mod labels;
+#[cfg(feature = "plot")]
+mod plot;
+mod matrix;
+mod matrix_file;
+mod numa;
+mod report;
+mod result;
+#[cfg(feature = "async")]
+mod tasks;
+mod verify;
+
+use {
+    self::{
+        allocations::{PhaseAllocations, RunAllocations},
+        burst::Pacer,
+        counters::HardwareCounters,
+        labels::Label,
+        numa::MemoryBinding,
+    },
+    crate::{
+        impls::*,
+        telemetry,
+        trace,
+        thread_id::ThreadID,
+        traits::{Histogram, SyncHistogram},
+    },
+    rand::SeedableRng,
+    rayon::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::{
+        fmt,
+        hint::black_box,
+        mem,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, Once,
+        },
+        thread,
+        time::Instant,
+    },
+};
+
+#[cfg(feature = "rwlock")]
+use std::sync::RwLock;
+
+#[cfg(feature = "dhat")]
+pub use allocations::HeapProfiler;
+pub use baseline::{Comparison, compare, load_baseline, save_baseline, write_comparison};
+pub use burst::Burst;
+pub use counters::HardwareCounts;
+pub use numa::{interleaved_cpus, node_cpus, numa_nodes};
+pub use input::{
+    BenchRng,
+    Distribution,
+    Exponential,
+    Gaussian,
+    InputGenerator,
+    SingleBin,
+    Uniform,
+    Zipf,
+};
+#[cfg(feature = "plot")]
+pub use plot::{ImageFormat, plot_scaling};
+pub use matrix::Matrix;
+pub use matrix_file::MatrixFile;
+pub use report::write_html_report;
+pub use result::{BenchResult, write_csv, write_json, write_markdown, write_table};
+pub use verify::{Accuracy, Mismatch, input_histogram, verify};
+
+const RNG_SEED: [u8; 16] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0,
+                            0x0f, 0xed, 0xcb, 0xa9, 0x87, 0x56, 0x43, 0x21];
+
+// Parameters of a benchmark run
+#[derive(Clone, Debug)]
+pub struct Config {
+    // How many bins the histogram has
+    pub num_bins: usize,
+
+    // How much data is inserted into histograms, rounded down to a multiple of
+    // the batch size
+    pub num_rolls: usize,
+
+    // How many entries are inserted per histogram fill
+    pub batch_size: usize,
+
+    // Number of buckets of bucketized strategies
+    pub num_buckets: usize,
+
+    // Number of independently locked groups of bins of the bin-sharded strategy
+    pub num_shards: usize,
+
+    // Number of bins per cache line of the padded atomic strategy
+    pub bins_per_line: usize,
+
+    // Number of threads used by parallel benchmarks
+    pub num_threads: usize,
+
+    // Number of threads which repeatedly read the number of hits while the
+    // histogram is being filled, in parallel benchmarks
+    pub num_readers: usize,
+
+    // How worker threads of parallel benchmarks are spawned and fed with work
+    pub backend: Backend,
+
+    // CPUs which the worker threads of parallel benchmarks are pinned to, if
+    // any. The i-th worker is pinned to the i-th CPU of the list, wrapping
+    // around if there are more workers than CPUs.
+    pub cpu_affinity: Option<Vec<usize>>,
+
+    // Bind all memory allocated by benchmarks, including histograms, to this
+    // NUMA node instead of letting threads allocate on their own node
+    pub memory_node: Option<usize>,
+
+    // Number of unmeasured runs before the measurement, and number of measured
+    // runs which timing statistics are computed from
+    pub warmup_runs: usize,
+    pub repetitions: usize,
+
+    // Distribution of the values which the histogram is filled with
+    pub distribution: Distribution,
+
+    // Alternate between bursts of filling and idle periods in each thread,
+    // instead of filling continuously
+    pub burst: Option<Burst>,
+
+    // Split the input of parallel benchmarks statically across threads, so
+    // that every run fills the same bins (see parallel_microbench)
+    pub deterministic: bool,
+
+    // Hand values over to histograms through fill_iter(), generating them as
+    // they are inserted, instead of generating each batch into a buffer first
+    pub fill_iter: bool,
+
+    // Insert values one by one with fill_one(), as call sites which fill one
+    // value per event do, instead of in batches. Batches are still the unit of
+    // work which is handed over to threads.
+    pub fill_one: bool,
+
+    // Fill and read out histograms from within functions named after the
+    // strategy and mode, for telling them apart in profiles (see labels.rs)
+    pub label_frames: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            num_bins: 1000,
+            num_rolls: 300_000_000,
+            batch_size: 100,
+            num_buckets: 2,
+            num_shards: 16,
+            bins_per_line: 1,
+            num_threads: num_cpus::get(),
+            num_readers: 0,
+            backend: Backend::Rayon,
+            cpu_affinity: None,
+            memory_node: None,
+            warmup_runs: 1,
+            repetitions: 5,
+            distribution: Distribution::default(),
+            burst: None,
+            deterministic: false,
+            fill_iter: false,
+            fill_one: false,
+            label_frames: false,
+        }
+    }
+}
+
+impl Config {
+    fn num_batches(&self) -> usize {
+        self.num_rolls / self.batch_size
+    }
+
+    fn num_hits(&self) -> usize {
+        self.num_batches() * self.batch_size
+    }
+}
+
+// Generate the Strategy enum, the lookup of strategies by name and the
+// construction of their histograms from the table of strategies
+macro_rules! strategy_enum {
+    (
+        sequential_only {
+            $($(#[$s_attr:meta])* $s_variant:ident: $s_name:ident => |$s_config:ident| $s_make:expr;)*
+        }
+        thread_safe {
+            $($(#[$attr:meta])* $variant:ident: $name:ident => |$config:ident| $make:expr;)*
+        }
+    ) => {
+        // Histogram synchronization strategies which can be benchmarked,
+        // depending on which implementations were enabled at build time
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        pub enum Strategy {
+            $($(#[$s_attr])* $s_variant,)*
+            $($(#[$attr])* $variant,)*
+        }
+
+        impl Strategy {
+            pub const ALL: &'static [Strategy] = &[
+                $($(#[$s_attr])* Strategy::$s_variant,)*
+                $($(#[$attr])* Strategy::$variant,)*
+            ];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($(#[$s_attr])* Strategy::$s_variant => stringify!($s_name),)*
+                    $($(#[$attr])* Strategy::$variant => stringify!($name),)*
+                }
+            }
+
+            // Only thread-safe histograms can be filled in parallel
+            pub fn supports(self, mode: Mode) -> bool {
+                match self {
+                    $($(#[$s_attr])* Strategy::$s_variant => mode == Mode::Sequential,)*
+                    $($(#[$attr])* Strategy::$variant => true,)*
+                }
+            }
+
+            // Histogram of this strategy configured as in `config`, if it is
+            // thread-safe, behind dynamic dispatch. Benchmarks do not use it,
+            // as virtual calls would be measured along with the histogram.
+            // Without thread-safe strategies, it can only return None.
+            #[allow(unused_variables, unreachable_code)]
+            pub fn new_histogram(self, config: &Config) -> Option<Box<dyn SyncHistogram + Send>> {
+                Some(match self {
+                    $($(#[$s_attr])* Strategy::$s_variant => return None,)*
+                    $(
+                        $(#[$attr])*
+                        Strategy::$variant => {
+                            let $config = config;
+                            Box::new($make)
+                        }
+                    )*
+                })
+            }
+        }
+
+        // Measure a strategy in a mode which it supports
+        fn measure(strategy: Strategy,
+                   mode: Mode,
+                   config: &Config,
+                   counters: &mut HardwareCounters,
+                   label: Label) -> Measurement {
+            match (strategy, mode) {
+                $(
+                    $(#[$s_attr])*
+                    (Strategy::$s_variant, Mode::Sequential) => {
+                        let $s_config = config;
+                        sequential_microbench(|| $s_make, config, counters, label)
+                    }
+                )*
+                $(
+                    $(#[$attr])*
+                    (Strategy::$variant, Mode::Sequential) => {
+                        let $config = config;
+                        sequential_microbench(|| $make, config, counters, label)
+                    }
+                    $(#[$attr])*
+                    (Strategy::$variant, Mode::Parallel) => {
+                        let $config = config;
+                        parallel_microbench(|| $make, config, counters, label)
+                    }
+                )*
+                _ => unreachable!("{} does not support {} mode", strategy, mode),
+            }
+        }
+    };
+}
+
+strategies!(strategy_enum);
+
+impl Strategy {
+    // Parallel speedups are measured against sequential use of this strategy
+    pub const BASELINE: Strategy = Strategy::Raw;
+
+    // Strategy with the given name, as displayed in results
+    pub fn from_name(name: &str) -> Option<Strategy> {
+        Strategy::ALL.iter().copied().find(|strategy| strategy.name() == name)
+    }
+
+    // Whether the strategy only estimates the contents of bins
+    pub fn is_approximate(self) -> bool {
+        match self {
+            #[cfg(feature = "count_min")]
+            Strategy::CountMin => true,
+            _ => false,
+        }
+    }
+
+    // Why histograms of the strategy cannot be built on this machine, if they
+    // cannot, in which case the strategy is skipped
+    pub fn unavailability(self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "gpu")]
+            Strategy::Gpu if !GpuHistogram::is_available() => Some("no usable GPU was found"),
+            _ => None,
+        }
+    }
+
+    // Bytes of counters which each inserted value increments, for estimating
+    // the memory bandwidth of fills. Replicas and staging buffers are not
+    // accounted for, as they only add traffic once per readout or batch.
+    pub fn bytes_per_hit(self) -> usize {
+        match self {
+            #[cfg(feature = "narrow_atomic")]
+            Strategy::AtomicU16 => mem::size_of::<u16>(),
+            #[cfg(feature = "narrow_atomic")]
+            Strategy::AtomicU32 => mem::size_of::<u32>(),
+            #[cfg(feature = "gpu")]
+            Strategy::Gpu => mem::size_of::<u32>(),
+            #[cfg(feature = "count_min")]
+            Strategy::CountMin => CountMinHistogram::DEFAULT_DEPTH * mem::size_of::<usize>(),
+            _ => mem::size_of::<usize>(),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Strategy::from_name(s).ok_or_else(|| format!("unknown or disabled strategy '{}'", s))
+    }
+}
+
+// Whether a histogram is filled by a single thread or by a thread pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Sequential,
+    Parallel,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 2] = [Mode::Sequential, Mode::Parallel];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Sequential => "sequential",
+            Mode::Parallel => "parallel",
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+// Drivers of parallel benchmarks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    // Batches are processed by a rayon thread pool, which balances them
+    // dynamically across threads using work stealing
+    #[default]
+    Rayon,
+
+    // Plain std threads are spawned, and statically get a contiguous chunk of
+    // the batches each, which measures the histograms without scheduler
+    // overhead
+    Threads,
+
+    // Many tasks are spawned on a multi-threaded tokio runtime, and yield to
+    // its work-stealing scheduler between batches
+    #[cfg(feature = "async")]
+    Tokio,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Rayon => "rayon",
+            Backend::Threads => "threads",
+            #[cfg(feature = "async")]
+            Backend::Tokio => "tokio",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+// Run the benchmark of a certain strategy in a certain mode
+pub fn run(strategy: Strategy, mode: Mode, config: &Config) -> BenchResult {
+    assert!(strategy.supports(mode), "{} does not support {} mode", strategy, mode);
+    let counters = &mut HardwareCounters::new();
+    let label = labels::label(strategy, mode, config);
+    let _memory_binding = config.memory_node.map(MemoryBinding::new);
+    let measurement = measure(strategy, mode, config, counters, label);
+    BenchResult::new(strategy, mode, config, &measurement)
+}
+
+// Outcome of a microbenchmark
+struct Measurement {
+    // Median, minimum and standard deviation of the time per inserted value
+    // across repetitions, in nanoseconds
+    ns_per_iter: f64,
+    min_ns_per_iter: f64,
+    stddev_ns_per_iter: f64,
+
+    // Hardware event counts of the median repetition
+    counts: Option<HardwareCounts>,
+
+    // Synchronization events of the median repetition, if telemetry is enabled
+    contention: Option<telemetry::Counts>,
+
+    // Heap allocations of the median repetition, if a heap profiler is running
+    allocations: Option<RunAllocations>,
+
+    // Median time taken to aggregate the final bin contents, in nanoseconds
+    aggregation_ns: f64,
+    memory_usage: usize,
+
+    // Number of distinct threads which filled the last histogram, for the
+    // strategies which keep track of it
+    threads_seen: Option<usize>,
+
+    // Checksum of the final bin contents, if the run is reproducible
+    checksum: Option<u64>,
+
+    // Rate at which reader threads queried the histogram during the fill
+    reads_per_sec: Option<f64>,
+}
+
+// FNV-1a hash of the bin contents of a histogram, which does not depend on the
+// machine's endianness or pointer width
+fn checksum(bins: &[usize]) -> u64 {
+    bins.iter()
+        .flat_map(|&bin| (bin as u64).to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+// Run a user-specified microbenchmark, which fills a fresh histogram from
+// `make_histogram` on each run. After `config.warmup_runs` unmeasured runs, the
+// microbenchmark is timed `config.repetitions` times, and the statistics of
+// these measurements are reported along with the contents of the last
+// histogram. Fills and readouts are performed under the label of the benchmark.
+fn microbench<H: Histogram>(config: &Config,
+                            counters: &mut HardwareCounters,
+                            label: Label,
+                            make_histogram: impl Fn() -> H,
+                            mut fill: impl FnMut(H) -> H) -> Measurement {
+    assert!(config.repetitions > 0, "At least one repetition is needed");
+    let mut runs = Vec::with_capacity(config.repetitions);
+    let mut aggregation_times = Vec::with_capacity(config.repetitions);
+    let mut last_histogram = None;
+    for run in 0..config.warmup_runs + config.repetitions {
+        let mut phase_allocations = PhaseAllocations::start();
+        let histogram = make_histogram();
+        let construction_allocations = phase_allocations.end_phase();
+        let events_before = telemetry::snapshot();
+        let ((histogram, duration), counts) = counters.measure(|| {
+            // Fills are only complete once buffered or delegated values have
+            // made it into the bins
+            let start = Instant::now();
+            let histogram = label.call(|| {
+                let mut histogram = fill(histogram);
+                trace::flush::<H, _>(|| histogram.flush_mut());
+                histogram
+            });
+            (histogram, start.elapsed())
+        });
+        let contention = telemetry::snapshot()
+            .zip(events_before)
+            .map(|(after, before)| after.since(&before));
+        let fill_allocations = phase_allocations.end_phase();
+        assert_eq!(histogram.num_hits(), config.num_hits());
+
+        // Time the production of the final histogram separately, as it is only
+        // done once per fill in real-world use
+        let start = Instant::now();
+        black_box(label.call(|| trace::aggregate::<H, _>(|| histogram.bins())));
+        let aggregation_time = start.elapsed();
+        let allocations = construction_allocations
+            .zip(fill_allocations)
+            .zip(phase_allocations.end_phase())
+            .map(|((construction, fill), readout)| RunAllocations { construction, fill, readout });
+
+        last_histogram = Some(histogram);
+        if run >= config.warmup_runs {
+            // Idle periods of bursty workloads are not accounted for
+            let busy_fraction = config.burst.map_or(1.0, |burst| burst.duty_cycle);
+            let busy_ns = duration.as_nanos() as f64 * busy_fraction;
+            runs.push((busy_ns / (config.num_hits() as f64), counts, contention, allocations));
+            aggregation_times.push(aggregation_time.as_nanos() as f64);
+        }
+    }
+
+    let histogram = last_histogram.expect("There should have been at least one run");
+
+    runs.sort_by(|(t1, _, _, _), (t2, _, _, _)| t1.total_cmp(t2));
+    let times = runs.iter().map(|&(t, _, _, _)| t).collect::<Vec<_>>();
+    let mid = times.len() / 2;
+    let mean = times.iter().sum::<f64>() / times.len() as f64;
+    let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / times.len() as f64;
+    Measurement {
+        ns_per_iter: median(&mut times.clone()),
+        min_ns_per_iter: times[0],
+        stddev_ns_per_iter: variance.sqrt(),
+        counts: runs[mid].1,
+        contention: runs[mid].2,
+        allocations: runs[mid].3,
+        aggregation_ns: median(&mut aggregation_times),
+        memory_usage: histogram.memory_usage(),
+        threads_seen: histogram.num_threads(),
+        checksum: config.deterministic.then(|| checksum(&histogram.bins())),
+        reads_per_sec: None,
+    }
+}
+
+// Median of a nonempty set of measurements
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] }
+}
+
+fn sequential_microbench<H: Histogram>(make_histogram: impl Fn() -> H,
+                                       config: &Config,
+                                       counters: &mut HardwareCounters,
+                                       label: Label) -> Measurement {
+    let id = ThreadID::load();
+    let input = config.distribution.generator(config.num_bins);
+    let mut buf = Vec::with_capacity(config.batch_size);
+    microbench(config, counters, label, make_histogram, |mut histogram| {
+        let mut rng = BenchRng::from_seed(RNG_SEED);
+        let mut pacer = Pacer::new(config.burst);
+        for _ in 0..config.num_batches() {
+            trace::fill::<H, _>(config.batch_size, || if config.fill_one {
+                for _ in 0..config.batch_size {
+                    histogram.fill_one_mut(input.gen(&mut rng));
+                }
+            } else if config.fill_iter {
+                histogram.fill_iter_mut((0..config.batch_size).map(|_| input.gen(&mut rng)));
+            } else {
+                histogram.fill_with_id_mut(input.gen_batch(&mut rng, &mut buf, config.batch_size),
+                                           id);
+            });
+            pacer.after_batch();
+        }
+        histogram
+    })
+}
+
+// With the rayon backend, batches are dynamically load-balanced across threads
+// by default, and each thread draws inputs from its own RNG, so which values
+// end up in the histogram depends on scheduling. In deterministic mode, and
+// with the threads backend, the batches are instead split into one contiguous
+// chunk per thread, and each chunk is drawn from its own RNG, so that the
+// histogram is filled with the same values on every run, at the cost of load
+// imbalance.
+fn parallel_microbench<H>(make_histogram: impl Fn() -> H,
+                          config: &Config,
+                          counters: &mut HardwareCounters,
+                          label: Label) -> Measurement
+    where H: SyncHistogram + Send + 'static
+{
+    // Thread pools and runtimes are set up outside of the measurement
+    enum Driver {
+        Pool(rayon::ThreadPool),
+        Threads,
+        #[cfg(feature = "async")]
+        Runtime(tokio::runtime::Runtime),
+    }
+    let driver = match config.backend {
+        Backend::Rayon => Driver::Pool(thread_pool(config)),
+        Backend::Threads => Driver::Threads,
+        #[cfg(feature = "async")]
+        Backend::Tokio => Driver::Runtime(tasks::runtime(config)),
+    };
+    let input = Arc::<dyn InputGenerator>::from(config.distribution.generator(config.num_bins));
+    let batch_size = config.batch_size;
+    let mut read_rates = Vec::with_capacity(config.warmup_runs + config.repetitions);
+    let mut measurement = microbench(config, counters, label, make_histogram, |histogram| {
+        let histogram = Arc::new(histogram);
+        let rng = Mutex::new(BenchRng::from_seed(RNG_SEED));
+        let read_rate = with_readers(&*histogram, config.num_readers, label, || match &driver {
+            Driver::Pool(pool) if !config.deterministic => pool.install(|| {
+                (0..config.num_batches())
+                    .into_par_iter()
+                    .for_each_init(
+                        || {
+                            let mut rng_lock = rng.lock().unwrap();
+                            let thread_rng = rng_lock.clone();
+                            rng_lock.jump();
+                            (thread_rng, ThreadID::load(), Vec::with_capacity(batch_size),
+                             Pacer::new(config.burst))
+                        },
+                        |(rng, id, buf, pacer), _| {
+                            label.call(|| fill_batch(&*histogram, &*input, rng, buf, *id, config));
+                            pacer.after_batch();
+                        }
+                    )
+            }),
+            Driver::Pool(pool) => pool.install(|| {
+                chunk_rngs(config.num_threads)
+                    .into_par_iter()
+                    .enumerate()
+                    .with_max_len(1)
+                    .for_each(|(chunk, rng)| {
+                        label.call(|| fill_chunk(&*histogram, &*input, rng, chunk, config))
+                    })
+            }),
+            // Threads are spawned in the measured region, which costs a few
+            // microseconds per thread
+            Driver::Threads => thread::scope(|s| {
+                for (chunk, rng) in chunk_rngs(config.num_threads).into_iter().enumerate() {
+                    let (histogram, input) = (&*histogram, &*input);
+                    s.spawn(move || {
+                        if let Some(cpus) = &config.cpu_affinity {
+                            pin_current_thread(cpus, chunk);
+                        }
+                        label.call(|| fill_chunk(histogram, input, rng, chunk, config))
+                    });
+                }
+            }),
+            #[cfg(feature = "async")]
+            Driver::Runtime(runtime) => tasks::fill(runtime, &histogram, &input, config, label),
+        });
+        read_rates.extend(read_rate);
+        Arc::try_unwrap(histogram)
+            .ok()
+            .expect("Benchmark threads should be done with the histogram")
+    });
+    if config.num_readers > 0 {
+        measurement.reads_per_sec = Some(median(&mut read_rates[config.warmup_runs..]));
+    }
+    measurement
+}
+
+// Run `fill` while `num_readers` threads repeatedly query the number of hits of
+// the histogram under `label`, and return the rate at which they did so in
+// reads per second
+fn with_readers(histogram: &impl SyncHistogram,
+                num_readers: usize,
+                label: Label,
+                fill: impl FnOnce()) -> Option<f64> {
+    if num_readers == 0 {
+        fill();
+        return None;
+    }
+    let done = AtomicBool::new(false);
+    let start = Instant::now();
+    let num_reads = thread::scope(|s| {
+        let readers = (0..num_readers)
+            .map(|_| s.spawn(|| label.call(|| {
+                let mut num_reads = 0usize;
+                while !done.load(Ordering::Relaxed) {
+                    black_box(histogram.num_hits());
+                    num_reads += 1;
+                }
+                num_reads
+            })))
+            .collect::<Vec<_>>();
+        fill();
+        done.store(true, Ordering::Relaxed);
+        readers.into_iter()
+               .map(|reader| reader.join().expect("A reader thread panicked"))
+               .sum::<usize>()
+    });
+    Some(num_reads as f64 / start.elapsed().as_secs_f64())
+}
+
+// One RNG per input chunk, in chunk order
+fn chunk_rngs(num_chunks: usize) -> Vec<BenchRng> {
+    let mut rng = BenchRng::from_seed(RNG_SEED);
+    (0..num_chunks)
+        .map(|_| {
+            let chunk_rng = rng.clone();
+            rng.jump();
+            chunk_rng
+        })
+        .collect()
+}
+
+// Number of input batches in the `chunk`-th of `num_chunks` contiguous chunks
+fn chunk_batches(config: &Config, chunk: usize, num_chunks: usize) -> usize {
+    let num_batches = config.num_batches();
+    num_batches * (chunk + 1) / num_chunks - num_batches * chunk / num_chunks
+}
+
+// Fill a histogram with the `chunk`-th of `config.num_threads` contiguous
+// chunks of input batches
+fn fill_chunk(histogram: &impl SyncHistogram,
+              input: &dyn InputGenerator,
+              mut rng: BenchRng,
+              chunk: usize,
+              config: &Config) {
+    let id = ThreadID::load();
+    let mut buf = Vec::with_capacity(config.batch_size);
+    let mut pacer = Pacer::new(config.burst);
+    for _ in 0..chunk_batches(config, chunk, config.num_threads) {
+        fill_batch(histogram, input, &mut rng, &mut buf, id, config);
+        pacer.after_batch();
+    }
+}
+
+// Fill a histogram with a batch of values, generated into `buf` first unless
+// they are handed over one by one or as an iterator
+fn fill_batch<H: SyncHistogram>(histogram: &H,
+                                 input: &dyn InputGenerator,
+                                 rng: &mut BenchRng,
+                                 buf: &mut Vec<f32>,
+                                 id: ThreadID,
+                                 config: &Config) {
+    trace::fill::<H, _>(config.batch_size, || if config.fill_one {
+        for _ in 0..config.batch_size {
+            histogram.fill_one_with_id(input.gen(rng), id);
+        }
+    } else if config.fill_iter {
+        histogram.fill_iter((0..config.batch_size).map(|_| input.gen(rng)))
+    } else {
+        histogram.fill_with_id(input.gen_batch(rng, buf, config.batch_size), id)
+    })
+}
+
+// Identifiers of the CPUs which threads can be pinned to
+pub fn cpu_ids() -> Vec<usize> {
+    core_affinity::get_core_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|core| core.id)
+        .collect()
+}
+
+// Build the thread pool of a parallel benchmark, pinning its workers to CPUs
+// if requested. A failure to pin threads is reported, but is not fatal.
+fn thread_pool(config: &Config) -> rayon::ThreadPool {
+    let mut builder = rayon::ThreadPoolBuilder::new().num_threads(config.num_threads);
+    if let Some(cpus) = config.cpu_affinity.clone() {
+        assert!(!cpus.is_empty(), "Cannot pin threads to an empty set of CPUs");
+        builder = builder.start_handler(move |worker| pin_current_thread(&cpus, worker));
+    }
+    builder.build().expect("Failed to build the benchmark thread pool")
+}
+
+// Pin the `worker`-th benchmark thread to a CPU from a list
+fn pin_current_thread(cpus: &[usize], worker: usize) {
+    let cpu = cpus[worker % cpus.len()];
+    if !core_affinity::set_for_current(core_affinity::CoreId { id: cpu }) {
+        static WARNING: Once = Once::new();
+        WARNING.call_once(|| {
+            eprintln!("WARNING: Failed to pin a benchmark thread to CPU {}", cpu);
+        });
+    }
+}
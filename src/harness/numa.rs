@@ -0,0 +1,139 @@
+// NUMA placement of benchmark threads and memory
+//
+// This relies on Linux's sysfs and memory policies. On other operating systems,
+// NUMA nodes cannot be listed and memory binding is not available.
+//
+// Worker threads are placed on NUMA nodes by pinning them to the CPUs of these
+// nodes, in which case the kernel's default first-touch policy puts the pages
+// which they write to first on their own node. Alternatively, all memory which
+// is allocated while a benchmark runs, including the histogram, can be bound to
+// a given node. This is done by setting the memory policy of the thread which
+// runs the benchmark, which the threads that it spawns inherit.
+
+use std::{
+    fs,
+    io,
+    sync::Once,
+};
+
+// NUMA nodes of the machine
+pub fn numa_nodes() -> io::Result<Vec<usize>> {
+    parse_list(&fs::read_to_string("/sys/devices/system/node/online")?)
+}
+
+// CPUs of a NUMA node
+pub fn node_cpus(node: usize) -> io::Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let cpus = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("Cannot list the CPUs of NUMA node {}: {}", node, e))
+    })?;
+    parse_list(&cpus)
+}
+
+// CPUs which the i-th worker thread should be pinned to, in order to spread
+// worker threads evenly across NUMA nodes
+pub fn interleaved_cpus(nodes: &[usize]) -> io::Result<Vec<usize>> {
+    let node_cpus = nodes.iter()
+        .map(|&node| node_cpus(node))
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(interleave(&node_cpus))
+}
+
+// Take the first CPU of every node, then the second one, and so on
+fn interleave(node_cpus: &[Vec<usize>]) -> Vec<usize> {
+    let max_cpus = node_cpus.iter().map(Vec::len).max().unwrap_or(0);
+    (0..max_cpus)
+        .flat_map(|i| node_cpus.iter().filter_map(move |cpus| cpus.get(i).copied()))
+        .collect()
+}
+
+// Parse the "0-3,8,10-11" list format of sysfs
+fn parse_list(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("Invalid sysfs list '{}'", list.trim()));
+    let mut result = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-')
+                              .map(|b| b.parse::<usize>().map_err(|_| invalid()));
+        let start = bounds.next().ok_or_else(invalid)??;
+        let end = bounds.next().transpose()?.unwrap_or(start);
+        result.extend(start..=end);
+    }
+    Ok(result)
+}
+
+// Binding of the memory allocated by the current thread, and by the threads
+// which it spawns, to a NUMA node. Memory allocation goes back to the default
+// first-touch policy when this is dropped.
+pub(super) struct MemoryBinding(());
+
+impl MemoryBinding {
+    // A failure to bind memory is reported, but is not fatal
+    pub(super) fn new(node: usize) -> Self {
+        if let Err(e) = bind_memory(Some(node)) {
+            static WARNING: Once = Once::new();
+            WARNING.call_once(|| {
+                eprintln!("WARNING: Failed to bind memory to NUMA node {}: {}", node, e);
+            });
+        }
+        Self(())
+    }
+}
+
+impl Drop for MemoryBinding {
+    fn drop(&mut self) {
+        // Going back to the default policy cannot fail in practice, and a
+        // failure would only affect the placement of later benchmarks
+        let _ = bind_memory(None);
+    }
+}
+
+// Set the memory policy of the current thread to binding to a node, or back to
+// the default policy. The libc crate does not wrap this system call.
+#[cfg(target_os = "linux")]
+fn bind_memory(node: Option<usize>) -> io::Result<()> {
+    // Memory policies, from linux/mempolicy.h
+    const MPOL_DEFAULT: libc::c_int = 0;
+    const MPOL_BIND: libc::c_int = 2;
+
+    const MASK_BITS: usize = libc::c_ulong::BITS as usize;
+    let (mode, mask) = match node {
+        Some(node) => {
+            let mut mask = vec![0 as libc::c_ulong; node / MASK_BITS + 1];
+            mask[node / MASK_BITS] |= 1 << (node % MASK_BITS);
+            (MPOL_BIND, mask)
+        }
+        None => (MPOL_DEFAULT, Vec::new()),
+    };
+    // The kernel expects the number of bits in the mask plus one
+    let max_node = if mask.is_empty() { 0 } else { mask.len() * MASK_BITS + 1 };
+    // Safe because the kernel reads at most max_node - 1 bits from the mask
+    let result = unsafe {
+        libc::syscall(libc::SYS_set_mempolicy, mode, mask.as_ptr(), max_node)
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_memory(_node: Option<usize>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Memory binding requires Linux"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sysfs_lists() {
+        assert_eq!(parse_list("0\n").unwrap(), vec![0]);
+        assert_eq!(parse_list("0-3,8,10-11\n").unwrap(), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_list("\n").unwrap(), Vec::<usize>::new());
+        assert!(parse_list("0-a").is_err());
+    }
+
+    #[test]
+    fn interleaving() {
+        assert_eq!(interleave(&[vec![0, 1, 2], vec![4, 5]]), vec![0, 4, 1, 5, 2]);
+    }
+}
@@ -0,0 +1,177 @@
+// Rendering of scaling curves from benchmark results
+//
+// Results are grouped by strategy, filling mode, input distribution and fixed
+// parameters, and each group becomes one curve of throughput versus a varying
+// benchmark parameter (number of threads, number of bins, batch size or bins
+// per cache line). Charts are only drawn for parameters which took several
+// values in the benchmark run, and only feature the strategies which the
+// parameter applies to.
+
+use {
+    super::{BenchResult, Distribution, Mode, Strategy},
+    plotters::{
+        coord::Shift,
+        prelude::*,
+    },
+    std::{
+        error::Error,
+        path::{Path, PathBuf},
+    },
+};
+
+// Supported image formats
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+const IMAGE_SIZE: (u32, u32) = (1024, 768);
+
+// Benchmark parameter which can be varied along the horizontal axis of a chart,
+// if it applies to the strategy of a result
+struct Parameter {
+    name: &'static str,
+    desc: &'static str,
+    value: fn(&BenchResult) -> Option<usize>,
+}
+
+const PARAMETERS: [Parameter; 4] = [
+    Parameter { name: "threads", desc: "Threads", value: |r| Some(r.threads) },
+    Parameter { name: "bins", desc: "Bins", value: |r| Some(r.bins) },
+    Parameter { name: "batch_size", desc: "Batch size", value: |r| Some(r.batch_size) },
+    Parameter { name: "bins_per_line", desc: "Bins per cache line", value: |r| r.bins_per_line },
+];
+
+// Render every meaningful scaling chart into `out_dir`, return the paths of the
+// files which were written
+pub fn plot_scaling(results: &[BenchResult],
+                    out_dir: &Path,
+                    format: ImageFormat) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut written = Vec::new();
+    for param in PARAMETERS.iter() {
+        let curves = curves(results, param);
+        if !curves.iter().any(|curve| curve.points.len() > 1) {
+            continue;
+        }
+        let path = out_dir.join(format!("throughput_vs_{}.{}", param.name, format.extension()));
+        match format {
+            ImageFormat::Svg => {
+                let root = SVGBackend::new(&path, IMAGE_SIZE).into_drawing_area();
+                draw_chart(&root, param.desc, &curves)?;
+                root.present()?;
+            }
+            ImageFormat::Png => {
+                let root = BitMapBackend::new(&path, IMAGE_SIZE).into_drawing_area();
+                draw_chart(&root, param.desc, &curves)?;
+                root.present()?;
+            }
+        }
+        written.push(path);
+    }
+    Ok(written)
+}
+
+// Throughput curve of a strategy in a given mode and input distribution, at
+// fixed values of the parameters which are not on the horizontal axis
+struct Curve {
+    key: (Strategy, Mode, Distribution, Vec<Option<usize>>),
+    label: String,
+    mode: Mode,
+    points: Vec<(usize, f64)>,
+}
+
+fn curves(results: &[BenchResult], abscissa: &Parameter) -> Vec<Curve> {
+    // Other parameters only need to be labeled if they vary
+    let others = PARAMETERS.iter()
+        .filter(|p| p.name != abscissa.name)
+        .collect::<Vec<_>>();
+    let varying = others.iter()
+        .filter(|p| results.iter().any(|r| (p.value)(r) != (p.value)(&results[0])))
+        .collect::<Vec<_>>();
+    let distributions = results.iter().any(|r| r.distribution != results[0].distribution);
+
+    let mut curves: Vec<Curve> = Vec::new();
+    for r in results {
+        let x = match (abscissa.value)(r) {
+            Some(x) => x,
+            None => continue,
+        };
+        let key = (r.strategy,
+                   r.mode,
+                   r.distribution,
+                   others.iter().map(|p| (p.value)(r)).collect());
+        let point = (x, r.throughput / 1e6);
+        match curves.iter_mut().find(|curve| curve.key == key) {
+            Some(curve) => curve.points.push(point),
+            None => {
+                let mut label = format!("{} ({}", r.strategy, r.mode);
+                if distributions {
+                    label.push_str(&format!(", {}", r.distribution));
+                }
+                for p in &varying {
+                    if let Some(value) = (p.value)(r) {
+                        label.push_str(&format!(", {}={}", p.name, value));
+                    }
+                }
+                label.push(')');
+                curves.push(Curve { key, label, mode: r.mode, points: vec![point] });
+            }
+        }
+    }
+    for curve in &mut curves {
+        curve.points.sort_by_key(|&(x, _)| x);
+    }
+    curves
+}
+
+fn draw_chart<DB>(root: &DrawingArea<DB, Shift>,
+                  x_desc: &str,
+                  curves: &[Curve]) -> Result<(), Box<dyn Error>>
+    where DB: DrawingBackend,
+          DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+    let points = curves.iter().flat_map(|curve| curve.points.iter());
+    let x_max = points.clone().map(|&(x, _)| x).max().unwrap_or(1).max(2);
+    let y_max = points.map(|&(_, y)| y).fold(0.0, f64::max) * 1.1;
+
+    // Bin counts span several orders of magnitude, so the abscissa is logarithmic
+    let x_range = (1..x_max).log_scale();
+    let mut chart = ChartBuilder::on(root)
+        .caption(format!("Throughput vs {}", x_desc.to_lowercase()), ("sans-serif", 30))
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(60)
+        .build_cartesian_2d(x_range, 0.0..y_max.max(1.0))?;
+    chart.configure_mesh()
+        .x_desc(x_desc)
+        .y_desc("Throughput (Mhits/s)")
+        .draw()?;
+
+    for (idx, curve) in curves.iter().enumerate() {
+        let color = Palette99::pick(idx).to_rgba();
+        let style = match curve.mode {
+            Mode::Sequential => color.stroke_width(1),
+            Mode::Parallel => color.stroke_width(3),
+        };
+        chart.draw_series(LineSeries::new(curve.points.iter().copied(), style))?
+            .label(curve.label.as_str())
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], style));
+        chart.draw_series(curve.points.iter().map(|&p| Circle::new(p, 3, color.filled())))?;
+    }
+    chart.configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+    Ok(())
+}
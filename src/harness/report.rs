@@ -0,0 +1,32 @@
+// Self-contained HTML report of benchmark results
+//
+// The report is a single HTML file, which embeds the results as JSON and draws
+// scaling charts from them with a bit of JavaScript, without fetching anything,
+// so that results can be shared with people who do not run the benchmarks. As
+// with the plot feature, a chart is drawn for each benchmark parameter which
+// took several values, with one curve per strategy, mode and values of the
+// other parameters. Readers can hide strategies, switch between throughput,
+// time per inserted value, speedup and memory usage, and hover points for
+// details.
+
+use {
+    super::BenchResult,
+    std::io::{self, Write},
+};
+
+const TEMPLATE: &str = include_str!("report.html");
+
+// Where the results go in the template
+const PLACEHOLDER: &str = "{{RESULTS}}";
+
+pub fn write_html_report(results: &[BenchResult], mut out: impl Write) -> io::Result<()> {
+    // "</script>" or "<!--" in a strategy name would end the script element
+    // which the results are embedded into, so the angle brackets of JSON
+    // strings are escaped
+    let json = serde_json::to_string(results)?.replace('<', "\\u003c");
+    let (head, tail) = TEMPLATE.split_once(PLACEHOLDER)
+                               .expect("The report template should have a placeholder");
+    out.write_all(head.as_bytes())?;
+    out.write_all(json.as_bytes())?;
+    out.write_all(tail.as_bytes())
+}
@@ -0,0 +1,417 @@
+// Benchmark results, and the various formats in which they can be emitted
+
+use {
+    super::{
+        Backend, Config, Distribution, HardwareCounts, Measurement, Mode, RunAllocations,
+        Strategy,
+    },
+    crate::telemetry,
+    serde::{Deserialize, Serialize},
+    std::io::{self, Write},
+};
+
+// Outcome of a single benchmark run
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub strategy: Strategy,
+    pub mode: Mode,
+    pub threads: usize,
+    pub bins: usize,
+    pub batch_size: usize,
+    pub buckets: usize,
+    pub distribution: Distribution,
+
+    // Bins per cache line, for the padded atomic strategy
+    pub bins_per_line: Option<usize>,
+
+    // Number of independently locked groups of bins, for the bin-sharded
+    // strategy
+    pub shards: Option<usize>,
+
+    // Driver of parallel benchmarks, and number of threads reading the
+    // histogram while it is filled, which older baselines do not record
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub readers: usize,
+
+    // Whether the input was split statically across threads, whether values
+    // were generated while they were inserted, and whether they were inserted
+    // one by one, which older baselines do not record
+    #[serde(default)]
+    pub deterministic: bool,
+    #[serde(default)]
+    pub fill_iter: bool,
+    #[serde(default)]
+    pub fill_one: bool,
+
+    // NUMA node which memory was bound to, if any
+    pub memory_node: Option<usize>,
+
+    // Batches per burst and duty cycle of bursty workloads
+    pub burst_batches: Option<usize>,
+    pub duty_cycle: Option<f64>,
+
+    // Nanoseconds spent per inserted value (median across repetitions),
+    // excluding the idle periods of bursty workloads
+    pub ns_per_iter: f64,
+
+    // Minimum and standard deviation of the nanoseconds spent per inserted
+    // value across repetitions, which older baselines do not have
+    #[serde(default)]
+    pub min_ns_per_iter: f64,
+    #[serde(default)]
+    pub stddev_ns_per_iter: f64,
+
+    // Inserted values per second
+    pub throughput: f64,
+
+    // Bytes of counters incremented per second, i.e. the memory bandwidth
+    // which fills would need if every increment went to memory, which older
+    // baselines do not have
+    #[serde(default)]
+    pub bytes_per_sec: f64,
+
+    // Nanoseconds spent aggregating the final bin contents after filling
+    // (locking buckets, summing replicas...), which older baselines do not have
+    #[serde(default)]
+    pub aggregation_ns: f64,
+
+    // Memory used by the histogram, including replicas
+    pub memory_bytes: usize,
+
+    // Number of distinct threads which filled the histogram, for the
+    // strategies which keep track of it
+    #[serde(default)]
+    pub threads_seen: Option<usize>,
+
+    // Speedup and parallel efficiency of parallel benchmarks with respect to
+    // the sequential baseline, when it is known
+    pub speedup: Option<f64>,
+    pub efficiency: Option<f64>,
+
+    // Hardware events per inserted value, if performance counters are enabled
+    pub cycles_per_iter: Option<f64>,
+    pub cache_misses_per_iter: Option<f64>,
+    pub llc_loads_per_iter: Option<f64>,
+
+    // Synchronization events per inserted value, and nanoseconds spent
+    // acquiring locks per inserted value, if telemetry is enabled
+    #[serde(default)]
+    pub locks_per_iter: Option<f64>,
+    #[serde(default)]
+    pub lock_wait_ns_per_iter: Option<f64>,
+    #[serde(default)]
+    pub cas_retries_per_iter: Option<f64>,
+    #[serde(default)]
+    pub rmws_per_iter: Option<f64>,
+
+    // Heap allocations and allocated bytes of the construction of the
+    // histogram, of its fill and of the readout of its bins, if a heap profiler
+    // was running
+    #[serde(default)]
+    pub construction_allocs: Option<u64>,
+    #[serde(default)]
+    pub construction_alloc_bytes: Option<u64>,
+    #[serde(default)]
+    pub fill_allocs: Option<u64>,
+    #[serde(default)]
+    pub fill_alloc_bytes: Option<u64>,
+    #[serde(default)]
+    pub readout_allocs: Option<u64>,
+    #[serde(default)]
+    pub readout_alloc_bytes: Option<u64>,
+
+    // Checksum of the final bin contents, in deterministic mode. Runs with the
+    // same parameters and number of threads fill the same bins, whatever the
+    // strategy and the machine.
+    pub bins_checksum: Option<u64>,
+
+    // Rate at which reader threads queried the number of hits, if any
+    pub reads_per_sec: Option<f64>,
+}
+
+impl BenchResult {
+    pub(super) fn new(strategy: Strategy,
+                      mode: Mode,
+                      config: &Config,
+                      measurement: &Measurement) -> Self {
+        let ns_per_iter = measurement.ns_per_iter;
+        let per_iter = |count: fn(&HardwareCounts) -> u64| {
+            measurement.counts.as_ref().map(|c| count(c) as f64 / config.num_hits() as f64)
+        };
+        let events_per_iter = |count: fn(&telemetry::Counts) -> u64| {
+            measurement.contention.as_ref().map(|c| count(c) as f64 / config.num_hits() as f64)
+        };
+        let allocations = |count: fn(&RunAllocations) -> u64| {
+            measurement.allocations.as_ref().map(count)
+        };
+        Self {
+            strategy,
+            mode,
+            threads: match mode {
+                Mode::Sequential => 1,
+                Mode::Parallel => config.num_threads,
+            },
+            bins: config.num_bins,
+            batch_size: config.batch_size,
+            buckets: config.num_buckets,
+            distribution: config.distribution,
+            bins_per_line: match strategy {
+                #[cfg(feature = "padded_atomic")]
+                Strategy::PaddedAtomic => Some(config.bins_per_line),
+                _ => None,
+            },
+            shards: match strategy {
+                #[cfg(feature = "bin_sharded")]
+                Strategy::BinSharded => Some(config.num_shards),
+                _ => None,
+            },
+            backend: config.backend,
+            readers: match mode {
+                Mode::Sequential => 0,
+                Mode::Parallel => config.num_readers,
+            },
+            deterministic: config.deterministic,
+            fill_iter: config.fill_iter,
+            fill_one: config.fill_one,
+            memory_node: config.memory_node,
+            burst_batches: config.burst.map(|burst| burst.batches),
+            duty_cycle: config.burst.map(|burst| burst.duty_cycle),
+            ns_per_iter,
+            min_ns_per_iter: measurement.min_ns_per_iter,
+            stddev_ns_per_iter: measurement.stddev_ns_per_iter,
+            throughput: 1e9 / ns_per_iter,
+            bytes_per_sec: 1e9 / ns_per_iter * strategy.bytes_per_hit() as f64,
+            aggregation_ns: measurement.aggregation_ns,
+            memory_bytes: measurement.memory_usage,
+            threads_seen: measurement.threads_seen,
+            speedup: None,
+            efficiency: None,
+            cycles_per_iter: per_iter(|c| c.cycles),
+            cache_misses_per_iter: per_iter(|c| c.cache_misses),
+            llc_loads_per_iter: per_iter(|c| c.llc_loads),
+            locks_per_iter: events_per_iter(|c| c.lock_acquisitions),
+            lock_wait_ns_per_iter: events_per_iter(|c| c.lock_wait_ns),
+            cas_retries_per_iter: events_per_iter(|c| c.cas_retries),
+            rmws_per_iter: events_per_iter(|c| c.atomic_rmws),
+            construction_allocs: allocations(|a| a.construction.blocks),
+            construction_alloc_bytes: allocations(|a| a.construction.bytes),
+            fill_allocs: allocations(|a| a.fill.blocks),
+            fill_alloc_bytes: allocations(|a| a.fill.bytes),
+            readout_allocs: allocations(|a| a.readout.blocks),
+            readout_alloc_bytes: allocations(|a| a.readout.bytes),
+            bins_checksum: measurement.checksum,
+            reads_per_sec: measurement.reads_per_sec,
+        }
+    }
+}
+
+// Human-readable summary table
+pub fn write_table(results: &[BenchResult], mut out: impl Write) -> io::Result<()> {
+    // Hardware counters and checksums are only displayed if they were measured
+    let has_counters = results.iter().any(|r| r.cycles_per_iter.is_some());
+    let has_telemetry = results.iter().any(|r| r.locks_per_iter.is_some());
+    let has_checksums = results.iter().any(|r| r.bins_checksum.is_some());
+    let has_readers = results.iter().any(|r| r.reads_per_sec.is_some());
+    let has_memory_nodes = results.iter().any(|r| r.memory_node.is_some());
+    let has_distributions = results.iter().any(|r| r.distribution != results[0].distribution);
+    let has_bins_per_line = results.iter().any(|r| r.bins_per_line.is_some());
+    let has_threads_seen = results.iter().any(|r| r.threads_seen.is_some());
+    let has_allocations = results.iter().any(|r| r.fill_allocs.is_some());
+    write!(out, "{:<30} {:<12} {:>10} {:>6} {:>8} {:>12} {:>12} {:>10} {:>16} {:>10} {:>10} {:>10} \
+                 {:>8} {:>10}",
+           "Strategy", "Mode", "Bins", "Batch", "Threads", "ns/iter", "Min ns/iter", "Std dev",
+           "Mhits/s", "GB/s", "Aggregate", "Memory", "Speedup", "Efficiency")?;
+    if has_counters {
+        write!(out, " {:>12} {:>12} {:>12}", "cycles/iter", "misses/iter", "LLC ld/iter")?;
+    }
+    if has_telemetry {
+        write!(out, " {:>12} {:>12} {:>12} {:>12}",
+               "locks/iter", "wait ns/iter", "retries/iter", "RMWs/iter")?;
+    }
+    if has_allocations {
+        write!(out, " {:>20} {:>20} {:>20}", "Build allocs", "Fill allocs", "Readout allocs")?;
+    }
+    if has_readers {
+        write!(out, " {:>12}", "Mreads/s")?;
+    }
+    if has_memory_nodes {
+        write!(out, " {:>8}", "Mem node")?;
+    }
+    if has_distributions {
+        write!(out, " {:>16}", "Distribution")?;
+    }
+    if has_bins_per_line {
+        write!(out, " {:>9}", "Bins/line")?;
+    }
+    if has_threads_seen {
+        write!(out, " {:>8}", "Seen")?;
+    }
+    if has_checksums {
+        write!(out, " {:>16}", "Bins checksum")?;
+    }
+    writeln!(out)?;
+
+    let optional = |x: Option<f64>, width: usize, precision: usize| {
+        x.map(|x| format!("{:>width$.precision$}", x, width = width, precision = precision))
+         .unwrap_or_else(|| " ".repeat(width))
+    };
+    for r in results {
+        let mut line = format!("{:<30} {:<12} {:>10} {:>6} {:>8} {:>12.3} {:>12.3} {:>10.3} {:>16.3} \
+                                {:>10.3} {:>10} {:>10} {} {}",
+                               r.strategy, r.mode, r.bins, r.batch_size, r.threads,
+                               r.ns_per_iter, r.min_ns_per_iter, r.stddev_ns_per_iter,
+                               r.throughput / 1e6, r.bytes_per_sec / 1e9,
+                               format_duration(r.aggregation_ns),
+                               format_bytes(r.memory_bytes),
+                               optional(r.speedup, 8, 2),
+                               optional(r.efficiency.map(|e| e * 100.0), 9, 1)
+                                   + if r.efficiency.is_some() { "%" } else { " " });
+        if has_counters {
+            line += &format!(" {} {} {}",
+                             optional(r.cycles_per_iter, 12, 3),
+                             optional(r.cache_misses_per_iter, 12, 3),
+                             optional(r.llc_loads_per_iter, 12, 3));
+        }
+        if has_telemetry {
+            line += &format!(" {} {} {} {}",
+                             optional(r.locks_per_iter, 12, 3),
+                             optional(r.lock_wait_ns_per_iter, 12, 3),
+                             optional(r.cas_retries_per_iter, 12, 3),
+                             optional(r.rmws_per_iter, 12, 3));
+        }
+        if has_allocations {
+            let allocations = |blocks: Option<u64>, bytes: Option<u64>| {
+                blocks.zip(bytes)
+                      .map(|(blocks, bytes)| {
+                          let allocs = format!("{} ({})", blocks, format_bytes(bytes as usize));
+                          format!(" {:>20}", allocs)
+                      })
+                      .unwrap_or_else(|| " ".repeat(21))
+            };
+            line += &allocations(r.construction_allocs, r.construction_alloc_bytes);
+            line += &allocations(r.fill_allocs, r.fill_alloc_bytes);
+            line += &allocations(r.readout_allocs, r.readout_alloc_bytes);
+        }
+        if has_readers {
+            line += &format!(" {}", optional(r.reads_per_sec.map(|r| r / 1e6), 12, 3));
+        }
+        if has_memory_nodes {
+            line += &r.memory_node
+                      .map(|n| format!(" {:>8}", n))
+                      .unwrap_or_else(|| " ".repeat(9));
+        }
+        if has_distributions {
+            line += &format!(" {:>16}", r.distribution);
+        }
+        if has_bins_per_line {
+            line += &r.bins_per_line
+                      .map(|n| format!(" {:>9}", n))
+                      .unwrap_or_else(|| " ".repeat(10));
+        }
+        if has_threads_seen {
+            line += &r.threads_seen
+                      .map(|n| format!(" {:>8}", n))
+                      .unwrap_or_else(|| " ".repeat(9));
+        }
+        if has_checksums {
+            line += &r.bins_checksum
+                      .map(|c| format!(" {:016x}", c))
+                      .unwrap_or_else(|| " ".repeat(17));
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+// Markdown table of the main results, ready to be pasted into the findings of
+// the README or into a pull request
+pub fn write_markdown(results: &[BenchResult], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "| Strategy | Mode | Bins | Batch | Threads | ns/iter | Mhits/s | Speedup | \
+                   Efficiency | Memory |")?;
+    writeln!(out, "|:---------|:-----|-----:|------:|--------:|--------:|--------:|--------:|\
+                   -----------:|-------:|")?;
+    for r in results {
+        writeln!(out, "| {} | {} | {} | {} | {} | {:.3} ± {:.3} | {:.1} | {} | {} | {} |",
+                 r.strategy, r.mode, r.bins, r.batch_size, r.threads,
+                 r.ns_per_iter, r.stddev_ns_per_iter, r.throughput / 1e6,
+                 r.speedup.map(|s| format!("{:.2}", s)).unwrap_or_default(),
+                 r.efficiency.map(|e| format!("{:.1}%", e * 100.0)).unwrap_or_default(),
+                 format_bytes(r.memory_bytes))?;
+    }
+    Ok(())
+}
+
+// Display a duration in nanoseconds with a suitable unit
+fn format_duration(ns: f64) -> String {
+    const UNITS: [&str; 4] = ["ns", "µs", "ms", "s"];
+    let mut value = ns;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// Display a number of bytes with a binary unit prefix
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// CSV with a header line, suitable for spreadsheets and dataframe libraries
+pub fn write_csv(results: &[BenchResult], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "strategy,mode,threads,bins,batch_size,buckets,distribution,bins_per_line,shards,\
+                   backend,readers,deterministic,fill_iter,fill_one,\
+                   memory_node,burst_batches,duty_cycle,ns_per_iter,min_ns_per_iter,stddev_ns_per_iter,\
+                   throughput,bytes_per_sec,aggregation_ns,memory_bytes,speedup,efficiency,cycles_per_iter,cache_misses_per_iter,\
+                   llc_loads_per_iter,locks_per_iter,lock_wait_ns_per_iter,cas_retries_per_iter,\
+                   rmws_per_iter,threads_seen,bins_checksum,reads_per_sec,construction_allocs,\
+                   construction_alloc_bytes,fill_allocs,fill_alloc_bytes,readout_allocs,\
+                   readout_alloc_bytes")?;
+    let optional = |x: Option<f64>| x.map(|x| x.to_string()).unwrap_or_default();
+    let count = |n: Option<u64>| n.map(|n| n.to_string()).unwrap_or_default();
+    for r in results {
+        writeln!(out, "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},\
+                       {},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                 r.strategy, r.mode, r.threads, r.bins, r.batch_size, r.buckets,
+                 r.distribution, r.bins_per_line.map(|n| n.to_string()).unwrap_or_default(),
+                 r.shards.map(|n| n.to_string()).unwrap_or_default(),
+                 r.backend, r.readers, r.deterministic, r.fill_iter, r.fill_one,
+                 r.memory_node.map(|n| n.to_string()).unwrap_or_default(),
+                 r.burst_batches.map(|b| b.to_string()).unwrap_or_default(),
+                 optional(r.duty_cycle), r.ns_per_iter, r.min_ns_per_iter,
+                 r.stddev_ns_per_iter, r.throughput, r.bytes_per_sec, r.aggregation_ns,
+                 r.memory_bytes,
+                 optional(r.speedup), optional(r.efficiency),
+                 optional(r.cycles_per_iter), optional(r.cache_misses_per_iter),
+                 optional(r.llc_loads_per_iter), optional(r.locks_per_iter),
+                 optional(r.lock_wait_ns_per_iter), optional(r.cas_retries_per_iter),
+                 optional(r.rmws_per_iter),
+                 r.threads_seen.map(|n| n.to_string()).unwrap_or_default(),
+                 r.bins_checksum.map(|c| c.to_string()).unwrap_or_default(),
+                 optional(r.reads_per_sec),
+                 count(r.construction_allocs), count(r.construction_alloc_bytes),
+                 count(r.fill_allocs), count(r.fill_alloc_bytes),
+                 count(r.readout_allocs), count(r.readout_alloc_bytes))?;
+    }
+    Ok(())
+}
+
+// JSON array of result objects
+pub fn write_json(results: &[BenchResult], mut out: impl Write) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut out, results)?;
+    writeln!(out)
+}
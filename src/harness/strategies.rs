@@ -0,0 +1,146 @@
+// Table of the strategies which can be benchmarked
+//
+// Every strategy is listed once, along with the cfg under which it is enabled,
+// the name under which it is selected and displayed, and how its histogram is
+// built from a benchmark configuration. `strategies!(consumer)` hands the table
+// over to the `consumer` macro, which generates whatever code has to go through
+// every strategy, such as the Strategy enum or the frames of labels. Entries
+// have the form
+//
+//     #[cfg(...)] Variant: name => |config| constructor expression;
+//
+// Strategies whose histogram can only be filled sequentially come first.
+macro_rules! strategies {
+    ($consumer:ident) => {
+        $consumer! {
+            sequential_only {
+                Raw: raw => |config| ToyHistogram::new(config.num_bins);
+            }
+            thread_safe {
+                #[cfg(feature = "atomic")]
+                Atomic: atomic => |config| AtomicHistogram::new(config.num_bins);
+                #[cfg(feature = "atomic")]
+                AtomicAcqRel: atomic_acq_rel => |config| {
+                    AtomicHistogram::<Global, AcqRelOrder>::with_ordering(config.num_bins)
+                };
+                #[cfg(feature = "atomic")]
+                AtomicSeqCst: atomic_seq_cst => |config| {
+                    AtomicHistogram::<Global, SeqCstOrder>::with_ordering(config.num_bins)
+                };
+                #[cfg(feature = "padded_atomic")]
+                PaddedAtomic: padded_atomic => |config| {
+                    PaddedAtomicHistogram::with_bins_per_line(config.num_bins,
+                                                              config.bins_per_line)
+                };
+                #[cfg(feature = "narrow_atomic")]
+                AtomicU16: atomic_u16 => |config| AtomicU16Histogram::new(config.num_bins);
+                #[cfg(feature = "narrow_atomic")]
+                AtomicU32: atomic_u32 => |config| AtomicU32Histogram::new(config.num_bins);
+                #[cfg(feature = "sorted")]
+                SortedAtomic: sorted_atomic => |config| {
+                    SortedAtomicHistogram::new(config.num_bins)
+                };
+                #[cfg(feature = "lazy")]
+                LazyAtomic: lazy_atomic => |config| LazyAtomicHistogram::new(config.num_bins);
+                #[cfg(feature = "mutex")]
+                Mutex: mutex => |config| Mutex::new(ToyHistogram::new(config.num_bins));
+                #[cfg(feature = "rwlock")]
+                RwLock: rwlock => |config| RwLock::new(ToyHistogram::new(config.num_bins));
+                #[cfg(feature = "parking_lot")]
+                ParkingLotMutex: parking_lot_mutex => |config| {
+                    parking_lot::Mutex::new(ToyHistogram::new(config.num_bins))
+                };
+                #[cfg(feature = "parking_lot")]
+                ParkingLotRwLock: parking_lot_rwlock => |config| {
+                    parking_lot::RwLock::new(ToyHistogram::new(config.num_bins))
+                };
+                #[cfg(feature = "spinlock")]
+                SpinLock: spinlock => |config| SpinLock::new(ToyHistogram::new(config.num_bins));
+                #[cfg(feature = "ticket_lock")]
+                TicketLock: ticket_lock => |config| {
+                    TicketLock::new(ToyHistogram::new(config.num_bins))
+                };
+                #[cfg(feature = "mcs_lock")]
+                McsLock: mcs_lock => |config| McsLock::new(ToyHistogram::new(config.num_bins));
+                #[cfg(feature = "tsx")]
+                Tsx: tsx => |config| TsxHistogram::new(config.num_bins);
+                #[cfg(feature = "seqlock")]
+                Seqlock: seqlock => |config| SeqlockHistogram::new(config.num_bins);
+                #[cfg(feature = "epoch")]
+                Epoch: epoch => |config| EpochHistogram::new(config.num_bins);
+                #[cfg(feature = "double_buffer")]
+                DoubleBuffer: double_buffer => |config| {
+                    DoubleBufferedHistogram::new(config.num_bins)
+                };
+                #[cfg(feature = "bin_sharded")]
+                BinSharded: bin_sharded => |config| {
+                    BinShardedHistogram::new(config.num_bins, config.num_shards)
+                };
+                #[cfg(feature = "flat_combining")]
+                FlatCombining: flat_combining => |config| {
+                    FlatCombiningHistogram::new(config.num_bins)
+                };
+                #[cfg(feature = "channel")]
+                Channel: channel => |config| ChannelHistogram::new(config.num_bins);
+                #[cfg(feature = "ring_buffer")]
+                RingBuffer: ring_buffer => |config| RingBufferHistogram::new(config.num_bins);
+                #[cfg(feature = "gpu")]
+                Gpu: gpu => |config| {
+                    GpuHistogram::try_new(config.num_bins).expect("Unavailable strategy")
+                };
+                #[cfg(feature = "thread_bucketized")]
+                ThreadBucketized: thread_bucketized => |config| {
+                    ThreadBucketizedHistogram::new(config.num_bins, config.num_buckets)
+                };
+                #[cfg(all(feature = "parking_lot", feature = "thread_bucketized"))]
+                ParkingLotBucketized: parking_lot_thread_bucketized => |config| {
+                    ParkingLotBucketizedHistogram::with_locks(config.num_bins, config.num_buckets)
+                };
+                #[cfg(all(feature = "spinlock", feature = "thread_bucketized"))]
+                SpinBucketized: spinlock_thread_bucketized => |config| {
+                    SpinBucketizedHistogram::with_locks(config.num_bins, config.num_buckets)
+                };
+                #[cfg(feature = "per_core")]
+                PerCore: per_core => |config| PerCoreHistogram::new(config.num_bins);
+                #[cfg(feature = "rseq")]
+                Rseq: rseq => |config| RseqHistogram::new(config.num_bins);
+                #[cfg(feature = "tls")]
+                Tls: tls => |config| TlsHistogram::new(config.num_bins);
+                #[cfg(feature = "buffered")]
+                Buffered: buffered => |config| {
+                    BufferedHistogram::new(AtomicHistogram::new(config.num_bins))
+                };
+                #[cfg(feature = "sparse")]
+                SparseDashMap: sparse_dashmap => |config| DashMapHistogram::new(config.num_bins);
+                #[cfg(feature = "sparse")]
+                SparseThreadLocal: sparse_thread_local => |config| {
+                    SparseThreadLocalHistogram::new(config.num_bins)
+                };
+                #[cfg(feature = "lazy")]
+                LazyThreadLocal: lazy_thread_local => |config| {
+                    LazyThreadLocalHistogram::new(config.num_bins)
+                };
+                #[cfg(feature = "count_min")]
+                CountMin: count_min => |config| CountMinHistogram::new(config.num_bins);
+                #[cfg(feature = "adaptive")]
+                Adaptive: adaptive => |config| AdaptiveHistogram::new(config.num_bins);
+                #[cfg(feature = "two_level")]
+                TwoLevel: two_level => |config| TwoLevelHistogram::new(config.num_bins);
+                #[cfg(feature = "thread_local")]
+                ThreadLocal: thread_local => |config| ThreadLocalHistogram::new(config.num_bins);
+                #[cfg(feature = "thread_local")]
+                ThreadLocalAcqRel: thread_local_acq_rel => |config| {
+                    ThreadLocalHistogram::<AcqRelOrder>::with_ordering(config.num_bins)
+                };
+                #[cfg(feature = "thread_local")]
+                ThreadLocalSeqCst: thread_local_seq_cst => |config| {
+                    ThreadLocalHistogram::<SeqCstOrder>::with_ordering(config.num_bins)
+                };
+                #[cfg(all(feature = "aggregated", feature = "thread_local"))]
+                AggregatedThreadLocal: aggregated_thread_local => |config| {
+                    AggregatedHistogram::new(ThreadLocalHistogram::new(config.num_bins))
+                };
+            }
+        }
+    };
+}
@@ -0,0 +1,80 @@
+// Async benchmark driver, where many tasks running on a multi-threaded tokio
+// runtime fill a shared histogram
+//
+// The input batches are split into contiguous chunks, TASKS_PER_THREAD per
+// worker thread, which are each processed by a task with its own RNG. Tasks
+// yield to the scheduler after every batch, so they can migrate between worker
+// threads as the runtime steals work, which is what happens to fills performed
+// by asynchronous applications.
+
+use {
+    super::{
+        Config, InputGenerator, Label, Pacer, chunk_batches, chunk_rngs, pin_current_thread,
+    },
+    crate::{trace, traits::SyncHistogram},
+    std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    tokio::runtime::{Builder, Runtime},
+};
+
+const TASKS_PER_THREAD: usize = 16;
+
+// Build the runtime of a parallel benchmark, pinning its workers to CPUs if
+// requested
+pub(super) fn runtime(config: &Config) -> Runtime {
+    let mut builder = Builder::new_multi_thread();
+    builder.worker_threads(config.num_threads);
+    if let Some(cpus) = config.cpu_affinity.clone() {
+        let next_worker = AtomicUsize::new(0);
+        builder.on_thread_start(move || {
+            pin_current_thread(&cpus, next_worker.fetch_add(1, Ordering::Relaxed))
+        });
+    }
+    builder.build().expect("Failed to build the benchmark runtime")
+}
+
+pub(super) fn fill<H>(runtime: &Runtime,
+                      histogram: &Arc<H>,
+                      input: &Arc<dyn InputGenerator>,
+                      config: &Config,
+                      label: Label)
+    where H: SyncHistogram + Send + 'static
+{
+    let num_tasks = config.num_threads * TASKS_PER_THREAD;
+    let batch_size = config.batch_size;
+    runtime.block_on(async {
+        let tasks = chunk_rngs(num_tasks)
+            .into_iter()
+            .enumerate()
+            .map(|(task, mut rng)| {
+                let (histogram, input) = (histogram.clone(), input.clone());
+                let task_batches = chunk_batches(config, task, num_tasks);
+                let burst = config.burst;
+                let (fill_iter, fill_one) = (config.fill_iter, config.fill_one);
+                tokio::spawn(async move {
+                    let mut buf = Vec::with_capacity(batch_size);
+                    let mut pacer = Pacer::new(burst);
+                    for _ in 0..task_batches {
+                        // Tasks may move to another thread when they yield
+                        label.call(|| trace::fill::<H, _>(batch_size, || if fill_one {
+                            for _ in 0..batch_size {
+                                histogram.fill_one(input.gen(&mut rng));
+                            }
+                        } else if fill_iter {
+                            histogram.fill_iter((0..batch_size).map(|_| input.gen(&mut rng)));
+                        } else {
+                            histogram.fill(input.gen_batch(&mut rng, &mut buf, batch_size));
+                        }));
+                        pacer.after_batch();
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.expect("A benchmark task panicked");
+        }
+    });
+}
@@ -0,0 +1,211 @@
+// Cross-implementation correctness verification
+//
+// Every implementation is filled with the same deterministic input stream, and
+// its bins are then compared one by one with those of a ToyHistogram which was
+// filled sequentially with the same inputs. Unlike the hit count check of the
+// benchmarks, this catches binning errors in concurrent implementations.
+//
+// Approximate strategies cannot fill the same bins as the reference. They are
+// only required to never underestimate a bin, which holds for the sketches
+// implemented here, and how far their estimates are from the reference is
+// reported instead.
+//
+// To keep the inputs independent of how batches are scheduled across threads,
+// each batch is generated by an RNG seeded with the batch's index.
+
+use {
+    super::{BenchRng, Config, InputGenerator, Mode, Strategy},
+    crate::{
+        impls::*,
+        thread_id::ThreadID,
+        traits::{Histogram, SyncHistogram},
+    },
+    rand::SeedableRng,
+    rayon::prelude::*,
+    std::fmt,
+};
+
+// Bin-by-bin difference between an implementation and the reference
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    pub strategy: Strategy,
+    pub mode: Mode,
+    pub bins: usize,
+    pub batch_size: usize,
+    pub threads: usize,
+
+    // First bin whose contents differ, with expected and actual contents
+    pub bin: usize,
+    pub expected: usize,
+    pub actual: usize,
+
+    // Number of bins whose contents differ
+    pub num_wrong_bins: usize,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} {} (bins={}, batch={}, threads={}): {} wrong bin(s), \
+                first is bin {} with {} hits instead of {}",
+               self.strategy, self.mode, self.bins, self.batch_size, self.threads,
+               self.num_wrong_bins, self.bin, self.actual,
+               self.expected)
+    }
+}
+
+// Accuracy of an approximate strategy with respect to the reference
+#[derive(Clone, Debug)]
+pub struct Accuracy {
+    pub strategy: Strategy,
+    pub mode: Mode,
+    pub bins: usize,
+    pub batch_size: usize,
+    pub threads: usize,
+
+    // Number of bins whose contents were overestimated
+    pub num_wrong_bins: usize,
+
+    // Largest overestimate of a single bin
+    pub max_error: usize,
+
+    // Sum of all overestimates, divided by the number of hits
+    pub relative_error: f64,
+}
+
+impl fmt::Display for Accuracy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} {} (bins={}, batch={}, threads={}): {} overestimated bin(s), \
+                by {} hits at most and {:.3}% of all hits in total",
+               self.strategy, self.mode, self.bins, self.batch_size, self.threads,
+               self.num_wrong_bins, self.max_error, 100.0 * self.relative_error)
+    }
+}
+
+// Check that a strategy, used in a certain mode, fills the same bins as the
+// sequential ToyHistogram. For approximate strategies, which must only never
+// underestimate a bin, their accuracy is returned.
+pub fn verify(strategy: Strategy,
+              mode: Mode,
+              config: &Config) -> Result<Option<Accuracy>, Mismatch> {
+    assert!(strategy.supports(mode), "{} does not support {} mode", strategy, mode);
+    let num_bins = config.num_bins;
+    let expected = sequential_fill(ToyHistogram::new(num_bins), config);
+    let actual = match (strategy.new_histogram(config), mode) {
+        (None, _) => sequential_fill(ToyHistogram::new(num_bins), config),
+        (Some(histogram), Mode::Sequential) => sequential_fill(histogram, config),
+        (Some(histogram), Mode::Parallel) => parallel_fill(histogram, config),
+    };
+    let contents = |bins: &[usize], bin: usize| bins.get(bin).copied().unwrap_or(0);
+    let bins = (0..num_bins.max(actual.len()))
+        .map(|bin| (bin, contents(&expected, bin), contents(&actual, bin)));
+    let threads = match mode {
+        Mode::Sequential => 1,
+        Mode::Parallel => config.num_threads,
+    };
+    let approximate = strategy.is_approximate();
+    let mut wrong_bins = bins.clone().filter(|&(_, expected, actual)| {
+        if approximate { actual < expected } else { actual != expected }
+    });
+    if let Some((bin, expected, actual)) = wrong_bins.next() {
+        return Err(Mismatch {
+            strategy,
+            mode,
+            bins: num_bins,
+            batch_size: config.batch_size,
+            threads,
+            bin,
+            expected,
+            actual,
+            num_wrong_bins: 1 + wrong_bins.count(),
+        });
+    }
+    if !approximate {
+        return Ok(None);
+    }
+    let errors = bins.map(|(_, expected, actual)| actual - expected).filter(|&error| error > 0);
+    let (num_wrong_bins, max_error, total_error) =
+        errors.fold((0, 0, 0), |(num, max, total), error| (num + 1, max.max(error), total + error));
+    Ok(Some(Accuracy {
+        strategy,
+        mode,
+        bins: num_bins,
+        batch_size: config.batch_size,
+        threads,
+        num_wrong_bins,
+        max_error,
+        relative_error: total_error as f64 / expected.iter().sum::<usize>().max(1) as f64,
+    }))
+}
+
+// Generate the inputs of a given batch
+fn gen_batch<'a>(input: &dyn InputGenerator,
+                 batch: usize,
+                 buf: &'a mut Vec<f32>,
+                 batch_size: usize) -> &'a [f32] {
+    let mut rng = BenchRng::seed_from_u64(batch as u64);
+    input.gen_batch(&mut rng, buf, batch_size)
+}
+
+// Inputs of a benchmark, binned into a histogram with another number of bins,
+// e.g. few enough of them to be rendered as a chart
+pub fn input_histogram(config: &Config, num_bins: usize) -> ToyHistogram {
+    ToyHistogram::from_bins(sequential_fill(ToyHistogram::new(num_bins), config))
+}
+
+fn sequential_fill(mut histogram: impl Histogram, config: &Config) -> Vec<usize> {
+    let id = ThreadID::load();
+    let input = config.distribution.generator(config.num_bins);
+    let mut buf = Vec::with_capacity(config.batch_size);
+    for batch in 0..config.num_batches() {
+        histogram.fill_with_id_mut(gen_batch(&*input, batch, &mut buf, config.batch_size), id);
+    }
+    histogram.flush_mut();
+    histogram.bins()
+}
+
+fn parallel_fill(histogram: impl SyncHistogram, config: &Config) -> Vec<usize> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.num_threads)
+        .build()
+        .expect("Failed to build the verification thread pool");
+    let input = config.distribution.generator(config.num_bins);
+    let batch_size = config.batch_size;
+    pool.install(|| {
+        (0..config.num_batches())
+            .into_par_iter()
+            .for_each_init(
+                || (ThreadID::load(), Vec::with_capacity(batch_size)),
+                |(id, buf), batch| histogram.fill_with_id(gen_batch(&*input, batch, buf, batch_size), *id)
+            );
+    });
+    histogram.flush();
+    histogram.bins()
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_strategies_match() {
+        let config = Config {
+            num_bins: 100,
+            num_rolls: 100_000,
+            batch_size: 10,
+            num_buckets: 2,
+            num_threads: 4,
+            ..Config::default()
+        };
+        for &mode in Mode::ALL.iter() {
+            for &strategy in Strategy::ALL.iter()
+                                      .filter(|s| s.supports(mode))
+                                      .filter(|s| s.unavailability().is_none()) {
+                if let Err(mismatch) = verify(strategy, mode, &config) {
+                    panic!("{}", mismatch);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+// Huge page backing of large bin arrays
+//
+// With millions of bins, randomly filled bins are spread over so many 4 KiB
+// pages that most increments miss the TLB. Backing the bins with 2 MiB pages
+// instead cuts the number of pages by 512. This relies on Linux's transparent
+// huge pages, which are requested for the bins using madvise. As the bins are
+// allocated by the global allocator, only the huge pages which fit entirely
+// within them can be used, so this only matters for bin arrays of several
+// megabytes.
+//
+// This is a best effort: on other operating systems, or if transparent huge
+// pages are disabled, requests are ignored.
+
+use std::mem;
+
+// Request huge pages for some bins, which should not have been touched yet
+pub(crate) fn advise<T>(bins: &[T]) {
+    let _ = sys::advise(bins.as_ptr().cast(), mem::size_of_val(bins));
+}
+
+#[cfg(all(target_os = "linux", not(miri)))]
+mod sys {
+    use std::io;
+
+    // Size of huge pages on the architectures that we care about
+    const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+    pub fn advise(start: *const u8, len: usize) -> io::Result<()> {
+        let huge_start = (start as usize).next_multiple_of(HUGE_PAGE_SIZE);
+        let huge_end = (start as usize + len) / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        if huge_end <= huge_start {
+            return Ok(());
+        }
+        // Safe because this only changes how the pages are backed
+        let result = unsafe {
+            libc::madvise(huge_start as *mut libc::c_void,
+                          huge_end - huge_start,
+                          libc::MADV_HUGEPAGE)
+        };
+        if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", not(miri))))]
+mod sys {
+    use std::io;
+
+    pub fn advise(_start: *const u8, _len: usize) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Huge pages require Linux"))
+    }
+}
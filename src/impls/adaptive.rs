@@ -0,0 +1,149 @@
+use {
+    crate::{
+        impls::{AtomicHistogram, ThreadLocalHistogram},
+        sync::{AtomicUsize, Ordering},
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::OnceLock,
+    },
+};
+
+// Histogram which starts as a single atomic histogram, and switches to
+// thread-local replicas once it observes contention
+//
+// Most histograms are never filled by several threads at once, and should not
+// pay for one copy of the bins per thread. So fills start out incrementing a
+// shared atomic histogram, using compare-and-swap loops which tell when another
+// thread modified a bin in the meantime. Once enough of these conflicts have
+// been observed, the histogram allocates thread-local replicas, which every
+// subsequent fill goes to.
+//
+// The shared histogram is left in place after the switch, and readouts add its
+// bins to those of the replicas. This way, fills which were still running on
+// the shared histogram during the switch are not lost, and no bin ever needs
+// to be moved from one storage to the other.
+//
+// Conflicts are counted over the whole lifetime of the histogram, so a
+// histogram which is filled under mild contention for long enough eventually
+// switches as well.
+//
+pub struct AdaptiveHistogram {
+    num_bins: usize,
+    shared: AtomicHistogram,
+    conflicts: AtomicUsize,
+    threshold: usize,
+    replicas: OnceLock<ThreadLocalHistogram>,
+}
+
+impl AdaptiveHistogram {
+    // Number of conflicts which trigger the switch to replicas by default
+    pub const DEFAULT_THRESHOLD: usize = 1024;
+
+    pub fn new(num_bins: usize) -> Self {
+        Self::with_threshold(num_bins, Self::DEFAULT_THRESHOLD)
+    }
+
+    pub fn with_threshold(num_bins: usize, threshold: usize) -> Self {
+        Self {
+            num_bins,
+            shared: AtomicHistogram::new(num_bins),
+            conflicts: AtomicUsize::new(0),
+            threshold,
+            replicas: OnceLock::new(),
+        }
+    }
+
+    // Truth that fills have switched to thread-local replicas
+    pub fn is_replicated(&self) -> bool {
+        self.replicas.get().is_some()
+    }
+
+    fn replicate(&self) -> &ThreadLocalHistogram {
+        self.replicas.get_or_init(|| ThreadLocalHistogram::new(self.num_bins))
+    }
+}
+
+impl SyncHistogram for AdaptiveHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.fill_with_id(values, ThreadID::load())
+    }
+
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        if let Some(replicas) = self.replicas.get() {
+            return replicas.fill_with_id(values, id);
+        }
+        // The conflict counter is only touched by contended fills
+        let conflicts = self.shared.fill_counting_conflicts(values);
+        if conflicts > 0
+           && self.conflicts.fetch_add(conflicts, Ordering::Relaxed) + conflicts >= self.threshold
+        {
+            self.replicate();
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.shared.num_hits() + self.replicas.get().map_or(0, |r| r.num_hits())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut bins = self.shared.bins();
+        if let Some(replicas) = self.replicas.get() {
+            for (dst, src) in bins.iter_mut().zip(replicas.bins()) {
+                *dst += src;
+            }
+        }
+        bins
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.shared.merge_bins(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.shared.memory_usage() - mem::size_of::<AtomicHistogram>()
+            + mem::size_of::<Self>()
+            + self.replicas.get().map_or(0, |r| {
+                r.memory_usage() - mem::size_of::<ThreadLocalHistogram>()
+            })
+    }
+
+    // Without concurrent fills, there is no contention to detect
+    fn fill_exclusive(&mut self, values: &[f32]) {
+        match self.replicas.get_mut() {
+            Some(replicas) => replicas.fill_exclusive(values),
+            None => self.shared.fill_exclusive(values),
+        }
+    }
+
+    fn fill_with_id_exclusive(&mut self, values: &[f32], _id: ThreadID) {
+        self.fill_exclusive(values)
+    }
+}
+
+impl_clone_by_merge!([] AdaptiveHistogram => |this, num_bins| {
+    Self::with_threshold(num_bins, this.threshold)
+});
+
+impl_eq_by_bins!([] AdaptiveHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_shared_bins_after_replication() {
+        let histogram = AdaptiveHistogram::new(2);
+        histogram.fill(&[0.25, 0.75, 0.75]);
+        assert!(!histogram.is_replicated());
+        histogram.replicate();
+        histogram.fill(&[0.25]);
+        histogram.merge_bins(&[1, 1]);
+        assert!(histogram.is_replicated());
+        assert_eq!(SyncHistogram::bins(&histogram), [3, 3]);
+        assert_eq!(histogram.num_hits(), 6);
+    }
+}
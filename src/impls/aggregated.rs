@@ -0,0 +1,235 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        thread_id::ThreadID,
+        trace,
+        traits::{Histogram, SyncHistogram},
+    },
+    std::{
+        mem,
+        sync::{Arc, Condvar, Mutex},
+        thread::{self, JoinHandle},
+        time::Duration,
+    },
+};
+
+// Wrapper which runs a low-priority background thread that periodically takes a
+// snapshot of the underlying histogram and publishes it, as online monitoring
+// does in DAQ systems
+//
+// Readouts return the last published snapshot instead of reading the bins, so
+// that frequent readers neither pay for summing the replicas of a thread-local
+// histogram nor slow down the threads which fill it, at the expense of seeing
+// results which are up to one period old. Unlike with other implementations,
+// readouts do not flush: flush() takes and publishes a fresh snapshot, after
+// which readouts account for every fill which returned before it.
+//
+// Snapshots are published behind a mutex which readers only hold for as long as
+// it takes to clone a reference-counted pointer to the last one.
+//
+pub struct AggregatedHistogram<H: SyncHistogram + Send + 'static> {
+    shared: Arc<Shared<H>>,
+    period: Duration,
+    aggregator: Option<JoinHandle<()>>,
+}
+
+// State which is shared with the aggregator thread
+struct Shared<H> {
+    inner: H,
+    published: Mutex<Published>,
+    // Held while a snapshot is taken and published, so that a snapshot which
+    // was taken by the aggregator before a flush cannot replace the snapshot of
+    // the flush once it is published
+    publishing: Mutex<()>,
+    // Set when the aggregator should stop, which it waits for between snapshots
+    stop: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+#[derive(Clone)]
+struct Published {
+    histogram: Arc<ToyHistogram>,
+    num_hits: usize,
+}
+
+impl<H: SyncHistogram> Shared<H> {
+    fn publish(&self) {
+        let _publishing = self.publishing.lock().unwrap();
+        let histogram = trace::aggregate::<H, _>(|| self.inner.snapshot());
+        let num_hits = Histogram::num_hits(&histogram);
+        *self.published.lock().unwrap() = Published { histogram: Arc::new(histogram), num_hits };
+    }
+
+    fn published(&self) -> Published {
+        self.published.lock().unwrap().clone()
+    }
+
+    // Body of the aggregator thread
+    fn aggregate(&self, period: Duration) {
+        lower_priority();
+        loop {
+            let stop = self.stop.lock().unwrap();
+            let (stop, _) = self.wakeup.wait_timeout_while(stop, period, |stop| !*stop).unwrap();
+            if *stop {
+                return;
+            }
+            mem::drop(stop);
+            self.publish();
+        }
+    }
+}
+
+// Let fills take precedence over the aggregator when there are more threads
+// than CPUs. On Linux, priorities are per-thread, so this only affects the
+// calling thread.
+fn lower_priority() {
+    #[cfg(all(target_os = "linux", feature = "libc", not(miri)))]
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, 0, 19);
+    }
+}
+
+impl<H: SyncHistogram + Send + 'static> AggregatedHistogram<H> {
+    // Period between snapshots by default
+    pub const DEFAULT_PERIOD: Duration = Duration::from_millis(10);
+
+    pub fn new(inner: H) -> Self {
+        Self::with_period(inner, Self::DEFAULT_PERIOD)
+    }
+
+    pub fn with_period(inner: H, period: Duration) -> Self {
+        assert!(period > Duration::ZERO, "Aggregation period must be positive");
+        let shared = Arc::new(Shared {
+            inner,
+            published: Mutex::new(Published {
+                histogram: Arc::new(ToyHistogram::new(0)),
+                num_hits: 0,
+            }),
+            publishing: Mutex::new(()),
+            stop: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+        shared.publish();
+        let aggregator = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("histogram aggregator".into())
+                .spawn(move || shared.aggregate(period))
+                .expect("Failed to spawn the aggregator thread")
+        };
+        Self {
+            shared,
+            period,
+            aggregator: Some(aggregator),
+        }
+    }
+
+    // Last published snapshot, without copying its bins
+    pub fn published(&self) -> Arc<ToyHistogram> {
+        self.shared.published().histogram
+    }
+}
+
+impl<H: SyncHistogram + Send + 'static> Drop for AggregatedHistogram<H> {
+    fn drop(&mut self) {
+        *self.shared.stop.lock().unwrap() = true;
+        self.shared.wakeup.notify_one();
+        if let Some(aggregator) = self.aggregator.take() {
+            aggregator.join().expect("The aggregator thread panicked");
+        }
+    }
+}
+
+impl<H: SyncHistogram + Send + 'static> SyncHistogram for AggregatedHistogram<H> {
+    fn fill(&self, values: &[f32]) {
+        self.shared.inner.fill(values)
+    }
+
+    fn fill_one(&self, value: f32) {
+        self.shared.inner.fill_one(value)
+    }
+
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        self.shared.inner.fill_with_id(values, id)
+    }
+
+    fn fill_one_with_id(&self, value: f32, id: ThreadID) {
+        self.shared.inner.fill_one_with_id(value, id)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.shared.published().num_hits
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.published().bins.to_vec()
+    }
+
+    fn snapshot(&self) -> ToyHistogram {
+        (*self.published()).clone()
+    }
+
+    // Merged bins are published along with the next snapshot
+    fn merge_bins(&self, bins: &[usize]) {
+        self.shared.inner.merge_bins(bins)
+    }
+
+    fn flush(&self) {
+        self.shared.inner.flush();
+        self.shared.publish()
+    }
+
+    fn num_threads(&self) -> Option<usize> {
+        self.shared.inner.num_threads()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.shared.inner.memory_usage() - mem::size_of::<H>()
+            + mem::size_of::<Self>()
+            + mem::size_of::<Shared<H>>()
+            + Histogram::memory_usage(&*self.published())
+    }
+}
+
+// The clone gets an aggregator of its own, with the same period
+impl<H: SyncHistogram + Clone + Send + 'static> Clone for AggregatedHistogram<H> {
+    fn clone(&self) -> Self {
+        Self::with_period(self.shared.inner.clone(), self.period)
+    }
+}
+
+impl<H: SyncHistogram + Send + 'static> PartialEq for AggregatedHistogram<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.published() == other.published()
+    }
+}
+
+impl<H: SyncHistogram + Send + 'static> Eq for AggregatedHistogram<H> {}
+
+
+#[cfg(all(test, feature = "atomic", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::impls::AtomicHistogram,
+    };
+
+    // Readouts lag behind fills until the aggregator or a flush catches up
+    #[test]
+    fn publish_snapshots() {
+        let histogram = AggregatedHistogram::with_period(AtomicHistogram::new(2),
+                                                         Duration::from_millis(1));
+        histogram.fill(&[0.25, 0.75, 0.75]);
+        while SyncHistogram::num_hits(&histogram) < 3 {
+            thread::yield_now();
+        }
+        assert_eq!(SyncHistogram::bins(&histogram), [1, 2]);
+
+        let histogram = AggregatedHistogram::with_period(AtomicHistogram::new(2),
+                                                         Duration::from_secs(3600));
+        histogram.fill(&[0.25]);
+        assert_eq!(SyncHistogram::num_hits(&histogram), 0);
+        histogram.flush();
+        assert_eq!(histogram.published().bins(), [1, 0]);
+    }
+}
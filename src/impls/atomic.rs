@@ -1,45 +1,308 @@
 use {
-    crate::traits::SyncHistogram,
-    std::sync::atomic::{AtomicUsize, Ordering},
+    crate::{
+        binning::Binner,
+        sync::{atomic_with_mut, AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    alloc::vec::Vec,
+    allocator_api2::alloc::{Allocator, Global},
+    core::{marker::PhantomData, mem},
 };
+#[cfg(feature = "huge_pages")]
+use crate::huge_pages;
+#[cfg(feature = "thread_local")]
+use {super::reduce::Replica, core::ops::Range};
+#[cfg(feature = "std")]
+use crate::thread_id::ThreadID;
 
 // Thread-safe histogram that works by modifying buckets using atomic RMW ops
-pub struct AtomicHistogram {
-    bins: Vec<AtomicUsize>,
+//
+// The memory orderings of these operations are selected by `O`. Relaxed ones
+// are enough for counting, and are used by default.
+pub struct AtomicHistogram<A: Allocator = Global, O = RelaxedOrder> {
+    bins: allocator_api2::vec::Vec<AtomicUsize, A>,
+    binner: Binner,
+    order: PhantomData<fn() -> O>,
+}
+
+// Memory orderings of the atomic operations on the bins of a histogram
+//
+// Bins only need atomicity, so that no increment is lost and readouts see
+// each bin at some point of its history, which Relaxed operations provide.
+// Stronger orderings also order bin accesses with respect to the other memory
+// accesses of the filling and reading threads, which nothing here relies on,
+// but which tells what users of such orderings pay for them. On x86, every
+// atomic RMW is a full barrier anyway, so that only SeqCst stores cost more,
+// whereas weakly ordered CPUs such as ARM need extra barriers or instructions.
+pub trait MemoryOrder {
+    // Ordering of increments and merges
+    const RMW: Ordering;
+
+    // Ordering of readouts
+    const LOAD: Ordering;
+
+    // Ordering of the stores of single-writer fills
+    const STORE: Ordering;
+}
+
+// Relaxed operations
+pub struct RelaxedOrder;
+
+impl MemoryOrder for RelaxedOrder {
+    const RMW: Ordering = Ordering::Relaxed;
+    const LOAD: Ordering = Ordering::Relaxed;
+    const STORE: Ordering = Ordering::Relaxed;
+}
+
+// Acquire loads, release stores and acquire-release RMWs
+pub struct AcqRelOrder;
+
+impl MemoryOrder for AcqRelOrder {
+    const RMW: Ordering = Ordering::AcqRel;
+    const LOAD: Ordering = Ordering::Acquire;
+    const STORE: Ordering = Ordering::Release;
+}
+
+// Sequentially consistent operations
+pub struct SeqCstOrder;
+
+impl MemoryOrder for SeqCstOrder {
+    const RMW: Ordering = Ordering::SeqCst;
+    const LOAD: Ordering = Ordering::SeqCst;
+    const STORE: Ordering = Ordering::SeqCst;
 }
 
 impl AtomicHistogram {
     pub fn new(num_bins: usize) -> Self {
+        Self::new_in(num_bins, Global)
+    }
+
+    // Back the bins with transparent huge pages where possible, which must be
+    // requested before the bins are first written to
+    #[cfg(feature = "huge_pages")]
+    pub fn with_huge_pages(num_bins: usize) -> Self {
+        let mut bins = allocator_api2::vec::Vec::with_capacity(num_bins);
+        huge_pages::advise(bins.spare_capacity_mut());
+        bins.extend((0..num_bins).map(|_| AtomicUsize::new(0)));
+        Self::from_raw_bins(bins)
+    }
+}
+
+impl<O: MemoryOrder> AtomicHistogram<Global, O> {
+    // Use the memory orderings of `O` instead of Relaxed ones
+    pub fn with_ordering(num_bins: usize) -> Self {
+        Self::from_raw_bins((0..num_bins).map(|_| AtomicUsize::new(0)).collect())
+    }
+}
+
+impl<A: Allocator> AtomicHistogram<A> {
+    // Allocate the bins with a custom allocator
+    pub fn new_in(num_bins: usize, alloc: A) -> Self {
+        let mut bins = allocator_api2::vec::Vec::with_capacity_in(num_bins, alloc);
+        bins.extend((0..num_bins).map(|_| AtomicUsize::new(0)));
+        Self::from_raw_bins(bins)
+    }
+}
+
+impl<A: Allocator, O: MemoryOrder> AtomicHistogram<A, O> {
+    fn from_raw_bins(bins: allocator_api2::vec::Vec<AtomicUsize, A>) -> Self {
         Self {
-            bins: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            binner: Binner::new(bins.len()),
+            bins,
+            order: PhantomData,
         }
     }
 
-    // In sequential mode, can go faster by using simple atomic load/store.
-    // With a sufficiently smart compiler, performance should become identical
-    // to that of the toy histogram.
-    //
-    // NOTE: Unfortunately, our Histogram impl cannot use this method because
-    //       that would require specialization, and Rust doesn't have it yet...
-    //
-    pub fn fill_mut_fast(&mut self, values: &[f32]) {
-        for value in values {
-            let bin = (value * (self.bins.len() as f32)) as usize;
-            let prev_bin = self.bins[bin].load(Ordering::Relaxed);
-            self.bins[bin].store(prev_bin + 1, Ordering::Relaxed);
+    // For histograms which other threads may read out concurrently, as long as
+    // only one thread fills them at a time, simple atomic load/stores are
+    // enough. If several threads fill the histogram concurrently, some of their
+    // increments will be lost.
+    pub fn fill_single_writer(&self, values: &[f32]) {
+        self.binner.for_each_bin(&self.bins, values, |bin| {
+            bin.store(bin.load(O::LOAD) + 1, O::STORE)
+        })
+    }
+
+    // Alternative fill path which sorts the batch by bin index first, so that
+    // bins are visited in order and repeated bins are incremented only once.
+    // This costs a sort and an allocation per batch, which pays off when the
+    // batch is large or its values fall into few bins.
+    pub fn fill_sorted(&self, values: &[f32]) {
+        let mut indices = Vec::with_capacity(values.len());
+        self.binner.for_each_index(values, |bin| indices.push(bin));
+        indices.sort_unstable();
+        let mut runs = 0;
+        for run in indices.chunk_by(|a, b| a == b) {
+            self.bins[run[0]].fetch_add(run.len(), O::RMW);
+            runs += 1;
         }
+        telemetry::atomic_rmws(runs);
+    }
+
+    // Fill using compare-and-swap loops instead of fetch_add, and return how
+    // many times another thread modified a bin between the load and the CAS,
+    // as a measure of contention
+    #[cfg(feature = "adaptive")]
+    pub(crate) fn fill_counting_conflicts(&self, values: &[f32]) -> usize {
+        let mut conflicts = 0;
+        self.binner.for_each_bin(&self.bins, values, |bin| {
+            let mut current = bin.load(O::LOAD);
+            while let Err(actual) = bin.compare_exchange(current, current + 1, O::RMW, O::LOAD) {
+                conflicts += 1;
+                current = actual;
+            }
+        });
+        telemetry::atomic_rmws(values.len());
+        telemetry::cas_retries(conflicts);
+        conflicts
+    }
+
+    // Memory of the bins, for NUMA placement purposes
+    #[cfg(all(feature = "numa", feature = "thread_local"))]
+    pub(crate) fn raw_bins(&self) -> &[AtomicUsize] {
+        &self.bins
     }
 }
 
-impl SyncHistogram for AtomicHistogram {
-    fn fill(&self, values: &[f32]) {
-        for value in values {
-            let bin = (value * (self.bins.len() as f32)) as usize;
-            self.bins[bin].fetch_add(1, Ordering::Relaxed);
+// Buckets of ThreadLocalHistogram
+#[cfg(feature = "thread_local")]
+impl<O: MemoryOrder> Replica for AtomicHistogram<Global, O> {
+    fn add_bins(&self, start: usize, sum: &mut [usize]) {
+        for (dst, src) in sum.iter_mut().zip(&self.bins[start..]) {
+            *dst += src.load(O::LOAD);
         }
     }
 
+    fn count_hits(&self, bins: Range<usize>) -> usize {
+        self.bins[bins].iter().map(|b| b.load(O::LOAD)).sum::<usize>()
+    }
+}
+
+impl<A: Allocator + Sync, O: MemoryOrder> SyncHistogram for AtomicHistogram<A, O> {
+    fn fill(&self, values: &[f32]) {
+        self.binner.for_each_bin(&self.bins, values, |bin| {
+            bin.fetch_add(1, O::RMW);
+        });
+        telemetry::atomic_rmws(values.len())
+    }
+
+    // Each value costs one atomic increment either way, so batching would
+    // only add the cost of copying values around
+    fn fill_iter(&self, values: impl IntoIterator<Item = f32>) {
+        let mut num_values = 0;
+        self.binner.for_each_index_iter(values, |bin| {
+            self.bins[bin].fetch_add(1, O::RMW);
+            num_values += 1;
+        });
+        telemetry::atomic_rmws(num_values)
+    }
+
+    fn fill_one(&self, value: f32) {
+        self.bins[self.binner.bin_index(value)].fetch_add(1, O::RMW);
+        telemetry::atomic_rmws(1)
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_one_with_id(&self, value: f32, _id: ThreadID) {
+        self.fill_one(value)
+    }
+
     fn num_hits(&self) -> usize {
-        self.bins.iter().map(|b| b.load(Ordering::Relaxed)).sum::<usize>()
+        self.bins.iter().map(|b| b.load(O::LOAD)).sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.bins.iter().map(|b| b.load(O::LOAD)).collect()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.bins.len(), "Histogram binning mismatch");
+        for (dst, &src) in self.bins.iter().zip(bins) {
+            dst.fetch_add(src, O::RMW);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + self.bins.capacity() * mem::size_of::<AtomicUsize>()
+    }
+
+    // In sequential mode, the bins can be incremented without atomic
+    // operations, which makes this as fast as the toy histogram given the same
+    // storage layout
+    fn fill_exclusive(&mut self, values: &[f32]) {
+        self.binner.for_each_bin_mut(&mut self.bins, values, |bin| {
+            atomic_with_mut(bin, |bin| *bin += 1)
+        })
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_with_id_exclusive(&mut self, values: &[f32], _id: ThreadID) {
+        self.fill_exclusive(values)
+    }
+}
+
+// Bins are copied one by one, so a clone taken during concurrent fills may miss
+// some of them, like any other readout
+impl<A: Allocator + Clone, O: MemoryOrder> Clone for AtomicHistogram<A, O> {
+    fn clone(&self) -> Self {
+        let mut bins = allocator_api2::vec::Vec::with_capacity_in(self.bins.len(),
+                                                                  self.bins.allocator().clone());
+        bins.extend(self.bins.iter().map(|bin| AtomicUsize::new(bin.load(O::LOAD))));
+        Self::from_raw_bins(bins)
+    }
+}
+
+impl<A, B, O, P> PartialEq<AtomicHistogram<B, P>> for AtomicHistogram<A, O>
+    where A: Allocator, B: Allocator, O: MemoryOrder, P: MemoryOrder
+{
+    fn eq(&self, other: &AtomicHistogram<B, P>) -> bool {
+        self.bins.len() == other.bins.len()
+            && self.bins.iter().zip(other.bins.iter())
+                   .all(|(a, b)| a.load(O::LOAD) == b.load(P::LOAD))
     }
-}
\ No newline at end of file
+}
+
+impl<A: Allocator, O: MemoryOrder> Eq for AtomicHistogram<A, O> {}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use {
+        super::*,
+        loom::{sync::Arc, thread},
+    };
+
+    // Concurrent fills must not lose any update
+    #[test]
+    fn concurrent_fill() {
+        loom::model(|| {
+            let histogram = Arc::new(AtomicHistogram::new(2));
+            let threads = [0.25, 0.75].iter()
+                .map(|&value| {
+                    let histogram = histogram.clone();
+                    thread::spawn(move || histogram.fill(&[value, 0.25]))
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(histogram.bins(), [3, 1]);
+        });
+    }
+
+    // Reading concurrently with a fill may observe any subset of the hits, but
+    // never more hits than were inserted
+    #[test]
+    fn fill_while_reading() {
+        loom::model(|| {
+            let histogram = Arc::new(AtomicHistogram::new(2));
+            let filler = {
+                let histogram = histogram.clone();
+                thread::spawn(move || histogram.fill(&[0.25, 0.75]))
+            };
+            assert!(histogram.num_hits() <= 2);
+            filler.join().unwrap();
+            assert_eq!(histogram.bins(), [1, 1]);
+        });
+    }
+}
@@ -0,0 +1,114 @@
+use {
+    crate::{
+        binning::Binner,
+        impls::ToyHistogram,
+        telemetry,
+        traits::SyncHistogram,
+    },
+    crossbeam_utils::CachePadded,
+    std::{
+        mem,
+        sync::{Mutex, MutexGuard},
+    },
+};
+
+// Lock striping over bins rather than over threads
+//
+// The bins are split into contiguous shards, each protected by its own mutex,
+// so that threads only contend when they fill bins from the same shard. Unlike
+// ThreadBucketizedHistogram, this does not need extra copies of the bins, but
+// a batch of values must lock every shard which it touches. Consecutive values
+// which fall into the same shard are inserted under a single lock acquisition.
+//
+// Mutexes are padded to their own cache line, so that threads which lock
+// different shards do not false-share the lock words.
+//
+pub struct BinShardedHistogram {
+    shards: Vec<CachePadded<Mutex<Vec<usize>>>>,
+    binner: Binner,
+    bins_per_shard: usize,
+}
+
+impl BinShardedHistogram {
+    pub fn new(num_bins: usize, num_shards: usize) -> Self {
+        assert!(num_shards > 0, "Need at least one shard");
+        let bins_per_shard = num_bins.div_ceil(num_shards).max(1);
+        let shards = (0..num_bins).step_by(bins_per_shard)
+            .map(|start| {
+                let len = bins_per_shard.min(num_bins - start);
+                CachePadded::new(Mutex::new(vec![0; len]))
+            })
+            .collect();
+        Self { shards, binner: Binner::new(num_bins), bins_per_shard }
+    }
+
+    fn lock_shard(&self, shard: usize) -> MutexGuard<'_, Vec<usize>> {
+        self.shards[shard].lock().unwrap()
+    }
+}
+
+impl SyncHistogram for BinShardedHistogram {
+    fn fill(&self, values: &[f32]) {
+        let mut locked: Option<(usize, MutexGuard<Vec<usize>>)> = None;
+        self.binner.for_each_index(values, |bin| {
+            let (shard, offset) = (bin / self.bins_per_shard, bin % self.bins_per_shard);
+            match &mut locked {
+                Some((locked_shard, bins)) if *locked_shard == shard => bins[offset] += 1,
+                _ => {
+                    // Only one shard may be locked at a time, otherwise threads
+                    // which lock shards in a different order could deadlock
+                    drop(locked.take());
+                    let mut bins = telemetry::lock(|| self.lock_shard(shard));
+                    bins[offset] += 1;
+                    locked = Some((shard, bins));
+                }
+            }
+        })
+    }
+
+    fn num_hits(&self) -> usize {
+        self.shards.iter()
+            .map(|shard| shard.lock().unwrap().iter().sum::<usize>())
+            .sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = Vec::with_capacity(self.binner.num_bins());
+        for shard in &self.shards {
+            result.extend_from_slice(&shard.lock().unwrap());
+        }
+        result
+    }
+
+    // Every shard is locked before the first one is read out. Fills release
+    // each shard before locking the next one, so a snapshot may contain part
+    // of a batch, but it does match a state that the bins were in.
+    fn snapshot(&self) -> ToyHistogram {
+        let shards = self.shards.iter().map(|shard| shard.lock().unwrap()).collect::<Vec<_>>();
+        ToyHistogram::from_bins(shards.iter().flat_map(|shard| shard.iter().copied()).collect())
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        for (shard, src) in self.shards.iter().zip(bins.chunks(self.bins_per_shard)) {
+            for (dst, &src) in shard.lock().unwrap().iter_mut().zip(src) {
+                *dst += src;
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let shard_heap = self.shards.iter()
+            .map(|shard| shard.lock().unwrap().capacity() * mem::size_of::<usize>())
+            .sum::<usize>();
+        mem::size_of::<Self>()
+            + self.shards.capacity() * mem::size_of::<CachePadded<Mutex<Vec<usize>>>>()
+            + shard_heap
+    }
+}
+
+impl_clone_by_merge!([] BinShardedHistogram => |this, num_bins| {
+    Self::new(num_bins, this.shards.len())
+});
+
+impl_eq_by_bins!([] BinShardedHistogram);
@@ -0,0 +1,146 @@
+use {
+    crate::{
+        impls::{per_thread::PerThread, ToyHistogram},
+        telemetry,
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::Mutex,
+    },
+};
+
+// Wrapper which stages the values inserted by each thread in a buffer of its
+// own, and only fills the underlying histogram once that buffer is full
+//
+// This is the write-combining approach to contention: small fills are batched
+// into larger ones, so that the synchronization cost of the underlying
+// histogram (locking, bucket lookup...) is paid once per buffer instead of once
+// per fill. Fills which are at least as large as the buffer go straight to the
+// underlying histogram. Staged values are flushed before readouts, so the bins
+// are the same as with the underlying histogram alone.
+//
+// Each buffer sits behind an uncontended mutex, as readouts must be able to
+// flush it while its thread is filling it.
+//
+pub struct BufferedHistogram<H: SyncHistogram> {
+    inner: H,
+    threshold: usize,
+    buffers: PerThread<Mutex<Vec<f32>>>,
+}
+
+impl<H: SyncHistogram> BufferedHistogram<H> {
+    // Number of values which are staged before they are flushed by default
+    pub const DEFAULT_THRESHOLD: usize = 256;
+
+    pub fn new(inner: H) -> Self {
+        Self::with_threshold(inner, Self::DEFAULT_THRESHOLD)
+    }
+
+    pub fn with_threshold(inner: H, threshold: usize) -> Self {
+        assert!(threshold > 0, "Flush threshold must be positive");
+        Self {
+            inner,
+            threshold,
+            buffers: PerThread::new(),
+        }
+    }
+
+    // Flush the staged values and return the underlying histogram
+    pub fn into_inner(self) -> H {
+        self.flush();
+        self.inner
+    }
+}
+
+impl<H: SyncHistogram> SyncHistogram for BufferedHistogram<H> {
+    fn fill(&self, values: &[f32]) {
+        if values.len() >= self.threshold {
+            self.inner.fill(values);
+            return;
+        }
+        let buffer = self.buffers.get_or_init(ThreadID::load(), || {
+            Mutex::new(Vec::with_capacity(self.threshold))
+        });
+        let mut buffer = telemetry::lock(|| buffer.lock().unwrap());
+        buffer.extend_from_slice(values);
+        if buffer.len() >= self.threshold {
+            self.inner.fill(&buffer);
+            buffer.clear();
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.flush();
+        self.inner.num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.flush();
+        self.inner.bins()
+    }
+
+    // Values staged concurrently with the flush may be missed
+    fn snapshot(&self) -> ToyHistogram {
+        self.flush();
+        self.inner.snapshot()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.inner.merge_bins(bins)
+    }
+
+    // Fill the underlying histogram with every staged value
+    fn flush(&self) {
+        for buffer in self.buffers.iter() {
+            let mut buffer = buffer.lock().unwrap();
+            if !buffer.is_empty() {
+                self.inner.fill(&buffer);
+                buffer.clear();
+            }
+        }
+        self.inner.flush()
+    }
+
+    fn memory_usage(&self) -> usize {
+        let buffer_heap = self.buffers.iter()
+            .map(|b| b.lock().unwrap().capacity() * mem::size_of::<f32>())
+            .sum::<usize>();
+        self.inner.memory_usage() - mem::size_of::<H>()
+            + mem::size_of::<Self>()
+            + self.buffers.heap_usage()
+            + self.buffers.iter().count() * mem::size_of::<Mutex<Vec<f32>>>()
+            + buffer_heap
+    }
+}
+
+// Staged values are flushed first, so that the clone starts with empty buffers
+impl<H: SyncHistogram + Clone> Clone for BufferedHistogram<H> {
+    fn clone(&self) -> Self {
+        self.flush();
+        Self::with_threshold(self.inner.clone(), self.threshold)
+    }
+}
+
+impl_eq_by_bins!([H: SyncHistogram] BufferedHistogram<H>);
+
+
+#[cfg(all(test, feature = "atomic", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::impls::AtomicHistogram,
+    };
+
+    #[test]
+    fn flush_when_full_and_on_readout() {
+        let histogram = BufferedHistogram::with_threshold(AtomicHistogram::new(2), 3);
+        histogram.fill(&[0.1, 0.9]);
+        assert_eq!(SyncHistogram::num_hits(&histogram.inner), 0);
+        histogram.fill(&[0.9]);
+        assert_eq!(SyncHistogram::bins(&histogram.inner), [1, 2]);
+        histogram.fill(&[0.1]);
+        assert_eq!(SyncHistogram::bins(&histogram), [2, 2]);
+    }
+}
@@ -0,0 +1,337 @@
+// Validated construction of histograms
+//
+// The constructors of the implementations take their parameters as they are
+// and assert that they make sense, so that a bad configuration (no bins, no
+// buckets, a bin count that a GPU cannot handle...) is reported by a panic,
+// sometimes only once the histogram is filled. HistogramBuilder gathers the
+// parameters of every strategy instead, and building an implementation with it
+// checks the parameters which this implementation uses first, reporting bad
+// ones as a BuildError. Parameters which an implementation does not use are
+// ignored, as with the C interface.
+//
+// Wrappers (locks, buffering, exponential binning) build the histogram which
+// they wrap from the same parameters, so that e.g. a
+// `SpinLock<ToyHistogram>` can be built like any other implementation.
+
+use {
+    super::*,
+    core::{fmt, time::Duration},
+};
+// Unused if no wrapper strategy is enabled
+#[allow(unused_imports)]
+use crate::traits::SyncHistogram;
+#[cfg(any(feature = "flat_combining", feature = "thread_bucketized"))]
+use crate::thread_id::ThreadIdProvider;
+
+// Parameters of a histogram, of which each implementation uses a subset
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramBuilder {
+    num_bins: usize,
+    num_buckets: usize,
+    num_shards: usize,
+    bins_per_line: usize,
+    flush_threshold: usize,
+    range: Option<(f32, f32)>,
+    period: Duration,
+}
+
+impl HistogramBuilder {
+    // Default number of buckets and shards, as in the benchmarks
+    pub const DEFAULT_BUCKETS: usize = 2;
+    pub const DEFAULT_SHARDS: usize = 16;
+
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            num_bins,
+            num_buckets: Self::DEFAULT_BUCKETS,
+            num_shards: Self::DEFAULT_SHARDS,
+            bins_per_line: 1,
+            // As BufferedHistogram::DEFAULT_THRESHOLD
+            flush_threshold: 256,
+            range: None,
+            // As AggregatedHistogram::DEFAULT_PERIOD
+            period: Duration::from_millis(10),
+        }
+    }
+
+    // Number of buckets of bucketized strategies
+    pub fn buckets(mut self, num_buckets: usize) -> Self {
+        self.num_buckets = num_buckets;
+        self
+    }
+
+    // Number of independently locked groups of bins of the bin-sharded strategy
+    pub fn shards(mut self, num_shards: usize) -> Self {
+        self.num_shards = num_shards;
+        self
+    }
+
+    // Number of bins per cache line of the padded atomic strategy
+    pub fn bins_per_line(mut self, bins_per_line: usize) -> Self {
+        self.bins_per_line = bins_per_line;
+        self
+    }
+
+    // Number of values which buffered histograms stage before flushing them
+    pub fn flush_threshold(mut self, threshold: usize) -> Self {
+        self.flush_threshold = threshold;
+        self
+    }
+
+    // Range of values covered by exponential bins, which other histograms
+    // cannot change from [0, 1[
+    pub fn range(mut self, min_value: f32, max_value: f32) -> Self {
+        self.range = Some((min_value, max_value));
+        self
+    }
+
+    // Period between the snapshots which aggregated histograms publish
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = period;
+        self
+    }
+
+    // Check the parameters which `H` uses, then build it
+    pub fn build<H: Build>(&self) -> Result<H, BuildError> {
+        if self.num_bins == 0 {
+            return Err(BuildError::NoBins);
+        }
+        H::build(self)
+    }
+}
+
+// Invalid histogram parameter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildError {
+    NoBins,
+    NoBuckets,
+    NoShards,
+    BinsPerLine(usize),
+    FlushThreshold,
+    Period,
+    NoRange,
+    Range { min_value: f32, max_value: f32 },
+    TooManyBins { num_bins: usize, max_bins: usize },
+    NoGpu,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::NoBins => write!(f, "a histogram needs at least one bin"),
+            BuildError::NoBuckets => write!(f, "a bucketized histogram needs at least one bucket"),
+            BuildError::NoShards => write!(f, "a bin-sharded histogram needs at least one shard"),
+            BuildError::BinsPerLine(bins) => {
+                write!(f, "cannot put {} bins per cache line, this must be a power of two \
+                           that fits in a line", bins)
+            }
+            BuildError::FlushThreshold => write!(f, "the flush threshold must be positive"),
+            BuildError::Period => write!(f, "the aggregation period must be positive"),
+            BuildError::NoRange => write!(f, "exponential bins need a range of values"),
+            BuildError::Range { min_value, max_value } => {
+                write!(f, "exponential bins cannot span [{}, {}[, which must be a range of \
+                           positive values", min_value, max_value)
+            }
+            BuildError::TooManyBins { num_bins, max_bins } => {
+                write!(f, "{} bins is more than the maximum of {}", num_bins, max_bins)
+            }
+            BuildError::NoGpu => write!(f, "no usable GPU was found"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BuildError {}
+
+// Histogram which can be built by a HistogramBuilder
+pub trait Build: Sized {
+    // Check the parameters which this histogram uses, which always include a
+    // nonzero number of bins, then build it
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError>;
+}
+
+// Implement Build for histograms which are built from their number of bins
+// alone, which may have generic parameters listed in brackets
+macro_rules! impl_build {
+    ($($(#[$attr:meta])* [$($generics:tt)*] $histogram:ty;)*) => {$(
+        $(#[$attr])*
+        impl<$($generics)*> Build for $histogram {
+            fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+                Ok(Self::new(builder.num_bins))
+            }
+        }
+    )*};
+}
+
+impl_build! {
+    [] ToyHistogram;
+    #[cfg(feature = "adaptive")] [] AdaptiveHistogram;
+    #[cfg(feature = "channel")] [] ChannelHistogram;
+    #[cfg(feature = "count_min")] [] CountMinHistogram;
+    #[cfg(feature = "double_buffer")] [] DoubleBufferedHistogram;
+    #[cfg(feature = "epoch")] [] EpochHistogram;
+    #[cfg(feature = "lazy")] [] LazyAtomicHistogram;
+    #[cfg(feature = "lazy")] [] LazyThreadLocalHistogram;
+    #[cfg(feature = "narrow_atomic")] [C: NarrowCounter] NarrowAtomicHistogram<C>;
+    #[cfg(feature = "per_core")] [] PerCoreHistogram;
+    #[cfg(feature = "ring_buffer")] [] RingBufferHistogram;
+    #[cfg(feature = "rseq")] [] RseqHistogram;
+    #[cfg(feature = "seqlock")] [] SeqlockHistogram;
+    #[cfg(feature = "sorted")] [] SortedAtomicHistogram;
+    #[cfg(feature = "sparse")] [] DashMapHistogram;
+    #[cfg(feature = "sparse")] [] SparseThreadLocalHistogram;
+    #[cfg(feature = "tls")] [] TlsHistogram;
+    #[cfg(feature = "tsx")] [] TsxHistogram;
+    #[cfg(feature = "two_level")] [] TwoLevelHistogram;
+}
+
+// Implement Build for locks around a histogram `H`
+macro_rules! impl_build_lock {
+    ($($(#[$attr:meta])* $lock:ty;)*) => {$(
+        $(#[$attr])*
+        impl<H: Build> Build for $lock {
+            fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+                Ok(<$lock>::new(H::build(builder)?))
+            }
+        }
+    )*};
+}
+
+impl_build_lock! {
+    #[cfg(feature = "mcs_lock")] McsLock<H>;
+    #[cfg(feature = "mutex")] Mutex<H>;
+    #[cfg(feature = "parking_lot")] parking_lot::Mutex<H>;
+    #[cfg(feature = "parking_lot")] parking_lot::RwLock<H>;
+    #[cfg(feature = "rwlock")] RwLock<H>;
+    #[cfg(feature = "spinlock")] SpinLock<H>;
+    #[cfg(feature = "ticket_lock")] TicketLock<H>;
+}
+
+#[cfg(feature = "atomic")]
+impl<O: MemoryOrder> Build for AtomicHistogram<Global, O> {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        Ok(Self::with_ordering(builder.num_bins))
+    }
+}
+
+#[cfg(feature = "aggregated")]
+impl<H: SyncHistogram + Build + Send + 'static> Build for AggregatedHistogram<H> {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        if builder.period.is_zero() {
+            return Err(BuildError::Period);
+        }
+        Ok(Self::with_period(H::build(builder)?, builder.period))
+    }
+}
+
+#[cfg(feature = "bin_sharded")]
+impl Build for BinShardedHistogram {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        if builder.num_shards == 0 {
+            return Err(BuildError::NoShards);
+        }
+        Ok(Self::new(builder.num_bins, builder.num_shards))
+    }
+}
+
+#[cfg(feature = "buffered")]
+impl<H: SyncHistogram + Build> Build for BufferedHistogram<H> {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        if builder.flush_threshold == 0 {
+            return Err(BuildError::FlushThreshold);
+        }
+        Ok(Self::with_threshold(H::build(builder)?, builder.flush_threshold))
+    }
+}
+
+#[cfg(feature = "exponential")]
+impl<H: SyncHistogram + Build> Build for ExponentialHistogram<H> {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        let (min_value, max_value) = builder.range.ok_or(BuildError::NoRange)?;
+        // Written so that NaNs are rejected too
+        if !(min_value > 0.0 && max_value > min_value) {
+            return Err(BuildError::Range { min_value, max_value });
+        }
+        let inner = H::build(builder)?;
+        Ok(Self::new(min_value, max_value, builder.num_bins, |_| inner))
+    }
+}
+
+#[cfg(feature = "flat_combining")]
+impl<P: ThreadIdProvider> Build for FlatCombiningHistogram<P> {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        Ok(Self::with_provider(builder.num_bins))
+    }
+}
+
+#[cfg(feature = "gpu")]
+impl Build for GpuHistogram {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        if builder.num_bins > Self::MAX_BINS {
+            return Err(BuildError::TooManyBins {
+                num_bins: builder.num_bins,
+                max_bins: Self::MAX_BINS,
+            });
+        }
+        Self::try_new(builder.num_bins).ok_or(BuildError::NoGpu)
+    }
+}
+
+#[cfg(feature = "padded_atomic")]
+impl Build for PaddedAtomicHistogram {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        let bins_per_line = builder.bins_per_line;
+        if !bins_per_line.is_power_of_two() || bins_per_line > Self::MAX_BINS_PER_LINE {
+            return Err(BuildError::BinsPerLine(bins_per_line));
+        }
+        Ok(Self::with_bins_per_line(builder.num_bins, bins_per_line))
+    }
+}
+
+#[cfg(feature = "thread_local")]
+impl<O: MemoryOrder> Build for ThreadLocalHistogram<O> {
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        Ok(Self::with_ordering(builder.num_bins))
+    }
+}
+
+#[cfg(feature = "thread_bucketized")]
+impl<L, P, B> Build for ThreadBucketizedHistogram<L, P, B>
+    where L: BucketLock, P: ThreadIdProvider, B: BucketAssign
+{
+    fn build(builder: &HistogramBuilder) -> Result<Self, BuildError> {
+        if builder.num_buckets == 0 {
+            return Err(BuildError::NoBuckets);
+        }
+        Ok(Self::with_locks(builder.num_bins, builder.num_buckets))
+    }
+}
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_bad_parameters() {
+        let builder = HistogramBuilder::new(4);
+        assert_eq!(HistogramBuilder::new(0).build::<ToyHistogram>().err(), Some(BuildError::NoBins));
+        assert_eq!(builder.build::<ToyHistogram>().map(|h| h.num_hits()), Ok(0));
+        #[cfg(feature = "thread_bucketized")]
+        assert!(matches!(builder.clone().buckets(0).build::<ThreadBucketizedHistogram>(),
+                         Err(BuildError::NoBuckets)));
+        #[cfg(all(feature = "spinlock", feature = "padded_atomic"))]
+        assert!(matches!(builder.clone().bins_per_line(3).build::<SpinLock<PaddedAtomicHistogram>>(),
+                         Err(BuildError::BinsPerLine(3))));
+        #[cfg(all(feature = "exponential", feature = "atomic"))]
+        {
+            let exponential = |builder: HistogramBuilder| {
+                builder.build::<ExponentialHistogram<AtomicHistogram>>().err()
+            };
+            assert_eq!(exponential(builder.clone()), Some(BuildError::NoRange));
+            assert_eq!(exponential(builder.clone().range(2.0, 1.0)),
+                       Some(BuildError::Range { min_value: 2.0, max_value: 1.0 }));
+            assert_eq!(exponential(builder.range(1.0, 2.0)), None);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        traits::{Histogram, SyncHistogram},
+    },
+    std::{
+        mem,
+        sync::mpsc::{self, SyncSender},
+        thread::{self, JoinHandle},
+    },
+};
+
+// Message passing instead of shared memory: filling threads send copies of
+// their batches to a dedicated thread, which owns a ToyHistogram and is the
+// only one to ever touch it.
+//
+// Filling threads never contend on the histogram, but they must copy and send
+// every batch, and the aggregator thread must keep up with all of them. The
+// channel is bounded so that, if it does not, filling threads are slowed down
+// instead of queuing up an unbounded amount of data.
+//
+pub struct ChannelHistogram {
+    num_bins: usize,
+    sender: Option<SyncSender<Message>>,
+    aggregator: Option<JoinHandle<()>>,
+}
+
+// Requests processed by the aggregator thread, in the order of their arrival
+enum Message {
+    Fill(Vec<f32>),
+    Merge(Vec<usize>),
+    Query(Box<dyn FnOnce(&ToyHistogram) + Send>),
+}
+
+// Number of messages which can be waiting for the aggregator thread
+const QUEUE_LEN: usize = 1024;
+
+impl ChannelHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let aggregator = thread::spawn(move || {
+            let mut histogram = ToyHistogram::new(num_bins);
+            for message in receiver {
+                match message {
+                    Message::Fill(values) => histogram.fill_mut(&values),
+                    Message::Merge(bins) => histogram.merge_bins_mut(&bins),
+                    Message::Query(query) => query(&histogram),
+                }
+            }
+        });
+        Self {
+            num_bins,
+            sender: Some(sender),
+            aggregator: Some(aggregator),
+        }
+    }
+
+    fn send(&self, message: Message) {
+        self.sender.as_ref()
+            .expect("Sender is only dropped on shutdown")
+            .send(message)
+            .expect("Aggregator thread has died")
+    }
+
+    // Run a query on the histogram once every previously sent message has been
+    // processed, and wait for its result
+    fn query<R: Send + 'static>(&self, query: impl FnOnce(&ToyHistogram) -> R + Send + 'static) -> R {
+        let (result_sender, result_receiver) = mpsc::sync_channel(1);
+        self.send(Message::Query(Box::new(move |histogram| {
+            // The querying thread waits for the result, so this cannot fail
+            let _ = result_sender.send(query(histogram));
+        })));
+        result_receiver.recv().expect("Aggregator thread has died")
+    }
+}
+
+impl SyncHistogram for ChannelHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.send(Message::Fill(values.to_vec()))
+    }
+
+    fn num_hits(&self) -> usize {
+        self.query(|histogram| histogram.num_hits())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.query(|histogram| histogram.bins())
+    }
+
+    // Checked here, as a panic of the aggregator thread would only be reported
+    // by the next message
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.num_bins, "Histogram binning mismatch");
+        self.send(Message::Merge(bins.to_vec()))
+    }
+
+    // Messages are processed in order, so an empty query waits for every
+    // previous fill to be applied
+    fn flush(&self) {
+        self.query(|_| ())
+    }
+
+    // Messages in flight are not accounted for
+    fn memory_usage(&self) -> usize {
+        let histogram = self.query(|histogram| histogram.memory_usage());
+        mem::size_of::<Self>() + QUEUE_LEN * mem::size_of::<Message>() + histogram
+    }
+}
+
+// Disconnecting the channel makes the aggregator thread exit
+impl Drop for ChannelHistogram {
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(aggregator) = self.aggregator.take() {
+            // A panic of the aggregator thread was already reported by a
+            // failure to send or receive a message
+            let _ = aggregator.join();
+        }
+    }
+}
+
+impl_clone_by_merge!([] ChannelHistogram);
+
+impl_eq_by_bins!([] ChannelHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    // Mismatched bins must make the caller panic, not the aggregator thread
+    #[test]
+    #[should_panic(expected = "Histogram binning mismatch")]
+    fn merge_mismatch() {
+        ChannelHistogram::new(4).merge_bins(&[1, 2, 3]);
+    }
+}
@@ -0,0 +1,171 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    std::mem,
+};
+
+// Approximate histogram based on a count-min sketch
+//
+// Instead of one counter per bin, the sketch has a fixed number of rows of
+// atomic counters. Each row maps bins to its counters with its own hash
+// function, and every hit increments one counter per row. The contents of a
+// bin are estimated as the smallest of its counters, which can only be too
+// high, when other bins hash to the same counters in every row.
+//
+// Memory usage thus only depends on the dimensions of the sketch, not on the
+// number of bins, and hits on neighboring bins are spread across the rows
+// instead of landing on the same cache lines. In exchange, every hit costs one
+// atomic increment per row, and readouts are approximate.
+//
+pub struct CountMinHistogram {
+    counters: Vec<AtomicUsize>,
+    hash_seeds: Vec<u64>,
+    width_bits: u32,
+    binner: Binner,
+}
+
+impl CountMinHistogram {
+    // Default dimensions of the sketch
+    pub const DEFAULT_WIDTH: usize = 1024;
+    pub const DEFAULT_DEPTH: usize = 4;
+
+    pub fn new(num_bins: usize) -> Self {
+        Self::with_dimensions(num_bins, Self::DEFAULT_WIDTH, Self::DEFAULT_DEPTH)
+    }
+
+    // Sketch with `depth` rows of `width` counters. The width is rounded up to
+    // the next power of two.
+    pub fn with_dimensions(num_bins: usize, width: usize, depth: usize) -> Self {
+        assert!(depth > 0, "A count-min sketch needs at least one row");
+        let width = width.max(2).next_power_of_two();
+        Self {
+            counters: (0..width * depth).map(|_| AtomicUsize::new(0)).collect(),
+            hash_seeds: (0..depth as u64).map(hash_seed).collect(),
+            width_bits: width.trailing_zeros(),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    fn width(&self) -> usize {
+        1 << self.width_bits
+    }
+
+    // Counters of a bin, one per row
+    fn bin_counters(&self, bin: usize) -> impl Iterator<Item = &AtomicUsize> + '_ {
+        self.hash_seeds.iter()
+            .zip(self.counters.chunks_exact(self.width()))
+            .map(move |(&seed, row)| {
+                // Multiply-shift hashing, which keeps the high bits
+                let hash = (bin as u64).wrapping_mul(seed) >> (u64::BITS - self.width_bits);
+                &row[hash as usize]
+            })
+    }
+}
+
+// Odd multipliers for multiply-shift hashing, derived from the row index with
+// SplitMix64 so that every row hashes differently
+fn hash_seed(row: u64) -> u64 {
+    let mut z = row.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) | 1
+}
+
+impl SyncHistogram for CountMinHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.binner.for_each_index(values, |bin| {
+            for counter in self.bin_counters(bin) {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        telemetry::atomic_rmws(values.len() * self.hash_seeds.len())
+    }
+
+    // Every row counts every hit exactly once
+    fn num_hits(&self) -> usize {
+        self.counters[..self.width()].iter()
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .sum::<usize>()
+    }
+
+    // Estimated contents of the bins, which are never lower than the actual
+    // contents, but may be higher
+    fn bins(&self) -> Vec<usize> {
+        (0..self.binner.num_bins())
+            .map(|bin| {
+                self.bin_counters(bin)
+                    .map(|counter| counter.load(Ordering::Relaxed))
+                    .min()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        for (bin, &count) in bins.iter().enumerate().filter(|(_, &count)| count > 0) {
+            for counter in self.bin_counters(bin) {
+                counter.fetch_add(count, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.counters.capacity() * mem::size_of::<AtomicUsize>()
+            + self.hash_seeds.capacity() * mem::size_of::<u64>()
+    }
+}
+
+// Merging estimated bins into a new sketch would inflate them further, so the
+// counters are copied instead
+impl Clone for CountMinHistogram {
+    fn clone(&self) -> Self {
+        Self {
+            counters: self.counters.iter()
+                .map(|counter| AtomicUsize::new(counter.load(Ordering::Relaxed)))
+                .collect(),
+            hash_seeds: self.hash_seeds.clone(),
+            width_bits: self.width_bits,
+            binner: self.binner,
+        }
+    }
+}
+
+impl_eq_by_bins!([] CountMinHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_underestimate() {
+        let histogram = CountMinHistogram::with_dimensions(1000, 64, 2);
+        let values = (0..10_000).map(|i| (i % 997) as f32 / 997.0).collect::<Vec<_>>();
+        histogram.fill(&values);
+        let mut expected = vec![0; 1000];
+        for &value in &values {
+            expected[(value * 1000.0) as usize] += 1;
+        }
+        let bins = SyncHistogram::bins(&histogram);
+        assert!(bins.iter().zip(&expected).all(|(actual, expected)| actual >= expected));
+        assert_eq!(histogram.num_hits(), values.len());
+        assert_eq!(histogram.memory_usage() - mem::size_of::<CountMinHistogram>(),
+                   64 * 2 * mem::size_of::<AtomicUsize>() + 2 * mem::size_of::<u64>());
+    }
+
+    // Estimates would grow if a clone was filled with them
+    #[test]
+    fn clone_keeps_estimates() {
+        let histogram = CountMinHistogram::with_dimensions(100, 4, 1);
+        histogram.fill(&[0.0, 0.5, 0.99]);
+        let clone = histogram.clone();
+        assert_eq!(SyncHistogram::bins(&clone), SyncHistogram::bins(&histogram));
+        assert!(clone == histogram);
+    }
+}
@@ -0,0 +1,155 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{spin_loop, AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::RwLock,
+    },
+};
+
+// Double-buffered histogram, for harvesting results while filling continues
+//
+// Writers fill the active buffer of atomic bins. Swapping the buffers makes
+// subsequent fills go to the other buffer, waits for the writers which were
+// still filling the previously active one, then merges its contents into the
+// published bins and clears it for the next swap. Readers of the published bins
+// thus get a consistent snapshot, which is only updated on swaps, without ever
+// blocking the writers.
+//
+// This is the same idea as EpochHistogram, but as the two buffers are reused
+// forever, no allocation and no memory reclamation scheme is needed.
+//
+pub struct DoubleBufferedHistogram {
+    buffers: [Buffer; 2],
+
+    // Index of the buffer which fills go to
+    active: AtomicUsize,
+
+    // Sum of the buffers which were swapped out so far. Swaps are serialized by
+    // holding the write lock of this RwLock.
+    published: RwLock<Vec<usize>>,
+
+    binner: Binner,
+}
+
+struct Buffer {
+    bins: Vec<AtomicUsize>,
+
+    // Number of writers which are filling this buffer
+    writers: AtomicUsize,
+}
+
+impl Buffer {
+    fn new(num_bins: usize) -> Self {
+        Self {
+            bins: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            writers: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl DoubleBufferedHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            buffers: [Buffer::new(num_bins), Buffer::new(num_bins)],
+            active: AtomicUsize::new(0),
+            published: RwLock::new(vec![0; num_bins]),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Publish the contents of the active buffer, and direct subsequent fills to
+    // the other one
+    pub fn swap(&self) {
+        let mut published = self.published.write().unwrap();
+        let old_idx = self.active.load(Ordering::Relaxed);
+        self.active.store(1 - old_idx, Ordering::SeqCst);
+        let old = &self.buffers[old_idx];
+        while old.writers.load(Ordering::SeqCst) != 0 {
+            spin_loop();
+        }
+        for (dst, src) in published.iter_mut().zip(&old.bins) {
+            *dst += src.swap(0, Ordering::Relaxed);
+        }
+    }
+
+    // Bins as of the last swap, without waiting for ongoing fills
+    pub fn published(&self) -> Vec<usize> {
+        self.published.read().unwrap().clone()
+    }
+}
+
+impl SyncHistogram for DoubleBufferedHistogram {
+    fn fill(&self, values: &[f32]) {
+        loop {
+            let idx = self.active.load(Ordering::Acquire);
+            let buffer = &self.buffers[idx];
+            // Register as a writer, then check that this buffer was not swapped
+            // out in the meantime. Pairs with swap(), which switches the active
+            // buffer then checks for writers.
+            buffer.writers.fetch_add(1, Ordering::SeqCst);
+            telemetry::atomic_rmws(2);
+            if self.active.load(Ordering::SeqCst) == idx {
+                self.binner.for_each_bin(&buffer.bins, values, |bin| {
+                    bin.fetch_add(1, Ordering::Relaxed);
+                });
+                telemetry::atomic_rmws(values.len());
+                buffer.writers.fetch_sub(1, Ordering::Release);
+                return;
+            }
+            buffer.writers.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins().iter().sum::<usize>()
+    }
+
+    // Readouts are expected to see every fill which happened before them, so
+    // they need to swap the buffers first
+    fn bins(&self) -> Vec<usize> {
+        self.swap();
+        self.published()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        let mut published = self.published.write().unwrap();
+        assert_eq!(bins.len(), published.len(), "Histogram binning mismatch");
+        for (dst, src) in published.iter_mut().zip(bins) {
+            *dst += src;
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let num_bins = self.published.read().unwrap().capacity();
+        mem::size_of::<Self>()
+            + num_bins * (2 * mem::size_of::<AtomicUsize>() + mem::size_of::<usize>())
+    }
+}
+
+impl_clone_by_merge!([] DoubleBufferedHistogram);
+
+impl_eq_by_bins!([] DoubleBufferedHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    // Fills only become visible to readers of the published bins on swaps
+    #[test]
+    fn publish_on_swap() {
+        let histogram = DoubleBufferedHistogram::new(2);
+        histogram.fill(&[0.1, 0.9]);
+        assert_eq!(histogram.published(), [0, 0]);
+        histogram.swap();
+        assert_eq!(histogram.published(), [1, 1]);
+        histogram.fill(&[0.9]);
+        assert_eq!(histogram.published(), [1, 1]);
+        assert_eq!(SyncHistogram::bins(&histogram), [1, 2]);
+    }
+}
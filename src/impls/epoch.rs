@@ -0,0 +1,173 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{spin_loop, AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    crossbeam_epoch::{self as epoch, Atomic, Owned},
+    std::{
+        mem,
+        sync::Mutex,
+    },
+};
+
+// Histogram whose readers get consistent snapshots of the bins, without
+// stopping the writers
+//
+// Writers fill the current generation of atomic bins. To take a snapshot, a
+// reader installs a fresh generation, which subsequent fills go to, waits for
+// the writers which were still filling the old generation, and adds the old
+// generation's bins to the contents of the previous snapshots. Every batch is
+// thus either entirely in the snapshot or entirely out of it.
+//
+// Writers may still hold a pointer to a generation after it has been replaced,
+// so retired generations are freed using epoch-based reclamation.
+//
+pub struct EpochHistogram {
+    current: Atomic<Generation>,
+    retired: Mutex<Vec<usize>>,
+    binner: Binner,
+}
+
+struct Generation {
+    bins: Vec<AtomicUsize>,
+
+    // Number of writers which are filling this generation
+    writers: AtomicUsize,
+}
+
+impl Generation {
+    fn new(num_bins: usize) -> Self {
+        Self {
+            bins: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            writers: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl EpochHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            current: Atomic::new(Generation::new(num_bins)),
+            retired: Mutex::new(vec![0; num_bins]),
+            binner: Binner::new(num_bins),
+        }
+    }
+}
+
+impl SyncHistogram for EpochHistogram {
+    fn fill(&self, values: &[f32]) {
+        let guard = epoch::pin();
+        loop {
+            let generation_ptr = self.current.load(Ordering::Acquire, &guard);
+            let generation = unsafe { generation_ptr.deref() };
+            // Register as a writer, then check that this generation was not
+            // retired in the meantime. Pairs with the retirement sequence of
+            // bins(), which swaps generations then checks for writers.
+            generation.writers.fetch_add(1, Ordering::SeqCst);
+            telemetry::atomic_rmws(2);
+            if self.current.load(Ordering::SeqCst, &guard) == generation_ptr {
+                self.binner.for_each_bin(&generation.bins, values, |bin| {
+                    bin.fetch_add(1, Ordering::Relaxed);
+                });
+                telemetry::atomic_rmws(values.len());
+                generation.writers.fetch_sub(1, Ordering::Release);
+                return;
+            }
+            generation.writers.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins().iter().sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut retired = self.retired.lock().unwrap();
+        let guard = epoch::pin();
+        let old_ptr = self.current.swap(Owned::new(Generation::new(retired.len())),
+                                        Ordering::SeqCst,
+                                        &guard);
+        let old = unsafe { old_ptr.deref() };
+        while old.writers.load(Ordering::SeqCst) != 0 {
+            spin_loop();
+        }
+        for (dst, src) in retired.iter_mut().zip(&old.bins) {
+            *dst += src.load(Ordering::Relaxed);
+        }
+        unsafe { guard.defer_destroy(old_ptr) };
+        retired.clone()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        let mut retired = self.retired.lock().unwrap();
+        assert_eq!(bins.len(), retired.len(), "Histogram binning mismatch");
+        for (dst, src) in retired.iter_mut().zip(bins) {
+            *dst += src;
+        }
+    }
+
+    // Retired generations which are not reclaimed yet are not accounted for
+    fn memory_usage(&self) -> usize {
+        let num_bins = self.retired.lock().unwrap().capacity();
+        mem::size_of::<Self>()
+            + mem::size_of::<Generation>()
+            + num_bins * (mem::size_of::<AtomicUsize>() + mem::size_of::<usize>())
+    }
+}
+
+impl Drop for EpochHistogram {
+    fn drop(&mut self) {
+        // No other thread can access the histogram anymore
+        unsafe {
+            drop(self.current.load(Ordering::Relaxed, epoch::unprotected()).into_owned());
+        }
+    }
+}
+
+impl_clone_by_merge!([] EpochHistogram);
+
+impl_eq_by_bins!([] EpochHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::traits::Histogram,
+        std::{sync::atomic::AtomicBool, thread},
+    };
+
+    // Readouts must contain every batch either entirely or not at all
+    #[test]
+    fn consistent_snapshots() {
+        const NUM_THREADS: usize = 4;
+        const NUM_BATCHES: usize = 100;
+        const BATCH: [f32; 8] = [0.1, 0.3, 0.6, 0.9, 0.1, 0.3, 0.6, 0.9];
+        let histogram = EpochHistogram::new(4);
+        let done = AtomicBool::new(false);
+        let check = |bins: &[usize]| {
+            assert_eq!(bins.iter().sum::<usize>() % BATCH.len(), 0);
+            assert!(bins.iter().all(|&bin| bin == bins[0]));
+        };
+        thread::scope(|s| {
+            let writers = (0..NUM_THREADS).map(|_| s.spawn(|| {
+                for _ in 0..NUM_BATCHES {
+                    histogram.fill(&BATCH);
+                }
+            })).collect::<Vec<_>>();
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    check(&SyncHistogram::bins(&histogram));
+                    check(&Histogram::bins(&histogram.snapshot()));
+                }
+            });
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(SyncHistogram::num_hits(&histogram), NUM_THREADS * NUM_BATCHES * BATCH.len());
+    }
+}
@@ -0,0 +1,184 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    std::{cell::Cell, mem},
+};
+
+// Wrapper which gives any histogram exponentially spaced bins, as in HDR
+// histograms or DDSketch
+//
+// Latency-style data spans several orders of magnitude, and what matters is the
+// relative error on each value rather than the absolute one. Bin i of this
+// histogram thus covers [min * g^i, min * g^(i+1)[ for some growth factor g,
+// which is obtained by filling the underlying histogram with the logarithms of
+// the values, rescaled to its [0, 1[ axis. Since the underlying histogram can be
+// of any kind, exponential binning can be compared with uniform binning under
+// every synchronization strategy. Values outside of [min, max[ are clamped to
+// the first or last bin, as in other histograms.
+//
+// Every fill computes one logarithm per value, into a per-thread scratch buffer
+// that is reused across fills.
+//
+#[derive(Clone)]
+pub struct ExponentialHistogram<H: SyncHistogram> {
+    inner: H,
+    scale: LogScale,
+}
+
+// Mapping from values to the [0, 1[ axis of the underlying histogram
+#[derive(Clone, Copy, Debug)]
+struct LogScale {
+    min_value: f32,
+    log_min: f32,
+    inv_log_range: f32,
+    growth: f32,
+}
+
+impl LogScale {
+    fn position(&self, value: f32) -> f32 {
+        (value.ln() - self.log_min) * self.inv_log_range
+    }
+
+    // Convert values to positions, and hand them over to `f`
+    fn with_positions<R>(&self, values: &[f32], f: impl FnOnce(&[f32]) -> R) -> R {
+        thread_local! {
+            static SCRATCH: Cell<Vec<f32>> = const { Cell::new(Vec::new()) };
+        }
+        // The buffer is taken out of the thread-local so that nested
+        // exponential histograms each get their own
+        let mut positions = SCRATCH.with(Cell::take);
+        positions.clear();
+        positions.extend(values.iter().map(|&value| self.position(value)));
+        let result = f(&positions);
+        SCRATCH.with(|scratch| scratch.set(positions));
+        result
+    }
+}
+
+impl<H: SyncHistogram> ExponentialHistogram<H> {
+    // Histogram with `num_bins` bins spanning [min_value, max_value[, whose
+    // underlying histogram is built by `make_inner` from its number of bins
+    pub fn new(min_value: f32,
+               max_value: f32,
+               num_bins: usize,
+               make_inner: impl FnOnce(usize) -> H) -> Self {
+        assert!(min_value > 0.0 && max_value > min_value,
+                "Exponential bins need a range of positive values");
+        let log_range = (max_value / min_value).ln();
+        Self {
+            inner: make_inner(num_bins),
+            scale: LogScale {
+                min_value,
+                log_min: min_value.ln(),
+                inv_log_range: 1.0 / log_range,
+                growth: (log_range / num_bins as f32).exp(),
+            },
+        }
+    }
+
+    // Histogram whose bins are narrow enough that every value in
+    // [min_value, max_value[ is known within a relative error of `accuracy`
+    pub fn with_relative_accuracy(min_value: f32,
+                                  max_value: f32,
+                                  accuracy: f32,
+                                  make_inner: impl FnOnce(usize) -> H) -> Self {
+        assert!(accuracy > 0.0 && accuracy < 1.0, "Relative accuracy must be in ]0, 1[");
+        let max_growth = (1.0 + accuracy) / (1.0 - accuracy);
+        let num_bins = ((max_value / min_value).ln() / max_growth.ln()).ceil() as usize;
+        Self::new(min_value, max_value, num_bins.max(1), make_inner)
+    }
+
+    // Value which best represents the contents of a bin, i.e. which is within
+    // the smallest relative error of every value in the bin
+    pub fn bin_value(&self, bin: usize) -> f32 {
+        let LogScale { min_value, growth, .. } = self.scale;
+        2.0 * min_value * growth.powi(bin as i32) * growth / (growth + 1.0)
+    }
+
+    // Relative error of bin_value with respect to the values in the bin
+    pub fn relative_accuracy(&self) -> f32 {
+        (self.scale.growth - 1.0) / (self.scale.growth + 1.0)
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<H: SyncHistogram> SyncHistogram for ExponentialHistogram<H> {
+    fn fill(&self, values: &[f32]) {
+        self.scale.with_positions(values, |positions| self.inner.fill(positions))
+    }
+
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        self.scale.with_positions(values, |positions| self.inner.fill_with_id(positions, id))
+    }
+
+    fn num_hits(&self) -> usize {
+        self.inner.num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.inner.bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.inner.merge_bins(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.inner.memory_usage() - mem::size_of::<H>() + mem::size_of::<Self>()
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+
+    fn snapshot(&self) -> ToyHistogram {
+        self.inner.snapshot()
+    }
+
+    fn fill_exclusive(&mut self, values: &[f32]) {
+        let inner = &mut self.inner;
+        self.scale.with_positions(values, |positions| inner.fill_exclusive(positions))
+    }
+
+    fn fill_with_id_exclusive(&mut self, values: &[f32], id: ThreadID) {
+        let inner = &mut self.inner;
+        self.scale.with_positions(values, |positions| inner.fill_with_id_exclusive(positions, id))
+    }
+}
+
+impl_eq_by_bins!([H: SyncHistogram] ExponentialHistogram<H>);
+
+
+#[cfg(all(test, feature = "atomic", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::impls::AtomicHistogram,
+    };
+
+    #[test]
+    fn bins_grow_exponentially() {
+        let histogram = ExponentialHistogram::new(0.001, 1.0, 3, AtomicHistogram::new);
+        histogram.fill(&[0.0, 0.003, 0.03, 0.3, 0.5, 2.0]);
+        assert_eq!(SyncHistogram::bins(&histogram), [2, 1, 3]);
+    }
+
+    #[test]
+    fn relative_accuracy() {
+        for &value in &[2e-6, 3.7e-5, 0.001, 0.42, 9.5] {
+            let histogram = ExponentialHistogram::with_relative_accuracy(1e-6, 10.0, 0.01,
+                                                                         AtomicHistogram::new);
+            assert!(histogram.relative_accuracy() <= 0.01);
+            histogram.fill(&[value]);
+            let bin = SyncHistogram::bins(&histogram).iter().position(|&hits| hits > 0).unwrap();
+            let error = (histogram.bin_value(bin) - value).abs() / value;
+            assert!(error <= 0.01 * 1.001, "{} was estimated as {}", value, histogram.bin_value(bin));
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// Filling histograms from iterators
+//
+// Extending a histogram fills it with fill_iter(), which inserts values in
+// batches or one by one depending on the implementation. Thread-safe histograms
+// can be extended through a shared reference, which fills them like fill()
+// does, or through a mutable one, which takes the faster exclusive path. With
+// the rayon feature, they can also be extended from parallel iterators, each
+// rayon job filling its own batches.
+//
+// Histograms cannot be collected from values, as there is no way to tell how
+// many bins they should have. A ToyHistogram can be collected from the contents
+// of its bins instead.
+//
+// Orphan rules prevent implementing Extend for the standard library's and
+// parking_lot's locks, whose histograms must be filled via SyncHistogram.
+
+use {
+    super::*,
+    crate::traits::Histogram,
+    core::iter::FromIterator,
+};
+// Unused if no thread-safe histogram is enabled
+#[allow(unused_imports)]
+use crate::traits::SyncHistogram;
+#[cfg(any(feature = "flat_combining", feature = "thread_bucketized"))]
+use crate::thread_id::ThreadIdProvider;
+// Likewise
+#[cfg(feature = "rayon")]
+#[allow(unused_imports)]
+use {
+    crate::traits::ITER_BATCH_SIZE,
+    alloc::vec::Vec,
+    rayon::iter::{IntoParallelIterator, ParallelExtend, ParallelIterator},
+};
+
+impl<A: Allocator> Extend<f32> for ToyHistogram<A> {
+    fn extend<I: IntoIterator<Item = f32>>(&mut self, values: I) {
+        self.fill_iter_mut(values)
+    }
+}
+
+impl<'a, A: Allocator> Extend<&'a f32> for ToyHistogram<A> {
+    fn extend<I: IntoIterator<Item = &'a f32>>(&mut self, values: I) {
+        self.extend(values.into_iter().copied())
+    }
+}
+
+// Histogram with the given bin contents
+impl FromIterator<usize> for ToyHistogram<Global> {
+    fn from_iter<I: IntoIterator<Item = usize>>(bins: I) -> Self {
+        Self::from_bins(bins.into_iter().collect())
+    }
+}
+
+// Implement Extend and ParallelExtend for thread-safe histograms, which may
+// have generic parameters listed in brackets
+macro_rules! impl_extend {
+    ($($(#[$attr:meta])* [$($generics:tt)*] $histogram:ty;)*) => {$(
+        $(#[$attr])*
+        impl<$($generics)*> Extend<f32> for $histogram {
+            fn extend<I: IntoIterator<Item = f32>>(&mut self, values: I) {
+                self.fill_iter_mut(values)
+            }
+        }
+
+        $(#[$attr])*
+        impl<$($generics)*> Extend<f32> for &$histogram {
+            fn extend<I: IntoIterator<Item = f32>>(&mut self, values: I) {
+                self.fill_iter(values)
+            }
+        }
+
+        $(#[$attr])*
+        #[cfg(feature = "rayon")]
+        impl<$($generics)*> ParallelExtend<f32> for $histogram {
+            fn par_extend<I: IntoParallelIterator<Item = f32>>(&mut self, values: I) {
+                let histogram = &*self;
+                values.into_par_iter()
+                    .fold(|| Vec::with_capacity(ITER_BATCH_SIZE), |mut batch, value| {
+                        batch.push(value);
+                        if batch.len() == ITER_BATCH_SIZE {
+                            histogram.fill(&batch);
+                            batch.clear();
+                        }
+                        batch
+                    })
+                    .for_each(|batch| histogram.fill(&batch))
+            }
+        }
+    )*};
+}
+
+impl_extend! {
+    #[cfg(feature = "adaptive")] [] AdaptiveHistogram;
+    #[cfg(feature = "aggregated")] [H: SyncHistogram + Send + 'static] AggregatedHistogram<H>;
+    #[cfg(feature = "atomic")] [A: Allocator + Sync, O: MemoryOrder] AtomicHistogram<A, O>;
+    #[cfg(feature = "bin_sharded")] [] BinShardedHistogram;
+    #[cfg(feature = "buffered")] [H: SyncHistogram] BufferedHistogram<H>;
+    #[cfg(feature = "channel")] [] ChannelHistogram;
+    #[cfg(feature = "count_min")] [] CountMinHistogram;
+    #[cfg(feature = "double_buffer")] [] DoubleBufferedHistogram;
+    #[cfg(feature = "epoch")] [] EpochHistogram;
+    #[cfg(feature = "exponential")] [H: SyncHistogram] ExponentialHistogram<H>;
+    #[cfg(feature = "flat_combining")] [P: ThreadIdProvider] FlatCombiningHistogram<P>;
+    #[cfg(feature = "gpu")] [] GpuHistogram;
+    #[cfg(feature = "lazy")] [] LazyAtomicHistogram;
+    #[cfg(feature = "lazy")] [] LazyThreadLocalHistogram;
+    #[cfg(feature = "mcs_lock")] [] McsLock<ToyHistogram>;
+    #[cfg(feature = "narrow_atomic")] [C: NarrowCounter] NarrowAtomicHistogram<C>;
+    #[cfg(feature = "padded_atomic")] [] PaddedAtomicHistogram;
+    #[cfg(feature = "per_core")] [] PerCoreHistogram;
+    #[cfg(feature = "ring_buffer")] [] RingBufferHistogram;
+    #[cfg(feature = "rseq")] [] RseqHistogram;
+    #[cfg(feature = "seqlock")] [] SeqlockHistogram;
+    #[cfg(feature = "sorted")] [] SortedAtomicHistogram;
+    #[cfg(feature = "sparse")] [] DashMapHistogram;
+    #[cfg(feature = "sparse")] [] SparseThreadLocalHistogram;
+    #[cfg(feature = "spinlock")] [] SpinLock<ToyHistogram>;
+    #[cfg(feature = "thread_bucketized")]
+    [L: BucketLock, P: ThreadIdProvider, B: BucketAssign] ThreadBucketizedHistogram<L, P, B>;
+    #[cfg(feature = "thread_local")] [O: MemoryOrder] ThreadLocalHistogram<O>;
+    #[cfg(feature = "ticket_lock")] [] TicketLock<ToyHistogram>;
+    #[cfg(feature = "tls")] [] TlsHistogram;
+    #[cfg(feature = "tsx")] [] TsxHistogram;
+    #[cfg(feature = "two_level")] [] TwoLevelHistogram;
+}
+
+
+#[cfg(all(test, feature = "atomic", not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_from_iterators() {
+        let values = (0..1000).map(|i| i as f32 / 1000.0);
+        let mut histogram = ToyHistogram::new(4);
+        histogram.extend(values.clone());
+        assert_eq!(histogram.bins(), [250; 4]);
+
+        let atomic = AtomicHistogram::new(4);
+        (&atomic).extend(values.clone());
+        #[cfg(feature = "rayon")]
+        {
+            let mut atomic = atomic;
+            atomic.par_extend(rayon::iter::repeat_n(0.1, 1000));
+            assert_eq!(SyncHistogram::bins(&atomic), [1250, 250, 250, 250]);
+        }
+        #[cfg(not(feature = "rayon"))]
+        assert_eq!(SyncHistogram::bins(&atomic), [250; 4]);
+
+        let bins = (1..4).collect::<ToyHistogram>();
+        assert_eq!(bins.bins(), [1, 2, 3]);
+    }
+}
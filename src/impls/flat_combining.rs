@@ -0,0 +1,192 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        sync::{spin_loop, AtomicPtr, AtomicUsize, Ordering},
+        telemetry,
+        thread_id::{BucketKey, ThreadID, ThreadIdProvider, TlsCounter},
+        traits::{Histogram, SyncHistogram},
+    },
+    crossbeam_utils::CachePadded,
+    std::{
+        marker::PhantomData,
+        mem,
+        ptr,
+        slice,
+        sync::Mutex,
+    },
+};
+
+// Flat combining: rather than all taking turns at locking the histogram,
+// threads publish their batches, and whichever thread gets the lock applies
+// every published batch on behalf of the others.
+//
+// Each thread publishes its batch in a slot selected by its bucket key, then
+// tries to become the combiner. If another thread is already combining, it
+// waits for its batch to be applied, or for the lock to become available. This
+// way, the lock is acquired once per round of batches instead of once per
+// batch, and the histogram stays in the combiner's cache.
+//
+pub struct FlatCombiningHistogram<P = TlsCounter> {
+    histogram: Mutex<ToyHistogram>,
+    slots: Vec<CachePadded<Slot>>,
+    provider: PhantomData<fn() -> P>,
+}
+
+// Publication slot. Threads whose keys map to the same slot take turns.
+struct Slot {
+    state: AtomicUsize,
+    values: AtomicPtr<f32>,
+    len: AtomicUsize,
+}
+
+// Slot states. A slot is claimed by a filling thread, which publishes its batch
+// (PENDING). A combiner then applies the batch (DONE), after which the filling
+// thread releases the slot (EMPTY).
+const EMPTY: usize = 0;
+const CLAIMED: usize = 1;
+const PENDING: usize = 2;
+const DONE: usize = 3;
+
+impl FlatCombiningHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self::with_provider(num_bins)
+    }
+}
+
+impl<P: ThreadIdProvider> FlatCombiningHistogram<P> {
+    // Select slots using thread IDs from another provider
+    pub fn with_provider(num_bins: usize) -> Self {
+        Self::with_slots(num_bins, num_cpus::get())
+    }
+
+    fn with_slots(num_bins: usize, num_slots: usize) -> Self {
+        Self {
+            histogram: Mutex::new(ToyHistogram::new(num_bins)),
+            slots: (0..num_slots)
+                .map(|_| CachePadded::new(Slot {
+                    state: AtomicUsize::new(EMPTY),
+                    values: AtomicPtr::new(ptr::null_mut()),
+                    len: AtomicUsize::new(0),
+                }))
+                .collect(),
+            provider: PhantomData,
+        }
+    }
+
+    // Apply every published batch, if no other thread is doing so already
+    fn try_combine(&self) {
+        if let Ok(mut histogram) = self.histogram.try_lock() {
+            for slot in &self.slots {
+                if slot.state.load(Ordering::Acquire) == PENDING {
+                    let values = slot.values.load(Ordering::Relaxed);
+                    let len = slot.len.load(Ordering::Relaxed);
+                    // The publishing thread does not return, and thus keeps
+                    // its batch alive, until the slot is marked DONE
+                    histogram.fill_mut(unsafe { slice::from_raw_parts(values, len) });
+                    slot.state.store(DONE, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    // Publish a batch in the slot of a key, and wait for it to be applied
+    fn publish(&self, values: &[f32], key: BucketKey) {
+        let slot = &self.slots[usize::from(key) % self.slots.len()];
+        while slot.state
+                  .compare_exchange(EMPTY, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+                  .is_err()
+        {
+            telemetry::cas_retries(1);
+            self.try_combine();
+            spin_loop();
+        }
+        telemetry::atomic_rmws(1);
+        slot.values.store(values.as_ptr() as *mut f32, Ordering::Relaxed);
+        slot.len.store(values.len(), Ordering::Relaxed);
+        slot.state.store(PENDING, Ordering::Release);
+        while slot.state.load(Ordering::Acquire) != DONE {
+            self.try_combine();
+            spin_loop();
+        }
+        slot.state.store(EMPTY, Ordering::Release);
+    }
+}
+
+impl<P: ThreadIdProvider> SyncHistogram for FlatCombiningHistogram<P> {
+    fn fill(&self, values: &[f32]) {
+        self.publish(values, P::load())
+    }
+
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        self.publish(values, id.into())
+    }
+
+    fn num_hits(&self) -> usize {
+        self.histogram.lock().unwrap().num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.histogram.lock().unwrap().bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.histogram.lock().unwrap().merge_bins_mut(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let histogram_heap = self.histogram.lock().unwrap().memory_usage()
+                             - mem::size_of::<ToyHistogram>();
+        mem::size_of::<Self>()
+            + self.slots.capacity() * mem::size_of::<CachePadded<Slot>>()
+            + histogram_heap
+    }
+}
+
+impl_clone_by_merge!([P: ThreadIdProvider] FlatCombiningHistogram<P> => |this, num_bins| {
+    Self::with_slots(num_bins, this.slots.len())
+});
+
+impl_eq_by_bins!([P: ThreadIdProvider] FlatCombiningHistogram<P>);
+
+
+// Kept small enough to run under Miri, which checks that combiners only access
+// batches which are still alive. Fewer slots than threads exercise the waiting
+// of threads which share a slot.
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // A combiner must apply the batches which other threads published, and
+    // mark them as applied
+    #[test]
+    fn combine_published_batches() {
+        let histogram = FlatCombiningHistogram::<TlsCounter>::with_slots(4, 2);
+        let batches: [[f32; 2]; 2] = [[0.1, 0.3], [0.9, 0.9]];
+        for (slot, batch) in histogram.slots.iter().zip(&batches) {
+            slot.values.store(batch.as_ptr() as *mut f32, Ordering::Relaxed);
+            slot.len.store(batch.len(), Ordering::Relaxed);
+            slot.state.store(PENDING, Ordering::Release);
+        }
+        histogram.try_combine();
+        assert!(histogram.slots.iter().all(|slot| slot.state.load(Ordering::Acquire) == DONE));
+        assert_eq!(SyncHistogram::bins(&histogram), [1, 1, 0, 2]);
+    }
+
+    // Threads which share a slot must wait for their turn
+    #[test]
+    fn shared_slot() {
+        const NUM_THREADS: usize = 4;
+        let histogram = FlatCombiningHistogram::<TlsCounter>::with_slots(4, 1);
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| histogram.fill(&[0.1, 0.3, 0.3, 0.9]));
+            }
+        });
+        assert_eq!(SyncHistogram::bins(&histogram),
+                   [NUM_THREADS, 2 * NUM_THREADS, 0, NUM_THREADS]);
+        assert!(histogram.slots.iter().all(|slot| slot.state.load(Ordering::Relaxed) == EMPTY));
+    }
+}
@@ -0,0 +1,310 @@
+use {
+    crate::{
+        binning::Binner,
+        traits::SyncHistogram,
+    },
+    std::{
+        convert::TryInto,
+        mem,
+        slice,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            OnceLock, RwLock,
+        },
+    },
+    wgpu::util::DeviceExt,
+};
+
+// Histogram whose bins live on a GPU, and are filled by a compute shader
+//
+// Each fill uploads its batch of values to the GPU, and dispatches a compute
+// shader which bins them with one atomic add per value. Fills do not wait for
+// the GPU to be done, so the CPU side only pays for the upload and the
+// submission, while readouts wait for every previous fill to complete before
+// downloading the bins.
+//
+// Binning uses the same arithmetic as the Binner of CPU implementations, so
+// finite values end up in the same bins. As GPUs may not handle NaNs the same
+// way, those may end up in any bin.
+//
+// GPU bins are 32-bit, which is the widest type that WGSL atomics support. So
+// that they cannot wrap around, they are downloaded into wider CPU-side bins
+// and reset before 2^32 values have been binned on the GPU since they were last
+// reset. Merged bins go straight to the CPU-side bins.
+//
+pub struct GpuHistogram {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    fill_pipeline: wgpu::ComputePipeline,
+    bins: wgpu::Buffer,
+    binner: Binner,
+
+    // Hits which were spilled from the GPU bins or merged. Fills hold a read
+    // lock while they dispatch work to the GPU, so that the write lock of a
+    // spill waits for them.
+    spilled: RwLock<Vec<usize>>,
+
+    // Values which were dispatched to the GPU since its bins were last reset,
+    // and how many may be before they must be spilled
+    pending: AtomicUsize,
+    max_pending: usize,
+}
+
+const SHADER: &str = r"
+override scale: f32;
+override last_bin: u32;
+
+@group(0) @binding(0) var<storage, read_write> bins: array<atomic<u32>>;
+@group(0) @binding(1) var<storage, read> input: array<u32>;
+
+// Dispatches are two-dimensional, as there may be more workgroups than fit in
+// one dimension
+fn input_index(id: vec3<u32>, num_groups: vec3<u32>) -> u32 {
+    return id.y * num_groups.x * 64u + id.x;
+}
+
+@compute @workgroup_size(64)
+fn fill(@builtin(global_invocation_id) id: vec3<u32>,
+        @builtin(num_workgroups) num_groups: vec3<u32>) {
+    let i = input_index(id, num_groups);
+    if i < arrayLength(&input) {
+        let value = bitcast<f32>(input[i]);
+        atomicAdd(&bins[min(u32(max(value * scale, 0.0)), last_bin)], 1u);
+    }
+}
+
+";
+
+// Must match the workgroup size of the shader
+const WORKGROUP_SIZE: usize = 64;
+
+// Maximal number of workgroups along one dimension of a dispatch
+const MAX_GROUPS_PER_DIM: usize = 65535;
+
+// Batches are uploaded in chunks which fit in the default storage buffer size
+// limit of 128 MiB
+const MAX_UPLOAD_LEN: usize = (128 << 20) / mem::size_of::<u32>();
+
+impl GpuHistogram {
+    // Largest number of bins which can be uploaded at once
+    pub const MAX_BINS: usize = MAX_UPLOAD_LEN;
+
+    // Set up a histogram on the default GPU. Panics if there is none.
+    pub fn new(num_bins: usize) -> Self {
+        Self::try_new(num_bins).expect("No usable GPU was found")
+    }
+
+    // Truth that histograms can be set up on this machine, which is only
+    // checked once as it takes a while
+    pub fn is_available() -> bool {
+        static AVAILABLE: OnceLock<bool> = OnceLock::new();
+        *AVAILABLE.get_or_init(|| Self::try_new(0).is_some())
+    }
+
+    // Same, but returns None if no GPU is available
+    pub fn try_new(num_bins: usize) -> Option<Self> {
+        assert!(num_bins <= MAX_UPLOAD_LEN, "Too many bins for a GPU histogram");
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::new_without_display_handle_from_env());
+        let adapter = pollster::block_on(instance.request_adapter(&Default::default())).ok()?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&Default::default())).ok()?;
+
+        let binner = Binner::new(num_bins);
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("GpuHistogram"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let constants = [("scale", f64::from(binner.scale())),
+                         ("last_bin", num_bins.saturating_sub(1) as f64)];
+        let fill_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("fill"),
+            layout: None,
+            module: &module,
+            entry_point: Some("fill"),
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants: &constants,
+                ..Default::default()
+            },
+            cache: None,
+        });
+
+        // Zero-sized buffers cannot be bound, so there is always one bin
+        let bins = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("bins"),
+            contents: as_bytes(&vec![0u32; num_bins.max(1)]),
+            usage: wgpu::BufferUsages::STORAGE
+                   | wgpu::BufferUsages::COPY_SRC
+                   | wgpu::BufferUsages::COPY_DST,
+        });
+
+        Some(Self {
+            device,
+            queue,
+            fill_pipeline,
+            bins,
+            binner,
+            spilled: RwLock::new(vec![0; num_bins]),
+            pending: AtomicUsize::new(0),
+            max_pending: u32::MAX as usize,
+        })
+    }
+
+    // Upload values and bin them, without waiting for the GPU to be done
+    fn dispatch(&self, input: &[u32]) {
+        let input = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("input"),
+            contents: as_bytes(input),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.fill_pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: self.bins.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: input.as_entire_binding() },
+            ],
+        });
+        let num_groups = input.size() as usize / mem::size_of::<u32>();
+        let num_groups = num_groups.div_ceil(WORKGROUP_SIZE);
+        let groups_x = num_groups.min(MAX_GROUPS_PER_DIM);
+        let groups_y = num_groups.div_ceil(groups_x);
+
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&self.fill_pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(groups_x as u32, groups_y as u32, 1);
+        }
+        self.queue.submit([encoder.finish()]);
+
+        // Let wgpu reclaim the buffers of fills which the GPU is done with
+        let _ = self.device.poll(wgpu::PollType::Poll);
+    }
+
+    // Wait for every previous fill to complete, and download the GPU bins
+    fn download(&self) -> Vec<usize> {
+        let size = self.bins.size();
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readout"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(&self.bins, 0, &staging, 0, size);
+        self.queue.submit([encoder.finish()]);
+        staging.map_async(wgpu::MapMode::Read, .., |result| result.expect("Failed to read bins"));
+        self.device.poll(wgpu::PollType::wait_indefinitely()).expect("Failed to wait for the GPU");
+        let bytes = staging.get_mapped_range(..).expect("Bins were not mapped");
+        bytes.chunks_exact(mem::size_of::<u32>())
+            .take(self.binner.num_bins())
+            .map(|bin| u32::from_ne_bytes(bin.try_into().unwrap()) as usize)
+            .collect()
+    }
+
+    // Move the hits of the GPU bins into the CPU-side bins, and reset them
+    fn spill(&self) {
+        let mut spilled = self.spilled.write().unwrap();
+        for (dst, src) in spilled.iter_mut().zip(self.download()) {
+            *dst += src;
+        }
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.clear_buffer(&self.bins, 0, None);
+        self.queue.submit([encoder.finish()]);
+        self.pending.store(0, Ordering::Relaxed);
+    }
+}
+
+impl SyncHistogram for GpuHistogram {
+    // Values are uploaded as their bits, so that the shader can reinterpret
+    // them without any conversion on the CPU side
+    fn fill(&self, values: &[f32]) {
+        if values.is_empty() {
+            return;
+        }
+        assert!(self.binner.num_bins() > 0, "Cannot fill a histogram without bins");
+        for chunk in values.chunks(MAX_UPLOAD_LEN) {
+            // Safe because f32 and u32 have the same size and alignment, and
+            // every bit pattern is a valid u32
+            let bits = unsafe { slice::from_raw_parts(chunk.as_ptr().cast::<u32>(), chunk.len()) };
+            loop {
+                let guard = self.spilled.read().unwrap();
+                let pending = self.pending.fetch_add(chunk.len(), Ordering::Relaxed);
+                if pending + chunk.len() <= self.max_pending {
+                    self.dispatch(bits);
+                    break;
+                }
+                // The GPU bins could wrap around, so spill them first
+                self.pending.fetch_sub(chunk.len(), Ordering::Relaxed);
+                drop(guard);
+                self.spill();
+            }
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins().iter().sum::<usize>()
+    }
+
+    // Waits for every previous fill to complete
+    fn bins(&self) -> Vec<usize> {
+        let spilled = self.spilled.read().unwrap();
+        spilled.iter().zip(self.download()).map(|(&cpu, gpu)| cpu + gpu).collect()
+    }
+
+    // Wait for every previous fill to complete on the GPU
+    fn flush(&self) {
+        self.device.poll(wgpu::PollType::wait_indefinitely()).expect("Failed to wait for the GPU");
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        let mut spilled = self.spilled.write().unwrap();
+        assert_eq!(bins.len(), spilled.len(), "Histogram binning mismatch");
+        for (dst, src) in spilled.iter_mut().zip(bins) {
+            *dst += src;
+        }
+    }
+
+    // Bins live in GPU memory, which is counted as well
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.spilled.read().unwrap().capacity() * mem::size_of::<usize>()
+            + self.bins.size() as usize
+    }
+}
+
+fn as_bytes(data: &[u32]) -> &[u8] {
+    // Safe because u32 has no padding, and u8 has no alignment requirement
+    unsafe { slice::from_raw_parts(data.as_ptr().cast(), mem::size_of_val(data)) }
+}
+
+impl_clone_by_merge!([] GpuHistogram);
+
+impl_eq_by_bins!([] GpuHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    // Skipped on machines without a GPU
+    #[test]
+    fn same_bins_as_cpu() {
+        let Some(histogram) = GpuHistogram::try_new(10) else { return };
+        histogram.fill(&[0.05, 0.15, 0.16, 0.99, -1.0, 2.0]);
+        histogram.merge_bins(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 3]);
+        assert_eq!(SyncHistogram::bins(&histogram), [2, 2, 0, 0, 0, 0, 0, 0, 0, 5]);
+        assert_eq!(histogram.num_hits(), 9);
+    }
+
+    // GPU bins must be spilled before they can wrap around
+    #[test]
+    fn spill_bins() {
+        let Some(histogram) = GpuHistogram::try_new(2) else { return };
+        let histogram = GpuHistogram { max_pending: 4, ..histogram };
+        histogram.fill(&[0.1, 0.1, 0.9]);
+        histogram.fill(&[0.1, 0.9, 0.9]);
+        assert_eq!(*histogram.spilled.read().unwrap(), [2, 1]);
+        assert_eq!(SyncHistogram::bins(&histogram), [3, 3]);
+    }
+}
@@ -0,0 +1,213 @@
+use {
+    crate::{
+        binning::Binner,
+        impls::per_thread::PerThread,
+        sync::{AtomicUsize, Ordering},
+        telemetry,
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::OnceLock,
+    },
+};
+
+// Histograms whose bins are allocated in pages, on first touch
+//
+// A histogram with 100M bins takes most of a gigabyte, which must be zeroed
+// before anything is filled. If only a fraction of the bins is ever hit, most
+// of this is wasted. Here, bins are split into pages which are only allocated
+// when one of their bins is first incremented, so that only the pages which
+// get hits are materialized. In exchange, every increment must first check
+// that the page of the bin was allocated.
+
+// Bins of a histogram, allocated page by page
+struct LazyBins {
+    pages: Box<[OnceLock<Box<[AtomicUsize]>>]>,
+}
+
+// Bins per page, sized to fill a page of memory
+const PAGE_LEN: usize = 4096 / mem::size_of::<usize>();
+
+impl LazyBins {
+    fn new(num_bins: usize) -> Self {
+        Self {
+            pages: (0..num_bins.div_ceil(PAGE_LEN)).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    // Counter of a bin, whose page is allocated if needed
+    fn bin(&self, bin: usize) -> &AtomicUsize {
+        let page = self.pages[bin / PAGE_LEN].get_or_init(|| {
+            (0..PAGE_LEN).map(|_| AtomicUsize::new(0)).collect()
+        });
+        &page[bin % PAGE_LEN]
+    }
+
+    // Add the contents of the allocated pages to some bins
+    fn add_to(&self, bins: &mut [usize]) {
+        for (page, dst) in self.pages.iter().zip(bins.chunks_mut(PAGE_LEN)) {
+            if let Some(page) = page.get() {
+                for (dst, src) in dst.iter_mut().zip(page.iter()) {
+                    *dst += src.load(Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.pages.iter()
+            .filter_map(OnceLock::get)
+            .flat_map(|page| page.iter())
+            .map(|bin| bin.load(Ordering::Relaxed))
+            .sum::<usize>()
+    }
+
+    fn heap_usage(&self) -> usize {
+        let num_allocated = self.pages.iter().filter(|page| page.get().is_some()).count();
+        self.pages.len() * mem::size_of::<OnceLock<Box<[AtomicUsize]>>>()
+            + num_allocated * PAGE_LEN * mem::size_of::<AtomicUsize>()
+    }
+}
+
+// Atomic histogram with lazily allocated bins
+pub struct LazyAtomicHistogram {
+    bins: LazyBins,
+    binner: Binner,
+}
+
+impl LazyAtomicHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            bins: LazyBins::new(num_bins),
+            binner: Binner::new(num_bins),
+        }
+    }
+}
+
+impl SyncHistogram for LazyAtomicHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.binner.for_each_index(values, |bin| {
+            self.bins.bin(bin).fetch_add(1, Ordering::Relaxed);
+        });
+        telemetry::atomic_rmws(values.len())
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins.num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = vec![0; self.binner.num_bins()];
+        self.bins.add_to(&mut result);
+        result
+    }
+
+    // Only the pages of nonzero bins are allocated
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        for (bin, &count) in bins.iter().enumerate().filter(|(_, &count)| count > 0) {
+            self.bins.bin(bin).fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + self.bins.heap_usage()
+    }
+}
+
+impl_clone_by_merge!([] LazyAtomicHistogram);
+
+impl_eq_by_bins!([] LazyAtomicHistogram);
+
+// Thread-local histogram with lazily allocated bins, i.e. ThreadLocalHistogram
+// where each thread only allocates the pages that it hits. As there, merged
+// bins are added atomically to bins of their own.
+pub struct LazyThreadLocalHistogram {
+    buckets: PerThread<LazyBins>,
+    merged: LazyBins,
+    binner: Binner,
+}
+
+impl LazyThreadLocalHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            buckets: PerThread::new(),
+            merged: LazyBins::new(num_bins),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Every bucket, including that of merged bins
+    fn all_buckets(&self) -> impl Iterator<Item = &LazyBins> {
+        self.buckets.iter().chain(Some(&self.merged))
+    }
+
+    fn bucket(&self, id: ThreadID) -> &LazyBins {
+        self.buckets.get_or_init(id, || LazyBins::new(self.binner.num_bins()))
+    }
+}
+
+impl SyncHistogram for LazyThreadLocalHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.fill_with_id(values, ThreadID::load())
+    }
+
+    // Only this thread fills its bucket, so atomic load/stores are enough
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        let bucket = self.bucket(id);
+        self.binner.for_each_index(values, |bin| {
+            let bin = bucket.bin(bin);
+            bin.store(bin.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+        })
+    }
+
+    fn num_hits(&self) -> usize {
+        self.all_buckets().map(LazyBins::num_hits).sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = vec![0; self.binner.num_bins()];
+        for bucket in self.all_buckets() {
+            bucket.add_to(&mut result);
+        }
+        result
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        for (bin, &count) in bins.iter().enumerate().filter(|(_, &count)| count > 0) {
+            self.merged.bin(bin).fetch_add(count, Ordering::Relaxed);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.buckets.heap_usage()
+            + self.merged.heap_usage()
+            + self.buckets.iter()
+                .map(|bucket| mem::size_of::<LazyBins>() + bucket.heap_usage())
+                .sum::<usize>()
+    }
+}
+
+impl_clone_by_merge!([] LazyThreadLocalHistogram);
+
+impl_eq_by_bins!([] LazyThreadLocalHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_touched_pages() {
+        let histogram = LazyAtomicHistogram::new(100 * PAGE_LEN);
+        let empty_usage = histogram.memory_usage();
+        histogram.fill(&[0.001, 0.002, 0.999]);
+        assert_eq!(histogram.memory_usage() - empty_usage,
+                   2 * PAGE_LEN * mem::size_of::<AtomicUsize>());
+        assert_eq!(histogram.num_hits(), 3);
+    }
+}
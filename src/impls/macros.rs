@@ -0,0 +1,49 @@
+// Trait implementations which most thread-safe histograms share
+//
+// Their bins are usually spread across buckets, shards or staging areas which
+// cannot be copied as they are, so they are cloned by building an empty
+// histogram with the same settings and merging a snapshot of the original's
+// bins into it, and compared by reading out their bins.
+
+// Implement Clone for a thread-safe histogram, which may have generic
+// parameters listed in brackets. The empty clone is built by the constructor
+// expression from the original histogram and its number of bins, or by new()
+// if there is none.
+// Unused if no thread-safe histogram is enabled
+#[allow(unused_macros)]
+macro_rules! impl_clone_by_merge {
+    ([$($generics:tt)*] $histogram:ty) => {
+        impl_clone_by_merge!([$($generics)*] $histogram => |this, num_bins| Self::new(num_bins));
+    };
+    ([$($generics:tt)*] $histogram:ty => |$this:ident, $num_bins:ident| $new:expr) => {
+        impl<$($generics)*> Clone for $histogram {
+            fn clone(&self) -> Self {
+                let bins = $crate::traits::SyncHistogram::snapshot(self).bins;
+                let clone: Self = {
+                    #[allow(unused_variables)]
+                    let ($this, $num_bins) = (self, bins.len());
+                    $new
+                };
+                $crate::traits::SyncHistogram::merge_bins(&clone, &bins);
+                clone
+            }
+        }
+    };
+}
+
+// Implement PartialEq and Eq for a thread-safe histogram, which may have
+// generic parameters listed in brackets. Histograms are equal if their bins
+// are, however these are laid out.
+#[allow(unused_macros)]
+macro_rules! impl_eq_by_bins {
+    ([$($generics:tt)*] $histogram:ty) => {
+        impl<$($generics)*> PartialEq for $histogram {
+            fn eq(&self, other: &Self) -> bool {
+                $crate::traits::SyncHistogram::bins(self)
+                    == $crate::traits::SyncHistogram::bins(other)
+            }
+        }
+
+        impl<$($generics)*> Eq for $histogram {}
+    };
+}
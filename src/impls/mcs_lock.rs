@@ -0,0 +1,168 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        sync::{spin_loop, AtomicBool, AtomicPtr, Ordering, UnsafeCell},
+        telemetry,
+        traits::{Histogram, SyncHistogram},
+    },
+    alloc::vec::Vec,
+    core::{mem, ptr},
+};
+
+// MCS queue lock
+//
+// Waiting threads form a linked list of nodes, each of which lives on the
+// stack of its thread. A thread which finds the lock taken appends its node to
+// the queue, then spins on a flag of its own node until its predecessor hands
+// the lock over. Whereas with centralized spinlocks every waiting thread
+// hammers the same cache line, here each release only touches the cache line
+// of the next thread in line, so the lock should scale better to many threads.
+// Like the ticket lock, it is fair.
+//
+pub struct McsLock<T> {
+    tail: AtomicPtr<McsNode>,
+    data: UnsafeCell<T>,
+}
+
+// Entry of the queue of threads waiting for the lock
+struct McsNode {
+    locked: AtomicBool,
+    next: AtomicPtr<McsNode>,
+}
+
+impl<T> McsLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Run a closure with exclusive access to the protected data
+    pub fn with_locked<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        // The node must not move until the lock is released, as other threads
+        // access it through the queue
+        let node = McsNode {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        };
+        let node_ptr = &node as *const McsNode as *mut McsNode;
+        telemetry::lock(|| {
+            let prev = self.tail.swap(node_ptr, Ordering::AcqRel);
+            if !prev.is_null() {
+                // The predecessor cannot release the lock, and thus free its
+                // node, until it has seen our node in its next pointer
+                unsafe { (*prev).next.store(node_ptr, Ordering::Release) };
+                while node.locked.load(Ordering::Acquire) {
+                    spin_loop();
+                }
+            }
+        });
+        let _guard = McsRelease { tail: &self.tail, node: &node };
+        self.data.with_mut(|data_ptr| f(unsafe { &mut *data_ptr }))
+    }
+}
+
+// Hands the lock over to the next thread in line on scope exit, so that a
+// panicking closure does not leave the lock held forever
+struct McsRelease<'a> {
+    tail: &'a AtomicPtr<McsNode>,
+    node: &'a McsNode,
+}
+
+impl Drop for McsRelease<'_> {
+    fn drop(&mut self) {
+        let node_ptr = self.node as *const McsNode as *mut McsNode;
+        let mut next = self.node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // No visible successor: free the lock, unless a thread is in the
+            // process of enqueuing itself, in which case we wait for it
+            if self.tail
+                   .compare_exchange(node_ptr, ptr::null_mut(), Ordering::Release, Ordering::Relaxed)
+                   .is_ok()
+            {
+                return;
+            }
+            loop {
+                next = self.node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                spin_loop();
+            }
+        }
+        // The successor's node stays alive until we set this flag
+        unsafe { (*next).locked.store(false, Ordering::Release) };
+    }
+}
+
+// The queue guarantees that only one thread accesses the data at a time
+unsafe impl<T: Send> Send for McsLock<T> {}
+unsafe impl<T: Send> Sync for McsLock<T> {}
+
+impl SyncHistogram for McsLock<ToyHistogram> {
+    fn fill(&self, values: &[f32]) {
+        self.with_locked(|histogram| histogram.fill_mut(values))
+    }
+
+    fn num_hits(&self) -> usize {
+        self.with_locked(|histogram| histogram.num_hits())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.with_locked(|histogram| histogram.bins())
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.with_locked(|histogram| histogram.merge_bins_mut(bins))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.with_locked(|histogram| histogram.memory_usage());
+        inner - mem::size_of::<ToyHistogram>() + mem::size_of::<Self>()
+    }
+}
+
+// The protected data is cloned under the lock
+impl<T: Clone> Clone for McsLock<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.with_locked(|data| data.clone()))
+    }
+}
+
+impl_eq_by_bins!([] McsLock<ToyHistogram>);
+
+
+// Kept small enough to run under Miri, which checks the accesses to the nodes
+// of other threads
+#[cfg(all(test, feature = "std", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Waiting threads must get the lock in the order in which they enqueued
+    // themselves, and the queue must be empty once they are done
+    #[test]
+    fn hand_over_in_order() {
+        const NUM_THREADS: usize = 4;
+        let lock = McsLock::new(Vec::new());
+        thread::scope(|s| {
+            lock.with_locked(|_| {
+                let mut tail = lock.tail.load(Ordering::Relaxed);
+                for i in 0..NUM_THREADS {
+                    let lock = &lock;
+                    s.spawn(move || lock.with_locked(|order| order.push(i)));
+                    // Wait for the thread to append its node to the queue
+                    while lock.tail.load(Ordering::Relaxed) == tail {
+                        thread::yield_now();
+                    }
+                    tail = lock.tail.load(Ordering::Relaxed);
+                }
+            });
+        });
+        assert_eq!(lock.with_locked(|order| order.clone()), (0..NUM_THREADS).collect::<Vec<_>>());
+        assert!(lock.tail.load(Ordering::Relaxed).is_null());
+    }
+}
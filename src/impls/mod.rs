@@ -1,55 +1,566 @@
+// Defines the trait implementations which the following modules share
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "adaptive")]
+mod adaptive;
+#[cfg(feature = "aggregated")]
+mod aggregated;
+#[cfg(feature = "atomic")]
 mod atomic;
+#[cfg(feature = "bin_sharded")]
+mod bin_sharded;
+#[cfg(feature = "buffered")]
+mod buffered;
+mod builder;
+#[cfg(feature = "channel")]
+mod channel;
+#[cfg(feature = "count_min")]
+mod count_min;
+#[cfg(feature = "double_buffer")]
+mod double_buffer;
+#[cfg(feature = "epoch")]
+mod epoch;
+#[cfg(feature = "exponential")]
+mod exponential;
+mod extend;
+#[cfg(feature = "flat_combining")]
+mod flat_combining;
+#[cfg(feature = "gpu")]
+mod gpu;
+#[cfg(feature = "lazy")]
+mod lazy;
+#[cfg(feature = "mcs_lock")]
+mod mcs_lock;
+#[cfg(feature = "narrow_atomic")]
+mod narrow_atomic;
+#[cfg(feature = "padded_atomic")]
+mod padded_atomic;
+#[cfg(feature = "parking_lot")]
+mod parking_lot_locks;
+#[cfg(feature = "per_core")]
+mod per_core;
+#[cfg(any(feature = "buffered", feature = "lazy", feature = "sparse",
+          feature = "thread_bucketized", feature = "thread_local", feature = "two_level"))]
+mod per_thread;
+#[cfg(any(feature = "thread_bucketized", feature = "thread_local"))]
+mod reduce;
+#[cfg(feature = "ring_buffer")]
+mod ring_buffer;
+#[cfg(feature = "rseq")]
+mod rseq;
+#[cfg(feature = "seqlock")]
+mod seqlock;
+#[cfg(feature = "sorted")]
+mod sorted;
+#[cfg(feature = "sparse")]
+mod sparse;
+#[cfg(feature = "spinlock")]
+mod spinlock;
+#[cfg(feature = "thread_bucketized")]
 mod thread_bucketized;
+#[cfg(feature = "thread_local")]
 mod thread_local;
+#[cfg(feature = "two_level")]
+mod two_level;
+#[cfg(feature = "ticket_lock")]
+mod ticket_lock;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tsx")]
+mod tsx;
 
 use {
-    crate::traits::{Histogram, SyncHistogram},
-    std::sync::Mutex,
+    crate::{
+        binning::Binner,
+        render,
+        traits::Histogram,
+    },
+    alloc::{string::String, vec::Vec},
+    core::{
+        fmt, mem,
+        ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign},
+    },
 };
+#[cfg(any(feature = "mutex", feature = "rwlock"))]
+use crate::traits::SyncHistogram;
+#[cfg(any(feature = "mutex", feature = "rwlock"))]
+use crate::telemetry;
+#[cfg(feature = "mutex")]
+use std::sync::Mutex;
+#[cfg(feature = "rwlock")]
+use std::sync::RwLock;
 
-pub use atomic::AtomicHistogram;
-pub use thread_bucketized::ThreadBucketizedHistogram;
+// Allocators which the bins of ToyHistogram and AtomicHistogram can be
+// allocated with, until the standard allocator API is stabilized
+pub use allocator_api2::alloc::{Allocator, Global};
+#[cfg(feature = "adaptive")]
+pub use adaptive::AdaptiveHistogram;
+#[cfg(feature = "aggregated")]
+pub use aggregated::AggregatedHistogram;
+#[cfg(feature = "atomic")]
+pub use atomic::{AcqRelOrder, AtomicHistogram, MemoryOrder, RelaxedOrder, SeqCstOrder};
+#[cfg(feature = "bin_sharded")]
+pub use bin_sharded::BinShardedHistogram;
+#[cfg(feature = "buffered")]
+pub use buffered::BufferedHistogram;
+pub use builder::{Build, BuildError, HistogramBuilder};
+#[cfg(feature = "channel")]
+pub use channel::ChannelHistogram;
+#[cfg(feature = "count_min")]
+pub use count_min::CountMinHistogram;
+#[cfg(feature = "double_buffer")]
+pub use double_buffer::DoubleBufferedHistogram;
+#[cfg(feature = "epoch")]
+pub use epoch::EpochHistogram;
+#[cfg(feature = "exponential")]
+pub use exponential::ExponentialHistogram;
+#[cfg(feature = "flat_combining")]
+pub use flat_combining::FlatCombiningHistogram;
+#[cfg(feature = "gpu")]
+pub use gpu::GpuHistogram;
+#[cfg(feature = "lazy")]
+pub use lazy::{LazyAtomicHistogram, LazyThreadLocalHistogram};
+#[cfg(feature = "mcs_lock")]
+pub use mcs_lock::McsLock;
+#[cfg(feature = "narrow_atomic")]
+pub use narrow_atomic::{
+    AtomicU16Histogram,
+    AtomicU32Histogram,
+    NarrowAtomicHistogram,
+    NarrowCounter,
+};
+#[cfg(feature = "padded_atomic")]
+pub use padded_atomic::PaddedAtomicHistogram;
+#[cfg(feature = "per_core")]
+pub use per_core::PerCoreHistogram;
+#[cfg(feature = "ring_buffer")]
+pub use ring_buffer::RingBufferHistogram;
+#[cfg(feature = "rseq")]
+pub use rseq::RseqHistogram;
+#[cfg(feature = "seqlock")]
+pub use seqlock::SeqlockHistogram;
+#[cfg(feature = "sorted")]
+pub use sorted::SortedAtomicHistogram;
+#[cfg(feature = "sparse")]
+pub use sparse::{DashMapHistogram, SparseThreadLocalHistogram};
+#[cfg(feature = "spinlock")]
+pub use spinlock::SpinLock;
+#[cfg(feature = "thread_bucketized")]
+pub use thread_bucketized::{
+    BucketAssign, BucketLock, CpuAssign, HashAssign, ModuloAssign, NodeAssign, RoundRobinAssign,
+    ThreadBucketizedHistogram,
+};
+#[cfg(feature = "thread_local")]
 pub use thread_local::ThreadLocalHistogram;
+#[cfg(feature = "two_level")]
+pub use two_level::TwoLevelHistogram;
+#[cfg(feature = "ticket_lock")]
+pub use ticket_lock::TicketLock;
+#[cfg(feature = "tls")]
+pub use tls::TlsHistogram;
+#[cfg(feature = "tsx")]
+pub use tsx::TsxHistogram;
+
+// Bucketized histogram whose buckets are protected by parking_lot mutexes
+#[cfg(all(feature = "parking_lot", feature = "thread_bucketized"))]
+pub type ParkingLotBucketizedHistogram = ThreadBucketizedHistogram<parking_lot::Mutex<ToyHistogram>>;
+
+// Bucketized histogram whose buckets are protected by spinlocks
+#[cfg(all(feature = "spinlock", feature = "thread_bucketized"))]
+pub type SpinBucketizedHistogram = ThreadBucketizedHistogram<SpinLock<ToyHistogram>>;
 
 
 // Toy histogram that's good enough for performance studies
 // One dimensional, every input has same weight, bin absciss in [0, 1[ range.
+// Values outside of this range are clamped to the first or last bin.
 //
 // Every other implementation will attempt to provide similar behaviour in a
 // multi-threaded filling environment.
 //
-pub struct ToyHistogram {
-    bins: Vec<usize>,
+// Bins are allocated with the global allocator by default, but can be put in
+// arenas, pinned or shared memory, etc, by providing another allocator.
+//
+pub struct ToyHistogram<A: Allocator = Global> {
+    pub(crate) bins: allocator_api2::vec::Vec<usize, A>,
+    binner: Binner,
 }
 
 impl ToyHistogram {
     pub fn new(num_bins: usize) -> Self {
+        Self::new_in(num_bins, Global)
+    }
+
+    // Histogram with some initial bin contents
+    pub fn from_bins(bins: Vec<usize>) -> Self {
         Self {
-            bins: vec![0; num_bins],
+            binner: Binner::new(bins.len()),
+            bins: bins.into_iter().collect(),
         }
     }
 }
 
-impl Histogram for ToyHistogram {
-    fn fill_mut(&mut self, values: &[f32]) {
-        for value in values {
-            let bin = (value * (self.bins.len() as f32)) as usize;
-            self.bins[bin] += 1;
+impl<A: Allocator> ToyHistogram<A> {
+    pub fn new_in(num_bins: usize, alloc: A) -> Self {
+        let mut bins = allocator_api2::vec::Vec::with_capacity_in(num_bins, alloc);
+        bins.resize(num_bins, 0);
+        Self {
+            bins,
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Bar chart of the bins, with bars up to `width` characters long, which
+    // are proportional to the logarithm of the bin contents if `log_scale` is
+    // set. This is also what Display prints, with the width of the format
+    // string, and with a logarithmic scale in alternate mode ("{:#}").
+    pub fn render_ascii(&self, width: usize, log_scale: bool) -> String {
+        let mut chart = String::new();
+        render::write_chart(&mut chart, &self.bins, width, log_scale)
+            .expect("Writing to a String cannot fail");
+        chart
+    }
+
+    // Coarser histogram, where each bin is the sum of `factor` adjacent bins of
+    // this one, e.g. to plot a snapshot with fewer bins than were filled
+    pub fn rebin(&self, factor: usize) -> Result<ToyHistogram, RebinError> {
+        if factor == 0 || !self.bins.len().is_multiple_of(factor) {
+            return Err(RebinError { num_bins: self.bins.len(), factor });
+        }
+        Ok(ToyHistogram::from_bins(
+            self.bins.chunks_exact(factor).map(|bins| bins.iter().sum()).collect()
+        ))
+    }
+
+    // Value below which a fraction `q` of the hits lies, or None if the
+    // histogram is empty. Hits are assumed to be spread uniformly within each
+    // bin, so that the result is interpolated between bin edges.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        assert!((0.0..=1.0).contains(&q), "Quantiles must be in [0, 1]");
+        let num_hits = self.bins.iter().sum::<usize>();
+        let target = q * num_hits as f64;
+        let mut below = 0;
+        for (index, &count) in self.bins.iter().enumerate() {
+            if count > 0 && (below + count) as f64 >= target {
+                let fraction = (target - below as f64) / count as f64;
+                return Some((index as f64 + fraction) / self.bins.len() as f64);
+            }
+            below += count;
         }
+        None
+    }
+
+    // Fraction of the hits which lie below the upper edge of each bin, or
+    // nothing if the histogram is empty
+    pub fn cdf(&self) -> Vec<f64> {
+        let num_hits = self.bins.iter().sum::<usize>();
+        if num_hits == 0 {
+            return Vec::new();
+        }
+        self.bins.iter()
+            .scan(0, |below, &count| {
+                *below += count;
+                Some(*below as f64 / num_hits as f64)
+            })
+            .collect()
+    }
+}
+
+// Rebinning factor which does not divide the number of bins
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RebinError {
+    pub num_bins: usize,
+    pub factor: usize,
+}
+
+impl fmt::Display for RebinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot merge {} bins into groups of {}", self.num_bins, self.factor)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RebinError {}
+
+impl<A: Allocator> Histogram for ToyHistogram<A> {
+    fn fill_mut(&mut self, values: &[f32]) {
+        self.binner.for_each_bin_mut(&mut self.bins, values, |bin| *bin += 1)
+    }
+
+    // Bins are incremented as values come, without batching
+    fn fill_iter_mut(&mut self, values: impl IntoIterator<Item = f32>) {
+        let bins = &mut self.bins;
+        self.binner.for_each_index_iter(values, |bin| bins[bin] += 1)
+    }
+
+    fn fill_one_mut(&mut self, value: f32) {
+        self.bins[self.binner.bin_index(value)] += 1
     }
 
     fn num_hits(&self) -> usize {
         self.bins.iter().sum::<usize>()
     }
+
+    fn bins(&self) -> Vec<usize> {
+        self.bins.to_vec()
+    }
+
+    fn merge_bins_mut(&mut self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.bins.len(), "Histogram binning mismatch");
+        for (dst, src) in self.bins.iter_mut().zip(bins) {
+            *dst += src;
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + self.bins.capacity() * mem::size_of::<usize>()
+    }
+}
+
+impl<A: Allocator + Clone> Clone for ToyHistogram<A> {
+    fn clone(&self) -> Self {
+        Self {
+            bins: self.bins.clone(),
+            binner: self.binner,
+        }
+    }
+}
+
+// Histograms are equal if their bins are, wherever these are allocated
+impl<A: Allocator, B: Allocator> PartialEq<ToyHistogram<B>> for ToyHistogram<A> {
+    fn eq(&self, other: &ToyHistogram<B>) -> bool {
+        self.bins[..] == other.bins[..]
+    }
+}
+
+impl<A: Allocator> Eq for ToyHistogram<A> {}
+
+// Arithmetic on the bins of histograms, e.g. to compare the bins filled by
+// different strategies after a benchmark. Adding histograms merges their bins,
+// subtracting them clamps bins which would become negative to zero, and scalar
+// division rounds down. Operands must have the same binning.
+impl<A: Allocator, B: Allocator> AddAssign<&ToyHistogram<B>> for ToyHistogram<A> {
+    fn add_assign(&mut self, other: &ToyHistogram<B>) {
+        self.merge_bins_mut(&other.bins)
+    }
+}
+
+impl<A: Allocator, B: Allocator> AddAssign<ToyHistogram<B>> for ToyHistogram<A> {
+    fn add_assign(&mut self, other: ToyHistogram<B>) {
+        *self += &other
+    }
+}
+
+impl<A: Allocator, B: Allocator> Add<&ToyHistogram<B>> for ToyHistogram<A> {
+    type Output = Self;
+
+    fn add(mut self, other: &ToyHistogram<B>) -> Self {
+        self += other;
+        self
+    }
+}
+
+impl<A: Allocator, B: Allocator> Add<ToyHistogram<B>> for ToyHistogram<A> {
+    type Output = Self;
+
+    fn add(self, other: ToyHistogram<B>) -> Self {
+        self + &other
+    }
+}
+
+impl<A: Allocator, B: Allocator> SubAssign<&ToyHistogram<B>> for ToyHistogram<A> {
+    fn sub_assign(&mut self, other: &ToyHistogram<B>) {
+        assert_eq!(other.bins.len(), self.bins.len(), "Histogram binning mismatch");
+        for (dst, src) in self.bins.iter_mut().zip(other.bins.iter()) {
+            *dst = dst.saturating_sub(*src);
+        }
+    }
+}
+
+impl<A: Allocator, B: Allocator> SubAssign<ToyHistogram<B>> for ToyHistogram<A> {
+    fn sub_assign(&mut self, other: ToyHistogram<B>) {
+        *self -= &other
+    }
+}
+
+impl<A: Allocator, B: Allocator> Sub<&ToyHistogram<B>> for ToyHistogram<A> {
+    type Output = Self;
+
+    fn sub(mut self, other: &ToyHistogram<B>) -> Self {
+        self -= other;
+        self
+    }
+}
+
+impl<A: Allocator, B: Allocator> Sub<ToyHistogram<B>> for ToyHistogram<A> {
+    type Output = Self;
+
+    fn sub(self, other: ToyHistogram<B>) -> Self {
+        self - &other
+    }
+}
+
+impl<A: Allocator> MulAssign<usize> for ToyHistogram<A> {
+    fn mul_assign(&mut self, factor: usize) {
+        for bin in self.bins.iter_mut() {
+            *bin *= factor;
+        }
+    }
+}
+
+impl<A: Allocator> Mul<usize> for ToyHistogram<A> {
+    type Output = Self;
+
+    fn mul(mut self, factor: usize) -> Self {
+        self *= factor;
+        self
+    }
+}
+
+impl<A: Allocator> DivAssign<usize> for ToyHistogram<A> {
+    fn div_assign(&mut self, divisor: usize) {
+        for bin in self.bins.iter_mut() {
+            *bin /= divisor;
+        }
+    }
+}
+
+impl<A: Allocator> Div<usize> for ToyHistogram<A> {
+    type Output = Self;
+
+    fn div(mut self, divisor: usize) -> Self {
+        self /= divisor;
+        self
+    }
+}
+
+impl<A: Allocator> fmt::Display for ToyHistogram<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        render::write_chart(f, &self.bins, f.width().unwrap_or(render::DEFAULT_WIDTH), f.alternate())
+    }
 }
 
 // A basic thread-safe implementation may be built via locking
-impl SyncHistogram for Mutex<ToyHistogram> {
+#[cfg(feature = "mutex")]
+impl<A: Allocator + Send> SyncHistogram for Mutex<ToyHistogram<A>> {
     fn fill(&self, values: &[f32]) {
-        self.lock().unwrap().fill_mut(values)
+        telemetry::lock(|| self.lock().unwrap()).fill_mut(values)
     }
 
     fn num_hits(&self) -> usize {
         self.lock().unwrap().num_hits()
     }
-}
\ No newline at end of file
+
+    fn bins(&self) -> Vec<usize> {
+        self.lock().unwrap().bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.lock().unwrap().merge_bins_mut(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.lock().unwrap().memory_usage();
+        inner - mem::size_of::<ToyHistogram<A>>() + mem::size_of::<Self>()
+    }
+}
+
+// Same as above with a reader-writer lock: fills take the write lock, and
+// readouts only take the read lock, so that concurrent readers do not block
+// each other. As fills are exclusive, this can only be slower than a mutex for
+// write-dominated workloads, and the question is by how much.
+#[cfg(feature = "rwlock")]
+impl<A: Allocator + Send + Sync> SyncHistogram for RwLock<ToyHistogram<A>> {
+    fn fill(&self, values: &[f32]) {
+        telemetry::lock(|| self.write().unwrap()).fill_mut(values)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.read().unwrap().num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.read().unwrap().bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.write().unwrap().merge_bins_mut(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.read().unwrap().memory_usage();
+        inner - mem::size_of::<ToyHistogram<A>>() + mem::size_of::<Self>()
+    }
+}
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        alloc::vec,
+        allocator_api2::alloc::{AllocError, Layout},
+        core::{cell::Cell, ptr::NonNull},
+    };
+
+    // Global allocator which keeps track of how much it allocated
+    #[derive(Default)]
+    struct CountingAlloc {
+        allocated: Cell<usize>,
+    }
+
+    unsafe impl Allocator for CountingAlloc {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocated.set(self.allocated.get() + layout.size());
+            Global.allocate(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    #[test]
+    fn bins_in_custom_allocator() {
+        let alloc = CountingAlloc::default();
+        let mut histogram = ToyHistogram::new_in(4, &alloc);
+        histogram.fill_mut(&[0.1, 0.6, 0.7]);
+        assert_eq!(histogram.bins(), [1, 0, 2, 0]);
+        assert_eq!(alloc.allocated.get(), 4 * mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn display_as_chart() {
+        let histogram = ToyHistogram::from_bins(vec![1, 3]);
+        assert_eq!(alloc::format!("{:3}", histogram), "0.000 |#   1\n0.500 |### 3\n");
+        assert_eq!(alloc::format!("{:#3}", histogram), histogram.render_ascii(3, true));
+    }
+
+    #[test]
+    fn bin_arithmetic() {
+        let signal = ToyHistogram::from_bins(vec![5, 9, 2]);
+        let background = ToyHistogram::from_bins(vec![1, 1, 3]);
+        assert_eq!((signal.clone() - &background).bins(), [4, 8, 0]);
+        assert_eq!((signal.clone() + background).bins(), [6, 10, 5]);
+        assert_eq!((signal * 3 / 2).bins(), [7, 13, 3]);
+    }
+
+    #[test]
+    fn merge_adjacent_bins() {
+        let histogram = ToyHistogram::from_bins(vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(histogram.rebin(3).unwrap().bins(), [6, 15]);
+        assert_eq!(histogram.rebin(4).err(), Some(RebinError { num_bins: 6, factor: 4 }));
+        assert!(histogram.rebin(0).is_err());
+    }
+
+    #[test]
+    fn interpolate_quantiles() {
+        let histogram = ToyHistogram::from_bins(vec![0, 2, 0, 6]);
+        assert_eq!(histogram.cdf(), [0.0, 0.25, 0.25, 1.0]);
+        assert_eq!(histogram.quantile(0.0), Some(0.25));
+        assert_eq!(histogram.quantile(0.125), Some(0.375));
+        assert_eq!(histogram.quantile(0.625), Some(0.875));
+        assert_eq!(histogram.quantile(1.0), Some(1.0));
+        assert_eq!(ToyHistogram::new(4).quantile(0.5), None);
+    }
+}
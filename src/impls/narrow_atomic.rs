@@ -0,0 +1,203 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{AtomicU16, AtomicU32, AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    alloc::vec::Vec,
+    core::mem,
+};
+
+// Variant of AtomicHistogram whose bins are narrower atomic counters
+//
+// With 16-bit or 32-bit bins, a histogram takes 4 or 2 times less cache than
+// with 64-bit ones, which matters when there are so many bins that they do not
+// fit in cache anymore. Narrow counters would overflow quickly, though, so once
+// a counter reaches half of its range, the thread which brought it there moves
+// that half into a full-width spill counter. The other half is headroom for the
+// increments of other threads in the meantime.
+//
+// That headroom can run out if the spilling thread is preempted before it moves
+// the counts, so counters are incremented with compare-and-swap loops which
+// never go past their maximum, rather than with fetch_add, which would wrap
+// around and lose a whole range of counts. Increments of a full counter go to
+// the spill counter instead.
+//
+// Spill counters are touched at most once every 32768 increments of a bin, so
+// they stay out of the cache in the common case.
+//
+pub struct NarrowAtomicHistogram<C: NarrowCounter> {
+    bins: Vec<C>,
+    spill: Vec<AtomicUsize>,
+    binner: Binner,
+}
+
+// Atomic counter which bins of a NarrowAtomicHistogram can be made of
+pub trait NarrowCounter: Sync {
+    // Counts are moved to the spill counter once they reach this value
+    const SPILL_AT: usize;
+
+    fn new() -> Self;
+
+    // Increment the counter unless it holds its maximum value, and return its
+    // previous value along with the number of failed compare-and-swaps
+    fn increment(&self) -> (Option<usize>, usize);
+
+    // Remove counts which were moved to the spill counter
+    fn subtract(&self, count: usize);
+
+    fn load(&self) -> usize;
+}
+
+macro_rules! impl_narrow_counter {
+    ($atomic:ty, $int:ty) => {
+        impl NarrowCounter for $atomic {
+            const SPILL_AT: usize = 1 << (<$int>::BITS - 1);
+
+            fn new() -> Self {
+                <$atomic>::new(0)
+            }
+
+            fn increment(&self) -> (Option<usize>, usize) {
+                let mut current = <$atomic>::load(self, Ordering::Relaxed);
+                let mut conflicts = 0;
+                while current != <$int>::MAX {
+                    match self.compare_exchange_weak(current, current + 1,
+                                                     Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => return (Some(current as usize), conflicts),
+                        Err(actual) => {
+                            conflicts += 1;
+                            current = actual;
+                        }
+                    }
+                }
+                (None, conflicts)
+            }
+
+            fn subtract(&self, count: usize) {
+                self.fetch_sub(count as $int, Ordering::Relaxed);
+            }
+
+            fn load(&self) -> usize {
+                <$atomic>::load(self, Ordering::Relaxed) as usize
+            }
+        }
+    };
+}
+impl_narrow_counter!(AtomicU16, u16);
+impl_narrow_counter!(AtomicU32, u32);
+
+impl<C: NarrowCounter> NarrowAtomicHistogram<C> {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            bins: (0..num_bins).map(|_| C::new()).collect(),
+            spill: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            binner: Binner::new(num_bins),
+        }
+    }
+}
+
+impl<C: NarrowCounter> SyncHistogram for NarrowAtomicHistogram<C> {
+    fn fill(&self, values: &[f32]) {
+        let mut spill_rmws = 0;
+        let mut conflicts = 0;
+        self.binner.for_each_index(values, |bin| {
+            let (previous, bin_conflicts) = self.bins[bin].increment();
+            conflicts += bin_conflicts;
+            match previous {
+                Some(count) if count == C::SPILL_AT - 1 => {
+                    // Subtract first, so that concurrent readouts may miss these
+                    // counts for a while, but never count them twice. The
+                    // counter cannot have been spilled by another thread since
+                    // it went past SPILL_AT, so this does not wrap around.
+                    self.bins[bin].subtract(C::SPILL_AT);
+                    self.spill[bin].fetch_add(C::SPILL_AT, Ordering::Relaxed);
+                    spill_rmws += 2;
+                }
+                Some(_) => {}
+                // The thread which should spill this counter has not done so yet
+                None => {
+                    self.spill[bin].fetch_add(1, Ordering::Relaxed);
+                    spill_rmws += 1;
+                }
+            }
+        });
+        telemetry::atomic_rmws(values.len() + spill_rmws);
+        telemetry::cas_retries(conflicts)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins().iter().sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.bins.iter()
+            .zip(&self.spill)
+            .map(|(bin, spill)| bin.load() + spill.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    // Merged counts could overflow the narrow counters, so they go straight to
+    // the spill counters
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.spill.len(), "Histogram binning mismatch");
+        for (dst, &src) in self.spill.iter().zip(bins) {
+            dst.fetch_add(src, Ordering::Relaxed);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.bins.capacity() * mem::size_of::<C>()
+            + self.spill.capacity() * mem::size_of::<AtomicUsize>()
+    }
+}
+
+// Histograms with 16-bit and 32-bit bins
+pub type AtomicU16Histogram = NarrowAtomicHistogram<AtomicU16>;
+pub type AtomicU32Histogram = NarrowAtomicHistogram<AtomicU32>;
+
+impl_clone_by_merge!([C: NarrowCounter] NarrowAtomicHistogram<C>);
+
+impl_eq_by_bins!([C: NarrowCounter] NarrowAtomicHistogram<C>);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        alloc::vec,
+    };
+
+    #[test]
+    fn spill_on_overflow() {
+        let histogram = AtomicU16Histogram::new(2);
+        let values = vec![0.25; 1 << 16];
+        histogram.fill(&values);
+        histogram.fill(&values);
+        histogram.fill(&[0.75]);
+        assert_eq!(SyncHistogram::bins(&histogram), [1 << 17, 1]);
+        assert!(NarrowCounter::load(&histogram.bins[0]) < AtomicU16::SPILL_AT);
+    }
+
+    // Increments of a counter which is stuck at its maximum, as when the
+    // thread which should spill it is preempted, go to the spill counter
+    // instead of wrapping the counter around
+    #[test]
+    fn full_counter() {
+        let histogram = AtomicU16Histogram::new(1);
+        histogram.bins[0].store(u16::MAX, Ordering::Relaxed);
+        assert_eq!(histogram.bins[0].increment(), (None, 0));
+        histogram.fill(&[0.5, 0.5, 0.5]);
+        assert_eq!(histogram.bins[0].load(Ordering::Relaxed), u16::MAX);
+        assert_eq!(SyncHistogram::bins(&histogram), [u16::MAX as usize + 3]);
+
+        // Once the late spill is done, the counter is spilled again as usual
+        histogram.bins[0].subtract(AtomicU16::SPILL_AT);
+        histogram.spill[0].fetch_add(AtomicU16::SPILL_AT, Ordering::Relaxed);
+        histogram.fill(&[0.5]);
+        assert_eq!(SyncHistogram::bins(&histogram), [u16::MAX as usize + 4]);
+        assert_eq!(NarrowCounter::load(&histogram.bins[0]), 0);
+    }
+}
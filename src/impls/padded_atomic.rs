@@ -0,0 +1,99 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    alloc::vec::Vec,
+    core::mem,
+    crossbeam_utils::CachePadded,
+};
+
+// Variant of AtomicHistogram where bins are grouped, and each group of bins
+// sits on its own cache line
+//
+// Threads which increment bins from different groups do not share cache lines
+// anymore, so with one bin per group, this only suffers from true contention,
+// at the cost of using a lot more memory and cache per bin. Comparing it with
+// AtomicHistogram tells how much of the latter's cost comes from false sharing,
+// and larger groups quantify the tradeoff between memory usage and contention.
+//
+pub struct PaddedAtomicHistogram {
+    lines: Vec<CachePadded<Line>>,
+    binner: Binner,
+    bins_per_line_log2: u32,
+}
+
+// Counters which fit in a padded cache line
+const LINE_LEN: usize = mem::size_of::<CachePadded<u8>>() / mem::size_of::<usize>();
+type Line = [AtomicUsize; LINE_LEN];
+
+impl PaddedAtomicHistogram {
+    // Put every bin on its own cache line
+    pub fn new(num_bins: usize) -> Self {
+        Self::with_bins_per_line(num_bins, 1)
+    }
+
+    // Put groups of `bins_per_line` consecutive bins on each cache line, which
+    // must be a power of two that fits in a line
+    pub fn with_bins_per_line(num_bins: usize, bins_per_line: usize) -> Self {
+        assert!(bins_per_line.is_power_of_two() && bins_per_line <= Self::MAX_BINS_PER_LINE,
+                "Invalid number of bins per cache line");
+        let num_lines = num_bins.div_ceil(bins_per_line);
+        Self {
+            lines: (0..num_lines)
+                .map(|_| CachePadded::new([(); LINE_LEN].map(|()| AtomicUsize::new(0))))
+                .collect(),
+            binner: Binner::new(num_bins),
+            bins_per_line_log2: bins_per_line.trailing_zeros(),
+        }
+    }
+
+    // Number of bins which can share a cache line, i.e. 16 on x86_64 where
+    // crossbeam pads values to pairs of cache lines
+    pub const MAX_BINS_PER_LINE: usize = LINE_LEN;
+
+    fn bin(&self, bin: usize) -> &AtomicUsize {
+        let offset = bin & ((1 << self.bins_per_line_log2) - 1);
+        &self.lines[bin >> self.bins_per_line_log2][offset]
+    }
+
+    fn bin_values(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.binner.num_bins()).map(move |bin| self.bin(bin).load(Ordering::Relaxed))
+    }
+}
+
+impl SyncHistogram for PaddedAtomicHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.binner.for_each_index(values, |bin| {
+            self.bin(bin).fetch_add(1, Ordering::Relaxed);
+        });
+        telemetry::atomic_rmws(values.len())
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bin_values().sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.bin_values().collect()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        for (bin, &src) in bins.iter().enumerate() {
+            self.bin(bin).fetch_add(src, Ordering::Relaxed);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + self.lines.capacity() * mem::size_of::<CachePadded<Line>>()
+    }
+}
+
+impl_clone_by_merge!([] PaddedAtomicHistogram => |this, num_bins| {
+    Self::with_bins_per_line(num_bins, 1 << this.bins_per_line_log2)
+});
+
+impl_eq_by_bins!([] PaddedAtomicHistogram);
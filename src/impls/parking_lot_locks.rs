@@ -0,0 +1,74 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        telemetry,
+        traits::{Histogram, SyncHistogram},
+    },
+    parking_lot::{Mutex, RwLock},
+    std::mem,
+};
+#[cfg(feature = "thread_bucketized")]
+use crate::impls::BucketLock;
+
+// Same as the standard library's locks, but with parking_lot's implementation
+//
+// Its locks have shorter uncontended paths, and spin for a little while before
+// putting threads to sleep under contention, which may close part of the gap
+// between lock-based and lock-free strategies.
+
+impl SyncHistogram for Mutex<ToyHistogram> {
+    fn fill(&self, values: &[f32]) {
+        telemetry::lock(|| self.lock()).fill_mut(values)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.lock().num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.lock().bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.lock().merge_bins_mut(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.lock().memory_usage();
+        inner - mem::size_of::<ToyHistogram>() + mem::size_of::<Self>()
+    }
+}
+
+impl SyncHistogram for RwLock<ToyHistogram> {
+    fn fill(&self, values: &[f32]) {
+        telemetry::lock(|| self.write()).fill_mut(values)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.read().num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.read().bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.write().merge_bins_mut(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.read().memory_usage();
+        inner - mem::size_of::<ToyHistogram>() + mem::size_of::<Self>()
+    }
+}
+
+#[cfg(feature = "thread_bucketized")]
+impl BucketLock for Mutex<ToyHistogram> {
+    fn new(bucket: ToyHistogram) -> Self {
+        Mutex::new(bucket)
+    }
+
+    fn with_locked<R>(&self, f: impl FnOnce(&mut ToyHistogram) -> R) -> R {
+        f(&mut telemetry::lock(|| self.lock()))
+    }
+}
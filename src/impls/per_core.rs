@@ -0,0 +1,99 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        telemetry,
+        thread_id::CpuId,
+        traits::{Histogram, SyncHistogram},
+    },
+    crossbeam_utils::CachePadded,
+    std::{
+        mem,
+        sync::Mutex,
+    },
+};
+
+// Histogram with one bucket per CPU core, like the kernel's per-cpu counters
+//
+// ThreadBucketizedHistogram assigns threads to buckets statically, so when
+// there are more threads than buckets, threads which run at the same time may
+// share a bucket while other buckets sit idle. Here, threads instead use the
+// bucket of the CPU which they are currently running on. As only one thread can
+// run on a CPU at a given time, buckets are only contended when a thread is
+// preempted or migrated to another CPU while filling.
+//
+// The kernel can disable preemption while it updates a per-cpu counter, but we
+// can't, so buckets still need to be locked. These locks are almost never
+// contended, and are padded to their own cache line to avoid false sharing.
+//
+pub struct PerCoreHistogram {
+    buckets: Vec<CachePadded<Mutex<ToyHistogram>>>,
+}
+
+impl PerCoreHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            buckets: (0..num_cpus::get())
+                .map(|_| CachePadded::new(Mutex::new(ToyHistogram::new(num_bins))))
+                .collect(),
+        }
+    }
+
+    // CPU indices may exceed the number of CPUs that we can run on, if the
+    // process is pinned to a subset of the CPUs
+    fn with_bucket<R>(&self, f: impl FnOnce(&mut ToyHistogram) -> R) -> R {
+        let bucket = &self.buckets[usize::from(CpuId::load()) % self.buckets.len()];
+        f(&mut telemetry::lock(|| bucket.lock().unwrap()))
+    }
+}
+
+impl SyncHistogram for PerCoreHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.with_bucket(|bucket| bucket.fill_mut(values))
+    }
+
+    fn num_hits(&self) -> usize {
+        self.buckets.iter()
+            .map(|b| b.lock().unwrap().num_hits())
+            .sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = self.buckets[0].lock().unwrap().bins();
+        for bucket in &self.buckets[1..] {
+            let bucket = bucket.lock().unwrap();
+            for (dst, &src) in result.iter_mut().zip(bucket.bins.iter()) {
+                *dst += src;
+            }
+        }
+        result
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.with_bucket(|bucket| bucket.merge_bins_mut(bins))
+    }
+
+    // Every bucket is locked before the first one is read out
+    fn snapshot(&self) -> ToyHistogram {
+        let buckets = self.buckets.iter().map(|b| b.lock().unwrap()).collect::<Vec<_>>();
+        let mut result = buckets[0].bins();
+        for bucket in &buckets[1..] {
+            for (dst, &src) in result.iter_mut().zip(bucket.bins.iter()) {
+                *dst += src;
+            }
+        }
+        ToyHistogram::from_bins(result)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let bucket_heap = self.buckets.iter()
+            .map(|b| b.lock().unwrap().memory_usage() - mem::size_of::<ToyHistogram>())
+            .sum::<usize>();
+        mem::size_of::<Self>()
+            + self.buckets.capacity() * mem::size_of::<CachePadded<Mutex<ToyHistogram>>>()
+            + bucket_heap
+    }
+}
+
+impl_clone_by_merge!([] PerCoreHistogram);
+
+impl_eq_by_bins!([] PerCoreHistogram);
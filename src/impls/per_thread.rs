@@ -0,0 +1,82 @@
+use {
+    crate::thread_id::ThreadID,
+    std::{
+        array,
+        mem,
+        sync::OnceLock,
+    },
+};
+
+// Storage for one value per thread, indexed by thread ID
+//
+// Values are allocated by the first access of each thread. As the number of
+// threads is not known in advance, they are stored in segments of
+// exponentially growing size, which are also allocated on first use.
+//
+pub(crate) struct PerThread<T> {
+    segments: [Segment<T>; NUM_SEGMENTS],
+}
+
+// Segment s holds the values of threads with IDs from 2^s - 1 to 2^(s+1) - 2
+type Segment<T> = OnceLock<Box<[OnceLock<T>]>>;
+
+// Enough segments for any thread ID
+const NUM_SEGMENTS: usize = usize::BITS as usize;
+
+// Segment and offset within it of the value of a thread
+fn location(id: usize) -> (usize, usize) {
+    let index = id + 1;
+    let segment = index.ilog2() as usize;
+    (segment, index - (1 << segment))
+}
+
+impl<T> PerThread<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            segments: array::from_fn(|_| OnceLock::new()),
+        }
+    }
+
+    // Value of a thread, which is initialized if needed
+    #[cfg_attr(not(any(feature = "buffered", feature = "lazy", feature = "sparse",
+                       feature = "thread_local", feature = "two_level")),
+               allow(dead_code))]
+    pub(crate) fn get_or_init(&self, id: ThreadID, init: impl FnOnce() -> T) -> &T {
+        self.get_or_init_at(usize::from(id), init)
+    }
+
+    // Same with an index which is not a thread ID, which must be small enough
+    // for its segment to be allocated
+    pub(crate) fn get_or_init_at(&self, index: usize, init: impl FnOnce() -> T) -> &T {
+        let (segment, offset) = location(index);
+        let values = self.segments[segment].get_or_init(|| {
+            (0..1usize << segment).map(|_| OnceLock::new()).collect()
+        });
+        values[offset].get_or_init(init)
+    }
+
+    // Value of the thread with this ID, if it was initialized
+    #[cfg_attr(not(feature = "thread_bucketized"), allow(dead_code))]
+    pub(crate) fn get(&self, id: usize) -> Option<&T> {
+        let (segment, offset) = location(id);
+        self.segments.get(segment)?.get()?[offset].get()
+    }
+
+    // Values which were initialized so far
+    #[cfg_attr(not(any(feature = "buffered", feature = "lazy", feature = "sparse",
+                       feature = "thread_local", feature = "two_level")),
+               allow(dead_code))]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.segments.iter()
+            .filter_map(OnceLock::get)
+            .flat_map(|values| values.iter().filter_map(OnceLock::get))
+    }
+
+    // Heap memory used by the storage itself, excluding that of the values
+    pub(crate) fn heap_usage(&self) -> usize {
+        self.segments.iter()
+            .filter_map(OnceLock::get)
+            .map(|values| values.len() * mem::size_of::<OnceLock<T>>())
+            .sum::<usize>()
+    }
+}
@@ -0,0 +1,96 @@
+// Summing the replicas of the bins which some strategies give each thread
+//
+// With many bins and threads, adding up the replicas on readout becomes a
+// bottleneck of its own. Bins are therefore summed one chunk at a time, each
+// chunk going through every replica while it is still in cache, and with the
+// rayon feature, chunks are summed in parallel. They are large enough that a
+// rayon job amortizes its scheduling overhead, so that histograms with less
+// bins than a chunk are summed sequentially.
+
+use {
+    super::ToyHistogram,
+    crate::trace,
+    core::ops::Range,
+};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+// Number of bins which a job sums at once
+const CHUNK_LEN: usize = 1 << 16;
+
+// Copy of the bins of a histogram, whose chunks can be read separately
+pub(crate) trait Replica: Sync {
+    // Add the bins from `start` onwards to `sum`, bin by bin
+    fn add_bins(&self, start: usize, sum: &mut [usize]);
+
+    // Number of hits in this range of bins
+    fn count_hits(&self, bins: Range<usize>) -> usize;
+}
+
+impl<R: Replica> Replica for &R {
+    fn add_bins(&self, start: usize, sum: &mut [usize]) {
+        (**self).add_bins(start, sum)
+    }
+
+    fn count_hits(&self, bins: Range<usize>) -> usize {
+        (**self).count_hits(bins)
+    }
+}
+
+impl Replica for ToyHistogram {
+    fn add_bins(&self, start: usize, sum: &mut [usize]) {
+        for (dst, &src) in sum.iter_mut().zip(&self.bins[start..]) {
+            *dst += src;
+        }
+    }
+
+    fn count_hits(&self, bins: Range<usize>) -> usize {
+        self.bins[bins].iter().sum::<usize>()
+    }
+}
+
+// Add the bins of all replicas to `sum`
+pub(crate) fn sum_bins<R: Replica>(replicas: &[R], sum: &mut [usize]) {
+    let sum_chunk = |(index, chunk): (usize, &mut [usize])| {
+        for replica in replicas {
+            replica.add_bins(index * CHUNK_LEN, chunk);
+        }
+    };
+    #[cfg(feature = "rayon")]
+    let chunks = sum.par_chunks_mut(CHUNK_LEN);
+    #[cfg(not(feature = "rayon"))]
+    let chunks = sum.chunks_mut(CHUNK_LEN);
+    trace::aggregate::<R, _>(|| chunks.enumerate().for_each(sum_chunk))
+}
+
+// Total number of hits in all replicas, which have `num_bins` bins each
+pub(crate) fn count_hits<R: Replica>(replicas: &[R], num_bins: usize) -> usize {
+    let count_chunk = |index: usize| {
+        let bins = index * CHUNK_LEN..num_bins.min((index + 1) * CHUNK_LEN);
+        replicas.iter().map(|replica| replica.count_hits(bins.clone())).sum::<usize>()
+    };
+    let num_chunks = num_bins.div_ceil(CHUNK_LEN);
+    #[cfg(feature = "rayon")]
+    let chunks = (0..num_chunks).into_par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let chunks = 0..num_chunks;
+    trace::aggregate::<R, _>(|| chunks.map(count_chunk).sum::<usize>())
+}
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {super::*, alloc::{vec, vec::Vec}};
+
+    #[test]
+    fn sum_across_chunks() {
+        let num_bins = 2 * CHUNK_LEN + 3;
+        let replicas = (1..=3).map(|i| ToyHistogram::from_bins(vec![i; num_bins]))
+                              .collect::<Vec<_>>();
+        let mut sum = vec![1; num_bins];
+        sum_bins(&replicas, &mut sum);
+        assert!(sum.iter().all(|&bin| bin == 7));
+        assert_eq!(count_hits(&replicas, num_bins), 6 * num_bins);
+        assert_eq!(count_hits(&replicas[..0], num_bins), 0);
+    }
+}
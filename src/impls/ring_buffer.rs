@@ -0,0 +1,302 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        sync::{fence, spin_loop, AtomicBool, AtomicUsize, Ordering, UnsafeCell},
+        telemetry,
+        traits::{Histogram, SyncHistogram},
+    },
+    crossbeam_utils::CachePadded,
+    std::{
+        mem,
+        sync::{Arc, Mutex, OnceLock},
+        thread::{self, JoinHandle, Thread},
+    },
+};
+
+// Delegation of fills to a consumer thread through a bounded lock-free queue
+//
+// This is the same idea as ChannelHistogram, but with a queue which is
+// specialized for the job: producers copy their batches into fixed-size chunks
+// of a preallocated ring buffer (a bounded MPSC variant of Dmitry Vyukov's
+// queue), so that sending a batch neither allocates nor takes a lock, and the
+// memory footprint of the queue is fixed. When the ring buffer is full,
+// producers wait for the consumer to catch up. When it is empty, the consumer
+// parks until a producer wakes it up.
+//
+pub struct RingBufferHistogram {
+    shared: Arc<Shared>,
+    consumer: Option<JoinHandle<()>>,
+}
+
+// State shared between the producers and the consumer thread
+struct Shared {
+    slots: Vec<CachePadded<Slot>>,
+
+    // Next position to be claimed by a producer, and next position to be
+    // consumed, which increase monotonically. The slot of a position is given
+    // by its remainder modulo the number of slots.
+    tail: CachePadded<AtomicUsize>,
+    head: CachePadded<AtomicUsize>,
+
+    // Only locked by the consumer thread, readers and merges
+    histogram: Mutex<ToyHistogram>,
+    shutdown: AtomicBool,
+
+    // Consumer thread, which is parked when it has been idle for a while and
+    // must then be unparked by the producer of the next chunk
+    consumer: OnceLock<Thread>,
+    parked: AtomicBool,
+}
+
+// A slot of the ring buffer. Its sequence number is equal to the position
+// which may be written into it when it is free, and to this position plus one
+// once the data has been written.
+struct Slot {
+    sequence: AtomicUsize,
+    chunk: UnsafeCell<Chunk>,
+}
+
+struct Chunk {
+    len: usize,
+    values: [f32; CHUNK_LEN],
+}
+
+// Batches are split into chunks of this many values
+const CHUNK_LEN: usize = 64;
+
+// Number of chunks in the ring buffer
+const NUM_SLOTS: usize = 1024;
+
+// Number of polls of the ring buffer after which waiting threads yield their CPU
+const SPINS_BEFORE_YIELD: usize = 64;
+
+// Number of polls of an empty ring buffer after which the consumer parks
+const SPINS_BEFORE_PARK: usize = 2 * SPINS_BEFORE_YIELD;
+
+impl RingBufferHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        let shared = Arc::new(Shared {
+            slots: (0..NUM_SLOTS)
+                .map(|pos| CachePadded::new(Slot {
+                    sequence: AtomicUsize::new(pos),
+                    chunk: UnsafeCell::new(Chunk { len: 0, values: [0.0; CHUNK_LEN] }),
+                }))
+                .collect(),
+            tail: CachePadded::new(AtomicUsize::new(0)),
+            head: CachePadded::new(AtomicUsize::new(0)),
+            histogram: Mutex::new(ToyHistogram::new(num_bins)),
+            shutdown: AtomicBool::new(false),
+            consumer: OnceLock::new(),
+            parked: AtomicBool::new(false),
+        });
+        let consumer = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.consume())
+        };
+        // Nothing can be pushed before this constructor returns
+        let _ = shared.consumer.set(consumer.thread().clone());
+        Self {
+            shared,
+            consumer: Some(consumer),
+        }
+    }
+}
+
+impl Shared {
+    // Enqueue a chunk of values, waiting for a free slot if needed
+    fn push(&self, values: &[f32]) {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        let mut spins = 0;
+        let slot = loop {
+            let slot = &self.slots[pos % NUM_SLOTS];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence == pos {
+                match self.tail.compare_exchange_weak(pos, pos + 1,
+                                                      Ordering::Relaxed,
+                                                      Ordering::Relaxed) {
+                    Ok(_) => {
+                        telemetry::atomic_rmws(1);
+                        break slot;
+                    }
+                    Err(current) => {
+                        telemetry::cas_retries(1);
+                        pos = current;
+                    }
+                }
+            } else {
+                // Either the ring buffer is full, or another producer claimed
+                // this position first
+                backoff(&mut spins);
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        };
+        // Claiming the position gave us exclusive access to the slot
+        slot.chunk.with_mut(|chunk_ptr| {
+            let chunk = unsafe { &mut *chunk_ptr };
+            chunk.len = values.len();
+            chunk.values[..values.len()].copy_from_slice(values);
+        });
+        slot.sequence.store(pos + 1, Ordering::Release);
+
+        // Either the consumer sees the chunk before parking, or this sees that
+        // it is parked
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::Relaxed) {
+            self.consumer.get().expect("Consumer is set on construction").unpark();
+        }
+    }
+
+    // Truth that the chunk at some position has been written by its producer
+    fn is_ready(&self, pos: usize) -> bool {
+        self.slots[pos % NUM_SLOTS].sequence.load(Ordering::Acquire) == pos + 1
+    }
+
+    // Main loop of the consumer thread
+    fn consume(&self) {
+        let mut head = 0;
+        let mut spins = 0;
+        loop {
+            if self.is_ready(head) {
+                // The histogram is only locked when there is something to fill
+                let mut histogram = self.histogram.lock().unwrap();
+                while self.is_ready(head) {
+                    // The producer is done with the slot, and nobody else will
+                    // touch it until it is marked free again below
+                    let slot = &self.slots[head % NUM_SLOTS];
+                    slot.chunk.with(|chunk_ptr| {
+                        let chunk = unsafe { &*chunk_ptr };
+                        histogram.fill_mut(&chunk.values[..chunk.len]);
+                    });
+                    slot.sequence.store(head + NUM_SLOTS, Ordering::Release);
+                    head += 1;
+                    self.head.store(head, Ordering::Release);
+                }
+                spins = 0;
+            } else if self.shutdown.load(Ordering::Acquire) {
+                return;
+            } else if spins < SPINS_BEFORE_PARK {
+                backoff(&mut spins);
+            } else {
+                // Producers check whether the consumer is parked after
+                // publishing a chunk, and shutdown unparks it too
+                self.parked.store(true, Ordering::Relaxed);
+                fence(Ordering::SeqCst);
+                if !self.is_ready(head) && !self.shutdown.load(Ordering::Acquire) {
+                    thread::park();
+                }
+                self.parked.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Poll for something with a spin-then-yield strategy, so that polling threads
+// do not starve the thread that they wait for when CPUs are oversubscribed
+fn wait_until(mut condition: impl FnMut() -> bool) {
+    let mut spins = 0;
+    while !condition() {
+        backoff(&mut spins);
+    }
+}
+
+fn backoff(spins: &mut usize) {
+    *spins += 1;
+    if *spins < SPINS_BEFORE_YIELD {
+        spin_loop();
+    } else {
+        thread::yield_now();
+    }
+}
+
+impl SyncHistogram for RingBufferHistogram {
+    fn fill(&self, values: &[f32]) {
+        for chunk in values.chunks(CHUNK_LEN) {
+            self.shared.push(chunk);
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.flush();
+        self.shared.histogram.lock().unwrap().num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.flush();
+        self.shared.histogram.lock().unwrap().bins()
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.shared.histogram.lock().unwrap().merge_bins_mut(bins)
+    }
+
+    // Wait until every chunk which was enqueued so far has been consumed
+    fn flush(&self) {
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        wait_until(|| self.shared.head.load(Ordering::Acquire) >= tail);
+    }
+
+    fn memory_usage(&self) -> usize {
+        let histogram_heap = self.shared.histogram.lock().unwrap().memory_usage()
+                             - mem::size_of::<ToyHistogram>();
+        mem::size_of::<Self>()
+            + mem::size_of::<Shared>()
+            + self.shared.slots.capacity() * mem::size_of::<CachePadded<Slot>>()
+            + histogram_heap
+    }
+}
+
+// Stop the consumer thread
+impl Drop for RingBufferHistogram {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        if let Some(consumer) = self.consumer.take() {
+            consumer.thread().unpark();
+            // Panics of the consumer thread were already reported by the
+            // panic hook, and there is nothing more to be done about them here
+            let _ = consumer.join();
+        }
+    }
+}
+
+// Producers and the consumer synchronize their accesses to chunks through the
+// sequence numbers of the slots
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl_clone_by_merge!([] RingBufferHistogram);
+
+impl_eq_by_bins!([] RingBufferHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    // Batches which span several chunks must be inserted entirely
+    #[test]
+    fn concurrent_fill() {
+        const NUM_THREADS: usize = 4;
+        let histogram = RingBufferHistogram::new(4);
+        let batch = [0.1, 0.3, 0.3, 0.9].repeat(CHUNK_LEN);
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| histogram.fill(&batch));
+            }
+        });
+        let hits = CHUNK_LEN * NUM_THREADS;
+        assert_eq!(SyncHistogram::num_hits(&histogram), 4 * hits);
+        assert_eq!(SyncHistogram::bins(&histogram), [hits, 2 * hits, 0, hits]);
+    }
+
+    // An idle consumer must park, and wake up for the next chunk
+    #[test]
+    fn park_when_idle() {
+        let histogram = RingBufferHistogram::new(4);
+        histogram.fill(&[0.1, 0.9]);
+        while !histogram.shared.parked.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        histogram.fill(&[0.3]);
+        assert_eq!(SyncHistogram::bins(&histogram), [1, 1, 0, 1]);
+    }
+}
@@ -0,0 +1,309 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{AtomicUsize, Ordering},
+        telemetry,
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    std::mem,
+};
+
+// Per-CPU histogram whose bins are incremented using restartable sequences
+//
+// PerCoreHistogram needs to lock its buckets because a thread may be preempted
+// or migrated to another CPU while it fills one. With Linux's restartable
+// sequences (rseq), the kernel instead aborts the increment if this happens,
+// and we retry it on the new CPU. Bins can then be incremented using plain,
+// non-atomic instructions, which makes increments about as cheap as in a
+// sequential histogram. This is experimental, and is meant as an upper bound of
+// what can be achieved with per-CPU bins.
+//
+// Restartable sequences are only implemented for x86_64 Linux with a glibc
+// which registers rseq areas for its threads (glibc 2.35 and later). Elsewhere,
+// bins are incremented using atomic read-modify-write operations instead. Bins
+// are atomics in any case, so that they can be read out during fills.
+//
+pub struct RseqHistogram {
+    // Bins of each CPU, which are only incremented using restartable sequences
+    cpus: Vec<Vec<AtomicUsize>>,
+
+    // Bins which are incremented using atomic RMW operations: merged bins, and
+    // values which cannot be inserted using restartable sequences
+    shared: Vec<AtomicUsize>,
+
+    binner: Binner,
+}
+
+impl RseqHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        let new_bins = || (0..num_bins).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>();
+        Self {
+            cpus: (0..sys::num_cpus()).map(|_| new_bins()).collect(),
+            shared: new_bins(),
+            binner: Binner::new(num_bins),
+        }
+    }
+}
+
+impl SyncHistogram for RseqHistogram {
+    fn fill(&self, values: &[f32]) {
+        let area = match sys::Area::current() {
+            Some(area) => area,
+            None => {
+                // Without rseq, spread the contention over the CPU bins using
+                // thread IDs, but with atomic increments
+                let bins = &self.cpus[usize::from(ThreadID::load()) % self.cpus.len()];
+                self.binner.for_each_bin(bins, values, |bin| {
+                    bin.fetch_add(1, Ordering::Relaxed);
+                });
+                telemetry::atomic_rmws(values.len());
+                return;
+            }
+        };
+        self.binner.for_each_index(values, |bin| {
+            loop {
+                let cpu = area.cpu_id();
+                match self.cpus.get(cpu) {
+                    // Only threads running on this CPU increment its bins
+                    Some(bins) => if unsafe { area.increment(cpu, &bins[bin]) } {
+                        break;
+                    },
+                    None => {
+                        self.shared[bin].fetch_add(1, Ordering::Relaxed);
+                        telemetry::atomic_rmws(1);
+                        break;
+                    }
+                }
+            }
+        })
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins().iter().sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = self.shared.iter().map(|b| b.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        for bins in &self.cpus {
+            for (dst, src) in result.iter_mut().zip(bins) {
+                *dst += src.load(Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.shared.len(), "Histogram binning mismatch");
+        for (dst, &src) in self.shared.iter().zip(bins) {
+            dst.fetch_add(src, Ordering::Relaxed);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.cpus.capacity() * mem::size_of::<Vec<AtomicUsize>>()
+            + (self.cpus.len() + 1) * self.shared.capacity() * mem::size_of::<AtomicUsize>()
+    }
+}
+
+// Restartable sequences, using the rseq areas which glibc registers
+#[cfg(all(target_os = "linux", target_arch = "x86_64", not(any(loom, miri))))]
+mod sys {
+    use {
+        std::{
+            arch::asm,
+            marker::PhantomData,
+            ptr,
+            sync::OnceLock,
+        },
+        super::AtomicUsize,
+    };
+
+    // Offset of the rseq area of each thread from its thread pointer, if glibc
+    // registered one. These symbols are looked up at runtime, because they are
+    // missing from older versions of glibc.
+    fn area_offset() -> Option<isize> {
+        static OFFSET: OnceLock<Option<isize>> = OnceLock::new();
+        *OFFSET.get_or_init(|| unsafe {
+            let lookup = |name: &[u8]| libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr().cast());
+            let offset = lookup(b"__rseq_offset\0").cast::<isize>();
+            let size = lookup(b"__rseq_size\0").cast::<u32>();
+            if offset.is_null() || size.is_null() || *size == 0 {
+                None
+            } else {
+                Some(*offset)
+            }
+        })
+    }
+
+    pub fn num_cpus() -> usize {
+        unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as usize
+    }
+
+    // Rseq area of the active thread
+    #[derive(Clone, Copy)]
+    pub struct Area {
+        ptr: *const u8,
+        _not_sendable_between_threads: PhantomData<*const u8>,
+    }
+
+    impl Area {
+        pub fn current() -> Option<Self> {
+            let offset = area_offset()?;
+            let thread_ptr: *const u8;
+            unsafe {
+                asm!("mov {}, fs:0", out(reg) thread_ptr, options(nostack, readonly, preserves_flags));
+            }
+            Some(Self {
+                ptr: thread_ptr.wrapping_offset(offset),
+                _not_sendable_between_threads: PhantomData,
+            })
+        }
+
+        // CPU which the thread is running on, kept up to date by the kernel
+        pub fn cpu_id(&self) -> usize {
+            unsafe { ptr::read_volatile(self.ptr.add(4).cast::<u32>()) as usize }
+        }
+
+        // Increment a counter, unless the thread is not running on the given
+        // CPU, or is preempted or migrated while doing so. Returns whether the
+        // counter was incremented.
+        //
+        // This is only safe if the counter is never modified by threads
+        // running on other CPUs, or in any other way than this function.
+        #[inline(always)]
+        pub unsafe fn increment(&self, cpu: usize, counter: &AtomicUsize) -> bool {
+            let committed: u32;
+            // The critical section spans from label 4 to label 5, and the add
+            // instruction commits it. If the kernel interrupts it, execution
+            // resumes at label 6, which must be preceded by the rseq signature.
+            // Label 3 is the descriptor of the critical section.
+            asm!(
+                ".pushsection __rseq_cs, \"aw\"",
+                ".balign 32",
+                "3:",
+                ".long 0, 0",
+                ".quad 4f, (5f - 4f), 6f",
+                ".popsection",
+                "lea {tmp}, [rip + 3b]",
+                "mov qword ptr [{area} + 8], {tmp}",
+                "4:",
+                "cmp dword ptr [{area} + 4], {cpu:e}",
+                "jne 6f",
+                "add qword ptr [{counter}], 1",
+                "5:",
+                "mov {committed:e}, 1",
+                "jmp 7f",
+                ".long 0x53053053",
+                "6:",
+                "xor {committed:e}, {committed:e}",
+                "7:",
+                area = in(reg) self.ptr,
+                cpu = in(reg) cpu as u32,
+                counter = in(reg) counter.as_ptr(),
+                tmp = out(reg) _,
+                committed = out(reg) committed,
+                options(nostack),
+            );
+            committed != 0
+        }
+    }
+}
+
+// Elsewhere, restartable sequences are never available
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64", not(any(loom, miri)))))]
+mod sys {
+    use super::AtomicUsize;
+
+    pub fn num_cpus() -> usize {
+        num_cpus::get()
+    }
+
+    pub enum Area {}
+
+    impl Area {
+        pub fn current() -> Option<Self> {
+            None
+        }
+
+        pub fn cpu_id(&self) -> usize {
+            match *self {}
+        }
+
+        pub unsafe fn increment(&self, _cpu: usize, _counter: &AtomicUsize) -> bool {
+            match *self {}
+        }
+    }
+}
+
+impl_clone_by_merge!([] RseqHistogram);
+
+impl_eq_by_bins!([] RseqHistogram);
+
+
+// Restartable sequences are only used where glibc registers rseq areas,
+// elsewhere these tests only exercise the atomic fallback
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Threads which fill concurrently only increment the bins of the CPU that
+    // they run on, and do not lose increments even when there are more of
+    // them than CPUs, so that they get preempted and migrated
+    #[test]
+    fn per_cpu_bins() {
+        const NUM_FILLS: usize = 100;
+        let num_threads = 2 * sys::num_cpus();
+        let histogram = RseqHistogram::new(4);
+        thread::scope(|s| {
+            for _ in 0..num_threads {
+                s.spawn(|| {
+                    for _ in 0..NUM_FILLS {
+                        histogram.fill(&[0.1, 0.3, 0.3, 0.9]);
+                    }
+                });
+            }
+        });
+        let expected = [num_threads * NUM_FILLS, 2 * num_threads * NUM_FILLS, 0,
+                        num_threads * NUM_FILLS];
+        assert_eq!(SyncHistogram::bins(&histogram), expected);
+        assert!(histogram.shared.iter().all(|bin| bin.load(Ordering::Relaxed) == 0));
+    }
+
+    // Threads which run on CPUs that have no bins of their own fall back to
+    // atomic increments of the shared bins
+    #[test]
+    fn shared_fallback() {
+        let histogram = RseqHistogram {
+            cpus: vec![(0..2).map(|_| AtomicUsize::new(0)).collect()],
+            ..RseqHistogram::new(2)
+        };
+        thread::scope(|s| {
+            for _ in 0..sys::num_cpus() {
+                s.spawn(|| histogram.fill(&[0.25, 0.75, 0.75]));
+            }
+        });
+        assert_eq!(SyncHistogram::bins(&histogram), [sys::num_cpus(), 2 * sys::num_cpus()]);
+    }
+
+    // Critical sections abort without touching the counter when the thread
+    // does not run on the expected CPU, as after a migration
+    #[test]
+    fn abort_on_other_cpu() {
+        let Some(area) = sys::Area::current() else {
+            return;
+        };
+        let counter = AtomicUsize::new(0);
+        // CPU IDs are always lower than the number of CPUs
+        assert!(!unsafe { area.increment(sys::num_cpus(), &counter) });
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+        // The thread may be migrated before its critical section starts, in
+        // which case the increment is retried like fill() does
+        while !unsafe { area.increment(area.cpu_id(), &counter) } {}
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+    }
+}
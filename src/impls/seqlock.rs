@@ -0,0 +1,134 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{fence, spin_loop, AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::Mutex,
+    },
+};
+
+// Histogram whose readouts never block, nor are blocked by, concurrent fills
+//
+// Fills are serialized by a mutex, and bracketed by increments of a sequence
+// number, which is odd while a fill is in progress. Readers do not take the
+// lock: they read the bins, then check that the sequence number was even and
+// did not change in the meantime, and retry otherwise. This gives readers a
+// consistent snapshot of the bins (unlike AtomicHistogram, where a readout may
+// observe half of a batch) without slowing down the writers, at the expense of
+// readers possibly retrying many times if fills are long and frequent.
+//
+// Bins are atomics because readers may race with the writer, but the writer
+// only needs plain loads and stores since it has exclusive write access.
+//
+pub struct SeqlockHistogram {
+    writer: Mutex<()>,
+    sequence: AtomicUsize,
+    bins: Vec<AtomicUsize>,
+    binner: Binner,
+}
+
+impl SeqlockHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            writer: Mutex::new(()),
+            sequence: AtomicUsize::new(0),
+            bins: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Modify the bins with exclusive write access
+    fn write(&self, f: impl FnOnce(&[AtomicUsize])) {
+        let _lock = telemetry::lock(|| self.writer.lock().unwrap());
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        f(&self.bins);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    // Compute something from a consistent snapshot of the bins, retrying if a
+    // fill happened in the meantime
+    fn read<R>(&self, mut f: impl FnMut(&[AtomicUsize]) -> R) -> R {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                spin_loop();
+                continue;
+            }
+            let result = f(&self.bins);
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return result;
+            }
+        }
+    }
+}
+
+impl SyncHistogram for SeqlockHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.write(|bins| {
+            self.binner.for_each_bin(bins, values, |bin| {
+                bin.store(bin.load(Ordering::Relaxed) + 1, Ordering::Relaxed)
+            })
+        })
+    }
+
+    fn num_hits(&self) -> usize {
+        self.read(|bins| bins.iter().map(|b| b.load(Ordering::Relaxed)).sum::<usize>())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.read(|bins| bins.iter().map(|b| b.load(Ordering::Relaxed)).collect())
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.bins.len(), "Histogram binning mismatch");
+        self.write(|dst_bins| {
+            for (dst, &src) in dst_bins.iter().zip(bins) {
+                dst.store(dst.load(Ordering::Relaxed) + src, Ordering::Relaxed);
+            }
+        })
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + self.bins.capacity() * mem::size_of::<AtomicUsize>()
+    }
+}
+
+impl_clone_by_merge!([] SeqlockHistogram);
+
+impl_eq_by_bins!([] SeqlockHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Readers must never observe a partially inserted batch
+    #[test]
+    fn consistent_reads() {
+        const NUM_BATCHES: usize = 10;
+        let histogram = SeqlockHistogram::new(4);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..NUM_BATCHES {
+                    histogram.fill(&[0.1, 0.3, 0.3, 0.9]);
+                }
+            });
+            for _ in 0..NUM_BATCHES {
+                let bins = histogram.bins();
+                assert_eq!(bins[1], 2 * bins[0]);
+                assert_eq!(bins[3], bins[0]);
+            }
+        });
+        assert_eq!(histogram.num_hits(), 4 * NUM_BATCHES);
+    }
+}
@@ -0,0 +1,160 @@
+use {
+    crate::{
+        binning::Binner,
+        impls::{per_thread::PerThread, ToyHistogram},
+        telemetry,
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    dashmap::DashMap,
+    std::{
+        collections::HashMap,
+        mem,
+        sync::Mutex,
+    },
+};
+
+// Sparse histograms, which only store the bins that were hit
+//
+// With millions of bins, as in multi-dimensional histograms, most bins are
+// never hit, and dense storage wastes memory and cache on them. Hash maps only
+// store the bins which were hit, at the expense of hashing every value and of
+// probing the map. Which synchronization strategy works best may well differ
+// from dense histograms, since the map itself must now be synchronized too.
+//
+// Both variants use the standard library's default hasher, and are read out as
+// dense bins like every other histogram.
+
+// Concurrent hash map of bins, which locks one shard of the map per increment
+pub struct DashMapHistogram {
+    bins: DashMap<usize, usize>,
+    binner: Binner,
+}
+
+impl DashMapHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            bins: DashMap::new(),
+            binner: Binner::new(num_bins),
+        }
+    }
+}
+
+impl SyncHistogram for DashMapHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.binner.for_each_index(values, |bin| *self.bins.entry(bin).or_insert(0) += 1)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins.iter().map(|entry| *entry.value()).sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = vec![0; self.binner.num_bins()];
+        for entry in self.bins.iter() {
+            result[*entry.key()] += *entry.value();
+        }
+        result
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        for (bin, &count) in bins.iter().enumerate().filter(|(_, &count)| count > 0) {
+            *self.bins.entry(bin).or_insert(0) += count;
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + map_usage(self.bins.capacity())
+    }
+}
+
+impl_clone_by_merge!([] DashMapHistogram);
+
+impl_eq_by_bins!([] DashMapHistogram);
+
+// Hash map of bins per thread, which are summed on readout
+pub struct SparseThreadLocalHistogram {
+    buckets: PerThread<Mutex<HashMap<usize, usize>>>,
+    binner: Binner,
+}
+
+impl SparseThreadLocalHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            buckets: PerThread::new(),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Bins of a thread, which only this thread fills. They are behind an
+    // uncontended mutex, as readouts may happen concurrently.
+    fn bucket(&self, id: ThreadID) -> &Mutex<HashMap<usize, usize>> {
+        self.buckets.get_or_init(id, || Mutex::new(HashMap::new()))
+    }
+}
+
+impl SyncHistogram for SparseThreadLocalHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.fill_with_id(values, ThreadID::load())
+    }
+
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        let mut bucket = telemetry::lock(|| self.bucket(id).lock().unwrap());
+        self.binner.for_each_index(values, |bin| *bucket.entry(bin).or_insert(0) += 1)
+    }
+
+    fn num_hits(&self) -> usize {
+        self.buckets.iter()
+            .map(|bucket| bucket.lock().unwrap().values().sum::<usize>())
+            .sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = vec![0; self.binner.num_bins()];
+        for bucket in self.buckets.iter() {
+            for (&bin, &count) in bucket.lock().unwrap().iter() {
+                result[bin] += count;
+            }
+        }
+        result
+    }
+
+    // Every bucket is locked before the first one is read out
+    fn snapshot(&self) -> ToyHistogram {
+        let buckets = self.buckets.iter().map(|b| b.lock().unwrap()).collect::<Vec<_>>();
+        let mut result = vec![0; self.binner.num_bins()];
+        for (&bin, &count) in buckets.iter().flat_map(|bucket| bucket.iter()) {
+            result[bin] += count;
+        }
+        ToyHistogram::from_bins(result)
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        let mut bucket = self.bucket(ThreadID::load()).lock().unwrap();
+        for (bin, &count) in bins.iter().enumerate().filter(|(_, &count)| count > 0) {
+            *bucket.entry(bin).or_insert(0) += count;
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let bucket_heap = self.buckets.iter()
+            .map(|bucket| map_usage(bucket.lock().unwrap().capacity()))
+            .sum::<usize>();
+        mem::size_of::<Self>()
+            + self.buckets.heap_usage()
+            + self.buckets.iter().count() * mem::size_of::<Mutex<HashMap<usize, usize>>>()
+            + bucket_heap
+    }
+}
+
+// Approximate heap usage of a hash map of bins, given its capacity: hashbrown
+// stores one control byte per entry besides the entries themselves
+fn map_usage(capacity: usize) -> usize {
+    capacity * (mem::size_of::<(usize, usize)>() + 1)
+}
+
+impl_clone_by_merge!([] SparseThreadLocalHistogram);
+
+impl_eq_by_bins!([] SparseThreadLocalHistogram);
@@ -0,0 +1,152 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        sync::{spin_loop, AtomicBool, Ordering, UnsafeCell},
+        telemetry,
+        traits::{Histogram, SyncHistogram},
+    },
+    alloc::vec::Vec,
+    core::mem,
+};
+#[cfg(feature = "thread_bucketized")]
+use crate::impls::BucketLock;
+
+// Minimal test-and-test-and-set spinlock
+//
+// Waiting threads spin on a plain load of the lock flag, which stays in their
+// cache until the lock is released, and only retry the swap which acquires the
+// lock when it looks free. This keeps the cache line of the lock from bouncing
+// between waiting threads. There is no fairness and no backoff: for critical
+// sections as short as a batched fill, the question is whether never putting
+// threads to sleep beats the more elaborate strategy of a real mutex.
+//
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Run a closure with exclusive access to the protected data
+    pub fn with_locked<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        telemetry::lock(|| {
+            while self.locked.swap(true, Ordering::Acquire) {
+                while self.locked.load(Ordering::Relaxed) {
+                    spin_loop();
+                }
+            }
+        });
+        let _guard = Unlock(&self.locked);
+        self.data.with_mut(|data_ptr| f(unsafe { &mut *data_ptr }))
+    }
+}
+
+// Releases the lock on scope exit, so that a panicking closure does not leave
+// the lock held forever
+struct Unlock<'a>(&'a AtomicBool);
+
+impl Drop for Unlock<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+// The lock flag guarantees that only one thread accesses the data at a time
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl SyncHistogram for SpinLock<ToyHistogram> {
+    fn fill(&self, values: &[f32]) {
+        self.with_locked(|histogram| histogram.fill_mut(values))
+    }
+
+    fn num_hits(&self) -> usize {
+        self.with_locked(|histogram| histogram.num_hits())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.with_locked(|histogram| histogram.bins())
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.with_locked(|histogram| histogram.merge_bins_mut(bins))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.with_locked(|histogram| histogram.memory_usage());
+        inner - mem::size_of::<ToyHistogram>() + mem::size_of::<Self>()
+    }
+}
+
+#[cfg(feature = "thread_bucketized")]
+impl BucketLock for SpinLock<ToyHistogram> {
+    fn new(bucket: ToyHistogram) -> Self {
+        SpinLock::new(bucket)
+    }
+
+    fn with_locked<R>(&self, f: impl FnOnce(&mut ToyHistogram) -> R) -> R {
+        SpinLock::with_locked(self, f)
+    }
+}
+
+// The protected data is cloned under the lock
+impl<T: Clone> Clone for SpinLock<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.with_locked(|data| data.clone()))
+    }
+}
+
+impl_eq_by_bins!([] SpinLock<ToyHistogram>);
+
+
+// Kept small enough to run under Miri, which would flag concurrent accesses to
+// the protected data if the lock failed to make them exclusive
+#[cfg(all(test, feature = "std", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::{
+            panic::{self, AssertUnwindSafe},
+            thread,
+        },
+    };
+
+    // Threads which increment a counter in several steps must not interleave
+    #[test]
+    fn exclusive_access() {
+        const NUM_THREADS: usize = 4;
+        const NUM_INCREMENTS: usize = 10;
+        let lock = SpinLock::new(0);
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| {
+                    for _ in 0..NUM_INCREMENTS {
+                        lock.with_locked(|count| {
+                            let old = *count;
+                            thread::yield_now();
+                            *count = old + 1;
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.with_locked(|count| *count), NUM_THREADS * NUM_INCREMENTS);
+    }
+
+    // A panic in the critical section must release the lock
+    #[test]
+    fn unlock_on_panic() {
+        let lock = SpinLock::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            lock.with_locked(|_| panic!("Panic in the critical section"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(lock.with_locked(|count| *count), 0);
+    }
+}
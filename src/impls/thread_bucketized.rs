@@ -1,14 +1,27 @@
 use {
     crate::{
-        impls::ToyHistogram,
-        thread_id::ThreadID,
+        impls::{
+            per_thread::PerThread,
+            reduce::{self, Replica},
+            ToyHistogram,
+        },
+        telemetry,
+        thread_id::{BucketKey, CpuId, IdSet, NumaNode, ThreadID, ThreadIdProvider, TlsCounter},
         traits::{Histogram, SyncHistogram},
     },
     std::{
-        ops::DerefMut,
-        sync::Mutex,
+        iter,
+        marker::PhantomData,
+        mem,
+        ops::Range,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
     },
 };
+#[cfg(feature = "numa")]
+use crate::numa::{BucketPlacement, Placement};
 
 // This is a compromise between Mutex<ToyHistogram> and ThreadLocalHistogram.
 //
@@ -19,36 +32,406 @@ use {
 // of buckets divides the number of threads evenly and the load is uniform.
 //
 // Notice that because buckets are shared between threads, a synchronization
-// strategy is needed. Here, we use a simple mutex.
+// strategy is needed. By default, we use a simple mutex, but other kinds of
+// locks can be plugged in via the BucketLock trait.
 //
-pub struct ThreadBucketizedHistogram {
-    buckets: Vec<Mutex<ToyHistogram>>,
+// Threads are mapped to buckets by a key, which is their thread ID by default,
+// modulo the number of buckets. Other keys can be used via the ThreadIdProvider
+// trait. The keys which used the buckets are recorded, so that readouts only
+// need to lock the buckets which were used.
+//
+// Other mappings can be plugged in via the BucketAssign trait. The modulo only
+// balances buckets when keys are dense, so that e.g. hashed keys are better
+// assigned to buckets in the order where threads first fill.
+//
+pub struct ThreadBucketizedHistogram<L = Mutex<ToyHistogram>, P = TlsCounter, B = ModuloAssign> {
+    num_bins: usize,
+    buckets: Vec<L>,
+    assign: B,
+    users: IdSet,
+    #[cfg(feature = "numa")]
+    placement: BucketPlacement,
+    provider: PhantomData<fn() -> P>,
+}
+
+// Lock which protects a bucket of a ThreadBucketizedHistogram
+pub trait BucketLock: Sync {
+    fn new(bucket: ToyHistogram) -> Self;
+
+    fn with_locked<R>(&self, f: impl FnOnce(&mut ToyHistogram) -> R) -> R;
+}
+
+impl BucketLock for Mutex<ToyHistogram> {
+    fn new(bucket: ToyHistogram) -> Self {
+        Mutex::new(bucket)
+    }
+
+    fn with_locked<R>(&self, f: impl FnOnce(&mut ToyHistogram) -> R) -> R {
+        f(&mut telemetry::lock(|| self.lock().unwrap()))
+    }
+}
+
+// Mapping from threads to the buckets of a ThreadBucketizedHistogram
+pub trait BucketAssign: Sync {
+    fn new(num_buckets: usize) -> Self;
+
+    // Bucket which the thread with this key fills
+    fn bucket(&self, key: BucketKey) -> usize;
+
+    // Bucket which the thread with this key filled, if it only depends on the
+    // key and was not forgotten. Otherwise, readouts cannot tell which buckets
+    // were used, and go through all of them.
+    fn bucket_of(&self, key: usize) -> Option<usize>;
+
+    // Heap memory used to keep track of the assignment
+    fn heap_usage(&self) -> usize {
+        0
+    }
+}
+
+// Key modulo the number of buckets, which spreads threads evenly if their keys
+// are dense
+pub struct ModuloAssign(usize);
+
+impl BucketAssign for ModuloAssign {
+    fn new(num_buckets: usize) -> Self {
+        Self(num_buckets)
+    }
+
+    fn bucket(&self, key: BucketKey) -> usize {
+        usize::from(key) % self.0
+    }
+
+    fn bucket_of(&self, key: usize) -> Option<usize> {
+        Some(key % self.0)
+    }
+}
+
+// Hash of the key, which spreads threads at random, so that keys which are
+// sparse but regularly spaced (e.g. multiples of the number of buckets) do not
+// end up in the same bucket
+pub struct HashAssign(usize);
+
+impl BucketAssign for HashAssign {
+    fn new(num_buckets: usize) -> Self {
+        Self(num_buckets)
+    }
+
+    fn bucket(&self, key: BucketKey) -> usize {
+        self.bucket_of(usize::from(key)).unwrap()
+    }
+
+    // Fibonacci hashing, which takes the high bits of a multiplicative hash
+    fn bucket_of(&self, key: usize) -> Option<usize> {
+        let hash = (key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        Some(hash as usize % self.0)
+    }
+}
+
+// Buckets handed out in turn, in the order where threads first fill, which
+// balances buckets whatever the keys of the threads are. Each fill looks up the
+// bucket of its thread, which is more expensive than computing it.
+//
+// As the assignment is stored in a table indexed by key, keys which are too
+// large to be stored in it, such as those of OsThreadHash, are hashed.
+pub struct RoundRobinAssign {
+    assigned: PerThread<usize>,
+    next: AtomicUsize,
+    fallback: HashAssign,
+}
+
+// Keys which RoundRobinAssign keeps track of
+const MAX_ROUND_ROBIN_KEY: usize = 1 << 16;
+
+impl BucketAssign for RoundRobinAssign {
+    fn new(num_buckets: usize) -> Self {
+        Self {
+            assigned: PerThread::new(),
+            next: AtomicUsize::new(0),
+            fallback: HashAssign::new(num_buckets),
+        }
+    }
+
+    fn bucket(&self, key: BucketKey) -> usize {
+        let index = usize::from(key);
+        if index >= MAX_ROUND_ROBIN_KEY {
+            return self.fallback.bucket(key);
+        }
+        *self.assigned.get_or_init_at(index, || {
+            self.next.fetch_add(1, Ordering::Relaxed) % self.fallback.0
+        })
+    }
+
+    fn bucket_of(&self, key: usize) -> Option<usize> {
+        if key >= MAX_ROUND_ROBIN_KEY {
+            return self.fallback.bucket_of(key);
+        }
+        self.assigned.get(key).copied()
+    }
+
+    fn heap_usage(&self) -> usize {
+        self.assigned.heap_usage()
+    }
+}
+
+// Index of the CPU which the thread is running on, modulo the number of
+// buckets, so that threads which run on the same CPU share a bucket whatever
+// their keys are
+pub struct CpuAssign(usize);
+
+impl BucketAssign for CpuAssign {
+    fn new(num_buckets: usize) -> Self {
+        Self(num_buckets)
+    }
+
+    fn bucket(&self, _key: BucketKey) -> usize {
+        usize::from(CpuId::load()) % self.0
+    }
+
+    fn bucket_of(&self, _key: usize) -> Option<usize> {
+        None
+    }
+}
+
+// Same with the NUMA node of the CPU, so that buckets are shared by the threads
+// of a socket, ideally with one bucket per node
+pub struct NodeAssign(usize);
+
+impl BucketAssign for NodeAssign {
+    fn new(num_buckets: usize) -> Self {
+        Self(num_buckets)
+    }
+
+    fn bucket(&self, _key: BucketKey) -> usize {
+        usize::from(NumaNode::load()) % self.0
+    }
+
+    fn bucket_of(&self, _key: usize) -> Option<usize> {
+        None
+    }
+}
+
+// Add the bins of some buckets to `sum`, locking each bucket in turn and keeping
+// it locked until the following buckets are locked too, then summing them all
+fn sum_locked<L: BucketLock>(buckets: &[&L], locked: Option<&Locked>, sum: &mut [usize]) {
+    match buckets.split_first() {
+        Some((first, rest)) => first.with_locked(|bucket| {
+            sum_locked(rest, Some(&Locked { bucket, outer: locked }), sum)
+        }),
+        None => {
+            let buckets = iter::successors(locked, |l| l.outer).map(|l| l.bucket)
+                                                                .collect::<Vec<_>>();
+            reduce::sum_bins(&buckets, sum)
+        }
+    }
+}
+
+// Buckets locked by sum_locked so far, from the last one to the first one
+struct Locked<'a> {
+    bucket: &'a ToyHistogram,
+    outer: Option<&'a Locked<'a>>,
+}
+
+// Bucket which is only locked while each chunk of its bins is read, so that
+// unsynchronized readouts hold up fills as little as possible
+struct LockedPerChunk<'a, L>(&'a L);
+
+impl<L: BucketLock> Replica for LockedPerChunk<'_, L> {
+    fn add_bins(&self, start: usize, sum: &mut [usize]) {
+        self.0.with_locked(|bucket| bucket.add_bins(start, sum))
+    }
+
+    fn count_hits(&self, bins: Range<usize>) -> usize {
+        self.0.with_locked(|bucket| bucket.count_hits(bins))
+    }
 }
 
 impl ThreadBucketizedHistogram {
     pub fn new(num_bins: usize, num_buckets: usize) -> Self {
+        Self::with_locks(num_bins, num_buckets)
+    }
+}
+
+impl<L: BucketLock, P: ThreadIdProvider, B: BucketAssign> ThreadBucketizedHistogram<L, P, B> {
+    // Protect buckets with another kind of lock than the standard mutex, and/or
+    // get thread IDs from another provider, and/or assign them to buckets in
+    // another way
+    pub fn with_locks(num_bins: usize, num_buckets: usize) -> Self {
+        Self {
+            num_bins,
+            buckets: (0..num_buckets).map(|_| L::new(ToyHistogram::new(num_bins))).collect(),
+            assign: B::new(num_buckets),
+            users: IdSet::new(),
+            #[cfg(feature = "numa")]
+            placement: BucketPlacement::new(Placement::FirstTouch, num_buckets),
+            provider: PhantomData,
+        }
+    }
+
+    // Choose on which NUMA node the bins of each bucket are allocated
+    #[cfg(feature = "numa")]
+    pub fn with_placement(num_bins: usize, num_buckets: usize, placement: Placement) -> Self {
         Self {
-            buckets: (0..num_buckets).map(|_| Mutex::new(ToyHistogram::new(num_bins))).collect(),
+            placement: BucketPlacement::new(placement, num_buckets),
+            ..Self::with_locks(num_bins, num_buckets)
         }
     }
 
-    fn lock_bucket(&self, id: ThreadID) -> impl DerefMut<Target=ToyHistogram> + '_ {
-        self.buckets[usize::from(id) % self.buckets.len()].lock().unwrap()
+    fn with_bucket<R>(&self, key: BucketKey, f: impl FnOnce(&mut ToyHistogram) -> R) -> R {
+        self.users.insert(key);
+        let bucket = self.assign.bucket(key);
+        self.buckets[bucket].with_locked(|histogram| {
+            #[cfg(feature = "numa")]
+            self.placement.place(bucket, &histogram.bins);
+            f(histogram)
+        })
+    }
+
+    // Buckets which were used by some thread, or all of them if the keys of
+    // these threads could not be recorded
+    fn used_buckets(&self) -> Vec<&L> {
+        let Some(keys) = self.users.iter() else {
+            return self.buckets.iter().collect();
+        };
+        let mut used = vec![false; self.buckets.len()];
+        for key in keys {
+            match self.assign.bucket_of(key) {
+                Some(bucket) => used[bucket] = true,
+                None => return self.buckets.iter().collect(),
+            }
+        }
+        self.buckets.iter().zip(used).filter_map(|(bucket, used)| used.then_some(bucket)).collect()
+    }
+
+    fn locked_per_chunk(&self) -> Vec<LockedPerChunk<'_, L>> {
+        self.used_buckets().into_iter().map(LockedPerChunk).collect()
     }
 }
 
-impl SyncHistogram for ThreadBucketizedHistogram {
+impl<L, P, B> SyncHistogram for ThreadBucketizedHistogram<L, P, B>
+    where L: BucketLock, P: ThreadIdProvider, B: BucketAssign
+{
     fn fill(&self, values: &[f32]) {
-        self.fill_with_id(values, ThreadID::load())
+        self.with_bucket(P::load(), |bucket| bucket.fill_mut(values))
     }
 
     fn fill_with_id(&self, values: &[f32], id: ThreadID) {
-        self.lock_bucket(id).fill_mut(values)
+        self.with_bucket(id.into(), |bucket| bucket.fill_mut(values))
     }
 
     fn num_hits(&self) -> usize {
-        self.buckets.iter()
-            .map(|b| b.lock().unwrap().num_hits())
-            .sum::<usize>()
+        reduce::count_hits(&self.locked_per_chunk(), self.num_bins)
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = vec![0; self.num_bins];
+        reduce::sum_bins(&self.locked_per_chunk(), &mut result);
+        result
     }
-}
\ No newline at end of file
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.with_bucket(P::load(), |bucket| bucket.merge_bins_mut(bins))
+    }
+
+    // Buckets are summed with every bucket locked, as fills only ever lock
+    // one bucket, so that the sum does not change during the readout
+    fn snapshot(&self) -> ToyHistogram {
+        let mut result = vec![0; self.num_bins];
+        sum_locked(&self.used_buckets(), None, &mut result);
+        ToyHistogram::from_bins(result)
+    }
+
+    fn num_threads(&self) -> Option<usize> {
+        self.users.iter().map(Iterator::count)
+    }
+
+    fn memory_usage(&self) -> usize {
+        let bucket_heap = self.buckets.iter()
+            .map(|b| b.with_locked(|b| b.memory_usage()) - mem::size_of::<ToyHistogram>())
+            .sum::<usize>();
+        mem::size_of::<Self>()
+            + self.buckets.capacity() * mem::size_of::<L>()
+            + bucket_heap
+            + self.assign.heap_usage()
+    }
+}
+
+// The clone has as many buckets as the original, placed in the same way. As all
+// bins end up in a single bucket, the clone does not tell which threads filled
+// the original.
+impl_clone_by_merge!([L: BucketLock, P: ThreadIdProvider, B: BucketAssign]
+                     ThreadBucketizedHistogram<L, P, B> => |this, num_bins| {
+    #[cfg(feature = "numa")]
+    let clone = Self::with_placement(num_bins, this.buckets.len(), this.placement.placement());
+    #[cfg(not(feature = "numa"))]
+    let clone = Self::with_locks(num_bins, this.buckets.len());
+    clone
+});
+
+impl_eq_by_bins!([L: BucketLock, P: ThreadIdProvider, B: BucketAssign]
+                 ThreadBucketizedHistogram<L, P, B>);
+
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::{
+            hint,
+            sync::atomic::{AtomicUsize, Ordering},
+            thread,
+        },
+    };
+
+    // The second thread only fills its bin after the first thread filled its
+    // own, so the first bin never has less hits than the second one at any
+    // point in time. Threads likely end up in different buckets, which a
+    // readout that does not lock them all at once may see in different states.
+    #[test]
+    fn snapshots_are_consistent_across_buckets() {
+        const NUM_FILLS: usize = 10_000;
+        let histogram = ThreadBucketizedHistogram::new(2, 64);
+        let first_fills = AtomicUsize::new(0);
+        let second_fills = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..NUM_FILLS {
+                    histogram.fill(&[0.25]);
+                    first_fills.store(i + 1, Ordering::Release);
+                }
+            });
+            s.spawn(|| {
+                for i in 0..NUM_FILLS {
+                    while first_fills.load(Ordering::Acquire) <= i {
+                        hint::spin_loop();
+                    }
+                    histogram.fill(&[0.75]);
+                    second_fills.store(i + 1, Ordering::Relaxed);
+                }
+            });
+            while second_fills.load(Ordering::Relaxed) < NUM_FILLS {
+                let bins = SyncHistogram::snapshot(&histogram).bins();
+                assert!(bins[0] >= bins[1], "Torn snapshot {:?}", bins);
+            }
+        });
+        assert_eq!(SyncHistogram::snapshot(&histogram).bins(), [NUM_FILLS, NUM_FILLS]);
+    }
+
+    // Thread IDs 0 and 2 share a bucket out of two when mapped by modulo, but
+    // not when buckets are handed out in turn
+    #[test]
+    fn round_robin_balances_sparse_ids() {
+        fn bucket_hits<B: BucketAssign>() -> Vec<usize> {
+            let histogram =
+                ThreadBucketizedHistogram::<Mutex<ToyHistogram>, TlsCounter, B>::with_locks(1, 2);
+            histogram.fill_with_id(&[0.5], ThreadID::fake(0));
+            histogram.fill_with_id(&[0.5; 2], ThreadID::fake(2));
+            histogram.fill_with_id(&[0.5], ThreadID::fake(0));
+            assert_eq!(SyncHistogram::num_hits(&histogram), 4);
+            histogram.buckets.iter().map(|b| b.with_locked(|b| b.num_hits())).collect()
+        }
+        assert_eq!(bucket_hits::<ModuloAssign>(), [4, 0]);
+        assert_eq!(bucket_hits::<RoundRobinAssign>(), [2, 2]);
+    }
+}
@@ -1,11 +1,17 @@
 use {
     crate::{
-        impls::AtomicHistogram,
+        impls::{per_thread::PerThread, reduce, AtomicHistogram, MemoryOrder, RelaxedOrder},
         thread_id::ThreadID,
         traits::SyncHistogram,
     },
-    std::cell::UnsafeCell,
+    allocator_api2::alloc::Global,
+    std::{
+        mem,
+        sync::OnceLock,
+    },
 };
+#[cfg(feature = "numa")]
+use crate::numa::{self, Placement};
 
 // Thread-safe histogram implementation which works by maintaining one histogram
 // per thread. Maximally scalable to many threads, but least memory efficient.
@@ -16,38 +22,206 @@ use {
 // compiler mis-optimization, filling this histogram should be as fast as
 // filling a ToyHistogram sequentially.
 //
-pub struct ThreadLocalHistogram {
-    buckets: Vec<UnsafeCell<AtomicHistogram>>,
+// Buckets are indexed by thread ID, and allocated by the first fill of each
+// thread, so that there is never more than one thread per bucket even when
+// there are more threads than CPUs. Programs which spawn many threads can bound
+// the number of buckets by filling with the IDs of a ThreadRegistry instead.
+//
+// Merged bins go to a bucket of their own, which is only updated atomically,
+// since the thread which merges them may share its ID with a registered thread
+// that fills the bucket of that ID at the same time.
+//
+// The loads and stores of the buckets use the memory orderings of `O`, which
+// are Relaxed by default (see AtomicHistogram).
+//
+pub struct ThreadLocalHistogram<O = RelaxedOrder> {
+    num_bins: usize,
+    buckets: PerThread<AtomicHistogram<Global, O>>,
+    merged: OnceLock<AtomicHistogram<Global, O>>,
+    #[cfg(feature = "numa")]
+    placement: Placement,
 }
 
 impl ThreadLocalHistogram {
     pub fn new(num_bins: usize) -> Self {
+        Self::with_ordering(num_bins)
+    }
+
+    // Choose on which NUMA node the bins of each bucket are allocated
+    #[cfg(feature = "numa")]
+    pub fn with_placement(num_bins: usize, placement: Placement) -> Self {
         Self {
-            buckets: (0..num_cpus::get()).map(|_| UnsafeCell::new(AtomicHistogram::new(num_bins))).collect(),
+            placement,
+            ..Self::new(num_bins)
         }
     }
+}
 
-    fn bucket(&self, id: ThreadID) -> &mut AtomicHistogram {
-        let bucket_ptr = self.buckets[usize::from(id) % self.buckets.len()].get();
-        unsafe { &mut *bucket_ptr }
+impl<O: MemoryOrder> ThreadLocalHistogram<O> {
+    // Use the memory orderings of `O` instead of Relaxed ones
+    pub fn with_ordering(num_bins: usize) -> Self {
+        Self {
+            num_bins,
+            buckets: PerThread::new(),
+            merged: OnceLock::new(),
+            #[cfg(feature = "numa")]
+            placement: Placement::FirstTouch,
+        }
+    }
+
+    // Empty bucket, whose bins are placed as configured
+    fn new_bucket(&self) -> AtomicHistogram<Global, O> {
+        let bucket = AtomicHistogram::with_ordering(self.num_bins);
+        #[cfg(feature = "numa")]
+        numa::place(self.placement, bucket.raw_bins());
+        bucket
+    }
+
+    // Bucket of a thread, which is allocated if needed
+    fn bucket(&self, id: ThreadID) -> &AtomicHistogram<Global, O> {
+        self.buckets.get_or_init(id, || self.new_bucket())
+    }
+
+    // Every bucket which was allocated, including that of merged bins
+    fn all_buckets(&self) -> Vec<&AtomicHistogram<Global, O>> {
+        self.buckets.iter().chain(self.merged.get()).collect()
     }
 }
 
-impl SyncHistogram for ThreadLocalHistogram {
+impl<O: MemoryOrder> SyncHistogram for ThreadLocalHistogram<O> {
     fn fill(&self, values: &[f32]) {
         self.fill_with_id(values, ThreadID::load())
     }
 
     fn fill_with_id(&self, values: &[f32], id: ThreadID) {
-        self.bucket(id).fill_mut_fast(values)
+        self.bucket(id).fill_single_writer(values)
     }
 
     fn num_hits(&self) -> usize {
-        self.buckets.iter()
-            .map(|b| unsafe { <AtomicHistogram as SyncHistogram>::num_hits(&*b.get()) })
-            .sum::<usize>()
+        reduce::count_hits(&self.all_buckets(), self.num_bins)
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let mut result = vec![0; self.num_bins];
+        reduce::sum_bins(&self.all_buckets(), &mut result);
+        result
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.merged.get_or_init(|| self.new_bucket()).merge_bins(bins)
+    }
+
+    // Buckets are only allocated for the threads which use them
+    fn num_threads(&self) -> Option<usize> {
+        Some(self.buckets.iter().count())
+    }
+
+    fn memory_usage(&self) -> usize {
+        let bucket_heap = self.all_buckets()
+            .into_iter()
+            .map(|b| b.memory_usage() - mem::size_of::<AtomicHistogram<Global, O>>())
+            .sum::<usize>();
+        mem::size_of::<Self>() + self.buckets.heap_usage() + bucket_heap
+    }
+}
+
+// Buckets of the clone are allocated with the same placement as the original's
+impl_clone_by_merge!([O: MemoryOrder] ThreadLocalHistogram<O> => |this, num_bins| Self {
+    #[cfg(feature = "numa")]
+    placement: this.placement,
+    ..Self::with_ordering(num_bins)
+});
+
+impl_eq_by_bins!([O: MemoryOrder] ThreadLocalHistogram<O>);
+
+// These tests are kept small enough to run under Miri, which checks that
+// concurrent fills and readouts do not race
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::thread_id::ThreadRegistry,
+        std::thread,
+    };
+
+    // Every thread gets a bucket of its own, however many threads there are
+    #[test]
+    fn concurrent_fill() {
+        const NUM_THREADS: usize = 4;
+        let histogram = ThreadLocalHistogram::new(4);
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| histogram.fill(&[0.1, 0.3, 0.3, 0.9]));
+            }
+        });
+        assert_eq!(histogram.num_hits(), 4 * NUM_THREADS);
+        assert_eq!(histogram.bins(), [NUM_THREADS, 2 * NUM_THREADS, 0, NUM_THREADS]);
+
+        histogram.merge_bins(&[1, 0, 1, 0]);
+        assert_eq!(histogram.bins(), [NUM_THREADS + 1, 2 * NUM_THREADS, 1, NUM_THREADS]);
+        assert!(histogram.memory_usage() >= (NUM_THREADS + 1) * 4 * mem::size_of::<usize>());
+    }
+
+    // Registered threads share the buckets of threads which unregistered, and
+    // bins can be merged meanwhile, whatever the ID of the merging thread
+    #[test]
+    fn registered_fill() {
+        const NUM_THREADS: usize = 4;
+        let histogram = ThreadLocalHistogram::new(4);
+        let registry = ThreadRegistry::new(2);
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| {
+                    let registration = registry.register_blocking();
+                    histogram.fill_with_id(&[0.1, 0.3], registration.id())
+                });
+            }
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| histogram.merge_bins(&[0, 0, 1, 0]));
+            }
+        });
+        assert_eq!(histogram.bins(), [NUM_THREADS, NUM_THREADS, NUM_THREADS, 0]);
+        assert!(histogram.buckets.iter().count() <= 2);
     }
 }
 
-unsafe impl Send for ThreadLocalHistogram {}
-unsafe impl Sync for ThreadLocalHistogram {}
\ No newline at end of file
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use {
+        super::*,
+        loom::{sync::Arc, thread},
+    };
+
+    // Threads which fill concurrently do not interfere
+    #[test]
+    fn concurrent_fill() {
+        loom::model(|| {
+            let histogram = Arc::new(ThreadLocalHistogram::new(2));
+            let threads = [0.25, 0.75].iter()
+                .map(|&value| {
+                    let histogram = histogram.clone();
+                    thread::spawn(move || histogram.fill(&[value, 0.25]))
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(histogram.bins(), [3, 1]);
+        });
+    }
+
+    // Reading the histogram while it is being filled is fine
+    #[test]
+    fn fill_while_reading() {
+        loom::model(|| {
+            let histogram = Arc::new(ThreadLocalHistogram::new(2));
+            let filler = {
+                let histogram = histogram.clone();
+                thread::spawn(move || histogram.fill(&[0.25, 0.75]))
+            };
+            assert!(histogram.num_hits() <= 2);
+            filler.join().unwrap();
+            assert_eq!(histogram.bins(), [1, 1]);
+        });
+    }
+}
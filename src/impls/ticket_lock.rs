@@ -0,0 +1,141 @@
+use {
+    crate::{
+        impls::ToyHistogram,
+        sync::{spin_loop, AtomicUsize, Ordering, UnsafeCell},
+        telemetry,
+        traits::{Histogram, SyncHistogram},
+    },
+    alloc::vec::Vec,
+    core::mem,
+};
+
+// FIFO spinlock, where threads are served in the order in which they arrived
+//
+// Each thread takes a ticket by incrementing a counter, then spins until the
+// ticket being served is its own. Unlike SpinLock and std's Mutex, this
+// prevents a thread from being starved by others that keep grabbing the lock
+// first, at the cost of handing the lock over to a thread which may not be
+// running yet. Under heavy contention, this trades throughput for fairness.
+//
+pub struct TicketLock<T> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+// Number of unsuccessful polls of the lock after which waiting threads yield
+// their CPU, if std is available
+#[cfg(all(feature = "std", not(loom)))]
+const SPINS_BEFORE_YIELD: usize = 1 << 10;
+
+impl<T> TicketLock<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Run a closure with exclusive access to the protected data
+    pub fn with_locked<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let ticket = telemetry::lock(|| {
+            let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+            let mut spins = 0usize;
+            while self.now_serving.load(Ordering::Acquire) != ticket {
+                spin_loop();
+                spins = spins.wrapping_add(1);
+                // When there are more threads than CPUs, the next thread in
+                // line may not be running, and only the OS scheduler can fix
+                // that
+                #[cfg(all(feature = "std", not(loom)))]
+                if spins.is_multiple_of(SPINS_BEFORE_YIELD) {
+                    std::thread::yield_now();
+                }
+            }
+            ticket
+        });
+        let _guard = Serve { now_serving: &self.now_serving, next: ticket.wrapping_add(1) };
+        self.data.with_mut(|data_ptr| f(unsafe { &mut *data_ptr }))
+    }
+}
+
+// Serves the next ticket on scope exit, so that a panicking closure does not
+// leave the lock held forever
+struct Serve<'a> {
+    now_serving: &'a AtomicUsize,
+    next: usize,
+}
+
+impl Drop for Serve<'_> {
+    fn drop(&mut self) {
+        self.now_serving.store(self.next, Ordering::Release);
+    }
+}
+
+// The ticket counters guarantee that only one thread accesses the data at a time
+unsafe impl<T: Send> Send for TicketLock<T> {}
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+impl SyncHistogram for TicketLock<ToyHistogram> {
+    fn fill(&self, values: &[f32]) {
+        self.with_locked(|histogram| histogram.fill_mut(values))
+    }
+
+    fn num_hits(&self) -> usize {
+        self.with_locked(|histogram| histogram.num_hits())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.with_locked(|histogram| histogram.bins())
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        self.with_locked(|histogram| histogram.merge_bins_mut(bins))
+    }
+
+    fn memory_usage(&self) -> usize {
+        let inner = self.with_locked(|histogram| histogram.memory_usage());
+        inner - mem::size_of::<ToyHistogram>() + mem::size_of::<Self>()
+    }
+}
+
+// The protected data is cloned under the lock
+impl<T: Clone> Clone for TicketLock<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.with_locked(|data| data.clone()))
+    }
+}
+
+impl_eq_by_bins!([] TicketLock<ToyHistogram>);
+
+
+// Kept small enough to run under Miri, like the tests of SpinLock
+#[cfg(all(test, feature = "std", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Threads must get the lock in the order in which they took their ticket,
+    // even if they had to yield while waiting
+    #[test]
+    fn fifo_order() {
+        const NUM_THREADS: usize = 4;
+        let lock = TicketLock::new(Vec::new());
+        thread::scope(|s| {
+            lock.with_locked(|_| {
+                for i in 0..NUM_THREADS {
+                    let lock = &lock;
+                    s.spawn(move || lock.with_locked(|order| order.push(i)));
+                    // The lock holds ticket 0, and thread i takes ticket i + 1
+                    while lock.next_ticket.load(Ordering::Relaxed) != i + 2 {
+                        thread::yield_now();
+                    }
+                }
+            });
+        });
+        assert_eq!(lock.with_locked(|order| order.clone()), (0..NUM_THREADS).collect::<Vec<_>>());
+    }
+}
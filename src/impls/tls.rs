@@ -0,0 +1,197 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{AtomicUsize, Ordering},
+        traits::SyncHistogram,
+    },
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        mem,
+        sync::{atomic, Arc, Mutex, Weak},
+    },
+};
+
+// Thread-local histogram using the standard library's thread_local! storage
+//
+// This is how most people would write a thread-local histogram: each thread
+// lazily allocates its own copy of the bins the first time that it fills the
+// histogram, keeps it in thread-local storage, and registers it in a list that
+// readouts sum over. Unlike ThreadLocalHistogram, which indexes an array with
+// thread IDs, this never shares buckets between threads, nor allocates buckets
+// for threads which never fill the histogram. But every fill must look up the
+// bucket of the histogram in a thread-local map, since thread_local! storage
+// is global and there may be several histograms.
+//
+// When a thread exits, the destructor of its thread-local storage merges its
+// buckets into the bins of exited threads of their histograms, and frees them,
+// so that programs which keep spawning short-lived threads do not accumulate
+// buckets. Buckets of threads whose thread-local destructors do not run, such
+// as the main thread on some platforms, are kept until the histogram is
+// dropped.
+//
+pub struct TlsHistogram {
+    id: usize,
+    binner: Binner,
+    registry: Arc<Mutex<Registry>>,
+}
+
+// Buckets of the live threads which filled the histogram, and sum of the
+// buckets of the threads which exited, which is allocated on first use
+struct Registry {
+    buckets: Vec<Arc<Bucket>>,
+    exited: Vec<usize>,
+}
+
+// Bins of a thread, which only this thread writes to. They are atomic because
+// they may be read out by other threads while being filled.
+struct Bucket {
+    bins: Vec<AtomicUsize>,
+}
+
+impl Bucket {
+    fn add(&self, bin: usize, count: usize) {
+        let bin = &self.bins[bin];
+        bin.store(bin.load(Ordering::Relaxed) + count, Ordering::Relaxed);
+    }
+}
+
+// Histogram IDs only need to be unique, so there is no need for loom to track
+// this counter
+static NEXT_ID: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+// Bucket of the active thread, which is merged into the bins of exited threads
+// of its histogram when dropped, if the histogram still exists
+struct LocalBucket {
+    bucket: Arc<Bucket>,
+    registry: Weak<Mutex<Registry>>,
+}
+
+impl Drop for LocalBucket {
+    fn drop(&mut self) {
+        let Some(registry) = self.registry.upgrade() else { return };
+        // Threads may exit while another one panicked with the lock held
+        let mut registry = registry.lock().unwrap_or_else(|e| e.into_inner());
+        registry.buckets.retain(|bucket| !Arc::ptr_eq(bucket, &self.bucket));
+        if registry.exited.is_empty() {
+            registry.exited = vec![0; self.bucket.bins.len()];
+        }
+        for (dst, src) in registry.exited.iter_mut().zip(&self.bucket.bins) {
+            *dst += src.load(Ordering::Relaxed);
+        }
+    }
+}
+
+thread_local! {
+    // Buckets of the active thread, indexed by histogram ID
+    static BUCKETS: RefCell<HashMap<usize, LocalBucket>> = RefCell::new(HashMap::new());
+}
+
+impl TlsHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed),
+            binner: Binner::new(num_bins),
+            registry: Arc::new(Mutex::new(Registry {
+                buckets: Vec::new(),
+                exited: Vec::new(),
+            })),
+        }
+    }
+
+    fn with_bucket<R>(&self, f: impl FnOnce(&Bucket) -> R) -> R {
+        BUCKETS.with(|buckets| {
+            let mut buckets = buckets.borrow_mut();
+            if !buckets.contains_key(&self.id) {
+                // Forget about the buckets of histograms which were dropped
+                buckets.retain(|_, bucket| bucket.registry.strong_count() > 0);
+                let bucket = Arc::new(Bucket {
+                    bins: (0..self.binner.num_bins()).map(|_| AtomicUsize::new(0)).collect(),
+                });
+                self.registry.lock().unwrap().buckets.push(bucket.clone());
+                buckets.insert(self.id, LocalBucket {
+                    bucket,
+                    registry: Arc::downgrade(&self.registry),
+                });
+            }
+            f(&buckets[&self.id].bucket)
+        })
+    }
+}
+
+impl SyncHistogram for TlsHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.with_bucket(|bucket| {
+            self.binner.for_each_bin(&bucket.bins, values, |bin| {
+                bin.store(bin.load(Ordering::Relaxed) + 1, Ordering::Relaxed)
+            })
+        })
+    }
+
+    fn num_hits(&self) -> usize {
+        self.bins().iter().sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let registry = self.registry.lock().unwrap();
+        let mut result = registry.exited.clone();
+        result.resize(self.binner.num_bins(), 0);
+        for bucket in &registry.buckets {
+            for (dst, src) in result.iter_mut().zip(&bucket.bins) {
+                *dst += src.load(Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.binner.num_bins(), "Histogram binning mismatch");
+        self.with_bucket(|bucket| {
+            for (bin, &count) in bins.iter().enumerate() {
+                bucket.add(bin, count);
+            }
+        })
+    }
+
+    fn memory_usage(&self) -> usize {
+        let registry = self.registry.lock().unwrap();
+        mem::size_of::<Self>()
+            + mem::size_of::<Mutex<Registry>>()
+            + registry.buckets.capacity() * mem::size_of::<Arc<Bucket>>()
+            + registry.buckets.len() * (mem::size_of::<Bucket>()
+                                        + self.binner.num_bins() * mem::size_of::<AtomicUsize>())
+            + registry.exited.capacity() * mem::size_of::<usize>()
+    }
+}
+
+impl_clone_by_merge!([] TlsHistogram);
+
+impl_eq_by_bins!([] TlsHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Threads are joined rather than scoped, as scopes do not wait for the
+    // thread-local destructors of their threads to run
+    #[test]
+    fn merge_buckets_of_exited_threads() {
+        let histogram = Arc::new(TlsHistogram::new(2));
+        let threads = (0..3)
+            .map(|_| {
+                let histogram = histogram.clone();
+                thread::spawn(move || histogram.fill(&[0.25, 0.75, 0.75]))
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(histogram.registry.lock().unwrap().buckets.is_empty());
+        histogram.fill(&[0.25]);
+        assert_eq!(SyncHistogram::bins(&*histogram), [4, 6]);
+    }
+}
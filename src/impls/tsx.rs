@@ -0,0 +1,246 @@
+use {
+    crate::{
+        binning::Binner,
+        sync::{spin_loop, AtomicBool, AtomicUsize, Ordering},
+        telemetry,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::Mutex,
+    },
+};
+
+// Histogram filled inside hardware transactions (Intel TSX/RTM), with a mutex
+// as a fallback
+//
+// Bin increments are short and rarely conflict, which is the use case that
+// hardware transactional memory was designed for: each chunk of a batch is
+// inserted inside of a transaction, which only aborts if another thread wrote
+// to the same cache lines in the meantime. After a few aborts, or if the CPU
+// does not support RTM (which includes many recent Intel CPUs where it was
+// disabled by microcode updates), we fall back to locking the mutex.
+//
+// Transactions read the `locked` flag, which the thread holding the mutex sets
+// before touching the bins, so that they abort whenever the lock is taken.
+//
+// Bins are atomics because threads inside of transactions and the lock holder
+// race with each other as far as the Rust memory model is concerned. They are
+// only accessed with plain loads and stores, the hardware takes care of the
+// atomicity of transactions.
+//
+pub struct TsxHistogram {
+    fallback: Mutex<()>,
+    locked: AtomicBool,
+    bins: Vec<AtomicUsize>,
+    use_rtm: bool,
+    binner: Binner,
+}
+
+// Number of values inserted per transaction. Larger chunks amortize the cost of
+// starting a transaction, but are more likely to abort due to conflicts or
+// exceeding the capacity of the transactional write set.
+const CHUNK_LEN: usize = 16;
+
+// Number of transactions that are attempted before falling back to the mutex
+const MAX_ATTEMPTS: usize = 4;
+
+impl TsxHistogram {
+    pub fn new(num_bins: usize) -> Self {
+        Self {
+            fallback: Mutex::new(()),
+            locked: AtomicBool::new(false),
+            bins: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            use_rtm: rtm::is_available(),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Increment the bins, with exclusive write access
+    fn insert(&self, values: &[f32]) {
+        self.binner.for_each_bin(&self.bins, values, |bin| {
+            bin.store(bin.load(Ordering::Relaxed) + 1, Ordering::Relaxed)
+        })
+    }
+
+    // Run some code with the fallback mutex locked, aborting transactions
+    fn locked<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _lock = telemetry::lock(|| self.fallback.lock().unwrap());
+        self.locked.store(true, Ordering::SeqCst);
+        let result = f();
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+
+    // Try to insert a chunk of values inside of a transaction
+    fn try_transaction(&self, chunk: &[f32]) -> bool {
+        for _ in 0..MAX_ATTEMPTS {
+            // Starting a transaction while the lock is held is doomed to abort
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+            match rtm::begin() {
+                Ok(()) => {
+                    if self.locked.load(Ordering::Relaxed) {
+                        rtm::abort();
+                    }
+                    self.insert(chunk);
+                    rtm::end();
+                    return true;
+                }
+                Err(status) if !rtm::may_succeed_on_retry(status) => return false,
+                Err(_) => {}
+            }
+        }
+        false
+    }
+}
+
+impl SyncHistogram for TsxHistogram {
+    fn fill(&self, values: &[f32]) {
+        for chunk in values.chunks(CHUNK_LEN) {
+            if !(self.use_rtm && self.try_transaction(chunk)) {
+                self.locked(|| self.insert(chunk));
+            }
+        }
+    }
+
+    fn num_hits(&self) -> usize {
+        self.locked(|| self.bins.iter().map(|b| b.load(Ordering::Relaxed)).sum::<usize>())
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        self.locked(|| self.bins.iter().map(|b| b.load(Ordering::Relaxed)).collect())
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.bins.len(), "Histogram binning mismatch");
+        self.locked(|| {
+            for (dst, src) in self.bins.iter().zip(bins) {
+                dst.store(dst.load(Ordering::Relaxed) + src, Ordering::Relaxed);
+            }
+        })
+    }
+
+    fn memory_usage(&self) -> usize {
+        mem::size_of::<Self>() + self.bins.capacity() * mem::size_of::<AtomicUsize>()
+    }
+}
+
+// Thin wrappers around the RTM instructions. The corresponding intrinsics are
+// not stable yet, so we use inline assembly instead.
+#[cfg(all(target_arch = "x86_64", not(any(loom, miri))))]
+mod rtm {
+    use std::arch::asm;
+
+    // Status of a transaction which started successfully
+    const STARTED: u32 = !0;
+
+    // Abort status bits which are set when the transaction was aborted by
+    // abort(), and when a retry may succeed
+    const EXPLICIT: u32 = 1 << 0;
+    const RETRY: u32 = 1 << 1;
+
+    pub fn is_available() -> bool {
+        std::is_x86_feature_detected!("rtm")
+    }
+
+    // Start a transaction. If it aborts, execution resumes here with memory and
+    // registers rolled back, and the abort status is returned.
+    #[inline(always)]
+    pub fn begin() -> Result<(), u32> {
+        let status: u32;
+        unsafe {
+            asm!("mov eax, -1", "xbegin 2f", "2:", out("eax") status, options(nostack));
+        }
+        if status == STARTED {
+            Ok(())
+        } else {
+            Err(status)
+        }
+    }
+
+    #[inline(always)]
+    pub fn end() {
+        unsafe { asm!("xend", options(nostack)) }
+    }
+
+    #[inline(always)]
+    pub fn abort() {
+        unsafe { asm!("xabort 0xff", options(nostack)) }
+    }
+
+    // Explicit aborts are only used when the lock is held, and the lock will
+    // eventually be released
+    pub fn may_succeed_on_retry(status: u32) -> bool {
+        status & (EXPLICIT | RETRY) != 0
+    }
+}
+
+// Elsewhere, transactions are never attempted
+#[cfg(not(all(target_arch = "x86_64", not(any(loom, miri)))))]
+mod rtm {
+    pub fn is_available() -> bool {
+        false
+    }
+
+    pub fn begin() -> Result<(), u32> {
+        Err(0)
+    }
+
+    pub fn end() {}
+
+    pub fn abort() {}
+
+    pub fn may_succeed_on_retry(_status: u32) -> bool {
+        false
+    }
+}
+
+impl_clone_by_merge!([] TsxHistogram);
+
+impl_eq_by_bins!([] TsxHistogram);
+
+
+// Transactions are only attempted on CPUs which support RTM, elsewhere these
+// tests only exercise the mutex
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Without RTM, every chunk of a batch goes through the mutex
+    #[test]
+    fn fallback() {
+        const NUM_THREADS: usize = 4;
+        let histogram = TsxHistogram { use_rtm: false, ..TsxHistogram::new(2) };
+        let values = [0.25; 3 * CHUNK_LEN + 1];
+        thread::scope(|s| {
+            for _ in 0..NUM_THREADS {
+                s.spawn(|| histogram.fill(&values));
+            }
+        });
+        assert_eq!(SyncHistogram::bins(&histogram), [NUM_THREADS * values.len(), 0]);
+    }
+
+    // Aborted transactions leave the bins as they were, and are worth retrying
+    // when they were aborted explicitly, as when the lock is taken
+    #[test]
+    fn aborted_transaction() {
+        if !rtm::is_available() {
+            return;
+        }
+        let histogram = TsxHistogram::new(2);
+        match rtm::begin() {
+            Ok(()) => {
+                histogram.insert(&[0.25]);
+                rtm::abort();
+                unreachable!("Explicit aborts should roll back the transaction");
+            }
+            Err(status) => assert!(rtm::may_succeed_on_retry(status)),
+        }
+        assert_eq!(SyncHistogram::bins(&histogram), [0, 0]);
+    }
+}
@@ -0,0 +1,154 @@
+use {
+    crate::{
+        binning::Binner,
+        impls::per_thread::PerThread,
+        sync::{AtomicUsize, Ordering},
+        telemetry,
+        thread_id::ThreadID,
+        traits::SyncHistogram,
+    },
+    std::{
+        mem,
+        sync::{Mutex, MutexGuard},
+    },
+};
+
+// Two-level histogram, where each thread counts hits in a small cache of bins,
+// which spills into a shared histogram of atomic bins
+//
+// Thread-local copies of the bins avoid shared writes, but cost one full copy
+// of the bins per thread, which does not scale to large histograms or to many
+// threads. Here, each thread only gets a fixed number of cache slots instead.
+// A slot counts the hits of one bin, and bins are mapped to slots by their
+// index modulo the number of slots, so that hits on any range of neighboring
+// bins which fits in the cache stay thread-local. When a hit maps to a slot
+// which holds another bin, the count of that other bin is spilled into the
+// shared histogram with one atomic increment, and the slot is reused.
+//
+// Readouts add the counts of every cache to the shared bins. They lock every
+// cache first, so that they cannot miss a count which is being spilled.
+//
+pub struct TwoLevelHistogram {
+    shared: Vec<AtomicUsize>,
+    caches: PerThread<Mutex<Vec<Slot>>>,
+    cache_slots: usize,
+    binner: Binner,
+}
+
+// Slot of a thread's cache
+#[derive(Clone, Copy)]
+struct Slot {
+    bin: usize,
+    count: usize,
+}
+
+impl TwoLevelHistogram {
+    // Number of cache slots of each thread by default
+    pub const DEFAULT_CACHE_SLOTS: usize = 64;
+
+    pub fn new(num_bins: usize) -> Self {
+        Self::with_cache_slots(num_bins, Self::DEFAULT_CACHE_SLOTS)
+    }
+
+    // Histogram where each thread caches `cache_slots` bins, rounded up to the
+    // next power of two
+    pub fn with_cache_slots(num_bins: usize, cache_slots: usize) -> Self {
+        Self {
+            shared: (0..num_bins).map(|_| AtomicUsize::new(0)).collect(),
+            caches: PerThread::new(),
+            cache_slots: cache_slots.max(1).next_power_of_two(),
+            binner: Binner::new(num_bins),
+        }
+    }
+
+    // Cache of a thread, which only this thread fills
+    fn cache(&self, id: ThreadID) -> &Mutex<Vec<Slot>> {
+        self.caches.get_or_init(id, || {
+            Mutex::new(vec![Slot { bin: 0, count: 0 }; self.cache_slots])
+        })
+    }
+
+    // Lock every cache, so that no count moves to the shared bins until the
+    // guards are dropped
+    fn lock_caches(&self) -> Vec<MutexGuard<'_, Vec<Slot>>> {
+        self.caches.iter().map(|cache| cache.lock().unwrap()).collect()
+    }
+}
+
+impl SyncHistogram for TwoLevelHistogram {
+    fn fill(&self, values: &[f32]) {
+        self.fill_with_id(values, ThreadID::load())
+    }
+
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        let mut cache = telemetry::lock(|| self.cache(id).lock().unwrap());
+        let mask = self.cache_slots - 1;
+        let mut spills = 0;
+        self.binner.for_each_index(values, |bin| {
+            let slot = &mut cache[bin & mask];
+            if slot.bin != bin {
+                if slot.count > 0 {
+                    self.shared[slot.bin].fetch_add(slot.count, Ordering::Relaxed);
+                    spills += 1;
+                }
+                *slot = Slot { bin, count: 0 };
+            }
+            slot.count += 1;
+        });
+        telemetry::atomic_rmws(spills)
+    }
+
+    fn num_hits(&self) -> usize {
+        let caches = self.lock_caches();
+        self.shared.iter().map(|bin| bin.load(Ordering::Relaxed)).sum::<usize>()
+            + caches.iter().flat_map(|cache| cache.iter()).map(|slot| slot.count).sum::<usize>()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        let caches = self.lock_caches();
+        let mut bins = self.shared.iter().map(|bin| bin.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        for slot in caches.iter().flat_map(|cache| cache.iter()) {
+            bins[slot.bin] += slot.count;
+        }
+        bins
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        assert_eq!(bins.len(), self.shared.len(), "Histogram binning mismatch");
+        for (dst, &src) in self.shared.iter().zip(bins) {
+            dst.fetch_add(src, Ordering::Relaxed);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        let num_caches = self.caches.iter().count();
+        mem::size_of::<Self>()
+            + self.shared.capacity() * mem::size_of::<AtomicUsize>()
+            + self.caches.heap_usage()
+            + num_caches * (mem::size_of::<Mutex<Vec<Slot>>>()
+                            + self.cache_slots * mem::size_of::<Slot>())
+    }
+}
+
+impl_clone_by_merge!([] TwoLevelHistogram => |this, num_bins| {
+    Self::with_cache_slots(num_bins, this.cache_slots)
+});
+
+impl_eq_by_bins!([] TwoLevelHistogram);
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spill_evicted_bins() {
+        let histogram = TwoLevelHistogram::with_cache_slots(8, 2);
+        histogram.fill(&[0.0, 0.0, 0.2, 0.3, 0.9]);
+        assert_eq!(histogram.shared.iter().map(|bin| bin.load(Ordering::Relaxed)).sum::<usize>(),
+                   3);
+        histogram.merge_bins(&[0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(SyncHistogram::bins(&histogram), [2, 2, 1, 0, 0, 0, 0, 2]);
+        assert_eq!(histogram.num_hits(), 7);
+    }
+}
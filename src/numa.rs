@@ -0,0 +1,143 @@
+// NUMA placement of the buckets of bucketized histograms
+//
+// By default, the kernel allocates memory on the NUMA node of the thread which
+// touches it first. For bucketized histograms, this is usually the thread which
+// builds the histogram, so all buckets end up on the same node, and threads on
+// other nodes pay for remote memory accesses on every fill. Buckets can instead
+// be moved to the node of the first thread which fills them, which is where the
+// other threads that share them are also likely to run if threads are pinned,
+// or be interleaved across every node so that no node is favored.
+//
+// This relies on Linux's memory policies. On other operating systems, placement
+// requests are ignored.
+#![cfg_attr(not(any(feature = "thread_bucketized", feature = "thread_local")), allow(dead_code))]
+
+use std::mem;
+#[cfg(feature = "thread_bucketized")]
+use std::sync::Once;
+
+// Where the bins of each bucket are allocated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    // On the node of the thread which touches them first (usually the thread
+    // which builds the histogram)
+    #[default]
+    FirstTouch,
+
+    // On the node of the first thread which fills the bucket
+    Local,
+
+    // Interleaved page by page across every node which we may allocate from
+    Interleaved,
+}
+
+// Placement of each bucket of a histogram, which happens on first use
+#[cfg(feature = "thread_bucketized")]
+pub(crate) struct BucketPlacement {
+    placement: Placement,
+    placed: Vec<Once>,
+}
+
+#[cfg(feature = "thread_bucketized")]
+impl BucketPlacement {
+    pub(crate) fn new(placement: Placement, num_buckets: usize) -> Self {
+        Self {
+            placement,
+            placed: (0..num_buckets).map(|_| Once::new()).collect(),
+        }
+    }
+
+    pub(crate) fn placement(&self) -> Placement {
+        self.placement
+    }
+
+    // Move the bins of a bucket where they belong, if not done yet
+    pub(crate) fn place<T>(&self, bucket: usize, bins: &[T]) {
+        if self.placement == Placement::FirstTouch {
+            return;
+        }
+        self.placed[bucket].call_once(|| place(self.placement, bins));
+    }
+}
+
+// Move some bins where they belong. This is a best effort: if the kernel
+// refuses, pages are left where they are.
+pub(crate) fn place<T>(placement: Placement, bins: &[T]) {
+    let _ = sys::place(bins.as_ptr().cast(), mem::size_of_val(bins), placement);
+}
+
+#[cfg(all(target_os = "linux", not(miri)))]
+mod sys {
+    use {
+        super::Placement,
+        crate::thread_id::NumaNode,
+        std::{io, ptr},
+    };
+
+    // Memory policies and flags, from linux/mempolicy.h
+    const MPOL_PREFERRED: libc::c_int = 1;
+    const MPOL_INTERLEAVE: libc::c_int = 3;
+    const MPOL_F_MEMS_ALLOWED: libc::c_ulong = 1 << 2;
+    const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+    // Node masks large enough for any machine that Linux supports by default
+    const MASK_BITS: usize = libc::c_ulong::BITS as usize;
+    const MAX_NODES: usize = 1024;
+    type NodeMask = [libc::c_ulong; MAX_NODES / MASK_BITS];
+
+    // Apply a placement to the pages which overlap with a range of memory.
+    // Placing whole pages also moves neighboring data, but for histograms that
+    // are smaller than a page, this does not matter much.
+    pub fn place(start: *const u8, len: usize, placement: Placement) -> io::Result<()> {
+        let (mode, mask) = match placement {
+            Placement::FirstTouch => return Ok(()),
+            Placement::Local => {
+                let mut mask: NodeMask = [0; MAX_NODES / MASK_BITS];
+                let node = usize::from(NumaNode::load());
+                mask[node / MASK_BITS] |= 1 << (node % MASK_BITS);
+                (MPOL_PREFERRED, mask)
+            }
+            Placement::Interleaved => (MPOL_INTERLEAVE, allowed_nodes()?),
+        };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let page_start = start as usize / page_size * page_size;
+        let page_end = (start as usize + len).div_ceil(page_size) * page_size;
+        // Safe because mbind only changes where the pages are, not their contents
+        let result = unsafe {
+            libc::syscall(libc::SYS_mbind,
+                          page_start,
+                          page_end - page_start,
+                          mode,
+                          mask.as_ptr(),
+                          MAX_NODES + 1,
+                          MPOL_MF_MOVE)
+        };
+        if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+
+    // NUMA nodes which the current thread may allocate memory from
+    fn allowed_nodes() -> io::Result<NodeMask> {
+        let mut mask: NodeMask = [0; MAX_NODES / MASK_BITS];
+        let result = unsafe {
+            libc::syscall(libc::SYS_get_mempolicy,
+                          ptr::null_mut::<libc::c_int>(),
+                          mask.as_mut_ptr(),
+                          MAX_NODES + 1,
+                          ptr::null_mut::<libc::c_void>(),
+                          MPOL_F_MEMS_ALLOWED)
+        };
+        if result == 0 { Ok(mask) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", not(miri))))]
+mod sys {
+    use {
+        super::Placement,
+        std::io,
+    };
+
+    pub fn place(_start: *const u8, _len: usize, _placement: Placement) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "NUMA placement requires Linux"))
+    }
+}
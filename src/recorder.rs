@@ -0,0 +1,124 @@
+// Recorder for the `metrics` facade, which backs its histograms with ours
+//
+// Services which report their measurements through the macros of the metrics
+// crate (`histogram!("latency").record(...)`) can install a HistogramRecorder
+// as their recorder, so that the strategy which won the benchmarks does the
+// synchronization of their histograms. One histogram is built per key, by a
+// closure which receives the key, and every value recorded under that key
+// fills it. Values are filled as they are, so histograms over other ranges than
+// [0, 1[ should be built as ExponentialHistogram or rescale them otherwise.
+//
+// Only histograms are recorded: counters and gauges do not need more than one
+// atomic variable, so they are left to other recorders and are no-ops here, as
+// are metric descriptions.
+
+use {
+    crate::traits::SyncHistogram,
+    metrics::{
+        Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString,
+        Unit,
+    },
+    std::{
+        collections::HashMap,
+        iter,
+        sync::{Arc, Mutex},
+    },
+};
+
+pub struct HistogramRecorder<H, F> {
+    make_histogram: F,
+    histograms: Mutex<HashMap<Key, Arc<MetricsHistogram<H>>>>,
+}
+
+impl<H, F> HistogramRecorder<H, F>
+    where H: SyncHistogram + Send + 'static, F: Fn(&Key) -> H
+{
+    pub fn new(make_histogram: F) -> Self {
+        Self {
+            make_histogram,
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Histogram which values recorded under this key went to, if any
+    pub fn histogram(&self, key: &Key) -> Option<Arc<MetricsHistogram<H>>> {
+        self.histograms.lock().unwrap().get(key).cloned()
+    }
+
+    // Every histogram registered so far, e.g. to export them
+    pub fn histograms(&self) -> Vec<(Key, Arc<MetricsHistogram<H>>)> {
+        self.histograms.lock().unwrap()
+            .iter()
+            .map(|(key, histogram)| (key.clone(), histogram.clone()))
+            .collect()
+    }
+}
+
+// Registration happens once per call site of the metrics macros, or once per
+// handle for handles which are kept around, so it does not need to be fast
+impl<H, F> Recorder for HistogramRecorder<H, F>
+    where H: SyncHistogram + Send + 'static, F: Fn(&Key) -> H
+{
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, _key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        Counter::noop()
+    }
+
+    fn register_gauge(&self, _key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(key.clone())
+            .or_insert_with(|| Arc::new(MetricsHistogram((self.make_histogram)(key))));
+        Histogram::from_arc(histogram.clone())
+    }
+}
+
+// Histogram of a HistogramRecorder, which the handles of the metrics crate fill
+pub struct MetricsHistogram<H>(H);
+
+impl<H> MetricsHistogram<H> {
+    pub fn inner(&self) -> &H {
+        &self.0
+    }
+}
+
+impl<H: SyncHistogram> HistogramFn for MetricsHistogram<H> {
+    fn record(&self, value: f64) {
+        self.0.fill_one(value as f32)
+    }
+
+    fn record_many(&self, value: f64, count: usize) {
+        self.0.fill_iter(iter::repeat_n(value as f32, count))
+    }
+}
+
+
+#[cfg(all(test, feature = "atomic", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::impls::AtomicHistogram,
+    };
+
+    #[test]
+    fn record_through_facade() {
+        let recorder = HistogramRecorder::new(|_: &Key| AtomicHistogram::new(4));
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!("occupancy").record(0.1);
+            metrics::histogram!("occupancy").record_many(0.9, 3);
+            metrics::histogram!("pileup").record(0.6);
+        });
+        let bins = |name| recorder.histogram(&Key::from_name(name)).unwrap().inner().bins();
+        assert_eq!(bins("occupancy"), [1, 0, 0, 3]);
+        assert_eq!(bins("pileup"), [0, 0, 1, 0]);
+        assert_eq!(recorder.histograms().len(), 2);
+    }
+}
@@ -0,0 +1,70 @@
+// Bar chart rendering of bins as text, for eyeballing a distribution in a
+// terminal
+//
+// Each bin gets one row, labeled with the lower edge of the bin on the [0, 1[
+// axis and followed by its contents. Bars are scaled so that the fullest bin
+// spans the whole width of the chart. With a logarithmic scale, bars are
+// proportional to log(1 + contents) instead, so that bins which are orders of
+// magnitude emptier than the fullest one remain visible.
+//
+// Logarithms are approximated from the position of the leading bit, as this
+// must work without std and a chart does not need more precision.
+
+use core::fmt::{self, Write};
+
+// Width of the bars of a chart when none is specified
+pub(crate) const DEFAULT_WIDTH: usize = 60;
+
+pub(crate) fn write_chart(out: &mut impl Write,
+                          bins: &[usize],
+                          width: usize,
+                          log_scale: bool) -> fmt::Result {
+    let scale = |count: usize| if log_scale { log2_1p(count) } else { count as f64 };
+    let max_count = bins.iter().copied().max().unwrap_or(0);
+    let max = scale(max_count);
+    let count_width = max_count.checked_ilog10().map_or(1, |digits| digits as usize + 1);
+    for (index, &count) in bins.iter().enumerate() {
+        let length = if max > 0.0 {
+            // Rounded to the nearest column, without std
+            (scale(count) / max * width as f64 + 0.5) as usize
+        } else {
+            0
+        };
+        write!(out, "{:.3} |", index as f64 / bins.len() as f64)?;
+        for column in 0..width {
+            out.write_char(if column < length { '#' } else { ' ' })?;
+        }
+        writeln!(out, " {:>count_width$}", count)?;
+    }
+    Ok(())
+}
+
+// Approximation of log2(1 + x), exact on powers of two and linearly
+// interpolated in between
+fn log2_1p(x: usize) -> f64 {
+    let x = x.saturating_add(1);
+    let exponent = x.ilog2();
+    exponent as f64 + x as f64 / (1usize << exponent) as f64 - 1.0
+}
+
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use {super::*, alloc::string::String};
+
+    #[test]
+    fn scale_bars_to_fullest_bin() {
+        let mut chart = String::new();
+        write_chart(&mut chart, &[2, 4, 0, 1], 4, false).unwrap();
+        assert_eq!(chart, "0.000 |##   2\n\
+                           0.250 |#### 4\n\
+                           0.500 |     0\n\
+                           0.750 |#    1\n");
+        chart.clear();
+        write_chart(&mut chart, &[0, 1, 3, 1023], 10, true).unwrap();
+        assert_eq!(chart, "0.000 |              0\n\
+                           0.250 |#             1\n\
+                           0.500 |##            3\n\
+                           0.750 |########## 1023\n");
+    }
+}
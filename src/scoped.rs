@@ -0,0 +1,80 @@
+// Privatize, work, merge
+//
+// A common way to fill a shared histogram is for each thread to fill a private
+// histogram of its own, then merge it into the shared one once it's done. The
+// shared histogram is then only synchronized once per thread instead of once
+// per batch, and fills need neither a thread ID nor a bucket lookup. This works
+// with any thread-safe histogram, at the expense of the private bins not being
+// visible to readers of the shared histogram until they are merged.
+
+use crate::{
+    impls::ToyHistogram,
+    trace,
+    traits::{Histogram, SyncHistogram},
+};
+
+// Handle which fills a private copy of the bins of a histogram, and merges it
+// into that histogram when it is flushed or dropped
+pub struct ScopedFiller<'a, H: SyncHistogram + ?Sized> {
+    target: &'a H,
+    private: ToyHistogram,
+}
+
+impl<'a, H: SyncHistogram + ?Sized> ScopedFiller<'a, H> {
+    // The binning of the target histogram is found by reading it out once
+    pub fn new(target: &'a H) -> Self {
+        let num_bins = target.bins().len();
+        Self::with_bins(target, num_bins)
+    }
+
+    // Skip reading out the target histogram when its number of bins is known.
+    // Flushing will panic if this does not match the target histogram.
+    pub fn with_bins(target: &'a H, num_bins: usize) -> Self {
+        Self {
+            target,
+            private: ToyHistogram::new(num_bins),
+        }
+    }
+
+    pub fn fill(&mut self, values: &[f32]) {
+        self.private.fill_mut(values)
+    }
+
+    // Merge the values inserted so far into the target histogram
+    pub fn flush(&mut self) {
+        if self.private.num_hits() == 0 {
+            return;
+        }
+        trace::merge::<H, _>(self.private.bins.len(), || self.target.merge_bins(&self.private.bins));
+        self.private.bins.iter_mut().for_each(|bin| *bin = 0);
+    }
+}
+
+impl<H: SyncHistogram + ?Sized> Drop for ScopedFiller<'_, H> {
+    fn drop(&mut self) {
+        self.flush()
+    }
+}
+
+
+#[cfg(all(test, feature = "atomic", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        crate::impls::AtomicHistogram,
+    };
+
+    #[test]
+    fn merge_on_flush_and_drop() {
+        let histogram = AtomicHistogram::new(2);
+        {
+            let mut filler = histogram.fill_scope();
+            filler.fill(&[0.1, 0.9]);
+            assert_eq!(SyncHistogram::num_hits(&histogram), 0);
+            filler.flush();
+            assert_eq!(SyncHistogram::bins(&histogram), [1, 1]);
+            filler.fill(&[0.9]);
+        }
+        assert_eq!(SyncHistogram::bins(&histogram), [1, 2]);
+    }
+}
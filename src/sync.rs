@@ -0,0 +1,80 @@
+// Synchronization primitives used by the lock-free implementations
+//
+// When building with `--cfg loom`, these are replaced by their loom
+// counterparts, so that the loom tests can explore every interleaving of the
+// atomic operations and check accesses to UnsafeCells for data races.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::Ordering;
+#[cfg(all(loom, any(feature = "std", feature = "atomic", feature = "padded_atomic",
+                    feature = "narrow_atomic", feature = "ticket_lock")))]
+pub(crate) use loom::sync::atomic::AtomicUsize;
+#[cfg(all(loom, feature = "narrow_atomic"))]
+pub(crate) use loom::sync::atomic::{AtomicU16, AtomicU32};
+#[cfg(all(loom, any(feature = "std", feature = "mcs_lock", feature = "ring_buffer",
+                    feature = "spinlock", feature = "tsx")))]
+pub(crate) use loom::sync::atomic::AtomicBool;
+#[cfg(all(loom, any(feature = "flat_combining", feature = "mcs_lock")))]
+pub(crate) use loom::sync::atomic::AtomicPtr;
+#[cfg(all(loom, any(feature = "double_buffer", feature = "epoch", feature = "flat_combining",
+                    feature = "mcs_lock", feature = "ring_buffer", feature = "seqlock",
+                    feature = "spinlock", feature = "ticket_lock", feature = "tsx")))]
+pub(crate) use loom::hint::spin_loop;
+#[cfg(all(loom, any(feature = "ring_buffer", feature = "seqlock")))]
+pub(crate) use loom::sync::atomic::fence;
+#[cfg(all(loom, any(feature = "mcs_lock", feature = "ring_buffer", feature = "spinlock",
+                    feature = "ticket_lock")))]
+pub(crate) use loom::cell::UnsafeCell;
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::Ordering;
+#[cfg(all(not(loom), any(feature = "std", feature = "atomic", feature = "padded_atomic",
+                         feature = "narrow_atomic", feature = "ticket_lock")))]
+pub(crate) use core::sync::atomic::AtomicUsize;
+#[cfg(all(not(loom), feature = "narrow_atomic"))]
+pub(crate) use core::sync::atomic::{AtomicU16, AtomicU32};
+#[cfg(all(not(loom), any(feature = "std", feature = "mcs_lock", feature = "ring_buffer",
+                         feature = "spinlock", feature = "tsx")))]
+pub(crate) use core::sync::atomic::AtomicBool;
+#[cfg(all(not(loom), any(feature = "flat_combining", feature = "mcs_lock")))]
+pub(crate) use core::sync::atomic::AtomicPtr;
+#[cfg(all(not(loom), any(feature = "double_buffer", feature = "epoch", feature = "flat_combining",
+                         feature = "mcs_lock", feature = "ring_buffer", feature = "seqlock",
+                         feature = "spinlock", feature = "ticket_lock", feature = "tsx")))]
+pub(crate) use core::hint::spin_loop;
+#[cfg(all(not(loom), any(feature = "ring_buffer", feature = "seqlock")))]
+pub(crate) use core::sync::atomic::fence;
+
+// Wrapper around core::cell::UnsafeCell with the closure-based API of loom's
+// UnsafeCell, which can only track accesses that are scoped this way
+#[cfg(all(not(loom), any(feature = "mcs_lock", feature = "ring_buffer", feature = "spinlock",
+                         feature = "ticket_lock")))]
+#[derive(Debug)]
+pub(crate) struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
+
+#[cfg(all(not(loom), any(feature = "mcs_lock", feature = "ring_buffer", feature = "spinlock",
+                         feature = "ticket_lock")))]
+impl<T> UnsafeCell<T> {
+    pub(crate) fn new(data: T) -> Self {
+        Self(core::cell::UnsafeCell::new(data))
+    }
+
+    #[cfg(feature = "ring_buffer")]
+    pub(crate) fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    pub(crate) fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
+
+// Access to the value of an atomic through an exclusive reference, which loom
+// only provides through a closure
+#[cfg(feature = "atomic")]
+pub(crate) fn atomic_with_mut<R>(atomic: &mut AtomicUsize, f: impl FnOnce(&mut usize) -> R) -> R {
+    #[cfg(loom)]
+    return atomic.with_mut(f);
+    #[cfg(not(loom))]
+    f(atomic.get_mut())
+}
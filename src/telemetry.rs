@@ -0,0 +1,204 @@
+// Contention telemetry, which tells why a strategy slows down under contention
+//
+// When the "telemetry" feature is enabled, the fills of histograms count their
+// lock acquisitions, the time they spent acquiring locks, the compare-and-swap
+// operations they had to retry and the other atomic read-modify-write
+// operations they performed. Without the feature, recording compiles down to
+// nothing and no counts are available.
+//
+// Events are counted in per-thread storage, so that counting does not add
+// contention of its own, and summed up when a snapshot is taken. Lock wait
+// times include the cost of acquiring an uncontended lock and of reading the
+// clock twice, so they are best compared across strategies and thread counts
+// rather than taken at face value. Only fills are instrumented, except in the
+// spinlock, ticket lock and MCS lock, which count every acquisition.
+
+use crate::trace;
+
+// Events counted since the program started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub lock_acquisitions: u64,
+    pub lock_wait_ns: u64,
+    pub cas_retries: u64,
+    pub atomic_rmws: u64,
+}
+
+impl Counts {
+    // Events which occurred between an earlier snapshot and this one
+    pub fn since(&self, earlier: &Counts) -> Counts {
+        Counts {
+            lock_acquisitions: self.lock_acquisitions - earlier.lock_acquisitions,
+            lock_wait_ns: self.lock_wait_ns - earlier.lock_wait_ns,
+            cas_retries: self.cas_retries - earlier.cas_retries,
+            atomic_rmws: self.atomic_rmws - earlier.atomic_rmws,
+        }
+    }
+}
+
+// Sum of the events counted by every thread so far, if telemetry is enabled
+pub fn snapshot() -> Option<Counts> {
+    #[cfg(all(feature = "telemetry", not(loom)))]
+    return Some(recorder::snapshot());
+    #[cfg(not(all(feature = "telemetry", not(loom))))]
+    None
+}
+
+// Acquire a lock with `acquire`, counting the acquisition and its duration
+#[inline]
+#[allow(dead_code)]
+pub(crate) fn lock<G>(acquire: impl FnOnce() -> G) -> G {
+    #[cfg(all(feature = "telemetry", not(loom)))]
+    {
+        let start = std::time::Instant::now();
+        let guard = trace::lock(acquire);
+        let wait_ns = start.elapsed().as_nanos() as u64;
+        recorder::record(|counts| {
+            counts.lock_acquisitions += 1;
+            counts.lock_wait_ns += wait_ns;
+        });
+        guard
+    }
+    #[cfg(not(all(feature = "telemetry", not(loom))))]
+    trace::lock(acquire)
+}
+
+// Count compare-and-swap operations which failed and had to be retried
+#[inline]
+#[allow(dead_code, unused_variables)]
+pub(crate) fn cas_retries(count: usize) {
+    #[cfg(all(feature = "telemetry", not(loom)))]
+    recorder::record(|counts| counts.cas_retries += count as u64)
+}
+
+// Count atomic read-modify-write operations other than the above
+#[inline]
+#[allow(dead_code, unused_variables)]
+pub(crate) fn atomic_rmws(count: usize) {
+    #[cfg(all(feature = "telemetry", not(loom)))]
+    recorder::record(|counts| counts.atomic_rmws += count as u64)
+}
+
+#[cfg(all(feature = "telemetry", not(loom)))]
+mod recorder {
+    use {
+        super::Counts,
+        std::{
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                Arc, Mutex,
+            },
+            vec::Vec,
+        },
+    };
+
+    // Counters of one thread, which only that thread writes to
+    #[derive(Default)]
+    struct ThreadCounters {
+        lock_acquisitions: AtomicU64,
+        lock_wait_ns: AtomicU64,
+        cas_retries: AtomicU64,
+        atomic_rmws: AtomicU64,
+    }
+
+    impl ThreadCounters {
+        fn load(&self) -> Counts {
+            Counts {
+                lock_acquisitions: self.lock_acquisitions.load(Ordering::Relaxed),
+                lock_wait_ns: self.lock_wait_ns.load(Ordering::Relaxed),
+                cas_retries: self.cas_retries.load(Ordering::Relaxed),
+                atomic_rmws: self.atomic_rmws.load(Ordering::Relaxed),
+            }
+        }
+
+        // No RMW is needed as there is a single writer
+        fn store(&self, counts: Counts) {
+            self.lock_acquisitions.store(counts.lock_acquisitions, Ordering::Relaxed);
+            self.lock_wait_ns.store(counts.lock_wait_ns, Ordering::Relaxed);
+            self.cas_retries.store(counts.cas_retries, Ordering::Relaxed);
+            self.atomic_rmws.store(counts.atomic_rmws, Ordering::Relaxed);
+        }
+    }
+
+    // Counters of live threads, and sum of those of exited threads
+    struct Registry {
+        live: Vec<Arc<ThreadCounters>>,
+        exited: Counts,
+    }
+
+    static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+        live: Vec::new(),
+        exited: Counts { lock_acquisitions: 0, lock_wait_ns: 0, cas_retries: 0, atomic_rmws: 0 },
+    });
+
+    // Registers the counters of a thread on first use, and folds them into
+    // those of exited threads when it exits
+    struct Registration(Arc<ThreadCounters>);
+
+    impl Registration {
+        fn new() -> Self {
+            let counters = Arc::new(ThreadCounters::default());
+            REGISTRY.lock().unwrap().live.push(counters.clone());
+            Self(counters)
+        }
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            let mut registry = REGISTRY.lock().unwrap();
+            registry.live.retain(|counters| !Arc::ptr_eq(counters, &self.0));
+            registry.exited = add(&registry.exited, &self.0.load());
+        }
+    }
+
+    thread_local! {
+        static COUNTERS: Registration = Registration::new();
+    }
+
+    pub(super) fn record(update: impl FnOnce(&mut Counts)) {
+        // Events which occur while the thread is exiting are not counted
+        let _ = COUNTERS.try_with(|registration| {
+            let mut counts = registration.0.load();
+            update(&mut counts);
+            registration.0.store(counts);
+        });
+    }
+
+    pub(super) fn snapshot() -> Counts {
+        let registry = REGISTRY.lock().unwrap();
+        registry.live.iter().fold(registry.exited, |sum, counters| add(&sum, &counters.load()))
+    }
+
+    fn add(a: &Counts, b: &Counts) -> Counts {
+        Counts {
+            lock_acquisitions: a.lock_acquisitions + b.lock_acquisitions,
+            lock_wait_ns: a.lock_wait_ns + b.lock_wait_ns,
+            cas_retries: a.cas_retries + b.cas_retries,
+            atomic_rmws: a.atomic_rmws + b.atomic_rmws,
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "telemetry", not(loom)))]
+mod tests {
+    use {
+        super::*,
+        std::thread,
+    };
+
+    // Other tests may record events concurrently, so only lower bounds hold
+    #[test]
+    fn count_events_of_exited_threads() {
+        let before = snapshot().unwrap();
+        thread::spawn(|| {
+            lock(|| ());
+            cas_retries(2);
+            atomic_rmws(3);
+        }).join().unwrap();
+        let events = snapshot().unwrap().since(&before);
+        assert!(events.lock_acquisitions >= 1);
+        assert!(events.cas_retries >= 2);
+        assert!(events.atomic_rmws >= 3);
+    }
+}
@@ -1,15 +1,166 @@
 // For efficient thread-local strategies, we need to give each thread a
 // numerical identifier. This small module encapsulates that.
+//
+// Identifiers are handed out on first use, and given back when their thread
+// exits, so that threads which are spawned later can reuse them. The smallest
+// free identifier is always picked first, which keeps identifiers dense in
+// processes that keep spawning and joining threads: they never exceed the
+// largest number of threads which were alive at the same time, and strategies
+// which index buckets with them do not end up with ever-growing storage or
+// skewed bucket assignment.
+//
+// Identifiers are given back by a thread-local destructor, so they must not be
+// used by the destructors of other thread-locals, which may run after it.
+//
+// With the "rayon_thread_ids" feature, the worker threads of rayon thread pools
+// ask for their index in the pool as an identifier. A pool's threads then get
+// identifiers 0 to N-1, which bucketized strategies map to buckets exactly,
+// instead of getting whichever identifiers were free at the time. Identifiers
+// must stay unique, so a worker whose index was already taken by another
+// thread, such as the main thread or a worker of another pool, gets the
+// smallest free identifier which is not below the size of its pool instead.
+//
+// Strategies which map threads to a fixed number of shared buckets can also
+// key them by other values, which ThreadIdProvider implementations return as a
+// BucketKey. These trade the density and uniqueness of the above identifiers
+// for lower lookup costs or a closer match with the hardware, and how buckets
+// end up being shared depends on these properties. As keys are neither unique
+// nor dense, they are a separate type from ThreadID, which strategies that give
+// each thread a bucket of its own rely on being both.
+//
+// Such strategies allocate one bucket per identifier, so the number of buckets
+// grows with the number of threads which are alive at the same time. Programs
+// which need to bound it can instead get identifiers from a ThreadRegistry,
+// which only hands out identifiers below a configured bound.
+//
+// Which identifiers are in use can be queried with live_ids(), and strategies
+// can record which bucket keys filled them in an IdSet, so that they only
+// aggregate the buckets which were used and can tell how many threads did so.
 
+use {
+    crate::sync::{AtomicBool, AtomicUsize, Ordering},
+    std::{
+        array,
+        marker::PhantomData,
+        sync::OnceLock,
+    },
+};
+#[cfg(not(loom))]
 use std::{
-    marker::PhantomData,
-    sync::atomic::{AtomicUsize, Ordering},
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    hash::{Hash, Hasher},
+    error::Error,
+    fmt,
+    sync::{Condvar, Mutex},
+    thread,
 };
 
-static THREAD_ID_CTR: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(loom))]
+static ALLOCATOR: Mutex<IdAllocator> = Mutex::new(IdAllocator::new());
 
+#[cfg(not(loom))]
 thread_local! {
-    pub static THREAD_ID: usize = THREAD_ID_CTR.fetch_add(1, Ordering::Relaxed);
+    static THREAD_ID: OwnedID = OwnedID::allocate();
+}
+
+// Identifiers at and above `next` were never handed out, those in `free` were
+// given back or skipped
+#[cfg(not(loom))]
+struct IdAllocator {
+    next: usize,
+    free: BTreeSet<usize>,
+}
+
+#[cfg(not(loom))]
+impl IdAllocator {
+    const fn new() -> Self {
+        Self {
+            next: 0,
+            free: BTreeSet::new(),
+        }
+    }
+
+    fn allocate(&mut self) -> usize {
+        self.allocate_from(0)
+    }
+
+    // Smallest free identifier which is not below `min`
+    fn allocate_from(&mut self, min: usize) -> usize {
+        if let Some(&id) = self.free.range(min..).next() {
+            self.free.remove(&id);
+            return id;
+        }
+        if self.next < min {
+            self.free.extend(self.next..min);
+            self.next = min;
+        }
+        self.next += 1;
+        self.next - 1
+    }
+
+    // Hand out `preferred` if it is free, otherwise the smallest free
+    // identifier which is not below `fallback_min`
+    #[cfg_attr(not(feature = "rayon_thread_ids"), allow(dead_code))]
+    fn claim(&mut self, preferred: usize, fallback_min: usize) -> usize {
+        if self.free.remove(&preferred) {
+            preferred
+        } else if preferred >= self.next {
+            self.free.extend(self.next..preferred);
+            self.next = preferred + 1;
+            preferred
+        } else {
+            self.allocate_from(fallback_min)
+        }
+    }
+
+    fn release(&mut self, id: usize) {
+        self.free.insert(id);
+    }
+}
+
+// Identifiers which are currently held by a live thread, in increasing order
+#[cfg(not(loom))]
+pub fn live_ids() -> Vec<usize> {
+    let allocator = ALLOCATOR.lock().unwrap();
+    (0..allocator.next).filter(|id| !allocator.free.contains(id)).collect()
+}
+
+// Identifier of the current thread, which is given back when it exits
+#[cfg(not(loom))]
+struct OwnedID(usize);
+
+#[cfg(not(loom))]
+impl OwnedID {
+    fn allocate() -> Self {
+        let mut allocator = ALLOCATOR.lock().unwrap();
+        #[cfg(feature = "rayon_thread_ids")]
+        if let Some(index) = rayon::current_thread_index() {
+            return Self(allocator.claim(index, rayon::current_num_threads()));
+        }
+        Self(allocator.allocate())
+    }
+}
+
+#[cfg(not(loom))]
+impl Drop for OwnedID {
+    fn drop(&mut self) {
+        // Threads may exit while another one panicked with the lock held
+        let mut allocator = ALLOCATOR.lock().unwrap_or_else(|e| e.into_inner());
+        allocator.release(self.0)
+    }
+}
+
+// Loom runs its threads on a single OS thread and needs thread IDs to be
+// allocated in the same way in every execution of a model, so they are not
+// recycled there
+#[cfg(loom)]
+loom::lazy_static! {
+    static ref THREAD_ID_CTR: AtomicUsize = AtomicUsize::new(0);
+}
+
+#[cfg(loom)]
+loom::thread_local! {
+    static THREAD_ID: usize = THREAD_ID_CTR.fetch_add(1, Ordering::Relaxed);
 }
 
 #[derive(Clone, Copy)]
@@ -20,10 +171,25 @@ pub struct ThreadID {
 
 impl ThreadID {
     pub fn load() -> Self {
-        THREAD_ID.with(|&id| Self {
+        #[cfg(not(loom))]
+        let id = THREAD_ID.with(|id| id.0);
+        #[cfg(loom)]
+        let id = THREAD_ID.with(|&id| id);
+        Self::new(id)
+    }
+
+    fn new(id: usize) -> Self {
+        Self {
             id,
             _not_sendable_between_threads: PhantomData,
-        })
+        }
+    }
+
+    // Made-up ID, for single-threaded tests which need specific IDs. Those may
+    // be held by other threads, which must then not use the same histogram.
+    #[cfg(all(test, feature = "thread_bucketized"))]
+    pub(crate) fn fake(id: usize) -> Self {
+        Self::new(id)
     }
 }
 
@@ -32,3 +198,445 @@ impl From<ThreadID> for usize {
         source.id
     }
 }
+
+// Set of bucket keys, which threads can add their own to concurrently
+//
+// This is a bitmap, which is allocated in segments of exponentially growing
+// size as larger keys are added. Keys which are too large to be worth a bit,
+// such as hashes, are not stored: they make the set give up on listing its
+// contents instead.
+#[cfg_attr(not(feature = "thread_bucketized"), allow(dead_code))]
+pub(crate) struct IdSet {
+    segments: [OnceLock<Box<[AtomicUsize]>>; ID_SET_SEGMENTS],
+    overflowed: AtomicBool,
+}
+
+// Segment s holds the bits of words 2^s - 1 to 2^(s+1) - 2
+#[cfg_attr(not(feature = "thread_bucketized"), allow(dead_code))]
+const ID_SET_SEGMENTS: usize = 16;
+#[cfg_attr(not(feature = "thread_bucketized"), allow(dead_code))]
+const WORD_BITS: usize = usize::BITS as usize;
+
+#[cfg_attr(not(feature = "thread_bucketized"), allow(dead_code))]
+impl IdSet {
+    pub(crate) fn new() -> Self {
+        Self {
+            segments: array::from_fn(|_| OnceLock::new()),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    // Add a key to the set, which is cheap if it is there already
+    pub(crate) fn insert(&self, key: BucketKey) {
+        let id = usize::from(key);
+        let word = id / WORD_BITS + 1;
+        let segment = word.ilog2() as usize;
+        if segment >= ID_SET_SEGMENTS {
+            self.overflowed.store(true, Ordering::Relaxed);
+            return;
+        }
+        let words = self.segments[segment].get_or_init(|| {
+            (0..1usize << segment).map(|_| AtomicUsize::new(0)).collect()
+        });
+        let word = &words[word - (1 << segment)];
+        let bit = 1 << (id % WORD_BITS);
+        if word.load(Ordering::Relaxed) & bit == 0 {
+            word.fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+
+    // Keys in the set, in increasing order, unless some were too large to be
+    // stored
+    pub(crate) fn iter(&self) -> Option<impl Iterator<Item = usize> + '_> {
+        if self.overflowed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let words = self.segments.iter()
+            .enumerate()
+            .filter_map(|(segment, words)| Some((segment, words.get()?)))
+            .flat_map(|(segment, words)| {
+                words.iter().enumerate().map(move |(offset, word)| {
+                    ((1 << segment) - 1 + offset, word.load(Ordering::Relaxed))
+                })
+            });
+        Some(words.flat_map(|(index, word)| {
+            (0..WORD_BITS).filter(move |bit| word & (1 << bit) != 0)
+                          .map(move |bit| index * WORD_BITS + bit)
+        }))
+    }
+}
+
+// Bounded space of thread identifiers, which threads must register with
+//
+// Registrations hold an identifier below the bound until they are dropped.
+// These identifiers are unique among the registrations of a registry, but not
+// with respect to ThreadID::load() or other registries, so a histogram which
+// is filled with them must only be filled with identifiers from one registry.
+#[cfg(not(loom))]
+pub struct ThreadRegistry {
+    max_threads: usize,
+    allocator: Mutex<IdAllocator>,
+    released: Condvar,
+}
+
+#[cfg(not(loom))]
+impl ThreadRegistry {
+    pub fn new(max_threads: usize) -> Self {
+        Self {
+            max_threads,
+            allocator: Mutex::new(IdAllocator::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn max_threads(&self) -> usize {
+        self.max_threads
+    }
+
+    // Register the current thread, failing if `max_threads` threads are
+    // registered already
+    pub fn register(&self) -> Result<Registration<'_>, RegistryFull> {
+        let mut allocator = self.allocator.lock().unwrap();
+        self.try_allocate(&mut allocator)
+            .map(|id| Registration::new(self, id))
+            .ok_or(RegistryFull { max_threads: self.max_threads })
+    }
+
+    // Same, but waiting for another thread to unregister instead of failing
+    pub fn register_blocking(&self) -> Registration<'_> {
+        let mut allocator = self.allocator.lock().unwrap();
+        loop {
+            if let Some(id) = self.try_allocate(&mut allocator) {
+                return Registration::new(self, id);
+            }
+            allocator = self.released.wait(allocator).unwrap();
+        }
+    }
+
+    fn try_allocate(&self, allocator: &mut IdAllocator) -> Option<usize> {
+        let id = allocator.allocate();
+        if id < self.max_threads {
+            Some(id)
+        } else {
+            allocator.release(id);
+            None
+        }
+    }
+}
+
+// Registration of a thread with a ThreadRegistry, which ends when dropped
+#[cfg(not(loom))]
+pub struct Registration<'registry> {
+    registry: &'registry ThreadRegistry,
+    id: usize,
+    _not_sendable_between_threads: PhantomData<*mut usize>,
+}
+
+#[cfg(not(loom))]
+impl<'registry> Registration<'registry> {
+    fn new(registry: &'registry ThreadRegistry, id: usize) -> Self {
+        Self {
+            registry,
+            id,
+            _not_sendable_between_threads: PhantomData,
+        }
+    }
+
+    // Identifier of the registered thread, which must not be used once the
+    // registration has been dropped
+    pub fn id(&self) -> ThreadID {
+        ThreadID::new(self.id)
+    }
+}
+
+#[cfg(not(loom))]
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut allocator = self.registry.allocator.lock().unwrap_or_else(|e| e.into_inner());
+        allocator.release(self.id);
+        self.registry.released.notify_one();
+    }
+}
+
+// Error returned when registering with a ThreadRegistry which is full
+#[cfg(not(loom))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegistryFull {
+    pub max_threads: usize,
+}
+
+#[cfg(not(loom))]
+impl fmt::Display for RegistryFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread registry is full ({} threads registered)", self.max_threads)
+    }
+}
+
+#[cfg(not(loom))]
+impl Error for RegistryFull {}
+
+// Key which a ThreadIdProvider maps the current thread to a bucket with
+//
+// Unlike a ThreadID, a key may be shared by several live threads, such as all
+// the threads which run on a CPU, and may be arbitrarily large, such as a hash.
+// It can therefore only select storage which threads share under
+// synchronization, and not the per-thread storage which ThreadIDs index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BucketKey(usize);
+
+impl BucketKey {
+    pub fn new(key: usize) -> Self {
+        Self(key)
+    }
+}
+
+impl From<ThreadID> for BucketKey {
+    fn from(source: ThreadID) -> Self {
+        Self(source.id)
+    }
+}
+
+impl From<BucketKey> for usize {
+    fn from(source: BucketKey) -> usize {
+        source.0
+    }
+}
+
+// Source of the bucket key of the current thread
+pub trait ThreadIdProvider {
+    fn load() -> BucketKey;
+}
+
+// Identifiers from ThreadID::load(), which are unique among live threads and
+// dense, but cost a thread-local lookup and are allocated under a global lock
+pub struct TlsCounter;
+
+impl ThreadIdProvider for TlsCounter {
+    fn load() -> BucketKey {
+        ThreadID::load().into()
+    }
+}
+
+// Index of the current thread in its rayon pool, or the above outside of
+// pools. Dense, but worker threads of different pools and threads outside of
+// pools share keys.
+#[cfg(all(feature = "rayon", not(loom)))]
+pub struct RayonIndex;
+
+#[cfg(all(feature = "rayon", not(loom)))]
+impl ThreadIdProvider for RayonIndex {
+    fn load() -> BucketKey {
+        match rayon::current_thread_index() {
+            Some(index) => BucketKey(index),
+            None => TlsCounter::load(),
+        }
+    }
+}
+
+// Hash of the identifier which the standard library gives to the current
+// thread. Unique in practice and never recycled, but sparse, so that threads
+// are spread over buckets at random instead of round-robin.
+#[cfg(not(loom))]
+pub struct OsThreadHash;
+
+#[cfg(not(loom))]
+thread_local! {
+    static OS_THREAD_HASH: usize = {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        hasher.finish() as usize
+    };
+}
+
+#[cfg(not(loom))]
+impl ThreadIdProvider for OsThreadHash {
+    fn load() -> BucketKey {
+        BucketKey(OS_THREAD_HASH.with(|&hash| hash))
+    }
+}
+
+// Index of the CPU which the current thread is running on, see CpuId. Dense,
+// but shared by all threads which run on a CPU, and only valid until the
+// thread migrates.
+pub struct CurrentCpu;
+
+impl ThreadIdProvider for CurrentCpu {
+    fn load() -> BucketKey {
+        BucketKey(CpuId::load().into())
+    }
+}
+
+// Index of the NUMA node which the current thread is running on, see NumaNode.
+// Threads which run on the same node share keys.
+pub struct CurrentNode;
+
+impl ThreadIdProvider for CurrentNode {
+    fn load() -> BucketKey {
+        BucketKey(NumaNode::load().into())
+    }
+}
+
+// Index of a CPU, as numbered by the operating system
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CpuId(usize);
+
+impl CpuId {
+    // CPU which the current thread is running on. The thread may migrate to
+    // another CPU at any time, so this is only a hint.
+    //
+    // On Linux, recent versions of glibc implement sched_getcpu() by reading
+    // the CPU index that the kernel keeps up to date in the thread's rseq area,
+    // without a system call. Elsewhere, or if this fails, the thread ID is
+    // returned instead, so that threads are still spread over CPU-indexed
+    // storage.
+    pub fn load() -> Self {
+        Self(topology::current_cpu())
+    }
+}
+
+impl From<CpuId> for usize {
+    fn from(source: CpuId) -> usize {
+        source.0
+    }
+}
+
+// Index of a NUMA node, as numbered by the operating system
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NumaNode(usize);
+
+impl NumaNode {
+    // NUMA node of the CPU which the current thread is running on, which is
+    // only a hint as for CpuId::load().
+    //
+    // On Linux, the node of each CPU is queried using the getcpu system call
+    // the first time a thread runs on it, and cached, so that later lookups
+    // only cost a sched_getcpu(). Elsewhere, or if this fails, every CPU is
+    // assumed to be on node 0.
+    pub fn load() -> Self {
+        Self(topology::current_node())
+    }
+}
+
+impl From<NumaNode> for usize {
+    fn from(source: NumaNode) -> usize {
+        source.0
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "libc", not(any(loom, miri))))]
+mod topology {
+    use {
+        super::ThreadID,
+        std::{
+            ptr,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                OnceLock,
+            },
+        },
+    };
+
+    // NUMA node of each CPU, or UNKNOWN if no thread ran on that CPU yet
+    static CPU_NODES: OnceLock<Box<[AtomicUsize]>> = OnceLock::new();
+    const UNKNOWN: usize = usize::MAX;
+
+    pub(super) fn current_cpu() -> usize {
+        match unsafe { libc::sched_getcpu() } {
+            cpu if cpu >= 0 => cpu as usize,
+            _ => usize::from(ThreadID::load()),
+        }
+    }
+
+    pub(super) fn current_node() -> usize {
+        let cpu_nodes = CPU_NODES.get_or_init(|| {
+            let num_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1);
+            (0..num_cpus).map(|_| AtomicUsize::new(UNKNOWN)).collect()
+        });
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu < 0 {
+            return 0;
+        }
+        if let Some(node) = cpu_nodes.get(cpu as usize) {
+            let node = node.load(Ordering::Relaxed);
+            if node != UNKNOWN {
+                return node;
+            }
+        }
+
+        // The thread may have migrated since sched_getcpu(), so the node is
+        // cached for the CPU which getcpu reports along with it
+        let (mut cpu, mut node): (libc::c_uint, libc::c_uint) = (0, 0);
+        let result = unsafe {
+            libc::syscall(libc::SYS_getcpu, &mut cpu, &mut node, ptr::null_mut::<libc::c_void>())
+        };
+        if result != 0 {
+            return 0;
+        }
+        if let Some(cached) = cpu_nodes.get(cpu as usize) {
+            cached.store(node as usize, Ordering::Relaxed);
+        }
+        node as usize
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "libc", not(any(loom, miri)))))]
+mod topology {
+    use super::ThreadID;
+
+    pub(super) fn current_cpu() -> usize {
+        usize::from(ThreadID::load())
+    }
+
+    pub(super) fn current_node() -> usize {
+        0
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_smallest_free_id() {
+        let mut allocator = IdAllocator::new();
+        assert_eq!((0..3).map(|_| allocator.allocate()).collect::<Vec<_>>(), [0, 1, 2]);
+        allocator.release(1);
+        allocator.release(0);
+        assert_eq!((0..3).map(|_| allocator.allocate()).collect::<Vec<_>>(), [0, 1, 3]);
+    }
+
+    #[test]
+    fn claim_preferred_id_if_free() {
+        let mut allocator = IdAllocator::new();
+        assert_eq!(allocator.allocate(), 0);
+        assert_eq!(allocator.claim(2, 4), 2);
+        assert_eq!(allocator.claim(0, 4), 4);
+        assert_eq!(allocator.claim(1, 4), 1);
+        assert_eq!(allocator.allocate(), 3);
+        assert_eq!(allocator.allocate(), 5);
+    }
+
+    #[test]
+    fn list_ids_in_set() {
+        let set = IdSet::new();
+        for id in [130, 0, 3, 130, 64] {
+            set.insert(BucketKey(id));
+        }
+        assert_eq!(set.iter().unwrap().collect::<Vec<_>>(), [0, 3, 64, 130]);
+        set.insert(BucketKey(usize::MAX));
+        assert!(set.iter().is_none());
+        let id = usize::from(ThreadID::load());
+        assert!(live_ids().contains(&id));
+    }
+
+    #[test]
+    fn bound_registered_ids() {
+        let registry = ThreadRegistry::new(2);
+        let first = registry.register().unwrap();
+        let second = registry.register().unwrap();
+        assert_eq!(registry.register().err(), Some(RegistryFull { max_threads: 2 }));
+        drop(first);
+        assert_eq!(usize::from(registry.register_blocking().id()), 0);
+        assert_eq!(usize::from(second.id()), 1);
+    }
+}
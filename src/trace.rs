@@ -0,0 +1,63 @@
+// Spans of the tracing crate, for correlating benchmark runs with profilers and
+// timeline viewers
+//
+// When the "tracing" feature is enabled, the fills, flushes, merges and final
+// readouts of the benchmark harness, the lock acquisitions which contention
+// telemetry counts, and the summing of per-thread replicas are wrapped in
+// TRACE-level spans. Spans carry the type of the histogram, which tells apart
+// the implementations in a run of several strategies. Without the feature, the
+// wrapped operations are called directly, so tracing costs nothing.
+//
+// Spans are created whether or not a subscriber is interested in them, which
+// tracing makes cheap but not free, so benchmark results should be compared
+// with tracing enabled on both sides.
+
+#[cfg(feature = "tracing")]
+use core::any;
+
+// A batch of `num_values` values filling a histogram of type `H`
+#[inline]
+#[allow(dead_code, unused_variables, clippy::extra_unused_type_parameters)]
+pub(crate) fn fill<H: ?Sized, R>(num_values: usize, fill: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("fill", histogram = any::type_name::<H>(), num_values)
+                    .entered();
+    fill()
+}
+
+// Completion of the fills of a histogram of type `H`
+#[inline]
+#[allow(dead_code, clippy::extra_unused_type_parameters)]
+pub(crate) fn flush<H: ?Sized, R>(flush: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("flush", histogram = any::type_name::<H>()).entered();
+    flush()
+}
+
+// Merge of bins into a histogram of type `H`
+#[inline]
+#[allow(dead_code, unused_variables, clippy::extra_unused_type_parameters)]
+pub(crate) fn merge<H: ?Sized, R>(num_bins: usize, merge: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("merge", histogram = any::type_name::<H>(), num_bins)
+                    .entered();
+    merge()
+}
+
+// Production of the bins of a histogram, or of its replicas of type `H`
+#[inline]
+#[allow(dead_code, clippy::extra_unused_type_parameters)]
+pub(crate) fn aggregate<H: ?Sized, R>(aggregate: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("aggregate", histogram = any::type_name::<H>()).entered();
+    aggregate()
+}
+
+// Acquisition of a lock, whose guard is of type `G`
+#[inline]
+#[allow(dead_code)]
+pub(crate) fn lock<G>(acquire: impl FnOnce() -> G) -> G {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("lock", guard = any::type_name::<G>()).entered();
+    acquire()
+}
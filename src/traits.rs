@@ -1,4 +1,7 @@
+#[cfg(feature = "std")]
 use crate::thread_id::ThreadID;
+use crate::{impls::ToyHistogram, scoped::ScopedFiller};
+use {alloc::{boxed::Box, vec::Vec}, core::slice};
 
 // Trait that any histogram must implement
 //
@@ -6,41 +9,260 @@ use crate::thread_id::ThreadID;
 // microbenchmarking synchronization strategies, so it's okay to restrict
 // ourselves to 1D histogram for the purpose of demonstration.
 //
+// Both traits can be used as trait objects, e.g. to pick an implementation at
+// runtime, as their only generic methods are restricted to sized types. Boxed
+// thread-safe histograms are thread-safe histograms themselves.
+//
 pub trait Histogram {
     // Insert a set of values into the histogram
     fn fill_mut(&mut self, values: &[f32]);
 
+    // Insert values which are not stored in a slice, such as values which are
+    // generated on the fly, without collecting them into a buffer first. By
+    // default, they are gathered into batches of ITER_BATCH_SIZE on the stack.
+    fn fill_iter_mut(&mut self, values: impl IntoIterator<Item = f32>) where Self: Sized {
+        fill_batched(values, |batch| self.fill_mut(batch))
+    }
+
+    // Insert a single value, as call sites which fill one value per event do
+    fn fill_one_mut(&mut self, value: f32) {
+        self.fill_mut(slice::from_ref(&value))
+    }
+
     // If the ID of the active thread is known, some implementations can use it
     // for optimization purposes by overriding this method
+    #[cfg(feature = "std")]
     fn fill_with_id_mut(&mut self, values: &[f32], _id: ThreadID) {
         self.fill_mut(values)
     }
 
     fn num_hits(&self) -> usize;
+
+    // Read out the (aggregated) contents of every bin of the histogram
+    fn bins(&self) -> Vec<usize>;
+
+    // Add the bin contents of another histogram with the same binning
+    fn merge_bins_mut(&mut self, bins: &[usize]);
+
+    // Number of bytes of memory used by the histogram, including replicas
+    fn memory_usage(&self) -> usize;
+
+    // Make sure that every previous fill is accounted for in the bins, for
+    // implementations which buffer or delegate fills (see SyncHistogram)
+    fn flush_mut(&mut self) {}
+
+    // Number of distinct threads which filled the histogram, for
+    // implementations which keep track of it (see SyncHistogram)
+    fn num_threads(&self) -> Option<usize> {
+        None
+    }
 }
 
 // Thread-safe version of Histogram that can be filled in parallel
 pub trait SyncHistogram: Sync {
     fn fill(&self, values: &[f32]);
 
+    // Same as Histogram::fill_iter_mut. Implementations where each value costs
+    // the same synchronization anyway can skip batching by overriding this.
+    fn fill_iter(&self, values: impl IntoIterator<Item = f32>) where Self: Sized {
+        fill_batched(values, |batch| self.fill(batch))
+    }
+
+    // Same as Histogram::fill_one_mut. By default, this is a fill with a batch
+    // of one value, and implementations which can skip some of the work of a
+    // batched fill override it.
+    fn fill_one(&self, value: f32) {
+        self.fill(slice::from_ref(&value))
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_one_with_id(&self, value: f32, id: ThreadID) {
+        self.fill_with_id(slice::from_ref(&value), id)
+    }
+
+    #[cfg(feature = "std")]
     fn fill_with_id(&self, values: &[f32], _id: ThreadID) {
         self.fill(values)
     }
 
     fn num_hits(&self) -> usize;
+
+    fn bins(&self) -> Vec<usize>;
+
+    fn merge_bins(&self, bins: &[usize]);
+
+    fn memory_usage(&self) -> usize;
+
+    // Make sure that every fill which returned before this call is accounted
+    // for in the bins, and wait for it to be if needed
+    //
+    // Implementations which stage values in buffers, or hand them over to
+    // another thread or device, may return from fills before the bins are
+    // updated. Their readouts flush implicitly, but calling this first tells
+    // apart the cost of finishing the fills from that of the readout, and
+    // makes sure that the former is not left out of measurements. Other
+    // implementations have nothing to do, except AggregatedHistogram, whose
+    // readouts lag behind fills until it is flushed.
+    fn flush(&self) {}
+
+    // Copy of the bins as they were at one point in time during the call
+    //
+    // bins() and num_hits() may read some buckets or shards of a histogram
+    // before a concurrent fill and others after it, so that their result does
+    // not match any state that the histogram was ever in. Snapshots do, for
+    // the strategies which can provide them. Where fills update the bins
+    // under a lock, as a whole batch, each concurrent fill is then either
+    // fully accounted for or not at all.
+    //
+    // By default, this is a copy of bins(), which is enough for strategies
+    // where it already reads the bins under a single lock or from a
+    // consistent generation of bins (locks, seqlock, epoch, double buffering,
+    // delegation to a thread...). Bucketized strategies lock every bucket at
+    // once instead. Strategies where concurrent fills increment atomic bins
+    // cannot be stopped, so their snapshots are only consistent in the absence
+    // of concurrent fills.
+    fn snapshot(&self) -> ToyHistogram {
+        ToyHistogram::from_bins(self.bins())
+    }
+
+    // Number of distinct threads which filled the histogram, or merged bins
+    // into it, for the implementations which keep track of it
+    //
+    // Bucketized implementations record which thread IDs used their buckets,
+    // so that readouts can skip the buckets which no thread used. As thread
+    // IDs are recycled, threads which do not live at the same time may count
+    // as one.
+    fn num_threads(&self) -> Option<usize> {
+        None
+    }
+
+    // When the histogram is not shared, some implementations can skip
+    // synchronization by overriding this method, which sequential fills use
+    fn fill_exclusive(&mut self, values: &[f32]) {
+        self.fill(values)
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_with_id_exclusive(&mut self, values: &[f32], id: ThreadID) {
+        self.fill_with_id(values, id)
+    }
+
+    // Fill a private copy of the bins, which is merged into this histogram when
+    // the returned handle is dropped (see the scoped module)
+    fn fill_scope(&self) -> ScopedFiller<'_, Self> where Self: Sized {
+        ScopedFiller::new(self)
+    }
 }
 
-// Any thread-safe histogram can be used sequentially
-impl<T: SyncHistogram> Histogram for T {
+// Any thread-safe histogram can be used sequentially, including trait objects
+impl<T: SyncHistogram + ?Sized> Histogram for T {
     fn fill_mut(&mut self, values: &[f32]) {
-        self.fill(values)
+        self.fill_exclusive(values)
     }
 
+    #[cfg(feature = "std")]
     fn fill_with_id_mut(&mut self, values: &[f32], id: ThreadID) {
-        self.fill_with_id(values, id)
+        self.fill_with_id_exclusive(values, id)
     }
 
     fn num_hits(&self) -> usize {
-        <T as SyncHistogram>::num_hits(&self)
+        <T as SyncHistogram>::num_hits(self)
     }
-}
\ No newline at end of file
+
+    fn bins(&self) -> Vec<usize> {
+        <T as SyncHistogram>::bins(self)
+    }
+
+    fn merge_bins_mut(&mut self, bins: &[usize]) {
+        self.merge_bins(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        <T as SyncHistogram>::memory_usage(self)
+    }
+
+    fn flush_mut(&mut self) {
+        self.flush()
+    }
+
+    fn num_threads(&self) -> Option<usize> {
+        <T as SyncHistogram>::num_threads(self)
+    }
+}
+
+impl<T: SyncHistogram + ?Sized> SyncHistogram for Box<T> {
+    fn fill(&self, values: &[f32]) {
+        (**self).fill(values)
+    }
+
+    fn fill_one(&self, value: f32) {
+        (**self).fill_one(value)
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_one_with_id(&self, value: f32, id: ThreadID) {
+        (**self).fill_one_with_id(value, id)
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_with_id(&self, values: &[f32], id: ThreadID) {
+        (**self).fill_with_id(values, id)
+    }
+
+    fn num_hits(&self) -> usize {
+        (**self).num_hits()
+    }
+
+    fn bins(&self) -> Vec<usize> {
+        SyncHistogram::bins(&**self)
+    }
+
+    fn merge_bins(&self, bins: &[usize]) {
+        (**self).merge_bins(bins)
+    }
+
+    fn memory_usage(&self) -> usize {
+        SyncHistogram::memory_usage(&**self)
+    }
+
+    fn flush(&self) {
+        (**self).flush()
+    }
+
+    fn snapshot(&self) -> ToyHistogram {
+        (**self).snapshot()
+    }
+
+    fn num_threads(&self) -> Option<usize> {
+        SyncHistogram::num_threads(&**self)
+    }
+
+    fn fill_exclusive(&mut self, values: &[f32]) {
+        (**self).fill_exclusive(values)
+    }
+
+    #[cfg(feature = "std")]
+    fn fill_with_id_exclusive(&mut self, values: &[f32], id: ThreadID) {
+        (**self).fill_with_id_exclusive(values, id)
+    }
+}
+
+// Number of values which fill_iter() and fill_iter_mut() insert at once
+pub(crate) const ITER_BATCH_SIZE: usize = 256;
+
+// Hand values over to `fill` in batches of ITER_BATCH_SIZE
+fn fill_batched(values: impl IntoIterator<Item = f32>, mut fill: impl FnMut(&[f32])) {
+    let mut batch = [0.0; ITER_BATCH_SIZE];
+    let mut len = 0;
+    for value in values {
+        batch[len] = value;
+        len += 1;
+        if len == ITER_BATCH_SIZE {
+            fill(&batch);
+            len = 0;
+        }
+    }
+    if len > 0 {
+        fill(&batch[..len]);
+    }
+}
@@ -0,0 +1,436 @@
+// Property-based tests checking the invariants that every histogram
+// implementation must uphold, using arbitrary inputs and configurations
+//
+// Implementations whose cargo feature is disabled are not tested, which leaves
+// some of the generated parameters unused.
+#![cfg_attr(not(feature = "all_strategies"), allow(unused))]
+
+use parallel_histograms::{
+    impls::*,
+    thread_id::*,
+    traits::*,
+};
+use proptest::{prelude::*, test_runner::Config};
+#[cfg(feature = "numa")]
+use parallel_histograms::numa::Placement;
+#[cfg(any(feature = "mutex", feature = "thread_bucketized"))]
+use std::sync::Mutex;
+#[cfg(feature = "rwlock")]
+use std::sync::RwLock;
+use std::thread;
+
+// Batches of values from the histogram axis
+fn batches() -> impl Strategy<Value = Vec<Vec<f32>>> {
+    prop::collection::vec(prop::collection::vec(0.0f32..1.0, 0..50), 0..20)
+}
+
+// Bin contents of a ToyHistogram filled sequentially with some batches, whose
+// values are first mapped to positions on its axis
+fn reference_bins(num_bins: usize,
+                  position: impl Fn(f32) -> f32,
+                  batches: &[Vec<f32>]) -> Vec<usize> {
+    let mut histogram = ToyHistogram::new(num_bins);
+    for batch in batches {
+        histogram.fill_mut(&batch.iter().map(|&value| position(value)).collect::<Vec<_>>());
+    }
+    histogram.bins()
+}
+
+// Values of uniform histograms are positions on their axis already
+fn uniform(value: f32) -> f32 {
+    value
+}
+
+// Range of the values of exponential histograms
+const EXPONENTIAL_MIN: f32 = 1e-3;
+const EXPONENTIAL_MAX: f32 = 10.0;
+
+// Values spread across the range of exponential histograms like the given ones
+// are across the histogram axis
+fn exponential_values(batches: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let growth = EXPONENTIAL_MAX / EXPONENTIAL_MIN;
+    batches.iter()
+        .map(|batch| batch.iter().map(|&value| EXPONENTIAL_MIN * growth.powf(value)).collect())
+        .collect()
+}
+
+// Position of a value on the axis of the histogram underlying an exponential
+// histogram, computed like the exponential histogram does, so that values which
+// round to a neighbouring bin do so on both sides
+fn exponential(value: f32) -> f32 {
+    let inv_log_range = 1.0 / (EXPONENTIAL_MAX / EXPONENTIAL_MIN).ln();
+    (value.ln() - EXPONENTIAL_MIN.ln()) * inv_log_range
+}
+
+fn num_values(batches: &[Vec<f32>]) -> usize {
+    batches.iter().map(Vec::len).sum()
+}
+
+// Fill a histogram sequentially, then merge the contents of another one
+fn check_sequential(histogram: impl Histogram,
+                    num_bins: usize,
+                    batches: &[Vec<f32>],
+                    merged: &[Vec<f32>]) -> Result<(), TestCaseError> {
+    check_sequential_on(histogram, num_bins, uniform, batches, merged)
+}
+
+// Same, for a histogram which maps values to the given positions on its axis
+fn check_sequential_on(mut histogram: impl Histogram,
+                       num_bins: usize,
+                       position: fn(f32) -> f32,
+                       batches: &[Vec<f32>],
+                       merged: &[Vec<f32>]) -> Result<(), TestCaseError> {
+    for batch in batches {
+        histogram.fill_mut(batch);
+    }
+    histogram.flush_mut();
+    prop_assert_eq!(histogram.num_hits(), num_values(batches));
+    prop_assert_eq!(histogram.bins(), reference_bins(num_bins, position, batches));
+
+    let merged_bins = reference_bins(num_bins, position, merged);
+    histogram.merge_bins_mut(&merged_bins);
+    histogram.flush_mut();
+    prop_assert_eq!(histogram.num_hits(), num_values(batches) + num_values(merged));
+    let expected = reference_bins(num_bins, position, batches).iter()
+        .zip(&merged_bins)
+        .map(|(a, b)| a + b)
+        .collect::<Vec<_>>();
+    prop_assert_eq!(histogram.bins(), expected);
+    Ok(())
+}
+
+// Fill a histogram from several threads, each inserting a share of the batches,
+// either as a whole or one value at a time
+fn check_parallel(histogram: impl SyncHistogram,
+                  num_bins: usize,
+                  num_threads: usize,
+                  batches: &[Vec<f32>]) -> Result<(), TestCaseError> {
+    check_parallel_on(histogram, num_bins, uniform, num_threads, batches)
+}
+
+// Same, for a histogram which maps values to the given positions on its axis
+fn check_parallel_on(histogram: impl SyncHistogram,
+                     num_bins: usize,
+                     position: fn(f32) -> f32,
+                     num_threads: usize,
+                     batches: &[Vec<f32>]) -> Result<(), TestCaseError> {
+    let histogram = &histogram;
+    thread::scope(|s| {
+        for thread_idx in 0..num_threads {
+            s.spawn(move || {
+                for batch in batches.iter().skip(thread_idx).step_by(num_threads) {
+                    if thread_idx % 2 == 0 {
+                        histogram.fill(batch);
+                    } else {
+                        batch.iter().for_each(|&value| histogram.fill_one(value));
+                    }
+                }
+            });
+        }
+    });
+    histogram.flush();
+    prop_assert_eq!(histogram.num_hits(), num_values(batches));
+    prop_assert_eq!(histogram.bins(), reference_bins(num_bins, position, batches));
+    Ok(())
+}
+
+// Fill a histogram, then check that a clone of it has the same bins and does
+// not share them with the original
+fn check_clone<H: SyncHistogram + Clone + PartialEq>(histogram: H,
+                                                     num_bins: usize,
+                                                     batches: &[Vec<f32>],
+                                                     more: &[Vec<f32>]) -> Result<(), TestCaseError> {
+    check_clone_on(histogram, num_bins, uniform, batches, more)
+}
+
+// Same, for a histogram which maps values to the given positions on its axis
+fn check_clone_on<H: SyncHistogram + Clone + PartialEq>(histogram: H,
+                                                        num_bins: usize,
+                                                        position: fn(f32) -> f32,
+                                                        batches: &[Vec<f32>],
+                                                        more: &[Vec<f32>]) -> Result<(), TestCaseError> {
+    for batch in batches {
+        histogram.fill(batch);
+    }
+    histogram.flush();
+    let clone = histogram.clone();
+    prop_assert!(clone == histogram);
+    prop_assert_eq!(SyncHistogram::bins(&clone), reference_bins(num_bins, position, batches));
+    for batch in more {
+        clone.fill(batch);
+    }
+    clone.flush();
+    prop_assert_eq!(clone == histogram, num_values(more) == 0);
+    prop_assert_eq!(SyncHistogram::bins(&histogram), reference_bins(num_bins, position, batches));
+    Ok(())
+}
+
+// Miri is orders of magnitude slower than native execution, and isolates the
+// tests from the filesystem where failing cases would be persisted
+fn config() -> Config {
+    if cfg!(miri) {
+        Config { cases: 4, failure_persistence: None, ..Config::default() }
+    } else {
+        Config::default()
+    }
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn sequential(num_bins in 1usize..1000,
+                  num_buckets in 1usize..8,
+                  bins_per_line_log2 in 0u32..4,
+                  batches in batches(),
+                  merged in batches()) {
+        check_sequential(ToyHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "atomic")]
+        check_sequential(AtomicHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "padded_atomic")]
+        check_sequential(PaddedAtomicHistogram::with_bins_per_line(num_bins, 1 << bins_per_line_log2),
+                         num_bins, &batches, &merged)?;
+        #[cfg(feature = "mutex")]
+        check_sequential(Mutex::new(ToyHistogram::new(num_bins)), num_bins, &batches, &merged)?;
+        #[cfg(feature = "rwlock")]
+        check_sequential(RwLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &merged)?;
+        #[cfg(feature = "parking_lot")]
+        check_sequential(parking_lot::Mutex::new(ToyHistogram::new(num_bins)),
+                         num_bins, &batches, &merged)?;
+        #[cfg(feature = "parking_lot")]
+        check_sequential(parking_lot::RwLock::new(ToyHistogram::new(num_bins)),
+                         num_bins, &batches, &merged)?;
+        #[cfg(feature = "spinlock")]
+        check_sequential(SpinLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &merged)?;
+        #[cfg(feature = "ticket_lock")]
+        check_sequential(TicketLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &merged)?;
+        #[cfg(feature = "mcs_lock")]
+        check_sequential(McsLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &merged)?;
+        #[cfg(feature = "tsx")]
+        check_sequential(TsxHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "seqlock")]
+        check_sequential(SeqlockHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "epoch")]
+        check_sequential(EpochHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "double_buffer")]
+        check_sequential(DoubleBufferedHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "bin_sharded")]
+        check_sequential(BinShardedHistogram::new(num_bins, num_buckets),
+                         num_bins, &batches, &merged)?;
+        #[cfg(feature = "flat_combining")]
+        check_sequential(FlatCombiningHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "channel")]
+        check_sequential(ChannelHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "ring_buffer")]
+        check_sequential(RingBufferHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "thread_bucketized")]
+        check_sequential(ThreadBucketizedHistogram::new(num_bins, num_buckets),
+                         num_bins, &batches, &merged)?;
+        #[cfg(feature = "per_core")]
+        check_sequential(PerCoreHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "rseq")]
+        check_sequential(RseqHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "narrow_atomic")]
+        check_sequential(AtomicU16Histogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "narrow_atomic")]
+        check_sequential(AtomicU32Histogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "sorted")]
+        check_sequential(SortedAtomicHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "tls")]
+        check_sequential(TlsHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "buffered")]
+        check_sequential(BufferedHistogram::with_threshold(AtomicHistogram::new(num_bins), 16),
+                         num_bins, &batches, &merged)?;
+        #[cfg(feature = "sparse")]
+        check_sequential(DashMapHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "sparse")]
+        check_sequential(SparseThreadLocalHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "lazy")]
+        check_sequential(LazyAtomicHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "lazy")]
+        check_sequential(LazyThreadLocalHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "adaptive")]
+        check_sequential(AdaptiveHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "two_level")]
+        check_sequential(TwoLevelHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(feature = "thread_local")]
+        check_sequential(ThreadLocalHistogram::new(num_bins), num_bins, &batches, &merged)?;
+        #[cfg(all(feature = "aggregated", feature = "thread_local"))]
+        check_sequential(AggregatedHistogram::new(ThreadLocalHistogram::new(num_bins)),
+                         num_bins, &batches, &merged)?;
+        #[cfg(all(feature = "exponential", feature = "atomic"))]
+        check_sequential_on(ExponentialHistogram::new(EXPONENTIAL_MIN, EXPONENTIAL_MAX, num_bins,
+                                                      AtomicHistogram::new),
+                            num_bins, exponential,
+                            &exponential_values(&batches), &exponential_values(&merged))?;
+    }
+
+    #[test]
+    fn parallel(num_bins in 1usize..1000,
+                num_buckets in 1usize..8,
+                bins_per_line_log2 in 0u32..4,
+                num_threads in 1usize..8,
+                batches in batches()) {
+        #[cfg(feature = "atomic")]
+        check_parallel(AtomicHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "padded_atomic")]
+        check_parallel(PaddedAtomicHistogram::with_bins_per_line(num_bins, 1 << bins_per_line_log2),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "mutex")]
+        check_parallel(Mutex::new(ToyHistogram::new(num_bins)), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "rwlock")]
+        check_parallel(RwLock::new(ToyHistogram::new(num_bins)), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "parking_lot")]
+        check_parallel(parking_lot::Mutex::new(ToyHistogram::new(num_bins)),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "parking_lot")]
+        check_parallel(parking_lot::RwLock::new(ToyHistogram::new(num_bins)),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "spinlock")]
+        check_parallel(SpinLock::new(ToyHistogram::new(num_bins)), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "ticket_lock")]
+        check_parallel(TicketLock::new(ToyHistogram::new(num_bins)), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "mcs_lock")]
+        check_parallel(McsLock::new(ToyHistogram::new(num_bins)), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "tsx")]
+        check_parallel(TsxHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "seqlock")]
+        check_parallel(SeqlockHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "epoch")]
+        check_parallel(EpochHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "double_buffer")]
+        check_parallel(DoubleBufferedHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "bin_sharded")]
+        check_parallel(BinShardedHistogram::new(num_bins, num_buckets),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "flat_combining")]
+        check_parallel(FlatCombiningHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "flat_combining")]
+        check_parallel(FlatCombiningHistogram::<OsThreadHash>::with_provider(num_bins),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "channel")]
+        check_parallel(ChannelHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "ring_buffer")]
+        check_parallel(RingBufferHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "thread_bucketized")]
+        check_parallel(ThreadBucketizedHistogram::new(num_bins, num_buckets),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "thread_bucketized")]
+        check_parallel(ThreadBucketizedHistogram::<Mutex<ToyHistogram>, CurrentCpu>::with_locks(
+                           num_bins, num_buckets),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(all(feature = "numa", feature = "thread_bucketized"))]
+        check_parallel(ThreadBucketizedHistogram::<Mutex<ToyHistogram>>::with_placement(
+                           num_bins, num_buckets, Placement::Local),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "per_core")]
+        check_parallel(PerCoreHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "rseq")]
+        check_parallel(RseqHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "narrow_atomic")]
+        check_parallel(AtomicU16Histogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "narrow_atomic")]
+        check_parallel(AtomicU32Histogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "sorted")]
+        check_parallel(SortedAtomicHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "tls")]
+        check_parallel(TlsHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "buffered")]
+        check_parallel(BufferedHistogram::with_threshold(AtomicHistogram::new(num_bins), 16),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(feature = "sparse")]
+        check_parallel(DashMapHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "sparse")]
+        check_parallel(SparseThreadLocalHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "lazy")]
+        check_parallel(LazyAtomicHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "lazy")]
+        check_parallel(LazyThreadLocalHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "adaptive")]
+        check_parallel(AdaptiveHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "two_level")]
+        check_parallel(TwoLevelHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(feature = "thread_local")]
+        check_parallel(ThreadLocalHistogram::new(num_bins), num_bins, num_threads, &batches)?;
+        #[cfg(all(feature = "aggregated", feature = "thread_local"))]
+        check_parallel(AggregatedHistogram::new(ThreadLocalHistogram::new(num_bins)),
+                       num_bins, num_threads, &batches)?;
+        #[cfg(all(feature = "exponential", feature = "atomic"))]
+        check_parallel_on(ExponentialHistogram::new(EXPONENTIAL_MIN, EXPONENTIAL_MAX, num_bins,
+                                                    AtomicHistogram::new),
+                          num_bins, exponential, num_threads, &exponential_values(&batches))?;
+    }
+
+    #[test]
+    fn clones(num_bins in 1usize..1000,
+              num_buckets in 1usize..8,
+              bins_per_line_log2 in 0u32..4,
+              batches in batches(),
+              more in batches()) {
+        #[cfg(feature = "atomic")]
+        check_clone(AtomicHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "padded_atomic")]
+        check_clone(PaddedAtomicHistogram::with_bins_per_line(num_bins, 1 << bins_per_line_log2),
+                    num_bins, &batches, &more)?;
+        #[cfg(feature = "spinlock")]
+        check_clone(SpinLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &more)?;
+        #[cfg(feature = "ticket_lock")]
+        check_clone(TicketLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &more)?;
+        #[cfg(feature = "mcs_lock")]
+        check_clone(McsLock::new(ToyHistogram::new(num_bins)), num_bins, &batches, &more)?;
+        #[cfg(feature = "tsx")]
+        check_clone(TsxHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "seqlock")]
+        check_clone(SeqlockHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "epoch")]
+        check_clone(EpochHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "double_buffer")]
+        check_clone(DoubleBufferedHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "bin_sharded")]
+        check_clone(BinShardedHistogram::new(num_bins, num_buckets), num_bins, &batches, &more)?;
+        #[cfg(feature = "flat_combining")]
+        check_clone(FlatCombiningHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "channel")]
+        check_clone(ChannelHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "ring_buffer")]
+        check_clone(RingBufferHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "thread_bucketized")]
+        check_clone(ThreadBucketizedHistogram::new(num_bins, num_buckets),
+                    num_bins, &batches, &more)?;
+        #[cfg(feature = "per_core")]
+        check_clone(PerCoreHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "rseq")]
+        check_clone(RseqHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "narrow_atomic")]
+        check_clone(AtomicU16Histogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "sorted")]
+        check_clone(SortedAtomicHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "tls")]
+        check_clone(TlsHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "buffered")]
+        check_clone(BufferedHistogram::with_threshold(AtomicHistogram::new(num_bins), 16),
+                    num_bins, &batches, &more)?;
+        #[cfg(feature = "sparse")]
+        check_clone(DashMapHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "sparse")]
+        check_clone(SparseThreadLocalHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "lazy")]
+        check_clone(LazyAtomicHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "lazy")]
+        check_clone(LazyThreadLocalHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "adaptive")]
+        check_clone(AdaptiveHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "two_level")]
+        check_clone(TwoLevelHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(feature = "thread_local")]
+        check_clone(ThreadLocalHistogram::new(num_bins), num_bins, &batches, &more)?;
+        #[cfg(all(feature = "aggregated", feature = "thread_local"))]
+        check_clone(AggregatedHistogram::new(ThreadLocalHistogram::new(num_bins)),
+                    num_bins, &batches, &more)?;
+        #[cfg(all(feature = "exponential", feature = "atomic"))]
+        check_clone_on(ExponentialHistogram::new(EXPONENTIAL_MIN, EXPONENTIAL_MAX, num_bins,
+                                                 AtomicHistogram::new),
+                       num_bins, exponential,
+                       &exponential_values(&batches), &exponential_values(&more))?;
+    }
+}